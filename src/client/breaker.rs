@@ -0,0 +1,124 @@
+use std::{collections::HashMap, time::Duration};
+
+use simplelog::{info, warn};
+use tokio::time::Instant;
+
+/// Stops connecting to an upstream that keeps failing, refusing connections
+/// for it right away until `cooldown` has passed, then lets a single attempt
+/// through to find out whether it is back
+pub struct Breaker {
+  threshold: u32,
+  cooldown: Duration,
+  /// Failed connects in a row
+  failures: u32,
+  /// When the breaker opened, while it is open
+  opened: Option<Instant>,
+  /// Whether the one attempt let through after the cooldown is still out
+  probing: bool,
+}
+
+impl Breaker {
+  pub fn new(threshold: u32, cooldown: Duration) -> Self {
+    Breaker {
+      threshold,
+      cooldown,
+      failures: 0,
+      opened: None,
+      probing: false,
+    }
+  }
+
+  #[cfg(test)]
+  pub fn is_open(&self) -> bool {
+    self.opened.is_some()
+  }
+
+  /// Whether a connect may be tried at `now`. Once the cooldown is over the
+  /// breaker half-opens, letting one attempt through until it is recorded.
+  pub fn allow(&mut self, now: Instant) -> bool {
+    match self.opened {
+      | None => true,
+      | Some(opened) if now.duration_since(opened) < self.cooldown => false,
+      | Some(_) if self.probing => false,
+      | Some(_) => {
+        self.probing = true;
+        true
+      },
+    }
+  }
+
+  /// Records a failed connect to `upstream`, opening the breaker once there
+  /// have been `threshold` of them in a row or the probe after a cooldown
+  /// failed
+  pub fn record_failure(&mut self, upstream: &str, now: Instant) {
+    self.failures = self.failures.saturating_add(1);
+    if self.probing || self.failures >= self.threshold {
+      if !self.probing {
+        warn!(
+          "Refusing connections to {upstream} for {}ms after {} failed connects",
+          self.cooldown.as_millis(),
+          self.failures
+        );
+      }
+      self.opened = Some(now);
+      self.probing = false;
+    }
+  }
+
+  /// Records a successful connect to `upstream`, closing the breaker
+  pub fn record_success(&mut self, upstream: &str) {
+    if self.opened.is_some() {
+      info!("Upstream {upstream} is reachable again");
+    }
+    self.failures = 0;
+    self.opened = None;
+    self.probing = false;
+  }
+}
+
+/// A breaker per upstream address, none of them ever opening when there is
+/// no threshold
+pub struct Breakers {
+  threshold: Option<u32>,
+  cooldown: Duration,
+  breakers: HashMap<String, Breaker>,
+}
+
+impl Breakers {
+  pub fn new(threshold: Option<u32>, cooldown: Duration) -> Self {
+    Breakers {
+      threshold,
+      cooldown,
+      breakers: HashMap::new(),
+    }
+  }
+
+  /// Whether a connect to `upstream` may be tried at `now`
+  pub fn allow(&mut self, upstream: &str, now: Instant) -> bool {
+    match self.breakers.get_mut(upstream) {
+      | Some(breaker) => breaker.allow(now),
+      | None => true,
+    }
+  }
+
+  pub fn record_failure(&mut self, upstream: &str, now: Instant) {
+    let threshold = match self.threshold {
+      | Some(threshold) => threshold,
+      | None => return,
+    };
+    let cooldown = self.cooldown;
+    self
+      .breakers
+      .entry(upstream.to_string())
+      .or_insert_with(|| Breaker::new(threshold, cooldown))
+      .record_failure(upstream, now);
+  }
+
+  /// Forgets the failures of `upstream`, which only needs a breaker again
+  /// once it fails again
+  pub fn record_success(&mut self, upstream: &str) {
+    if let Some(mut breaker) = self.breakers.remove(upstream) {
+      breaker.record_success(upstream);
+    }
+  }
+}
@@ -1,21 +1,63 @@
 use std::{
   fs::File,
-  io::{BufReader, BufWriter, Read, Write},
+  io::{BufWriter, Write},
+  process::exit,
   time::{SystemTime, UNIX_EPOCH},
 };
 
 use once_cell::sync::Lazy;
-use proxy_router::constants::{
-  ConfigFile, Runtime, DEFAULT_THREAD_COUNT, SETTING_FILE_PATH,
+use proxy_router::{
+  constants::{
+    auth_from_env, prompt_auth, resolve_auth, ConfigFile, Runtime,
+    DEFAULT_BREAKER_COOLDOWN_MS, DEFAULT_CHANNEL_CAPACITY,
+    DEFAULT_HASH_ENCODING, DEFAULT_MAX_HEADER_BYTES,
+    DEFAULT_RECONNECT_BACKOFF_MAX_MS, DEFAULT_RECONNECT_BACKOFF_MS,
+    DEFAULT_THREAD_COUNT, DEFAULT_UPSTREAM_CONNECT_RETRIES, SETTING_FILE_PATH,
+  },
+  functions::{
+    deserialize_bytes, deserialize_separator, serialize_bytes,
+    serialize_separator, Endpoint, Forward, HashEncoding, PortSet,
+    PortSetError, Separator,
+  },
+  settings::{load_settings, LoadError, Settings},
+  websocket::Transport,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::{from_reader, to_string_pretty, Error};
+use serde_json::to_string_pretty;
 use simplelog::{debug, error, info, trace, warn};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Target {
   pub address: String,
   pub port: u16,
+  /// Prefix each new upstream connection with a PROXY protocol v1 header
+  /// carrying the address of the client that connected to the server
+  #[serde(default)]
+  pub proxy_protocol: bool,
+  /// Port the server listens on for this target, when it is not `port`; the
+  /// server is told where it is forwarded along with it
+  pub listen_port: Option<u16>,
+}
+
+impl Target {
+  /// The port the server listens on for this target
+  pub fn server_port(&self) -> u16 {
+    self.listen_port.unwrap_or(self.port)
+  }
+
+  /// What to ask the server for in the auth packet
+  pub fn forward(&self) -> Forward {
+    match self.listen_port {
+      | Some(port) => Forward {
+        port,
+        upstream: Some(Endpoint {
+          host: self.address.to_owned(),
+          port: self.port,
+        }),
+      },
+      | None => Forward::from(self.port),
+    }
+  }
 }
 
 pub trait ThreadType {
@@ -34,32 +76,132 @@ impl ThreadType for Runtime {
 #[allow(non_snake_case)]
 pub struct Config<T: ThreadType> {
   pub targets: Vec<Target>,
-  pub separator: String,
-  pub auth: String,
+  /// Written as the separator's bytes when it has control characters, and
+  /// read back from either that or a plain string
+  #[serde(
+    serialize_with = "serialize_separator",
+    deserialize_with = "deserialize_separator"
+  )]
+  pub separator: Separator,
+  /// Written as a string when it is printable text, and as its bytes
+  /// otherwise, so a secret need not be valid UTF-8
+  #[serde(
+    serialize_with = "serialize_bytes",
+    deserialize_with = "deserialize_bytes"
+  )]
+  pub auth: Vec<u8>,
   pub redirect_to: Target,
   pub threads: T::THREAD,
+  /// Upstream connections expected open at once; the upstream table is sized
+  /// for it up front
   pub concurrency: usize,
+  /// How many data packets may be queued for a single upstream before reading
+  /// from the server is paused
+  #[serde(default = "default_channel_capacity")]
+  pub channel_capacity: usize,
+  /// Longest packet header accepted from the server, in bytes
+  #[serde(default = "default_max_header_bytes")]
+  pub max_header_bytes: usize,
+  /// How long to wait for an upstream's name to resolve and for it to accept
+  /// a connection before giving up on that attempt, unbounded when unset
+  pub upstream_connect_timeout_ms: Option<u64>,
+  /// How many more times to look an upstream's name up and connect to it
+  /// when that fails in a way that may pass, waiting 100ms before the first
+  /// and twice as long before each after it. Giving up takes at most
+  /// `(upstream_connect_retries + 1) * upstream_connect_timeout_ms` plus
+  /// `100ms * (2^upstream_connect_retries - 1)`.
+  #[serde(default = "default_upstream_connect_retries")]
+  pub upstream_connect_retries: u32,
+  /// How long to wait for the server to answer our AUTH before treating it as
+  /// unreachable and connecting again, forever when unset
+  pub authtry_timeout_ms: Option<u64>,
+  /// How the hashes in data packets are written, `hex`, `base64` or `raw`,
+  /// until the server announces its own
+  #[serde(default = "default_hash_encoding")]
+  pub hash_encoding: HashEncoding,
+  /// How long to wait before connecting to the server again after the
+  /// control connection is lost, doubled after every failed attempt
+  #[serde(default = "default_reconnect_backoff_ms")]
+  pub reconnect_backoff_ms: u64,
+  /// Longest wait between attempts to connect to the server again
+  #[serde(default = "default_reconnect_backoff_max_ms")]
+  pub reconnect_backoff_max_ms: u64,
+  /// Failed connects in a row to an upstream after which connections for it
+  /// are refused without trying, for `breaker_cooldown_ms`; never when unset
+  pub breaker_threshold: Option<u32>,
+  /// How long an upstream's connections are refused once its breaker opens
+  #[serde(default = "default_breaker_cooldown_ms")]
+  pub breaker_cooldown_ms: u64,
+  /// How the control connection is carried, plain TCP or WebSocket messages
+  /// upgraded from a request for `path`, matching the server's
+  #[serde(default)]
+  pub transport: Transport,
+}
+
+fn default_channel_capacity() -> usize {
+  DEFAULT_CHANNEL_CAPACITY
+}
+
+fn default_max_header_bytes() -> usize {
+  DEFAULT_MAX_HEADER_BYTES
+}
+
+fn default_hash_encoding() -> HashEncoding {
+  DEFAULT_HASH_ENCODING
+}
+
+fn default_reconnect_backoff_ms() -> u64 {
+  DEFAULT_RECONNECT_BACKOFF_MS
+}
+
+fn default_reconnect_backoff_max_ms() -> u64 {
+  DEFAULT_RECONNECT_BACKOFF_MAX_MS
+}
+
+fn default_breaker_cooldown_ms() -> u64 {
+  DEFAULT_BREAKER_COOLDOWN_MS
+}
+
+fn default_upstream_connect_retries() -> u32 {
+  DEFAULT_UPSTREAM_CONNECT_RETRIES
 }
 
 pub static DEFAULT_SETTINGS: Lazy<Config<ConfigFile>> = Lazy::new(|| Config {
-  auth: String::from("CH4ng3M3!"),
-  separator: String::from("\u{0000}"),
+  auth: b"CH4ng3M3!".to_vec(),
+  separator: Separator::default(),
   redirect_to: Target {
     address: String::from("0.0.0.0"),
     port: 65535,
+    proxy_protocol: false,
+    listen_port: None,
   },
   targets: vec![
     Target {
       address: String::from("0.0.0.0"),
-      port: 0,
+      port: 25565,
+      proxy_protocol: false,
+      listen_port: None,
     },
     Target {
       address: String::from("localhost"),
-      port: 0,
+      port: 8080,
+      proxy_protocol: false,
+      listen_port: None,
     },
   ],
   threads: None,
   concurrency: 1024,
+  channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+  max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+  upstream_connect_timeout_ms: None,
+  upstream_connect_retries: DEFAULT_UPSTREAM_CONNECT_RETRIES,
+  authtry_timeout_ms: None,
+  hash_encoding: DEFAULT_HASH_ENCODING,
+  reconnect_backoff_ms: DEFAULT_RECONNECT_BACKOFF_MS,
+  reconnect_backoff_max_ms: DEFAULT_RECONNECT_BACKOFF_MAX_MS,
+  breaker_threshold: None,
+  breaker_cooldown_ms: DEFAULT_BREAKER_COOLDOWN_MS,
+  transport: Transport::Tcp,
 });
 
 fn save_default() -> Result<(), ()> {
@@ -100,43 +242,31 @@ fn save_default() -> Result<(), ()> {
   }
 }
 
-fn backup_settings(mut reader: BufReader<File>) -> Result<(), ()> {
-  let mut settings: String = String::new();
-  match reader.read_to_string(&mut settings) {
-    | Ok(_) => {
-      let backup_file: Result<File, std::io::Error> = File::create(format!(
-        "{}-invalid-{}.json",
-        SETTING_FILE_PATH.strip_suffix(".json").unwrap(),
-        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
-      ));
-      debug!(
-        "Backup file name: {}",
-        format!(
-          "{}-invalid-{}.json",
-          SETTING_FILE_PATH.strip_suffix(".json").unwrap(),
-          SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
-        )
-      );
-      trace!("Backup file contents: {}", settings);
-      match backup_file {
-        | Ok(mut backup_file) => {
-          match backup_file.write_all(&settings.as_bytes()) {
-            | Ok(_) => {
-              info!("Settings file backed up!");
-              return Result::Ok(());
-            },
-            | Err(e) => {
-              error!(
-                "Failed to write to settings backup file: {}",
-                e
-              );
-              return Result::Err(());
-            },
-          }
+fn backup_settings(settings: &String) -> Result<(), ()> {
+  let backup_file: Result<File, std::io::Error> = File::create(format!(
+    "{}-invalid-{}.json",
+    SETTING_FILE_PATH.strip_suffix(".json").unwrap(),
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+  ));
+  debug!(
+    "Backup file name: {}",
+    format!(
+      "{}-invalid-{}.json",
+      SETTING_FILE_PATH.strip_suffix(".json").unwrap(),
+      SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    )
+  );
+  trace!("Backup file contents: {}", settings);
+  match backup_file {
+    | Ok(mut backup_file) => {
+      match backup_file.write_all(&settings.as_bytes()) {
+        | Ok(_) => {
+          info!("Settings file backed up!");
+          return Result::Ok(());
         },
         | Err(e) => {
           error!(
-            "Failed to create settings backup file: {}",
+            "Failed to write to settings backup file: {}",
             e
           );
           return Result::Err(());
@@ -144,13 +274,16 @@ fn backup_settings(mut reader: BufReader<File>) -> Result<(), ()> {
       }
     },
     | Err(e) => {
-      error!("Failed to read settings file: {}", e);
+      error!(
+        "Failed to create settings backup file: {}",
+        e
+      );
       return Result::Err(());
     },
   }
 }
 
-fn file_to_runtime(config: Config<ConfigFile>) -> Config<Runtime> {
+pub fn file_to_runtime(config: Config<ConfigFile>) -> Config<Runtime> {
   let threads: usize = match config.threads {
     | Some(threads) => threads,
     | _ => match std::thread::available_parallelism() {
@@ -171,39 +304,105 @@ fn file_to_runtime(config: Config<ConfigFile>) -> Config<Runtime> {
     threads,
     redirect_to: config.redirect_to,
     targets: config.targets,
+    channel_capacity: config.channel_capacity,
+    max_header_bytes: config.max_header_bytes,
+    upstream_connect_timeout_ms: config.upstream_connect_timeout_ms,
+    upstream_connect_retries: config.upstream_connect_retries,
+    authtry_timeout_ms: config.authtry_timeout_ms,
+    hash_encoding: config.hash_encoding,
+    reconnect_backoff_ms: config.reconnect_backoff_ms,
+    reconnect_backoff_max_ms: config.reconnect_backoff_max_ms,
+    breaker_threshold: config.breaker_threshold,
+    breaker_cooldown_ms: config.breaker_cooldown_ms,
+    transport: config.transport,
+  }
+}
+
+/// The inverse of [`file_to_runtime`], for writing edited settings back. The
+/// thread count is kept as resolved, and the separator is written as a string
+/// or as its bytes just like the defaults are.
+#[allow(dead_code)]
+pub fn runtime_to_file(config: Config<Runtime>) -> Config<ConfigFile> {
+  Config {
+    auth: config.auth,
+    concurrency: config.concurrency,
+    separator: config.separator,
+    threads: Some(config.threads),
+    redirect_to: config.redirect_to,
+    targets: config.targets,
+    channel_capacity: config.channel_capacity,
+    max_header_bytes: config.max_header_bytes,
+    upstream_connect_timeout_ms: config.upstream_connect_timeout_ms,
+    upstream_connect_retries: config.upstream_connect_retries,
+    authtry_timeout_ms: config.authtry_timeout_ms,
+    hash_encoding: config.hash_encoding,
+    reconnect_backoff_ms: config.reconnect_backoff_ms,
+    reconnect_backoff_max_ms: config.reconnect_backoff_max_ms,
+    breaker_threshold: config.breaker_threshold,
+    breaker_cooldown_ms: config.breaker_cooldown_ms,
+    transport: config.transport,
+  }
+}
+
+impl Settings for Config<ConfigFile> {
+  type Error = PortSetError;
+
+  fn defaults() -> &'static Self {
+    &DEFAULT_SETTINGS
+  }
+
+  fn separator(&self) -> &Separator {
+    &self.separator
+  }
+
+  fn hash_encoding(&self) -> HashEncoding {
+    self.hash_encoding
+  }
+
+  /// The server refuses the whole auth request over a single bad port, so
+  /// it is caught here rather than failing to authenticate.
+  fn check(&self) -> Result<(), PortSetError> {
+    PortSet::new(self.targets.iter().map(Target::forward).collect()).map(|_| ())
   }
 }
 
 pub fn get_settings() -> Config<Runtime> {
   let settings: Config<ConfigFile> = DEFAULT_SETTINGS.clone();
-  let file: Result<File, std::io::Error> = File::open(SETTING_FILE_PATH);
-  match file {
-    | Ok(file) => {
-      let reader: BufReader<File> = BufReader::new(file);
-      let settings_from_files: Result<Config<ConfigFile>, Error> =
-        from_reader(&mut reader.get_ref().try_clone().unwrap());
-      match settings_from_files {
-        | Ok(settings_from_files) => {
-          trace!("{:?}", settings_from_files);
-          return file_to_runtime(settings_from_files);
+  match load_settings(SETTING_FILE_PATH) {
+    | Ok(settings_from_files) => {
+      trace!("{:?}", settings_from_files);
+      return file_to_runtime(settings_from_files);
+    },
+    | Err(LoadError::Invalid(contents, e)) => {
+      error!("Failed to deserialize settings: {}", e);
+      warn!("Using default settings");
+      match backup_settings(&contents) {
+        | Ok(_) => {
+          save_default().unwrap();
         },
-        | Err(e) => {
-          error!("Failed to deserialize settings: {}", e);
-          warn!("Using default settings");
-          match backup_settings(BufReader::new(
-            File::open(SETTING_FILE_PATH).unwrap(),
-          )) {
-            | Ok(_) => {
-              save_default().unwrap();
-            },
-            | Err(_) => {
-              error!("Failed to backup settings");
-            },
-          }
+        | Err(_) => {
+          error!("Failed to backup settings");
         },
       }
     },
-    | Err(e) => {
+    | Err(LoadError::Separator(e)) => {
+      // Falling back to the default separator would only leave this side
+      // unable to talk to the other one, so refuse to start instead.
+      error!("{}", e.value());
+      exit(1);
+    },
+    | Err(LoadError::Check(e)) => {
+      error!("Invalid targets: {}", e.value());
+      exit(1);
+    },
+    | Err(LoadError::NotAFile(e)) => {
+      error!(
+        "Failed to open settings file: {} ({SETTING_FILE_PATH} is not a regular file, leaving it alone)",
+        e
+      );
+      exit(1);
+    },
+    | Err(LoadError::Read(e)) => {
       error!("Failed to open settings file: {}", e);
       warn!("Using default settings");
       save_default().unwrap();
@@ -211,3 +410,23 @@ pub fn get_settings() -> Config<Runtime> {
   }
   file_to_runtime(settings)
 }
+
+/// Overrides the auth secret from the settings file with the one in the
+/// environment, or with one typed at the terminal when `ask`
+pub fn override_auth(config: &mut Config<Runtime>, ask: bool) {
+  let prompted = match ask {
+    | true => match prompt_auth() {
+      | Ok(auth) => Some(auth),
+      | Err(err) => {
+        error!("Failed to read the auth secret: {err}");
+        exit(1);
+      },
+    },
+    | false => None,
+  };
+  config.auth = resolve_auth(
+    std::mem::take(&mut config.auth),
+    auth_from_env(),
+    prompted,
+  );
+}
@@ -1,22 +1,36 @@
+mod breaker;
 mod config;
+mod resolver;
 mod socket;
 mod socket2;
+mod tests;
+mod upstream;
 
 use std::{process::exit, thread};
 
 use clap::{value_parser, Arg, ArgAction, Command};
-use proxy_router::logging::{init_logger, LoggerSettings};
+use proxy_router::{
+  constants::{ignore_sigpipe, ConfigFile},
+  logging::{
+    init_logger, lower_log_level, parse_facility, raise_log_level,
+    startup_banner, LogSink, LoggerSettings,
+  },
+  settings,
+};
 use signal_hook::{
-  consts::{SIGINT, SIGTERM},
+  consts::{SIGINT, SIGTERM, SIGUSR1, SIGUSR2},
   iterator::Signals,
 };
 #[allow(unused_imports)]
 use simplelog::{debug, error, info, trace, warn};
+use syslog::Facility;
 
-fn main() {
+#[tokio::main]
+async fn main() {
   let mut logger_settings = LoggerSettings {
     level: simplelog::LevelFilter::Info,
     file_level: simplelog::LevelFilter::Debug,
+    sinks: Vec::new(),
   };
 
   let level: simplelog::LevelFilter;
@@ -87,6 +101,21 @@ fn main() {
         .conflicts_with_all(&["trace", "debug", "error", "warn", "info"])
         .help("Sets the logging level to off"),
     )
+    .arg(
+      Arg::new("quiet")
+        .long("quiet")
+        .num_args(0)
+        .action(ArgAction::SetTrue)
+        .conflicts_with("trace")
+        .help("Leaves out the startup lines saying where logs go"),
+    )
+    .arg(
+      Arg::new("ask-auth")
+        .long("ask-auth")
+        .num_args(0)
+        .action(ArgAction::SetTrue)
+        .help("Asks for the auth secret on the terminal, overriding the settings file and the PROXY_ROUTER_AUTH environment variable"),
+    )
     .arg(
       Arg::new("trace-file")
         .long("trace-file")
@@ -103,8 +132,32 @@ fn main() {
         .conflicts_with("trace-file")
         .help("Disables the log file"),
     )
+    .arg(
+      Arg::new("syslog")
+        .long("syslog")
+        .value_name("FACILITY")
+        .value_parser(parse_facility)
+        .help("Also sends INFO and above to syslog under FACILITY, such as daemon or local0"),
+    )
+    .subcommand(
+      Command::new("init")
+        .about("Writes the default settings file and exits")
+        .arg(
+          Arg::new("force")
+            .long("force")
+            .num_args(0)
+            .action(ArgAction::SetTrue)
+            .help("Overwrites the settings file if it already exists"),
+        ),
+    )
     .get_matches();
 
+  if let Some(init) = matches.subcommand_matches("init") {
+    exit(settings::init::<
+      config::Config<ConfigFile>,
+    >(init.get_flag("force")));
+  }
+
   if matches.get_flag("trace") {
     logger_settings.level = simplelog::LevelFilter::Trace;
     level = simplelog::LevelFilter::Trace;
@@ -134,38 +187,56 @@ fn main() {
     file_level = simplelog::LevelFilter::Debug;
   }
 
-  init_logger(logger_settings);
-
-  match level {
-    | simplelog::LevelFilter::Trace => info!("TRACE calls logging to terminal"),
-    | simplelog::LevelFilter::Debug => info!("DEBUG calls logging to terminal"),
-    | simplelog::LevelFilter::Info => info!("INFO calls logging to terminal"),
-    | simplelog::LevelFilter::Warn => info!("WARN calls logging to terminal"),
-    | simplelog::LevelFilter::Error => info!("ERROR calls logging to terminal"),
-    | simplelog::LevelFilter::Off => info!("Disabled logging to terminal"),
+  if let Some(facility) = matches.get_one::<Facility>("syslog") {
+    logger_settings.sinks.push(LogSink::Syslog {
+      facility: *facility,
+      level: simplelog::LevelFilter::Info,
+    });
   }
 
-  match file_level {
-    | simplelog::LevelFilter::Trace => info!("TRACE calls logging to file"),
-    | simplelog::LevelFilter::Debug => info!("DEBUG calls logging to file"),
-    | simplelog::LevelFilter::Off => info!("Disabled logging to file"),
-    | _ => (),
+  init_logger(logger_settings);
+
+  for line in startup_banner(
+    level,
+    file_level,
+    matches.get_flag("quiet"),
+  ) {
+    info!("{line}");
   }
 
+  ignore_sigpipe();
   let mut signals: signal_hook::iterator::SignalsInfo =
-    Signals::new(&[SIGINT, SIGTERM]).unwrap();
+    Signals::new(&[SIGINT, SIGTERM, SIGUSR1, SIGUSR2]).unwrap();
 
   thread::spawn(move || {
     for sig in signals.forever() {
       match sig {
         | SIGINT => info!("Received SIGINT"),
         | SIGTERM => info!("Received SIGTERM"),
+        | SIGUSR1 => {
+          warn!(
+            "Terminal log level raised to {}",
+            raise_log_level()
+          );
+          continue;
+        },
+        | SIGUSR2 => {
+          warn!(
+            "Terminal log level lowered to {}",
+            lower_log_level()
+          );
+          continue;
+        },
         | _ => unreachable!(),
       }
       exit(0);
     }
   });
 
-  let config = config::get_settings();
-  socket::connect(&config);
+  let mut config = config::get_settings();
+  config::override_auth(
+    &mut config,
+    matches.get_flag("ask-auth"),
+  );
+  socket::supervise(&config).await;
 }
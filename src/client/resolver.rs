@@ -0,0 +1,112 @@
+use std::{
+  collections::HashMap,
+  future::Future,
+  io::{Error, ErrorKind},
+  net::SocketAddr,
+  pin::Pin,
+  sync::{Arc, Mutex, MutexGuard},
+  time::Duration,
+};
+
+use simplelog::warn;
+use tokio::{
+  net::lookup_host,
+  time::{sleep, Instant},
+};
+
+/// Looks up the addresses an `address:port` resolves to
+pub type Lookup = Arc<
+  dyn Fn(
+      String,
+    )
+      -> Pin<Box<dyn Future<Output = Result<Vec<SocketAddr>, Error>> + Send>>
+    + Send
+    + Sync,
+>;
+
+/// Looks addresses up with the system resolver
+pub fn system_lookup() -> Lookup {
+  Arc::new(|address| {
+    Box::pin(async move { Ok(lookup_host(address).await?.collect()) })
+  })
+}
+
+/// The addresses each upstream resolved to, and when
+type Cache = HashMap<String, (Vec<SocketAddr>, Instant)>;
+
+/// Resolves upstream addresses, trying a failed lookup again up to `retries`
+/// times with a backoff doubling from `backoff`, and reusing what a lookup
+/// found for `ttl` so connection churn doesn't look the same name up again
+/// and again. Shared by the connects running for each upstream.
+pub struct Resolver {
+  lookup: Lookup,
+  retries: u32,
+  backoff: Duration,
+  ttl: Duration,
+  cache: Mutex<Cache>,
+}
+
+impl Resolver {
+  pub fn new(
+    lookup: Lookup, retries: u32, backoff: Duration, ttl: Duration,
+  ) -> Self {
+    Resolver {
+      lookup,
+      retries,
+      backoff,
+      ttl,
+      cache: Mutex::new(HashMap::new()),
+    }
+  }
+
+  fn cache(&self) -> MutexGuard<'_, Cache> {
+    match self.cache.lock() {
+      | Ok(cache) => cache,
+      | Err(err) => err.into_inner(),
+    }
+  }
+
+  pub async fn resolve(&self, address: &str) -> Result<Vec<SocketAddr>, Error> {
+    if let Some((addresses, resolved)) = self.cache().get(address) {
+      if resolved.elapsed() < self.ttl {
+        return Ok(addresses.to_owned());
+      }
+    }
+    let mut backoff = self.backoff;
+    let mut attempt = 0;
+    loop {
+      let result = match (self.lookup)(address.to_string()).await {
+        | Ok(addresses) if addresses.is_empty() => Err(Error::new(
+          ErrorKind::NotFound,
+          "no addresses found",
+        )),
+        | result => result,
+      };
+      match result {
+        | Ok(addresses) => {
+          self.cache().insert(
+            address.to_string(),
+            (addresses.clone(), Instant::now()),
+          );
+          return Ok(addresses);
+        },
+        | Err(err) if attempt < self.retries => {
+          attempt += 1;
+          warn!(
+            "Failed to resolve {address} ({err}), trying again in {}ms",
+            backoff.as_millis()
+          );
+          sleep(backoff).await;
+          backoff *= 2;
+        },
+        | Err(err) => return Err(err),
+      }
+    }
+  }
+
+  /// Drops what `address` resolved to, so the next connect looks it up again
+  /// instead of reusing addresses that may have gone stale
+  pub fn forget(&self, address: &str) {
+    self.cache().remove(address);
+  }
+}
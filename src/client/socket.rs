@@ -1,59 +1,391 @@
-// use uuid::Uuid;
-use std::net::{Shutdown, TcpStream};
-use std::{
-  io,
-  io::{Read, Write},
+use std::{sync::Arc, time::Duration};
+
+use proxy_router::{
+  constants::{
+    peer_label, Runtime, AT_CAPACITY, DEFAULT_INTEGRITY, SEPARATOR_MISMATCH,
+  },
+  functions::{AuthStatus, AuthTryInfo, Client, PacketType, ParseOptions},
+  logging::{log_packet, Direction},
+  websocket::{
+    check_upgrade, new_key, upgrade_request, Decoder, Message, Transport,
+    WebSocketWriter,
+  },
+};
+use simplelog::{debug, error, info, warn};
+use tokio::{
+  io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  net::TcpStream,
+  sync::Mutex,
+  time::{sleep, timeout_at, Instant},
 };
 
-use proxy_router::constants::Runtime;
-use proxy_router::functions::Client;
+use crate::{
+  config::Config,
+  upstream::{Control, Upstreams},
+};
 
-use crate::config::Config;
+/// Why a control connection ended
+#[derive(Debug, PartialEq)]
+pub enum Disconnect {
+  /// The server could not be reached, was full, or hung up or went quiet
+  /// before authenticating us
+  Unreachable,
+  /// The connection was lost after the server authenticated us
+  Lost,
+  /// The server refused our auth, so trying again would not help
+  Refused,
+}
 
-pub fn connect(config: &Config<Runtime>) -> () {
-  // Connect to the TCP server
-  let mut stream = TcpStream::connect(format!(
-    "127.0.0.1:{}",
-    config.redirect_to.port
-  ))
-  .unwrap();
-  stream
-    .write_all(
-      Client::build_auth_packet(
-        &config.auth.to_owned(),
-        &vec![3000, 4000, 5000],
-        &config.separator,
-      )
-      .as_slice(),
-    )
-    .unwrap();
+/// A state the tunnel to the server moved into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TunnelState {
+  /// The server authenticated us for the first time
+  Created,
+  /// The connection was lost after the server authenticated us
+  Died,
+  /// The server authenticated us again after the tunnel died
+  Resurrected,
+  /// The server refused our auth, so the supervisor stopped
+  GaveUp,
+}
 
-  loop {
-    // Read input from the user
-    let mut input = String::new();
-    io::stdin().read_line(&mut input).unwrap();
-    let message = input.trim();
+/// Passed to a [`TunnelCallback`] at each transition of the tunnel
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunnelEvent {
+  /// The server the tunnel goes to, as `address:port`
+  pub target: String,
+  pub state: TunnelState,
+}
 
-    // Send the message to the server
-    stream.write_all(message.as_bytes()).unwrap();
+pub type TunnelCallback = Box<dyn Fn(TunnelEvent) + Send>;
 
-    // Check if the user wants to quit
-    if message == "quit" {
-      break;
+/// Tracks whether the tunnel is up across control connections, telling the
+/// callback, if there is one, whenever that changes
+pub struct Tunnel {
+  target: String,
+  on_event: Option<TunnelCallback>,
+  up: bool,
+  /// Whether the tunnel was ever up, which makes the next one a resurrection
+  created: bool,
+}
+
+impl Tunnel {
+  pub fn new(
+    config: &Config<Runtime>, on_event: Option<TunnelCallback>,
+  ) -> Self {
+    Tunnel {
+      target: format!(
+        "{}:{}",
+        config.redirect_to.address, config.redirect_to.port
+      ),
+      on_event,
+      up: false,
+      created: false,
     }
+  }
+
+  fn emit(&self, state: TunnelState) {
+    if let Some(on_event) = &self.on_event {
+      on_event(TunnelEvent {
+        target: self.target.clone(),
+        state,
+      });
+    }
+  }
+
+  /// Records that the server authenticated us. A server asking us to
+  /// authenticate again does not bring the tunnel down, so only the first
+  /// success on a connection counts.
+  fn authenticated(&mut self) {
+    if self.up {
+      return;
+    }
+    self.up = true;
+    if self.created {
+      self.emit(TunnelState::Resurrected);
+    } else {
+      self.emit(TunnelState::Created);
+    }
+    self.created = true;
+  }
+
+  /// Records how a control connection ended
+  fn ended(&mut self, disconnect: &Disconnect) {
+    let was_up = std::mem::replace(&mut self.up, false);
+    match disconnect {
+      | Disconnect::Refused => self.emit(TunnelState::GaveUp),
+      | _ if was_up => self.emit(TunnelState::Died),
+      | _ => (),
+    }
+  }
+}
 
-    // Read the server's response
-    let mut buffer = [0; 1024];
-    let bytes_read = stream.read(&mut buffer).unwrap();
-    let received_data = &buffer[..bytes_read];
+/// Keeps a control connection to the server up, connecting again with
+/// exponential backoff whenever it is lost, until the server refuses our auth
+pub async fn supervise(config: &Config<Runtime>) {
+  supervise_with(config, None).await
+}
 
-    // Process received data
-    println!(
-      "Received: {}",
-      String::from_utf8_lossy(received_data)
+/// Like [`supervise`], calling `on_event` whenever the tunnel to the server
+/// is created, dies, is resurrected or given up on
+pub async fn supervise_with(
+  config: &Config<Runtime>, on_event: Option<TunnelCallback>,
+) {
+  let mut tunnel = Tunnel::new(config, on_event);
+  let initial = Duration::from_millis(config.reconnect_backoff_ms);
+  let max = Duration::from_millis(config.reconnect_backoff_max_ms);
+  let mut backoff = initial;
+  loop {
+    match connect(config, &mut tunnel).await {
+      | Disconnect::Refused => return,
+      // The session was up, so this is a fresh outage rather than another
+      // failed attempt in a row.
+      | Disconnect::Lost => backoff = initial,
+      | Disconnect::Unreachable => (),
+    }
+    warn!(
+      "Connecting to the server again in {}ms",
+      backoff.as_millis()
     );
+    sleep(backoff).await;
+    backoff = (backoff * 2).min(max);
+  }
+}
+
+/// Upgrades `stream` to a WebSocket for `path`, returning whatever was read
+/// past the server's response
+async fn upgrade(
+  stream: &mut TcpStream, host: &str, path: &str,
+) -> Result<Vec<u8>, String> {
+  let key = new_key();
+  stream
+    .write_all(&upgrade_request(host, path, &key))
+    .await
+    .map_err(|err| err.to_string())?;
+  let mut response = Vec::new();
+  let mut buffer = [0u8; 4096];
+  loop {
+    let bytes_read =
+      stream.read(&mut buffer).await.map_err(|err| err.to_string())?;
+    if bytes_read == 0 {
+      return Err(String::from(
+        "connection closed during the upgrade",
+      ));
+    }
+    response.extend_from_slice(&buffer[..bytes_read]);
+    match check_upgrade(&response, &key) {
+      | Ok(Some(consumed)) => return Ok(response.split_off(consumed)),
+      | Ok(None) => (),
+      | Err(err) => return Err(err.value()),
+    }
   }
+}
+
+/// Runs one control connection to the server, returning once it ends
+pub async fn connect(
+  config: &Config<Runtime>, tunnel: &mut Tunnel,
+) -> Disconnect {
+  let disconnect = run(config, tunnel).await;
+  tunnel.ended(&disconnect);
+  disconnect
+}
 
-  // Close the TCP connection
-  stream.shutdown(Shutdown::Both).unwrap();
+async fn run(config: &Config<Runtime>, tunnel: &mut Tunnel) -> Disconnect {
+  let address = format!(
+    "{}:{}",
+    config.redirect_to.address, config.redirect_to.port
+  );
+  let mut stream = match TcpStream::connect(&address).await {
+    | Ok(stream) => stream,
+    | Err(err) => {
+      error!("Failed to connect to {address}: {err}");
+      return Disconnect::Unreachable;
+    },
+  };
+  info!(
+    "Connected to {address} ({})",
+    peer_label(&stream.peer_addr().ok())
+  );
+
+  // Bytes read along with the upgrade response, which belong to the
+  // WebSocket
+  let mut read_ahead = None;
+  let mut decoder = match &config.transport {
+    | Transport::Tcp => None,
+    | Transport::WebSocket {
+      path,
+    } => match upgrade(&mut stream, &address, path).await {
+      | Ok(rest) => {
+        read_ahead = Some(rest).filter(|rest| !rest.is_empty());
+        Some(Decoder::new())
+      },
+      | Err(err) => {
+        error!("Failed to open a WebSocket to {address}: {err}");
+        return Disconnect::Unreachable;
+      },
+    },
+  };
+
+  let (mut reader, writer) = stream.into_split();
+  let mut writer: Box<dyn AsyncWrite + Send + Unpin> = match decoder {
+    | Some(_) => Box::new(WebSocketWriter::new(writer)),
+    | None => Box::new(writer),
+  };
+  let forwards = config.targets.iter().map(|target| target.forward()).collect();
+  let auth = Client::build_auth_packet_with_forwards(
+    &config.auth, &forwards, &config.separator,
+  );
+  if let Err(err) = writer.write_all(auth.as_slice()).await {
+    error!("Failed to send auth packet: {err}");
+    return Disconnect::Unreachable;
+  }
+
+  let control: Control = Arc::new(Mutex::new(writer));
+  let mut upstreams = Upstreams::new(config, Arc::clone(&control));
+  let mut options = ParseOptions {
+    max_header_bytes: config.max_header_bytes,
+    hash_encoding: config.hash_encoding,
+    ..ParseOptions::default()
+  };
+  let mut disconnect = Disconnect::Unreachable;
+  // Cleared once the server answers our AUTH, one way or another
+  let mut authtry_deadline = config
+    .authtry_timeout_ms
+    .map(|timeout| Instant::now() + Duration::from_millis(timeout));
+  let mut buffer = [0u8; 65536];
+  'read: loop {
+    let chunk = match read_ahead.take() {
+      | Some(chunk) => chunk,
+      | None => {
+        let read = reader.read(&mut buffer);
+        let read = match authtry_deadline {
+          | Some(deadline) => match timeout_at(deadline, read).await {
+            | Ok(read) => read,
+            | Err(_) => {
+              error!(
+                "The server did not answer our auth within {}ms",
+                config.authtry_timeout_ms.unwrap_or_default()
+              );
+              break;
+            },
+          },
+          | None => read.await,
+        };
+        let bytes_read = match read {
+          | Ok(0) => {
+            info!("Connection closed by server");
+            break;
+          },
+          | Ok(bytes_read) => bytes_read,
+          | Err(err) => {
+            error!("Failed to read from server: {err}");
+            break;
+          },
+        };
+        buffer[..bytes_read].to_vec()
+      },
+    };
+    let mut closing = false;
+    let packets = match &mut decoder {
+      | None => vec![chunk],
+      | Some(decoder) => match decoder.push(&chunk) {
+        | Ok(messages) => {
+          let mut packets = Vec::new();
+          for message in messages {
+            match message {
+              | Message::Binary(packet) => packets.push(packet),
+              | Message::Ping(_) => debug!("Ignoring ping from server"),
+              | Message::Close => {
+                info!("Server closed the WebSocket");
+                closing = true;
+                break;
+              },
+            }
+          }
+          packets
+        },
+        | Err(err) => {
+          error!("{err} from server");
+          break;
+        },
+      },
+    };
+    for packet in packets {
+      let packet =
+        Client::parse_packet_with(packet, &config.separator, &options);
+      if let Ok(packet) = &packet {
+        log_packet(Direction::Received, packet);
+      }
+      match packet {
+        | Ok(PacketType::Data(packet)) => upstreams.on_data(packet).await,
+        | Ok(PacketType::Close(packet)) => upstreams.on_close(packet).await,
+        | Ok(PacketType::AuthTry(packet)) => {
+          authtry_deadline = None;
+          match AuthTryInfo::from_body(&packet.body) {
+            | Ok(info) => match info.status {
+              | AuthStatus::Success => {
+                // The server decides which hashes data packets carry, both
+                // ways, so its choice replaces whatever was used before.
+                disconnect = Disconnect::Lost;
+                tunnel.authenticated();
+                let integrity = info.integrity.unwrap_or(DEFAULT_INTEGRITY);
+                options.integrity = integrity;
+                upstreams.set_integrity(integrity);
+                upstreams.set_window(info.window);
+                let hash_encoding =
+                  info.encoding.unwrap_or(config.hash_encoding);
+                options.hash_encoding = hash_encoding;
+                upstreams.set_hash_encoding(hash_encoding);
+                match (info.name, info.version) {
+                  | (Some(name), Some(version)) => {
+                    info!("Authenticated by {name} (v{version})")
+                  },
+                  | (Some(name), None) => info!("Authenticated by {name}"),
+                  | _ => info!("Authenticated"),
+                }
+              },
+              | AuthStatus::Forbidden => {
+                match info.reason.as_deref() {
+                  // Another try may find room, so back off and retry
+                  // instead of giving up.
+                  | Some(AT_CAPACITY) => {
+                    error!("Server refused authentication: it is full");
+                    break 'read;
+                  },
+                  | Some(SEPARATOR_MISMATCH) => error!(
+                    "Server refused authentication: it uses another separator"
+                  ),
+                  | _ => error!("Server refused authentication"),
+                }
+                disconnect = Disconnect::Refused;
+                break 'read;
+              },
+              | AuthStatus::Reauth => {
+                info!("Server asked to authenticate again");
+                let mut control = control.lock().await;
+                if let Err(err) = control.write_all(auth.as_slice()).await {
+                  error!("Failed to send auth packet: {err}");
+                  break 'read;
+                }
+              },
+            },
+            | Err(err) => error!(
+              "Error parsing auth result: {}",
+              err.value()
+            ),
+          }
+        },
+        | Ok(PacketType::Noop(_)) => (),
+        | Ok(PacketType::Open(packet)) => upstreams.on_open(packet).await,
+        | Ok(PacketType::Ack(packet)) => upstreams.on_ack(packet),
+        | Ok(_) => debug!("Ignoring unexpected packet from server"),
+        | Err(err) => error!("Error parsing packet: {}", err.value()),
+      }
+    }
+    if closing {
+      break;
+    }
+  }
+  upstreams.close_all();
+  disconnect
 }
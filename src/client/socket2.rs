@@ -1,9 +1,10 @@
 use hydrogen::{HydrogenSocket, Stream as HydrogenStream};
+use proxy_router::constants::{random_ids, IdSource, PendingWrite};
 use simplelog::{debug, error, info, trace, warn};
 use std::{
   cell::UnsafeCell,
   collections::HashMap,
-  io::{Error, ErrorKind, Read, Write},
+  io::{Error, ErrorKind, Read},
   net::{Shutdown, TcpStream},
   os::{
     fd::FromRawFd,
@@ -23,6 +24,7 @@ pub struct ServerConfig {
 
 pub struct Stream {
   inner: TcpStream,
+  pending: PendingWrite,
 }
 
 impl Stream {
@@ -30,6 +32,7 @@ impl Stream {
     tcp_stream.set_nonblocking(true).unwrap();
     Stream {
       inner: tcp_stream,
+      pending: PendingWrite::default(),
     }
   }
 }
@@ -76,7 +79,7 @@ impl HydrogenStream for Stream {
   // This method is called when a previous attempt to write has returned `ErrorKind::WouldBlock`
   // and epoll has reported that the socket is now writable.
   fn send(&mut self, buf: &[u8]) -> Result<(), Error> {
-    self.inner.write_all(buf)
+    self.pending.write(&mut self.inner, buf)
   }
 
   // This method is called when connection has been reported as reset by epoll, or when any
@@ -96,6 +99,7 @@ impl AsRawFd for Stream {
 struct Server {
   connections: HashMap<RawFd, Uuid>,
   config: ServerConfig,
+  ids: IdSource,
 }
 
 impl hydrogen::Handler for Server {
@@ -124,7 +128,7 @@ impl hydrogen::Handler for Server {
     // For example:
     let tcp_stream = unsafe { TcpStream::from_raw_fd(fd) };
     let stream = Stream::from_tcp_stream(tcp_stream);
-    let uuid = Uuid::new_v4();
+    let uuid = (self.ids)();
     self.connections.insert(fd, uuid);
     info!("New connection: {}", uuid);
     Arc::new(UnsafeCell::new(stream))
@@ -158,6 +162,7 @@ pub fn main(config: &ServerConfig) {
     Box::new(Server {
       connections: HashMap::new(),
       config: config.to_owned(),
+      ids: random_ids(),
     }),
     hydrogen::Config {
       addr: String::from("0.0.0.0"),
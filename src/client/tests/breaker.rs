@@ -0,0 +1,62 @@
+#[allow(unused_imports)]
+use crate::breaker::Breaker;
+#[allow(unused_imports)]
+use std::time::Duration;
+#[allow(unused_imports)]
+use tokio::time::Instant;
+
+#[test]
+fn failures_trip_breaker() {
+  let mut breaker = Breaker::new(3, Duration::from_secs(10));
+  let start = Instant::now();
+  for _ in 0..2 {
+    assert!(breaker.allow(start));
+    breaker.record_failure("localhost:8080", start);
+  }
+  assert!(!breaker.is_open());
+  breaker.record_failure("localhost:8080", start);
+  assert!(breaker.is_open());
+  assert!(!breaker.allow(start + Duration::from_secs(9)));
+}
+
+#[test]
+fn success_resets_failures() {
+  let mut breaker = Breaker::new(2, Duration::from_secs(10));
+  let start = Instant::now();
+  breaker.record_failure("localhost:8080", start);
+  breaker.record_success("localhost:8080");
+  breaker.record_failure("localhost:8080", start);
+  assert!(!breaker.is_open());
+  assert!(breaker.allow(start));
+}
+
+#[test]
+fn cooldown_lets_one_probe_through() {
+  let mut breaker = Breaker::new(1, Duration::from_secs(10));
+  let start = Instant::now();
+  breaker.record_failure("localhost:8080", start);
+  assert!(!breaker.allow(start + Duration::from_secs(5)));
+
+  let after = start + Duration::from_secs(10);
+  assert!(breaker.allow(after));
+  // Only the one probe goes out while it is being tried.
+  assert!(!breaker.allow(after));
+
+  // A failed probe opens the breaker for another full cooldown.
+  breaker.record_failure("localhost:8080", after);
+  assert!(breaker.is_open());
+  assert!(!breaker.allow(after + Duration::from_secs(9)));
+  assert!(breaker.allow(after + Duration::from_secs(10)));
+}
+
+#[test]
+fn successful_probe_closes_breaker() {
+  let mut breaker = Breaker::new(1, Duration::from_secs(10));
+  let start = Instant::now();
+  breaker.record_failure("localhost:8080", start);
+  assert!(breaker.allow(start + Duration::from_secs(10)));
+  breaker.record_success("localhost:8080");
+  assert!(!breaker.is_open());
+  assert!(breaker.allow(start + Duration::from_secs(10)));
+  assert!(breaker.allow(start + Duration::from_secs(10)));
+}
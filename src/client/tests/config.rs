@@ -0,0 +1,128 @@
+#[allow(unused_imports)]
+use crate::config::{
+  file_to_runtime, runtime_to_file, Config, Target, DEFAULT_SETTINGS,
+};
+#[allow(unused_imports)]
+use proxy_router::{
+  constants::ConfigFile,
+  functions::{PortSetError, Separator, SeparatorError},
+  settings::{load_settings, LoadError},
+};
+#[allow(unused_imports)]
+use serde_json::{json, to_string_pretty};
+#[allow(unused_imports)]
+use std::{env::temp_dir, fs, process};
+
+#[test]
+fn single_read_feeds_parse_and_report() {
+  let path = temp_dir().join(format!(
+    "proxy-settings-{}.json",
+    process::id()
+  ));
+  let path = path.to_str().unwrap();
+
+  fs::write(
+    path,
+    to_string_pretty(&DEFAULT_SETTINGS.clone()).unwrap(),
+  )
+  .unwrap();
+  assert!(load_settings::<Config<ConfigFile>>(path).is_ok());
+
+  let invalid = "{ \"auth\": ";
+  fs::write(path, invalid).unwrap();
+  let result = load_settings::<Config<ConfigFile>>(path);
+  fs::remove_file(path).unwrap();
+  match result {
+    | Err(LoadError::Invalid(contents, _)) => assert_eq!(contents, invalid),
+    | _ => panic!("Settings should be rejected with what was read"),
+  }
+
+  match load_settings::<Config<ConfigFile>>(path) {
+    | Err(LoadError::Read(_)) => (),
+    | _ => panic!("A missing file should fail to read"),
+  }
+}
+
+#[test]
+fn separator_colliding_with_headers_rejected() {
+  let path = temp_dir().join(format!(
+    "proxy-settings-separator-{}.json",
+    process::id()
+  ));
+  let path = path.to_str().unwrap();
+
+  for separator in [" ", "a"] {
+    // Written by hand, as no Separator can hold these
+    let mut settings = json!(DEFAULT_SETTINGS.clone());
+    settings["separator"] = json!(separator);
+    fs::write(
+      path,
+      to_string_pretty(&settings).unwrap(),
+    )
+    .unwrap();
+    let result = load_settings::<Config<ConfigFile>>(path);
+    fs::remove_file(path).unwrap();
+    match result {
+      | Err(LoadError::Separator(SeparatorError::Reserved(byte))) => {
+        assert_eq!(byte, separator.as_bytes()[0])
+      },
+      | _ => panic!("Separator {separator:?} should be rejected"),
+    }
+  }
+}
+
+#[test]
+fn targets_must_ask_for_distinct_ports() {
+  let path = temp_dir().join(format!(
+    "proxy-settings-targets-{}.json",
+    process::id()
+  ));
+  let path = path.to_str().unwrap();
+
+  let target = |port: u16, listen_port: Option<u16>| Target {
+    address: String::from("localhost"),
+    port,
+    proxy_protocol: false,
+    listen_port,
+  };
+  for (targets, expected) in [
+    (
+      vec![target(8080, None), target(80, Some(8080))],
+      PortSetError::Duplicate(8080),
+    ),
+    (
+      vec![target(0, None)],
+      PortSetError::Zero,
+    ),
+  ] {
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.targets = targets;
+    fs::write(
+      path,
+      to_string_pretty(&settings).unwrap(),
+    )
+    .unwrap();
+    let result = load_settings::<Config<ConfigFile>>(path);
+    fs::remove_file(path).unwrap();
+    match result {
+      | Err(LoadError::Check(error)) => assert_eq!(error, expected),
+      | _ => panic!("Targets should be rejected with {expected:?}"),
+    }
+  }
+}
+
+#[test]
+fn runtime_settings_written_back() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.threads = Some(2);
+  settings.separator = Separator::new("|").unwrap();
+  settings.upstream_connect_timeout_ms = Some(1500);
+  settings.upstream_connect_retries = 5;
+
+  let written = runtime_to_file(file_to_runtime(settings.clone()));
+  assert_eq!(
+    to_string_pretty(&written).unwrap(),
+    to_string_pretty(&settings).unwrap()
+  );
+  assert!(to_string_pretty(&written).unwrap().contains("\"separator\": \"|\""));
+}
@@ -0,0 +1,5 @@
+mod breaker;
+mod config;
+mod resolver;
+mod socket;
+mod upstream;
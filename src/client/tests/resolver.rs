@@ -0,0 +1,91 @@
+#[allow(unused_imports)]
+use crate::resolver::{Lookup, Resolver};
+#[allow(unused_imports)]
+use std::{
+  io::{Error, ErrorKind},
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+/// A lookup that fails `failures` times before resolving to 127.0.0.1,
+/// counting each call in `calls`
+#[cfg(test)]
+fn flaky_lookup(failures: usize, calls: Arc<AtomicUsize>) -> Lookup {
+  Arc::new(move |address| {
+    let call = calls.fetch_add(1, Ordering::SeqCst);
+    Box::pin(async move {
+      if call < failures {
+        return Err(Error::other(
+          "Temporary failure in name resolution",
+        ));
+      }
+      let port = address.rsplit(':').next().unwrap().parse().unwrap();
+      Ok(vec![SocketAddr::from((
+        [127, 0, 0, 1],
+        port,
+      ))])
+    })
+  })
+}
+
+#[tokio::test]
+async fn failed_lookup_retried_then_cached() {
+  let calls = Arc::new(AtomicUsize::new(0));
+  let resolver = Resolver::new(
+    flaky_lookup(1, Arc::clone(&calls)),
+    2,
+    Duration::from_millis(1),
+    Duration::from_secs(60),
+  );
+  let expected = vec![SocketAddr::from(([127, 0, 0, 1], 8080))];
+  assert_eq!(
+    resolver.resolve("upstream.test:8080").await.unwrap(),
+    expected
+  );
+  assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+  // Churn reuses the first lookup until it is forgotten.
+  assert_eq!(
+    resolver.resolve("upstream.test:8080").await.unwrap(),
+    expected
+  );
+  assert_eq!(calls.load(Ordering::SeqCst), 2);
+  resolver.forget("upstream.test:8080");
+  resolver.resolve("upstream.test:8080").await.unwrap();
+  assert_eq!(calls.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn lookup_fails_once_retries_run_out() {
+  let calls = Arc::new(AtomicUsize::new(0));
+  let resolver = Resolver::new(
+    flaky_lookup(1, Arc::clone(&calls)),
+    0,
+    Duration::from_millis(1),
+    Duration::from_secs(60),
+  );
+  assert!(resolver.resolve("upstream.test:8080").await.is_err());
+  assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+  // Nothing is cached from a failure, and the next lookup goes through.
+  assert!(resolver.resolve("upstream.test:8080").await.is_ok());
+  assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn expired_lookup_resolved_again() {
+  let calls = Arc::new(AtomicUsize::new(0));
+  let resolver = Resolver::new(
+    flaky_lookup(0, Arc::clone(&calls)),
+    2,
+    Duration::from_millis(1),
+    Duration::ZERO,
+  );
+  resolver.resolve("upstream.test:8080").await.unwrap();
+  resolver.resolve("upstream.test:8080").await.unwrap();
+  assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,379 @@
+#[allow(unused_imports)]
+use crate::{
+  config::{file_to_runtime, Config, Target, DEFAULT_SETTINGS},
+  socket::{
+    connect, supervise, supervise_with, Disconnect, Tunnel, TunnelEvent,
+    TunnelState,
+  },
+};
+#[allow(unused_imports)]
+use proxy_router::constants::{Runtime, AT_CAPACITY};
+#[allow(unused_imports)]
+use proxy_router::functions::{
+  AuthStatus, AuthTryInfo, Client, Separator, Server,
+};
+#[allow(unused_imports)]
+use proxy_router::websocket::{
+  accept_upgrade, server_frame, Decoder, Message, Transport,
+};
+#[allow(unused_imports)]
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+#[allow(unused_imports)]
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+  time::{sleep, timeout, Instant},
+};
+#[allow(unused_imports)]
+use uuid::Uuid;
+
+#[cfg(test)]
+fn config(server: u16, target: u16) -> Config<Runtime> {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  config.redirect_to = Target {
+    address: String::from("127.0.0.1"),
+    port: server,
+    proxy_protocol: false,
+    listen_port: None,
+  };
+  config.targets = vec![Target {
+    address: String::from("127.0.0.1"),
+    port: target,
+    proxy_protocol: false,
+    listen_port: None,
+  }];
+  config.reconnect_backoff_ms = 10;
+  config
+}
+
+#[cfg(test)]
+async fn accept_auth(server: &TcpListener, auth: &Vec<u8>) -> TcpStream {
+  let (mut control, _) = timeout(Duration::from_secs(5), server.accept())
+    .await
+    .expect("Client should connect")
+    .unwrap();
+  let mut buffer = [0u8; 1024];
+  let bytes_read = control.read(&mut buffer).await.unwrap();
+  assert_eq!(&buffer[..bytes_read].to_vec(), auth);
+  control
+}
+
+#[tokio::test]
+async fn reconnects_after_control_connection_lost() {
+  let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = upstream.local_addr().unwrap().port();
+  let config = config(
+    server.local_addr().unwrap().port(),
+    port,
+  );
+  let separator = config.separator.clone();
+  let auth = Client::build_auth_packet(&config.auth, &vec![port], &separator);
+  let success = Server::build_auth_try_packet(
+    &AuthTryInfo {
+      status: AuthStatus::Success,
+      name: None,
+      version: None,
+      integrity: None,
+      window: None,
+      encoding: None,
+      reason: None,
+    },
+    &separator,
+  );
+  let supervisor = tokio::spawn(async move { supervise(&config).await });
+
+  let mut control = accept_auth(&server, &auth).await;
+  control.write_all(&success).await.unwrap();
+  drop(control);
+
+  // AUTH is sent again on the new connection, and forwarding picks up.
+  let mut control = accept_auth(&server, &auth).await;
+  control.write_all(&success).await.unwrap();
+  // Packets are not framed, so give the client time to read them one by one.
+  sleep(Duration::from_millis(100)).await;
+  let id = Uuid::new_v4();
+  control
+    .write_all(&Server::build_data_packet(
+      &id,
+      &port,
+      &separator,
+      &"Hello".into(),
+    ))
+    .await
+    .unwrap();
+
+  let (mut upstream, _) = timeout(
+    Duration::from_secs(5),
+    upstream.accept(),
+  )
+  .await
+  .expect("Client should open the upstream")
+  .unwrap();
+  let mut buffer = [0u8; 16];
+  let bytes_read = upstream.read(&mut buffer).await.unwrap();
+  assert_eq!(
+    &buffer[..bytes_read],
+    "Hello".as_bytes()
+  );
+  supervisor.abort();
+}
+
+#[tokio::test]
+async fn refused_auth_stops_reconnecting() {
+  let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let config = config(
+    server.local_addr().unwrap().port(),
+    8080,
+  );
+  let separator = config.separator.clone();
+  let auth = Client::build_auth_packet(&config.auth, &vec![8080], &separator);
+  let supervisor = tokio::spawn(async move { supervise(&config).await });
+
+  let mut control = accept_auth(&server, &auth).await;
+  control
+    .write_all(&Server::build_auth_try_packet(
+      &AuthTryInfo {
+        status: AuthStatus::Forbidden,
+        name: None,
+        version: None,
+        integrity: None,
+        window: None,
+        encoding: None,
+        reason: None,
+      },
+      &separator,
+    ))
+    .await
+    .unwrap();
+
+  timeout(Duration::from_secs(5), supervisor)
+    .await
+    .expect("Supervisor should give up once refused")
+    .unwrap();
+}
+
+#[tokio::test]
+async fn tunnel_events_follow_death_and_resurrection() {
+  let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let config = config(
+    server.local_addr().unwrap().port(),
+    8080,
+  );
+  let target = format!("127.0.0.1:{}", config.redirect_to.port);
+  let auth = Client::build_auth_packet(
+    &config.auth,
+    &vec![8080],
+    &config.separator,
+  );
+  let auth_try = |status| {
+    Server::build_auth_try_packet(
+      &AuthTryInfo {
+        status,
+        name: None,
+        version: None,
+        integrity: None,
+        window: None,
+        encoding: None,
+        reason: None,
+      },
+      &config.separator,
+    )
+  };
+  let success = auth_try(AuthStatus::Success);
+  let forbidden = auth_try(AuthStatus::Forbidden);
+  let events = Arc::new(Mutex::new(Vec::new()));
+  let seen = Arc::clone(&events);
+  let supervisor = tokio::spawn(async move {
+    supervise_with(
+      &config,
+      Some(Box::new(move |event| {
+        seen.lock().unwrap().push(event)
+      })),
+    )
+    .await
+  });
+
+  let mut control = accept_auth(&server, &auth).await;
+  control.write_all(&success).await.unwrap();
+  sleep(Duration::from_millis(100)).await;
+  drop(control);
+  let mut control = accept_auth(&server, &auth).await;
+  control.write_all(&success).await.unwrap();
+  sleep(Duration::from_millis(100)).await;
+  control.write_all(&forbidden).await.unwrap();
+  timeout(Duration::from_secs(5), supervisor)
+    .await
+    .expect("Supervisor should give up once refused")
+    .unwrap();
+
+  let states: Vec<TunnelState> = events
+    .lock()
+    .unwrap()
+    .iter()
+    .map(|event: &TunnelEvent| {
+      assert_eq!(event.target, target);
+      event.state
+    })
+    .collect();
+  assert_eq!(
+    states,
+    vec![
+      TunnelState::Created,
+      TunnelState::Died,
+      TunnelState::Resurrected,
+      TunnelState::GaveUp,
+    ]
+  );
+}
+
+#[tokio::test]
+async fn silent_server_times_out_auth() {
+  let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let mut config = config(
+    server.local_addr().unwrap().port(),
+    8080,
+  );
+  config.authtry_timeout_ms = Some(100);
+  let auth = Client::build_auth_packet(
+    &config.auth,
+    &vec![8080],
+    &config.separator,
+  );
+  let started = Instant::now();
+  let client = tokio::spawn(async move {
+    connect(&config, &mut Tunnel::new(&config, None)).await
+  });
+
+  // Read the AUTH, then never answer it.
+  let _control = accept_auth(&server, &auth).await;
+  let disconnect = timeout(Duration::from_secs(5), client)
+    .await
+    .expect("Client should give up waiting for the auth result")
+    .unwrap();
+  assert_eq!(disconnect, Disconnect::Unreachable);
+  assert!(started.elapsed() >= Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn websocket_transport_round_trip() {
+  let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = upstream.local_addr().unwrap().port();
+  let mut config = config(
+    server.local_addr().unwrap().port(),
+    port,
+  );
+  config.transport = Transport::WebSocket {
+    path: String::from("/proxy"),
+  };
+  let separator = config.separator.clone();
+  let auth = Client::build_auth_packet(&config.auth, &vec![port], &separator);
+  let client = tokio::spawn(async move {
+    connect(&config, &mut Tunnel::new(&config, None)).await
+  });
+
+  let (mut control, _) = timeout(Duration::from_secs(5), server.accept())
+    .await
+    .expect("Client should connect")
+    .unwrap();
+  let mut request = Vec::new();
+  let mut buffer = [0u8; 1024];
+  let (response, consumed) = loop {
+    let bytes_read = control.read(&mut buffer).await.unwrap();
+    request.extend_from_slice(&buffer[..bytes_read]);
+    if let Some(upgrade) = accept_upgrade(&request, "/proxy").unwrap() {
+      break upgrade;
+    }
+  };
+  control.write_all(&response).await.unwrap();
+  let mut decoder = Decoder::new();
+  let mut messages = decoder.push(&request[consumed..]).unwrap();
+  while messages.is_empty() {
+    let bytes_read = control.read(&mut buffer).await.unwrap();
+    messages = decoder.push(&buffer[..bytes_read]).unwrap();
+  }
+  assert_eq!(messages, vec![Message::Binary(auth)]);
+
+  // Each packet is its own message, so both can go out in one write.
+  let mut frames = server_frame(&Server::build_auth_try_packet(
+    &AuthTryInfo {
+      status: AuthStatus::Success,
+      name: None,
+      version: None,
+      integrity: None,
+      window: None,
+      encoding: None,
+      reason: None,
+    },
+    &separator,
+  ));
+  frames.extend(server_frame(
+    &Server::build_data_packet(
+      &Uuid::new_v4(),
+      &port,
+      &separator,
+      &"Hello".into(),
+    ),
+  ));
+  control.write_all(&frames).await.unwrap();
+
+  let (mut upstream, _) = timeout(
+    Duration::from_secs(5),
+    upstream.accept(),
+  )
+  .await
+  .expect("Client should open the upstream")
+  .unwrap();
+  let mut buffer = [0u8; 16];
+  let bytes_read = upstream.read(&mut buffer).await.unwrap();
+  assert_eq!(
+    &buffer[..bytes_read],
+    "Hello".as_bytes()
+  );
+  drop(control);
+  assert_eq!(
+    timeout(Duration::from_secs(5), client).await.unwrap().unwrap(),
+    Disconnect::Lost
+  );
+}
+
+#[tokio::test]
+async fn full_server_is_retried() {
+  let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let config = config(
+    server.local_addr().unwrap().port(),
+    8080,
+  );
+  let separator = config.separator.clone();
+  let auth = Client::build_auth_packet(&config.auth, &vec![8080], &separator);
+  let client = tokio::spawn(async move {
+    connect(&config, &mut Tunnel::new(&config, None)).await
+  });
+
+  let mut control = accept_auth(&server, &auth).await;
+  control
+    .write_all(&Server::build_auth_try_packet(
+      &AuthTryInfo {
+        status: AuthStatus::Forbidden,
+        name: None,
+        version: None,
+        integrity: None,
+        window: None,
+        encoding: None,
+        reason: Some(AT_CAPACITY.to_string()),
+      },
+      &separator,
+    ))
+    .await
+    .unwrap();
+
+  let disconnect = timeout(Duration::from_secs(5), client)
+    .await
+    .expect("Client should hang up once refused")
+    .unwrap();
+  assert_eq!(disconnect, Disconnect::Unreachable);
+}
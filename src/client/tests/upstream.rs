@@ -0,0 +1,457 @@
+#[allow(unused_imports)]
+use crate::{
+  config::{file_to_runtime, Config, Target, DEFAULT_SETTINGS},
+  upstream::{Control, Upstreams},
+};
+#[allow(unused_imports)]
+use proxy_router::constants::Runtime;
+#[allow(unused_imports)]
+use proxy_router::functions::{
+  Client, OpenInfo, PacketType, Separator, Server,
+};
+#[allow(unused_imports)]
+use std::{
+  net::{TcpListener, TcpStream},
+  os::unix::io::AsRawFd,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+#[allow(unused_imports)]
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  sync::Mutex,
+  time::timeout,
+};
+#[allow(unused_imports)]
+use uuid::Uuid;
+
+#[cfg(test)]
+fn config(port: u16, channel_capacity: usize) -> Config<Runtime> {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  config.targets = vec![Target {
+    address: String::from("127.0.0.1"),
+    port,
+    proxy_protocol: false,
+    listen_port: None,
+  }];
+  config.channel_capacity = channel_capacity;
+  config
+}
+
+#[tokio::test]
+async fn refused_upstream_sends_close() {
+  let separator = Separator::default();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+  drop(listener);
+
+  let (control, mut server) = tokio::io::duplex(1024);
+  let control: Control = Arc::new(Mutex::new(Box::new(control)));
+  let mut upstreams = Upstreams::new(&config(port, 64), control);
+
+  let id = Uuid::new_v4();
+  let packet =
+    Server::build_data_packet(&id, &port, &separator, &"Hello".into());
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(packet)) => upstreams.on_data(packet).await,
+    | _ => panic!("Packet is not a data packet"),
+  }
+
+  let mut buffer = [0u8; 1024];
+  let bytes_read = server.read(&mut buffer).await.unwrap();
+  assert_eq!(
+    buffer[..bytes_read].to_vec(),
+    Client::close_connection_packet(&id, &separator)
+  );
+  assert_eq!(upstreams.contains(&id), false);
+}
+
+#[tokio::test]
+async fn reachable_upstream_registers_connection() {
+  let separator = Separator::default();
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+
+  let (control, _server) = tokio::io::duplex(1024);
+  let control: Control = Arc::new(Mutex::new(Box::new(control)));
+  let mut upstreams = Upstreams::new(&config(port, 64), control);
+
+  let id = Uuid::new_v4();
+  let packet =
+    Server::build_data_packet(&id, &port, &separator, &"Hello".into());
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(packet)) => upstreams.on_data(packet).await,
+    | _ => panic!("Packet is not a data packet"),
+  }
+
+  let (mut upstream, _) = listener.accept().await.unwrap();
+  let mut buffer = [0u8; 1024];
+  let bytes_read = upstream.read(&mut buffer).await.unwrap();
+  assert_eq!(
+    buffer[..bytes_read].to_vec(),
+    "Hello".as_bytes().to_vec()
+  );
+  assert_eq!(upstreams.contains(&id), true);
+}
+
+#[tokio::test]
+async fn full_upstream_queue_blocks_on_data() {
+  let separator = Separator::default();
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+
+  let (control, _server) = tokio::io::duplex(1024);
+  let control: Control = Arc::new(Mutex::new(Box::new(control)));
+  let mut upstreams = Upstreams::new(&config(port, 1), control);
+
+  let id = Uuid::new_v4();
+  let body = vec![0u8; 1024 * 1024];
+  let data = || {
+    let packet = Server::build_data_packet(&id, &port, &separator, &body);
+    match Client::parse_packet(packet, &separator) {
+      | Ok(PacketType::Data(packet)) => packet,
+      | _ => panic!("Packet is not a data packet"),
+    }
+  };
+  upstreams.on_data(data()).await;
+  // The upstream accepts but never reads, so once the socket buffers and the
+  // queue fill up on_data has to stop returning.
+  let _upstream = listener.accept().await.unwrap();
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  for _ in 0..64 {
+    let sent = timeout(
+      Duration::from_millis(200),
+      upstreams.on_data(data()),
+    )
+    .await;
+    if sent.is_err() {
+      return;
+    }
+  }
+  panic!("on_data never waited for the upstream");
+}
+
+#[tokio::test]
+async fn full_queue_while_connecting_closes() {
+  let separator = Separator::default();
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+
+  let (control, mut server) = tokio::io::duplex(1024);
+  let control: Control = Arc::new(Mutex::new(Box::new(control)));
+  let mut upstreams = Upstreams::new(&config(port, 1), control);
+
+  // Nothing here yields, so the connect hasn't even started when the second
+  // packet finds the queue full.
+  let id = Uuid::new_v4();
+  for _ in 0..2 {
+    let packet =
+      Server::build_data_packet(&id, &port, &separator, &"Hello".into());
+    match Client::parse_packet(packet, &separator) {
+      | Ok(PacketType::Data(packet)) => upstreams.on_data(packet).await,
+      | _ => panic!("Packet is not a data packet"),
+    }
+  }
+
+  let mut buffer = [0u8; 1024];
+  let bytes_read = server.read(&mut buffer).await.unwrap();
+  assert_eq!(
+    buffer[..bytes_read].to_vec(),
+    Client::close_connection_packet(&id, &separator)
+  );
+  assert!(!upstreams.contains(&id));
+}
+
+#[tokio::test]
+async fn upstream_connect_times_out() {
+  let separator = Separator::default();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap();
+  // Never accepts, and with no backlog left the kernel drops further SYNs.
+  unsafe { libc::listen(listener.as_raw_fd(), 0) };
+  let mut queued = Vec::new();
+  while let Ok(stream) =
+    TcpStream::connect_timeout(&address, Duration::from_millis(100))
+  {
+    queued.push(stream);
+  }
+
+  let (control, mut server) = tokio::io::duplex(1024);
+  let control: Control = Arc::new(Mutex::new(Box::new(control)));
+  let mut config = config(address.port(), 64);
+  config.upstream_connect_timeout_ms = Some(200);
+  let mut upstreams = Upstreams::new(&config, control);
+
+  let id = Uuid::new_v4();
+  let packet = Server::build_data_packet(
+    &id,
+    &address.port(),
+    &separator,
+    &"Hello".into(),
+  );
+  let started = Instant::now();
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(packet)) => upstreams.on_data(packet).await,
+    | _ => panic!("Packet is not a data packet"),
+  }
+  // The connect runs on its own, leaving the caller free to read on.
+  assert!(started.elapsed() < Duration::from_millis(200));
+
+  let mut buffer = [0u8; 1024];
+  let bytes_read = server.read(&mut buffer).await.unwrap();
+  assert!(started.elapsed() >= Duration::from_millis(200));
+  assert_eq!(
+    buffer[..bytes_read].to_vec(),
+    Client::close_connection_packet(&id, &separator)
+  );
+  assert_eq!(upstreams.contains(&id), false);
+}
+
+#[tokio::test]
+async fn upstream_reads_while_writing() {
+  let separator = Separator::default();
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+  // Echoes everything back, so reads and writes on the upstream overlap.
+  tokio::spawn(async move {
+    let (mut upstream, _) = listener.accept().await.unwrap();
+    let (mut reader, mut writer) = upstream.split();
+    tokio::io::copy(&mut reader, &mut writer).await
+  });
+
+  let (control, mut server) = tokio::io::duplex(1 << 16);
+  let control: Control = Arc::new(Mutex::new(Box::new(control)));
+  let mut upstreams = Upstreams::new(&config(port, 4), control);
+
+  let id = Uuid::new_v4();
+  let body = vec![7u8; 64 * 1024];
+  let total = 32 * body.len();
+  let echoed = tokio::spawn(async move {
+    let mut buffer = [0u8; 1 << 16];
+    let mut read = 0;
+    while read < total {
+      read += server.read(&mut buffer).await.unwrap();
+    }
+  });
+
+  let sent = timeout(Duration::from_secs(5), async {
+    for sent in 0..32 {
+      let packet = Server::build_data_packet(&id, &port, &separator, &body);
+      match Client::parse_packet(packet, &separator) {
+        | Ok(PacketType::Data(packet)) => upstreams.on_data(packet).await,
+        | _ => panic!("Packet is not a data packet"),
+      }
+      // Lets the upstream connect, so the rest is waited on rather than
+      // overflowing its queue.
+      if sent == 0 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+      }
+    }
+  })
+  .await;
+  assert!(sent.is_ok());
+  assert!(timeout(Duration::from_secs(5), echoed).await.is_ok());
+}
+
+#[tokio::test]
+async fn failing_upstream_trips_breaker() {
+  let separator = Separator::default();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+  drop(listener);
+
+  let (control, mut server) = tokio::io::duplex(1024);
+  let control: Control = Arc::new(Mutex::new(Box::new(control)));
+  let mut config = config(port, 64);
+  config.breaker_threshold = Some(1);
+  config.breaker_cooldown_ms = 60_000;
+  let mut upstreams = Upstreams::new(&config, control);
+  let data_packet = |id: Uuid| {
+    let packet =
+      Server::build_data_packet(&id, &port, &separator, &"Hello".into());
+    match Client::parse_packet(packet, &separator) {
+      | Ok(PacketType::Data(packet)) => packet,
+      | _ => panic!("Packet is not a data packet"),
+    }
+  };
+
+  let refused = Uuid::new_v4();
+  upstreams.on_data(data_packet(refused)).await;
+  let mut buffer = [0u8; 1024];
+  let bytes_read = server.read(&mut buffer).await.unwrap();
+  assert_eq!(
+    buffer[..bytes_read].to_vec(),
+    Client::close_connection_packet(&refused, &separator)
+  );
+
+  // Back up, but the breaker keeps refusing it without trying.
+  let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+  listener.set_nonblocking(true).unwrap();
+  let tripped = Uuid::new_v4();
+  upstreams.on_data(data_packet(tripped)).await;
+  let bytes_read = server.read(&mut buffer).await.unwrap();
+  assert_eq!(
+    buffer[..bytes_read].to_vec(),
+    Client::close_connection_packet(&tripped, &separator)
+  );
+  assert!(listener.accept().is_err());
+  assert!(!upstreams.contains(&tripped));
+}
+
+#[tokio::test]
+async fn open_connects_before_any_data() {
+  let separator = Separator::default();
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+  // Greets first, like SMTP or MySQL, and waits for nothing before that.
+  tokio::spawn(async move {
+    let (mut upstream, _) = listener.accept().await.unwrap();
+    upstream.write_all(b"220 ready").await.unwrap();
+    upstream.read(&mut [0u8; 16]).await
+  });
+
+  let (control, mut server) = tokio::io::duplex(1024);
+  let control: Control = Arc::new(Mutex::new(Box::new(control)));
+  let mut upstreams = Upstreams::new(&config(port, 64), control);
+
+  let id = Uuid::new_v4();
+  let packet = Server::build_open_packet(
+    &id,
+    &port,
+    &OpenInfo {
+      peer: None,
+      local: None,
+      upstream: None,
+    },
+    &separator,
+  );
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Open(packet)) => upstreams.on_open(packet).await,
+    | _ => panic!("Packet is not an open packet"),
+  }
+
+  let mut buffer = [0u8; 1024];
+  let bytes_read = timeout(
+    Duration::from_secs(5),
+    server.read(&mut buffer),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  match Server::parse_packet(
+    buffer[..bytes_read].to_vec(),
+    &separator,
+  ) {
+    | Ok(PacketType::Data(packet)) => {
+      assert_eq!(packet.id, id);
+      assert_eq!(packet.body, b"220 ready".to_vec());
+    },
+    | _ => panic!("Packet is not a data packet"),
+  }
+  assert!(upstreams.contains(&id));
+}
+
+#[tokio::test]
+async fn data_after_failed_open_is_dropped() {
+  let separator = Separator::default();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+  drop(listener);
+
+  let (control, mut server) = tokio::io::duplex(1024);
+  let control: Control = Arc::new(Mutex::new(Box::new(control)));
+  let mut upstreams = Upstreams::new(&config(port, 64), control);
+
+  let id = Uuid::new_v4();
+  let packet = Server::build_open_packet(
+    &id,
+    &port,
+    &OpenInfo {
+      peer: None,
+      local: None,
+      upstream: None,
+    },
+    &separator,
+  );
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Open(packet)) => upstreams.on_open(packet).await,
+    | _ => panic!("Packet is not an open packet"),
+  }
+  let mut buffer = [0u8; 1024];
+  let bytes_read = timeout(
+    Duration::from_secs(5),
+    server.read(&mut buffer),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  assert_eq!(
+    buffer[..bytes_read].to_vec(),
+    Client::close_connection_packet(&id, &separator)
+  );
+
+  // Data the server sent before it got the CLOSE doesn't dial again.
+  let packet =
+    Server::build_data_packet(&id, &port, &separator, &"Hello".into());
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(packet)) => upstreams.on_data(packet).await,
+    | _ => panic!("Packet is not a data packet"),
+  }
+  assert!(!upstreams.contains(&id));
+  assert!(
+    timeout(
+      Duration::from_millis(100),
+      server.read(&mut buffer)
+    )
+    .await
+    .is_err(),
+    "No second CLOSE should have been sent"
+  );
+}
+
+#[tokio::test]
+async fn refused_connect_is_tried_again() {
+  let separator = Separator::default();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+  drop(listener);
+
+  let (control, mut server) = tokio::io::duplex(1024);
+  let control: Control = Arc::new(Mutex::new(Box::new(control)));
+  let mut config = config(port, 64);
+  config.upstream_connect_retries = 3;
+  let mut upstreams = Upstreams::new(&config, control);
+
+  let id = Uuid::new_v4();
+  let packet =
+    Server::build_data_packet(&id, &port, &separator, &"Hello".into());
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(packet)) => upstreams.on_data(packet).await,
+    | _ => panic!("Packet is not a data packet"),
+  }
+
+  // Refused at first, but up before the retries run out.
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  let listener =
+    tokio::net::TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+  let (mut upstream, _) = timeout(
+    Duration::from_secs(5),
+    listener.accept(),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+  let mut buffer = [0u8; 5];
+  upstream.read_exact(&mut buffer).await.unwrap();
+  assert_eq!(&buffer, b"Hello");
+  assert!(upstreams.contains(&id));
+  assert!(
+    timeout(
+      Duration::from_millis(50),
+      server.read(&mut [0u8; 1024])
+    )
+    .await
+    .is_err(),
+    "No CLOSE should have been sent"
+  );
+}
@@ -0,0 +1,693 @@
+use std::{
+  collections::HashMap,
+  io::{Error, ErrorKind},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, MutexGuard,
+  },
+  time::Duration,
+};
+
+use proxy_router::{
+  constants::{
+    peer_label, Runtime, DEFAULT_INTEGRITY, UPSTREAM_RESOLVE_TTL_MS,
+    UPSTREAM_RETRY_BACKOFF_MS,
+  },
+  functions::{
+    Ack, Client, Close, Data, HashEncoding, Integrity, Open, OpenInfo, Packet,
+    ParseOptions, Separator, Server,
+  },
+  logging::log_sent_packet,
+  window::Window,
+};
+use simplelog::{debug, error, info, warn};
+use tokio::{
+  io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpStream,
+  },
+  sync::{
+    mpsc::{channel, error::TrySendError, Receiver, Sender},
+    Mutex as AsyncMutex,
+  },
+  task::JoinHandle,
+  time::{sleep, timeout, Instant},
+};
+use uuid::Uuid;
+
+use crate::{
+  breaker::Breakers,
+  config::{Config, Target},
+  resolver::{system_lookup, Resolver},
+};
+
+pub type Control = Arc<AsyncMutex<Box<dyn AsyncWrite + Send + Unpin>>>;
+
+pub struct Upstream {
+  pub sender: Sender<Vec<u8>>,
+  /// Connects to the upstream, then reads from it
+  pub reader: JoinHandle<()>,
+  /// Set once the upstream is connected, until then data for it is never
+  /// waited on
+  pub connected: Arc<AtomicBool>,
+  /// Data packets sent to the server and not acknowledged yet, when the
+  /// server announced a data window
+  pub window: Option<Arc<Window>>,
+  /// Data packets received from the server so far
+  pub received: u64,
+}
+
+pub struct Upstreams {
+  targets: HashMap<u16, Target>,
+  connections: Arc<Mutex<HashMap<Uuid, Upstream>>>,
+  separator: Separator,
+  channel_capacity: usize,
+  connect_timeout: Option<Duration>,
+  connect_retries: u32,
+  /// Upstreams failing to connect are refused right away for a while
+  breakers: Arc<Mutex<Breakers>>,
+  resolver: Arc<Resolver>,
+  hash_encoding: HashEncoding,
+  /// Hashes put in data packets, as announced by the server
+  integrity: Integrity,
+  /// Data packets each upstream may have unacknowledged, as announced by the
+  /// server
+  window: Option<u64>,
+  /// Whether the server announces connections with OPEN packets, in which
+  /// case data for an id that isn't open belongs to one already closed
+  opens: bool,
+  control: Control,
+}
+
+impl Upstreams {
+  pub fn new(config: &Config<Runtime>, control: Control) -> Self {
+    Self {
+      targets: config
+        .targets
+        .iter()
+        .map(|target| (target.server_port(), target.to_owned()))
+        .collect(),
+      connections: Arc::new(Mutex::new(HashMap::with_capacity(
+        config.concurrency,
+      ))),
+      separator: config.separator.to_owned(),
+      channel_capacity: config.channel_capacity,
+      connect_timeout: config
+        .upstream_connect_timeout_ms
+        .map(Duration::from_millis),
+      connect_retries: config.upstream_connect_retries,
+      breakers: Arc::new(Mutex::new(Breakers::new(
+        config.breaker_threshold,
+        Duration::from_millis(config.breaker_cooldown_ms),
+      ))),
+      // Single-shot, as each connect attempt looks the name up again and
+      // retrying both would multiply the retries.
+      resolver: Arc::new(Resolver::new(
+        system_lookup(),
+        0,
+        Duration::from_millis(UPSTREAM_RETRY_BACKOFF_MS),
+        Duration::from_millis(UPSTREAM_RESOLVE_TTL_MS),
+      )),
+      hash_encoding: config.hash_encoding,
+      integrity: DEFAULT_INTEGRITY,
+      window: None,
+      opens: false,
+      control,
+    }
+  }
+
+  /// Switches the hashes put in data packets for upstreams opened from now on
+  pub fn set_integrity(&mut self, integrity: Integrity) {
+    self.integrity = integrity;
+  }
+
+  /// Switches the encoding of the hashes put in data packets for upstreams
+  /// opened from now on
+  pub fn set_hash_encoding(&mut self, hash_encoding: HashEncoding) {
+    self.hash_encoding = hash_encoding;
+  }
+
+  /// Switches the data window for upstreams opened from now on, acknowledging
+  /// the server's data packets while there is one
+  pub fn set_window(&mut self, window: Option<u64>) {
+    self.window = window;
+  }
+
+  #[cfg(test)]
+  pub fn contains(&self, id: &Uuid) -> bool {
+    match self.connections.lock() {
+      | Ok(connections) => connections.contains_key(id),
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        false
+      },
+    }
+  }
+
+  /// Starts connecting to the upstream as soon as the server accepts a
+  /// connection, so targets that speak first can greet the client without
+  /// waiting for it to send anything
+  pub async fn on_open(&mut self, packet: Packet<Server, Open>) {
+    self.opens = true;
+    let info = match OpenInfo::from_body(&packet.body) {
+      | Ok(info) => info,
+      | Err(err) => {
+        error!(
+          "Failed to parse open packet ({}): {err}",
+          packet.id
+        );
+        return;
+      },
+    };
+    if self.queue(&packet.id).is_some() {
+      debug!(
+        "Ignoring repeated open packet for {}",
+        packet.id
+      );
+      return;
+    }
+    if self.open(&packet.id, &packet.port, Some(info)).is_none() {
+      self.send_close(&packet.id).await;
+    }
+  }
+
+  /// The queue of the upstream for `id`, and whether it is connected yet
+  fn queue(&self, id: &Uuid) -> Option<(Sender<Vec<u8>>, bool)> {
+    match self.connections.lock() {
+      | Ok(connections) => connections.get(id).map(|upstream| {
+        (
+          upstream.sender.clone(),
+          upstream.connected.load(Ordering::SeqCst),
+        )
+      }),
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        None
+      },
+    }
+  }
+
+  pub async fn on_data(&mut self, packet: Packet<Server, Data>) {
+    // Servers that predate OPEN packets only announce a connection with its
+    // first bytes, so the upstream is connected then instead.
+    let (sender, connected) = match self.queue(&packet.id) {
+      | Some(queue) => queue,
+      | None if self.opens => {
+        // Its upstream failed or hung up, and the server was told already.
+        debug!(
+          "Dropping data for closed upstream: {}",
+          packet.id
+        );
+        return;
+      },
+      | None => match self.open(&packet.id, &packet.port, None) {
+        | Some(sender) => (sender, false),
+        | None => {
+          self.send_close(&packet.id).await;
+          return;
+        },
+      },
+    };
+
+    if connected {
+      // Waits while the upstream's queue is full, which stops the caller from
+      // reading any more from the server until the upstream catches up.
+      if let Err(err) = sender.send(packet.body).await {
+        error!(
+          "Failed to queue data for upstream ({}): {err}",
+          packet.id
+        );
+        return;
+      }
+    } else {
+      // A connect can take the whole timeout and its retries, too long to
+      // hold up every other upstream, so one still connecting is closed
+      // rather than waited on once its queue fills up.
+      match sender.try_send(packet.body) {
+        | Ok(_) => (),
+        | Err(TrySendError::Full(_)) => {
+          warn!(
+            "Too much data for upstream {} while it connects, closing it",
+            packet.id
+          );
+          self.remove(&packet.id);
+          self.send_close(&packet.id).await;
+          return;
+        },
+        | Err(err) => {
+          error!(
+            "Failed to queue data for upstream ({}): {err}",
+            packet.id
+          );
+          return;
+        },
+      }
+    }
+    if let Some(sequence) = self.received(&packet.id) {
+      send(
+        &self.control,
+        &Client::build_ack_packet(&packet.id, sequence, &self.separator),
+      )
+      .await;
+    }
+  }
+
+  /// Counts one more data packet received for `id`, returning its sequence
+  /// when the upstream has a window to acknowledge it for
+  fn received(&self, id: &Uuid) -> Option<u64> {
+    match self.connections.lock() {
+      | Ok(mut connections) => {
+        let upstream = connections.get_mut(id)?;
+        upstream.window.as_ref()?;
+        upstream.received += 1;
+        Some(upstream.received)
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        None
+      },
+    }
+  }
+
+  pub fn on_ack(&mut self, packet: Packet<Server, Ack>) {
+    let window = match self.connections.lock() {
+      | Ok(connections) => connections
+        .get(&packet.id)
+        .and_then(|upstream| upstream.window.as_ref().map(Arc::clone)),
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        return;
+      },
+    };
+    match window {
+      | Some(window) => {
+        if !window.ack(packet.sequence()) {
+          debug!(
+            "Ignoring out of order ack for {}",
+            packet.id
+          );
+        }
+      },
+      | None => debug!(
+        "Failed to find window for upstream: {}",
+        packet.id
+      ),
+    }
+  }
+
+  pub async fn on_close(&mut self, packet: Packet<Server, Close>) {
+    match self.remove(&packet.id) {
+      | true => debug!("Closing upstream: {}", packet.id),
+      | false => debug!(
+        "Failed to find upstream for connection: {}",
+        packet.id
+      ),
+    }
+  }
+
+  /// Drops the upstream for `id`, returning whether there was one. Dropping
+  /// the sender lets the writer flush what is already queued and then shut
+  /// the upstream down. A connect still underway is given up on.
+  fn remove(&self, id: &Uuid) -> bool {
+    let upstream = match self.connections.lock() {
+      | Ok(mut connections) => connections.remove(id),
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        return false;
+      },
+    };
+    match upstream {
+      | Some(upstream) => {
+        upstream.reader.abort();
+        true
+      },
+      | None => false,
+    }
+  }
+
+  /// Registers an upstream for `id` and starts connecting to it in a task of
+  /// its own, so a slow upstream never holds up the others. Data for it is
+  /// queued meanwhile, up to `channel_capacity` packets, and if the connect
+  /// fails the id is dropped again and the server told to close it.
+  fn open(
+    &self, id: &Uuid, port: &u16, info: Option<OpenInfo>,
+  ) -> Option<Sender<Vec<u8>>> {
+    let target = match self.targets.get(port) {
+      | Some(target) => target,
+      | None => {
+        error!("No target configured for port {port}, refusing {id}");
+        return None;
+      },
+    };
+    let upstream = info.as_ref().and_then(|info| info.upstream.as_ref());
+    if let Some(upstream) = upstream {
+      // Only ever connect where this side was configured to, whatever the
+      // server says.
+      if upstream.host != target.address || upstream.port != target.port {
+        error!(
+          "Server forwards port {port} to {}, not to the configured target, refusing {id}",
+          upstream.value()
+        );
+        return None;
+      }
+    }
+    let address = format!("{}:{}", target.address, target.port);
+    if !lock_breakers(&self.breakers).allow(&address, Instant::now()) {
+      error!("Upstream {address} keeps failing, refusing {id}");
+      return None;
+    }
+    // Written before any data so it is the first thing the upstream reads.
+    let header = target.proxy_protocol.then(|| {
+      info
+        .unwrap_or(OpenInfo {
+          peer: None,
+          local: None,
+          upstream: None,
+        })
+        .proxy_v1_header()
+    });
+    let connected = Arc::new(AtomicBool::new(false));
+    let dial = Dial {
+      id: id.to_owned(),
+      connected: Arc::clone(&connected),
+      address,
+      header,
+      timeout: self.connect_timeout,
+      retries: self.connect_retries,
+      breakers: Arc::clone(&self.breakers),
+      resolver: Arc::clone(&self.resolver),
+      connections: Arc::clone(&self.connections),
+      separator: self.separator.to_owned(),
+      hash_encoding: self.hash_encoding,
+      integrity: self.integrity,
+      control: Arc::clone(&self.control),
+    };
+    let (sender, receiver) = channel(self.channel_capacity);
+    let window = self.window.map(|window| Arc::new(Window::new(window)));
+    match self.connections.lock() {
+      | Ok(mut connections) => {
+        // The task removes the id once the upstream fails or hangs up, so it
+        // is only spawned while the lock is held to make sure the insert
+        // lands first.
+        let reader = tokio::spawn(dial.run(receiver));
+        connections.insert(
+          id.to_owned(),
+          Upstream {
+            sender: sender.clone(),
+            reader,
+            connected,
+            window,
+            received: 0,
+          },
+        );
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        return None;
+      },
+    }
+    Some(sender)
+  }
+
+  /// Drops every upstream once the control connection they were opened for
+  /// is gone. Whatever was already queued is still written before each one
+  /// is shut down.
+  pub fn close_all(&mut self) {
+    let upstreams = match self.connections.lock() {
+      | Ok(mut connections) => connections.drain().collect::<Vec<_>>(),
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        return;
+      },
+    };
+    if !upstreams.is_empty() {
+      info!("Closing {} upstreams", upstreams.len());
+    }
+    for (id, upstream) in upstreams {
+      upstream.reader.abort();
+      debug!("Closing upstream: {id}");
+    }
+  }
+
+  async fn send_close(&self, id: &Uuid) {
+    send(
+      &self.control,
+      &Client::close_connection_packet(id, &self.separator),
+    )
+    .await;
+  }
+}
+
+/// Whether a failed connect may well go through if tried again shortly
+fn is_transient(err: &Error) -> bool {
+  matches!(
+    err.kind(),
+    ErrorKind::ConnectionRefused
+      | ErrorKind::ConnectionReset
+      | ErrorKind::ConnectionAborted
+      | ErrorKind::TimedOut
+      | ErrorKind::Interrupted
+  )
+}
+
+fn lock_breakers(breakers: &Mutex<Breakers>) -> MutexGuard<'_, Breakers> {
+  match breakers.lock() {
+    | Ok(breakers) => breakers,
+    | Err(err) => err.into_inner(),
+  }
+}
+
+/// Everything a spawned connect needs to reach an upstream, and then to
+/// forward what it reads to the server
+struct Dial {
+  id: Uuid,
+  /// Set once connected, shared with the [`Upstream`] registered for `id`
+  connected: Arc<AtomicBool>,
+  address: String,
+  /// The PROXY header, for targets that speak the PROXY protocol
+  header: Option<Vec<u8>>,
+  timeout: Option<Duration>,
+  retries: u32,
+  breakers: Arc<Mutex<Breakers>>,
+  resolver: Arc<Resolver>,
+  connections: Arc<Mutex<HashMap<Uuid, Upstream>>>,
+  separator: Separator,
+  hash_encoding: HashEncoding,
+  integrity: Integrity,
+  control: Control,
+}
+
+impl Dial {
+  /// Resolves the upstream and tries connecting to it once, along with
+  /// whether a failure may pass if tried again: always for the lookup, only
+  /// when [`is_transient`] for the connect
+  async fn try_once(&self) -> Result<TcpStream, (Error, bool)> {
+    let addresses = match self.resolver.resolve(&self.address).await {
+      | Ok(addresses) => addresses,
+      | Err(err) => return Err((err, true)),
+    };
+    TcpStream::connect(addresses.as_slice()).await.map_err(|err| {
+      let transient = is_transient(&err);
+      (err, transient)
+    })
+  }
+
+  /// Runs [`Dial::try_once`], giving up with `TimedOut` after `timeout`, the
+  /// lookup included
+  async fn attempt(&self) -> Result<TcpStream, (Error, bool)> {
+    match self.timeout {
+      | Some(duration) => match timeout(duration, self.try_once()).await {
+        | Ok(stream) => stream,
+        | Err(_) => Err((
+          Error::new(
+            ErrorKind::TimedOut,
+            format!(
+              "timed out after {}ms",
+              duration.as_millis()
+            ),
+          ),
+          true,
+        )),
+      },
+      | None => self.try_once().await,
+    }
+  }
+
+  /// Connects to the upstream, trying again up to `retries` times with a
+  /// backoff doubling from [`UPSTREAM_RETRY_BACKOFF_MS`] while it fails in a
+  /// way that may pass. With a `timeout` of `t`, giving up takes at most
+  /// `(retries + 1) * t` plus `(2^retries - 1)` times the first backoff.
+  async fn connect(&self) -> Option<TcpStream> {
+    let (id, address) = (&self.id, &self.address);
+    let mut backoff = Duration::from_millis(UPSTREAM_RETRY_BACKOFF_MS);
+    let mut attempt = 0;
+    loop {
+      let (err, transient) = match self.attempt().await {
+        | Ok(stream) => {
+          lock_breakers(&self.breakers).record_success(address);
+          return Some(stream);
+        },
+        | Err(failure) => failure,
+      };
+      // Looked up again next time, in case the addresses went stale
+      self.resolver.forget(address);
+      if attempt >= self.retries || !transient {
+        error!("Failed to connect to upstream {address} for {id}: {err}");
+        lock_breakers(&self.breakers).record_failure(address, Instant::now());
+        return None;
+      }
+      attempt += 1;
+      warn!(
+        "Failed to connect to upstream {address} for {id} ({err}), trying again in {}ms",
+        backoff.as_millis()
+      );
+      sleep(backoff).await;
+      backoff *= 2;
+    }
+  }
+
+  /// Connects to the upstream, then writes what is queued for it from
+  /// `receiver` while reading what it sends back, until it hangs up
+  async fn run(self, receiver: Receiver<Vec<u8>>) {
+    let id = self.id;
+    let stream = match self.connect().await {
+      | Some(stream) => stream,
+      | None => {
+        match self.connections.lock() {
+          | Ok(mut connections) => {
+            connections.remove(&id);
+          },
+          | Err(err) => {
+            error!("Failed while aquiring lock from connections: {err}")
+          },
+        }
+        send(
+          &self.control,
+          &Client::close_connection_packet(&id, &self.separator),
+        )
+        .await;
+        return;
+      },
+    };
+    info!(
+      "New connection: {id} -> {} ({})",
+      self.address,
+      peer_label(&stream.peer_addr().ok())
+    );
+    self.connected.store(true, Ordering::SeqCst);
+
+    let (reader, mut writer) = stream.into_split();
+    if let Some(header) = &self.header {
+      if let Err(err) = writer.write_all(header).await {
+        error!("Failed to write PROXY header to upstream ({id}): {err}");
+      }
+    }
+    tokio::spawn(write_upstream(id, receiver, writer));
+    read_upstream(
+      id, reader, self.connections, self.separator, self.hash_encoding,
+      self.integrity, self.control,
+    )
+    .await;
+  }
+}
+
+async fn send(control: &Control, packet: &Vec<u8>) {
+  let mut control = control.lock().await;
+  match control.write_all(packet.as_slice()).await {
+    | Ok(_) => (),
+    | Err(err) => error!("Failed to write to control connection: {err}"),
+  }
+}
+
+async fn write_upstream(
+  id: Uuid, mut receiver: Receiver<Vec<u8>>, mut writer: OwnedWriteHalf,
+) {
+  while let Some(data) = receiver.recv().await {
+    match writer.write_all(&data).await {
+      | Ok(_) => debug!("Wrote data to upstream: {id}"),
+      | Err(err) => {
+        error!("Failed to write data to upstream ({id}): {err}");
+        break;
+      },
+    }
+  }
+  match writer.shutdown().await {
+    | Ok(_) => debug!("Closed upstream: {id}"),
+    | Err(err) => debug!("Failed to close upstream ({id}): {err}"),
+  }
+}
+
+async fn read_upstream(
+  id: Uuid, mut reader: OwnedReadHalf,
+  connections: Arc<Mutex<HashMap<Uuid, Upstream>>>, separator: Separator,
+  hash_encoding: HashEncoding, integrity: Integrity, control: Control,
+) {
+  // Spawned while the lock is held for the insert, so the upstream is always
+  // there by the time this gets the lock, unless it was closed meanwhile.
+  let window = match connections.lock() {
+    | Ok(connections) => connections
+      .get(&id)
+      .and_then(|upstream| upstream.window.as_ref().map(Arc::clone)),
+    | Err(err) => {
+      error!("Failed while aquiring lock from connections: {err}");
+      None
+    },
+  };
+  let mut buffer = [0u8; 4098];
+  // Only this task reads from the upstream and no lock is held while it
+  // waits, so the writer and the other upstreams are never held up by it.
+  loop {
+    let bytes_read = match reader.read(&mut buffer).await {
+      | Ok(0) => {
+        debug!("Upstream hung up: {id}");
+        break;
+      },
+      | Ok(bytes_read) => bytes_read,
+      | Err(err) => {
+        error!("Failed to read from upstream ({id}): {err}");
+        break;
+      },
+    };
+    // Stops reading from the upstream until the server catches up.
+    if let Some(window) = &window {
+      if !window.reserve().await {
+        break;
+      }
+    }
+    let packet = Client::build_data_packet_with(
+      &id,
+      &separator,
+      &buffer[..bytes_read].to_vec(),
+      hash_encoding,
+      integrity,
+    );
+    log_sent_packet(&packet, |packet| {
+      Server::parse_packet_with(
+        packet,
+        &separator,
+        &ParseOptions {
+          hash_encoding,
+          integrity,
+          ..ParseOptions::default()
+        },
+      )
+    });
+    send(&control, &packet).await;
+  }
+
+  info!("{id} removed");
+  match connections.lock() {
+    | Ok(mut connections) => {
+      connections.remove(&id);
+    },
+    | Err(err) => {
+      error!("Failed while aquiring lock from connections: {err}")
+    },
+  }
+  send(
+    &control,
+    &Client::close_connection_packet(&id, &separator),
+  )
+  .await;
+}
@@ -1,11 +1,20 @@
 use hydrogen::Stream as HydrogenStream;
+use simplelog::warn;
 use std::{
-  io::{Error, ErrorKind, Read, Write},
-  net::{Shutdown, TcpStream},
-  os::unix::io::{AsRawFd, RawFd},
+  env::var_os,
+  io::{self, Error, ErrorKind, Read, Write},
+  net::{Shutdown, SocketAddr, TcpStream},
+  os::unix::{
+    ffi::OsStringExt,
+    io::{AsRawFd, RawFd},
+  },
+  sync::{Arc, Mutex, MutexGuard},
+  time::{Duration, Instant},
 };
 use uuid::Uuid;
 
+use crate::functions::{HashEncoding, Integrity};
+
 pub const SETTING_FILE_PATH: &'static str = "config.json";
 
 pub const LOG_PATH: &'static str = "logs";
@@ -16,31 +25,236 @@ pub const BACKLOG: u16 = 100;
 
 pub const DEFAULT_THREAD_COUNT: usize = 4;
 
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 64;
+
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 4096;
+
+/// How much of the packet a parse error keeps, starting at the bad field
+pub const PARSE_ERROR_CONTEXT_BYTES: usize = 16;
+
+/// How much of a packet's body a packet trace dumps
+pub const TRACE_BODY_BYTES: usize = 64;
+
+pub const DEFAULT_HASH_ENCODING: HashEncoding = HashEncoding::Hex;
+
+pub const DEFAULT_INTEGRITY: Integrity = Integrity::Dual;
+
+pub const DEFAULT_AUTH_FAILURE_WINDOW_SECS: u64 = 60;
+
+pub const DEFAULT_AUTH_BLOCK_SECS: u64 = 300;
+
+pub const DEFAULT_REAUTH_TIMEOUT_SECS: u64 = 30;
+
+pub const DEFAULT_RECONNECT_BACKOFF_MS: u64 = 500;
+
+pub const DEFAULT_RECONNECT_BACKOFF_MAX_MS: u64 = 30_000;
+
+pub const DEFAULT_BREAKER_COOLDOWN_MS: u64 = 10_000;
+
+pub const DEFAULT_UPSTREAM_CONNECT_RETRIES: u32 = 2;
+
+/// Wait before the first retry of a failed upstream lookup or connect,
+/// doubled for each one after it
+pub const UPSTREAM_RETRY_BACKOFF_MS: u64 = 100;
+
+/// How long the addresses an upstream resolved to are reused before looking
+/// it up again
+pub const UPSTREAM_RESOLVE_TTL_MS: u64 = 30_000;
+
+/// Refusal reason sent to a client whose separator is not the server's
+pub const SEPARATOR_MISMATCH: &'static str = "separator";
+
+/// Refusal reason sent to a client past the server's `max_connections`
+pub const AT_CAPACITY: &'static str = "capacity";
+
+/// Bytes read from a downstream that may wait on its data window before the
+/// downstream is closed for outrunning the client
+pub const WINDOW_BACKLOG_BYTES: usize = 4 * 1024 * 1024;
+
+/// How long a connection past `max_connections` has to send something, which
+/// its refusal is then written to match, before it is closed without one
+pub const OVER_CAPACITY_GRACE_MS: u64 = 1_000;
+
+/// Asking for more than this many times `max_ports_per_session` gets the
+/// connection closed instead of just trimmed to the limit
+pub const PORT_LIMIT_CLOSE_FACTOR: usize = 4;
+
+/// How often a draining server checks whether its connections have closed
+pub const DRAIN_POLL_MS: u64 = 100;
+
+/// How often the server retries downstream writes that would have blocked
+pub const PENDING_RETRY_MS: u64 = 100;
+
+/// Largest WebSocket message taken in, which is far above any packet
+pub const MAX_WEBSOCKET_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Environment variable that overrides the auth secret from the settings file
+pub const AUTH_ENV: &'static str = "PROXY_ROUTER_AUTH";
+
+/// Identifies a tunneled connection on both ends of the control connection
+pub type ConnectionId = Uuid;
+
+/// Hands out the id of each newly accepted connection, so tests can swap the
+/// random ids for a known sequence
+pub type IdSource = Arc<dyn Fn() -> ConnectionId + Send + Sync>;
+
+pub fn random_ids() -> IdSource {
+  Arc::new(Uuid::new_v4)
+}
+
 #[derive(Clone, Debug)]
 pub enum Runtime {}
 
 #[derive(Clone, Debug)]
 pub enum ConfigFile {}
 
+/// Bytes a nonblocking write could not hand to the OS yet
+#[derive(Default)]
+pub struct PendingWrite {
+  tail: Vec<u8>,
+  /// When the tail was last left unwritten after being fully drained
+  blocked_since: Option<Instant>,
+}
+
+impl PendingWrite {
+  /// Writes whatever was left over from the last call, followed by `buf`.
+  ///
+  /// If the writer would block, the unwritten tail is kept and `WouldBlock` is
+  /// returned, meaning every byte of `buf` is now owned by this buffer. Call
+  /// again once the socket is writable (with an empty `buf` when there is
+  /// nothing new) to push the rest out.
+  ///
+  /// The writer is flushed once it took every byte, so a buffering writer
+  /// can't sit on a small write until more data fills it.
+  pub fn write<W: Write>(
+    &mut self, writer: &mut W, buf: &[u8],
+  ) -> Result<(), Error> {
+    self.tail.extend_from_slice(buf);
+    let mut written = 0;
+    let result = loop {
+      if written >= self.tail.len() {
+        break writer.flush();
+      }
+      match writer.write(&self.tail[written..]) {
+        | Ok(0) => break Err(Error::from(ErrorKind::WriteZero)),
+        | Ok(bytes) => written += bytes,
+        | Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+        | Err(err) => break Err(err),
+      }
+    };
+    self.tail.drain(..written);
+    if self.tail.is_empty() {
+      self.blocked_since = None;
+    } else if self.blocked_since.is_none() {
+      self.blocked_since = Some(Instant::now());
+    }
+    result
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.tail.is_empty()
+  }
+
+  /// How long bytes have been waiting on the writer without it ever taking
+  /// all of them, `None` while nothing is waiting
+  pub fn blocked_for(&self) -> Option<Duration> {
+    self.blocked_since.map(|since| since.elapsed())
+  }
+}
+
 pub struct Stream {
   inner: TcpStream,
   pub id: Uuid,
+  /// Address of the remote end, if the OS could still report it on accept
+  pub peer: Option<SocketAddr>,
+  /// Address the connection was accepted on
+  pub local: Option<SocketAddr>,
+  /// Shared by every clone of the stream, so whichever one is written through
+  /// next pushes out what another one left waiting
+  pending: Arc<Mutex<PendingWrite>>,
+  /// `send` fails with `TimedOut` once data has waited on the peer this long
+  pub write_timeout: Option<Duration>,
+  /// Set once the peer hung up, reported on the next `recv` so the data read
+  /// alongside the hang-up is still delivered first
+  eof: bool,
 }
 
 impl Stream {
   pub fn from_tcp_stream(tcp_stream: TcpStream) -> Stream {
+    Stream::with_id(tcp_stream, Uuid::new_v4())
+  }
+
+  pub fn with_id(tcp_stream: TcpStream, id: ConnectionId) -> Stream {
     tcp_stream.set_nonblocking(true).unwrap();
+    let peer = tcp_stream.peer_addr().ok();
+    let local = tcp_stream.local_addr().ok();
     Stream {
       inner: tcp_stream,
-      id: Uuid::new_v4(),
+      id,
+      peer,
+      local,
+      pending: Arc::new(Mutex::new(PendingWrite::default())),
+      write_timeout: None,
+      eof: false,
+    }
+  }
+
+  pub fn peer_label(&self) -> String {
+    peer_label(&self.peer)
+  }
+
+  fn pending(&self) -> MutexGuard<'_, PendingWrite> {
+    match self.pending.lock() {
+      | Ok(pending) => pending,
+      | Err(err) => err.into_inner(),
     }
   }
 }
 
+/// Formats an optional peer address for logging
+pub fn peer_label(peer: &Option<SocketAddr>) -> String {
+  match peer {
+    | Some(peer) => peer.to_string(),
+    | None => String::from("unknown peer"),
+  }
+}
+
+/// Picks the auth secret to use: the one typed at the `--ask-auth` prompt,
+/// then the one in [`AUTH_ENV`], then the settings file's
+pub fn resolve_auth(
+  file: Vec<u8>, env: Option<Vec<u8>>, prompted: Option<Vec<u8>>,
+) -> Vec<u8> {
+  prompted.or(env).unwrap_or(file)
+}
+
+/// The auth secret in [`AUTH_ENV`], taken as bytes, if it is set
+pub fn auth_from_env() -> Option<Vec<u8>> {
+  var_os(AUTH_ENV).map(OsStringExt::into_vec)
+}
+
+/// Asks for the auth secret on the terminal, without echoing it
+pub fn prompt_auth() -> io::Result<Vec<u8>> {
+  rpassword::prompt_password("Auth secret: ").map(String::into_bytes)
+}
+
+/// Ignores SIGPIPE, so writing to a socket the peer has closed fails with
+/// EPIPE where the write is handled, instead of killing the process
+pub fn ignore_sigpipe() {
+  if unsafe { libc::signal(libc::SIGPIPE, libc::SIG_IGN) } == libc::SIG_ERR {
+    warn!(
+      "Failed to ignore SIGPIPE: {}",
+      Error::last_os_error()
+    );
+  }
+}
+
 impl HydrogenStream for Stream {
   // This method is called when epoll reports data is available for reading.
   fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
     let mut msgs = Vec::<Vec<u8>>::new();
+    if self.eof {
+      return Err(Error::from(ErrorKind::UnexpectedEof));
+    }
 
     // Our socket is set to non-blocking, we need to read until
     // there is an error or the system returns WouldBlock.
@@ -50,18 +264,20 @@ impl HydrogenStream for Stream {
     let mut total_read = Vec::<u8>::new();
     loop {
       let mut buf = [0u8; 4098];
-      let read_result = self.inner.read(&mut buf);
-      if read_result.is_err() {
-        let err = read_result.unwrap_err();
-        if err.kind() == ErrorKind::WouldBlock {
+      match self.inner.read(&mut buf) {
+        // A clean hang-up, told apart from real errors by its kind.
+        | Ok(0) if total_read.is_empty() => {
+          return Err(Error::from(ErrorKind::UnexpectedEof));
+        },
+        | Ok(0) => {
+          self.eof = true;
           break;
-        }
-
-        return Err(err);
+        },
+        | Ok(num_read) => total_read.extend_from_slice(&buf[0..num_read]),
+        | Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+        | Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+        | Err(err) => return Err(err),
       }
-
-      let num_read = read_result.unwrap();
-      total_read.extend_from_slice(&buf[0..num_read]);
     }
 
     // Multiple frames, or "msgs", could have been gathered here. Break up
@@ -79,7 +295,23 @@ impl HydrogenStream for Stream {
   // This method is called when a previous attempt to write has returned `ErrorKind::WouldBlock`
   // and epoll has reported that the socket is now writable.
   fn send(&mut self, buf: &[u8]) -> Result<(), Error> {
-    self.inner.write_all(buf)
+    let mut pending = self.pending();
+    let result = pending.write(&mut &self.inner, buf);
+    match (
+      self.write_timeout,
+      pending.blocked_for(),
+    ) {
+      | (Some(timeout), Some(blocked)) if blocked >= timeout => {
+        Err(Error::new(
+          ErrorKind::TimedOut,
+          format!(
+            "peer stopped reading {}ms ago",
+            blocked.as_millis()
+          ),
+        ))
+      },
+      | _ => result,
+    }
   }
 
   // This method is called when connection has been reported as reset by epoll, or when any
@@ -100,6 +332,11 @@ impl Clone for Stream {
     Stream {
       inner: self.inner.try_clone().unwrap(),
       id: self.id,
+      peer: self.peer,
+      local: self.local,
+      pending: Arc::clone(&self.pending),
+      write_timeout: self.write_timeout,
+      eof: self.eof,
     }
   }
 }
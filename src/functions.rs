@@ -1,11 +1,27 @@
-use std::fmt::{Display, Formatter};
+use std::{
+  collections::{HashMap, HashSet},
+  fmt::{Debug, Display, Formatter},
+  hash::Hash,
+  io::{self, BufRead, Read, Seek, SeekFrom, Write},
+  net::SocketAddr,
+  ops::Deref,
+  str::{FromStr, Utf8Error},
+};
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use digest::Digest;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use sha1::Sha1;
 use sha2::Sha512;
 use simplelog::warn;
-use uuid::Uuid;
+use uuid::{fmt::Hyphenated, Uuid};
 
+use crate::constants::{
+  DEFAULT_HASH_ENCODING, DEFAULT_INTEGRITY, DEFAULT_MAX_HEADER_BYTES,
+  PARSE_ERROR_CONTEXT_BYTES,
+};
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum PacketAction {
   /// Data packet
   ///
@@ -17,9 +33,18 @@ pub enum PacketAction {
   ///
   /// {action} {id} {port} {sha1} {sha512}{separator}{body}
   ///
+  /// or, when the server announced `integrity=sha512_only`:
+  ///
+  /// {action} {id} {port} {sha512}{separator}{body}
+  ///
   /// ## Example
   ///
   /// DATA 123e4567-e89b-12d3-a456-426614174000 8080 0a0a9f2a6772942557ab5355d76af442f8f65e01 374d794a95cdcfd8b35993185fef9ba368f160d8daf432d08ba9f1ed1e5abe6cc69291e0fa2fe0006a52570ef18c19def4e617c33ce52ef0a6e5fbe318cb0387\u0000Hello, world!
+  ///
+  /// When the server announced `encoding=raw`, the hashes are the digests
+  /// themselves rather than hex. They may then contain spaces or the
+  /// separator, so the header ends a fixed length after the last space
+  /// before them.
   DATA,
   /// Close packet
   ///
@@ -43,12 +68,90 @@ pub enum PacketAction {
   ///
   /// The packet must follow this format:
   ///
-  /// {action} {ports}{separator}{auth}
+  /// {action} {ports length}{separator}{ports}{auth}
+  ///
+  /// The ports are sent comma-separated at the start of the body, and the
+  /// header only carries how many bytes of the body they take, so the header
+  /// stays small no matter how many ports are requested. A port may be
+  /// followed by `={host}:{port}`, the upstream the client forwards it to.
   ///
   /// ## Example
   ///
-  /// AUTH 8080,8081,8082\u0000CH4ng3M3!
+  /// AUTH 14\u00008080,8081,8082CH4ng3M3!
+  ///
+  /// AUTH 22\u00008080=127.0.0.1:80,8081CH4ng3M3!
   AUTH,
+  /// Auth result packet
+  ///
+  /// This packet is sent by the server as the answer to an auth packet.
+  ///
+  /// # Usage
+  ///
+  /// The packet must follow this format:
+  ///
+  /// {action}{separator}{status}[;{key}={value}]...
+  ///
+  /// The status is either `success` or `forbidden`. A successful answer may
+  /// carry the server identification as `name` and `v` (version) fields.
+  ///
+  /// ## Example
+  ///
+  /// AUTHTRY\u0000success;name=edge1;v=0.0.1
+  AUTHTRY,
+  /// No-op packet
+  ///
+  /// This packet carries nothing and is ignored by whoever receives it. It can
+  /// be sent as filler to keep a connection open, and unlike a heartbeat it
+  /// must not be taken as a sign of liveness.
+  ///
+  /// # Usage
+  ///
+  /// The packet must follow this format:
+  ///
+  /// {action}{separator}
+  ///
+  /// ## Example
+  ///
+  /// NOOP\u0000
+  NOOP,
+  /// Open packet
+  ///
+  /// This packet is sent by the server when a new connection is accepted on
+  /// one of the forwarded ports, before any of its data.
+  ///
+  /// # Usage
+  ///
+  /// The packet must follow this format:
+  ///
+  /// {action} {id} {port}{separator}{peer address} {local address}[ {upstream}]
+  ///
+  /// Either address is `unknown` when the server could not read it. The
+  /// upstream is only there when the client named one for the port in its
+  /// auth packet.
+  ///
+  /// ## Example
+  ///
+  /// OPEN 123e4567-e89b-12d3-a456-426614174000 8080\u0000203.0.113.7:56324 10.0.0.1:8080
+  OPEN,
+  /// Acknowledgement packet
+  ///
+  /// This packet is sent back for data packets when the server announced a
+  /// data window, so the other side knows how many are still in flight.
+  ///
+  /// # Usage
+  ///
+  /// The packet must follow this format:
+  ///
+  /// {action} {id}{separator}{sequence}
+  ///
+  /// The data packets of a connection are numbered from 1 in the order they
+  /// are sent, and the sequence is the number of the last one received. It
+  /// acknowledges every data packet up to it.
+  ///
+  /// ## Example
+  ///
+  /// ACK 123e4567-e89b-12d3-a456-426614174000\u000042
+  ACK,
 }
 
 #[derive(Debug)]
@@ -59,12 +162,26 @@ pub enum ParseErrorType {
   Hash,
   Port,
   Ports,
+  Status,
+  Length,
+  Trailing,
+  Address,
+  Sequence,
+}
+
+/// Where in a packet parsing failed
+#[derive(Debug, PartialEq)]
+pub struct ErrorContext {
+  /// Bytes into the packet where the offending field starts
+  pub offset: usize,
+  /// The packet from `offset` on, cut to a few bytes
+  pub context: Vec<u8>,
 }
 
 #[derive(Debug)]
 pub enum ParseError {
   Header(ParseErrorType),
-  Other(ParseErrorType),
+  Other(ParseErrorType, Option<ErrorContext>),
 }
 
 impl ParseErrorType {
@@ -76,6 +193,11 @@ impl ParseErrorType {
       | ParseErrorType::Hash => "Invalid hash".to_string(),
       | ParseErrorType::Port => "Invalid port".to_string(),
       | ParseErrorType::Ports => "Invalid ports".to_string(),
+      | ParseErrorType::Status => "Invalid status".to_string(),
+      | ParseErrorType::Length => "Too long".to_string(),
+      | ParseErrorType::Trailing => "Unexpected trailing data".to_string(),
+      | ParseErrorType::Address => "Invalid address".to_string(),
+      | ParseErrorType::Sequence => "Invalid sequence".to_string(),
     }
   }
 }
@@ -92,11 +214,30 @@ impl ParseError {
       | ParseError::Header(error) => {
         format!("Invalid header: {}", error.value())
       },
-      | ParseError::Other(error) => {
+      | ParseError::Other(error, None) => {
         format!("Invalid packet: {}", error.value())
       },
+      | ParseError::Other(error, Some(context)) => format!(
+        "Invalid packet: {} at byte {} near {:?}",
+        error.value(),
+        context.offset,
+        String::from_utf8_lossy(&context.context)
+      ),
     }
   }
+
+  /// An error in the field starting `offset` bytes into `packet`
+  fn at(error: ParseErrorType, packet: &[u8], offset: usize) -> ParseError {
+    let start = offset.min(packet.len());
+    let end = (start + PARSE_ERROR_CONTEXT_BYTES).min(packet.len());
+    ParseError::Other(
+      error,
+      Some(ErrorContext {
+        offset,
+        context: packet[start..end].to_vec(),
+      }),
+    )
+  }
 }
 
 impl Display for ParseError {
@@ -106,12 +247,37 @@ impl Display for ParseError {
 }
 
 impl PacketAction {
-  pub fn from_string(string: String) -> PacketAction {
+  /// Every action understood by the protocol
+  pub fn all() -> &'static [PacketAction] {
+    &[
+      PacketAction::DATA,
+      PacketAction::CLOSE,
+      PacketAction::AUTH,
+      PacketAction::AUTHTRY,
+      PacketAction::NOOP,
+      PacketAction::OPEN,
+      PacketAction::ACK,
+    ]
+  }
+
+  /// Looks up an action by its token, ignoring case
+  pub fn try_from_str(string: &str) -> Option<PacketAction> {
     match string.to_lowercase().as_str() {
-      | "data" => PacketAction::DATA,
-      | "close" => PacketAction::CLOSE,
-      | "auth" => PacketAction::AUTH,
-      | _ => panic!("Invalid packet type: {}", string),
+      | "data" => Some(PacketAction::DATA),
+      | "close" => Some(PacketAction::CLOSE),
+      | "auth" => Some(PacketAction::AUTH),
+      | "authtry" => Some(PacketAction::AUTHTRY),
+      | "noop" => Some(PacketAction::NOOP),
+      | "open" => Some(PacketAction::OPEN),
+      | "ack" => Some(PacketAction::ACK),
+      | _ => None,
+    }
+  }
+
+  pub fn from_string(string: String) -> PacketAction {
+    match PacketAction::try_from_str(&string) {
+      | Some(action) => action,
+      | None => panic!("Invalid packet type: {}", string),
     }
   }
 
@@ -120,18 +286,37 @@ impl PacketAction {
       | PacketAction::DATA => "DATA".to_string(),
       | PacketAction::CLOSE => "CLOSE".to_string(),
       | PacketAction::AUTH => "AUTH".to_string(),
+      | PacketAction::AUTHTRY => "AUTHTRY".to_string(),
+      | PacketAction::NOOP => "NOOP".to_string(),
+      | PacketAction::OPEN => "OPEN".to_string(),
+      | PacketAction::ACK => "ACK".to_string(),
     }
   }
 }
 
+// The markers derive the comparison traits only so that `Packet` and
+// `PacketType` can derive them too; they are never instantiated.
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub enum Server {}
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub enum Client {}
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub enum Data {}
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub enum Auth {}
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum AuthTry {}
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub enum Close {}
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum Noop {}
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum Open {}
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum Ack {}
 
 pub trait Environment {
-  type PortType;
+  type PortType: Debug + Eq + Hash;
 }
 
 impl Environment for Server {
@@ -143,10 +328,10 @@ impl Environment for Client {
 }
 
 pub trait PacketTrait {
-  type Sha1Type;
-  type Sha512Type;
-  type PortsType;
-  type IDType;
+  type Sha1Type: Debug + Eq + Hash;
+  type Sha512Type: Debug + Eq + Hash;
+  type PortsType: Debug + Eq + Hash;
+  type IDType: Debug + Eq + Hash;
 }
 
 impl PacketTrait for Data {
@@ -159,7 +344,14 @@ impl PacketTrait for Data {
 impl PacketTrait for Auth {
   type Sha1Type = ();
   type Sha512Type = ();
-  type PortsType = Vec<u16>;
+  type PortsType = PortSet;
+  type IDType = ();
+}
+
+impl PacketTrait for AuthTry {
+  type Sha1Type = ();
+  type Sha512Type = ();
+  type PortsType = ();
   type IDType = ();
 }
 
@@ -170,6 +362,28 @@ impl PacketTrait for Close {
   type IDType = Uuid;
 }
 
+impl PacketTrait for Noop {
+  type Sha1Type = ();
+  type Sha512Type = ();
+  type PortsType = ();
+  type IDType = ();
+}
+
+impl PacketTrait for Open {
+  type Sha1Type = ();
+  type Sha512Type = ();
+  type PortsType = ();
+  type IDType = Uuid;
+}
+
+impl PacketTrait for Ack {
+  type Sha1Type = ();
+  type Sha512Type = ();
+  type PortsType = ();
+  type IDType = Uuid;
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct Packet<Env: Environment, PacketSubset: PacketTrait> {
   pub action: PacketAction,
   pub id: PacketSubset::IDType,
@@ -180,274 +394,1904 @@ pub struct Packet<Env: Environment, PacketSubset: PacketTrait> {
   pub body: Vec<u8>,
 }
 
+impl<Env: Environment, PacketSubset: PacketTrait> Packet<Env, PacketSubset> {
+  /// Borrows the body as text, without copying it
+  pub fn body_as_str(&self) -> Result<&str, Utf8Error> {
+    std::str::from_utf8(&self.body)
+  }
+}
+
+impl<Env: Environment> Packet<Env, Ack> {
+  /// The last data packet acknowledged, checked when the packet was parsed
+  pub fn sequence(&self) -> u64 {
+    parse_sequence(&self.body).unwrap_or(0)
+  }
+}
+
+fn parse_sequence(body: &[u8]) -> Option<u64> {
+  if body.is_empty() || !body.iter().all(u8::is_ascii_digit) {
+    return None;
+  }
+  std::str::from_utf8(body).ok()?.parse::<u64>().ok()
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub enum PacketType<Env: Environment> {
   Data(Packet<Env, Data>),
   Auth(Packet<Env, Auth>),
+  AuthTry(Packet<Env, AuthTry>),
   Close(Packet<Env, Close>),
+  Noop(Packet<Env, Noop>),
+  Open(Packet<Env, Open>),
+  Ack(Packet<Env, Ack>),
+  Custom(CustomPacket),
 }
 
-pub fn hash_sha1(data: &Vec<u8>) -> String {
-  let mut sha1 = Sha1::new();
-  sha1.update(data);
-  let result_sha1 = sha1.finalize();
-  format!("{:x}", result_sha1)
+impl<Env: Environment> PacketType<Env> {
+  /// The action the packet was sent with, as its header spells it
+  pub fn action(&self) -> String {
+    match self {
+      | PacketType::Data(packet) => packet.action.value(),
+      | PacketType::Auth(packet) => packet.action.value(),
+      | PacketType::AuthTry(packet) => packet.action.value(),
+      | PacketType::Close(packet) => packet.action.value(),
+      | PacketType::Noop(packet) => packet.action.value(),
+      | PacketType::Open(packet) => packet.action.value(),
+      | PacketType::Ack(packet) => packet.action.value(),
+      | PacketType::Custom(packet) => packet.action.to_owned(),
+    }
+  }
+
+  pub fn body(&self) -> &[u8] {
+    match self {
+      | PacketType::Data(packet) => &packet.body,
+      | PacketType::Auth(packet) => &packet.body,
+      | PacketType::AuthTry(packet) => &packet.body,
+      | PacketType::Close(packet) => &packet.body,
+      | PacketType::Noop(packet) => &packet.body,
+      | PacketType::Open(packet) => &packet.body,
+      | PacketType::Ack(packet) => &packet.body,
+      | PacketType::Custom(packet) => &packet.body,
+    }
+  }
 }
 
-pub fn hash_sha512(data: &Vec<u8>) -> String {
-  let mut sha512 = Sha512::new();
-  sha512.update(data);
-  let result_sha512 = sha512.finalize();
-  format!("{:x}", result_sha512)
+/// A well-formed packet whose action is not one of [`PacketAction`], left for
+/// an [`ActionRegistry`] to handle
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct CustomPacket {
+  /// The action, uppercased
+  pub action: String,
+  /// Everything in the header after the action
+  pub header: Vec<u8>,
+  pub body: Vec<u8>,
 }
 
-pub fn split(
-  packet: &Vec<u8>, separator: &Vec<u8>,
-) -> Option<(Vec<u8>, Vec<u8>)> {
-  if separator.is_empty() || packet.is_empty() {
-    return None;
+impl CustomPacket {
+  /// Accepts actions made of ASCII letters, digits and underscores only
+  pub fn new(
+    action: String, header: Vec<u8>, body: Vec<u8>,
+  ) -> Result<CustomPacket, ParseError> {
+    if !is_custom_action(&action) {
+      return Err(ParseError::Other(
+        ParseErrorType::Action,
+        None,
+      ));
+    }
+    Ok(CustomPacket {
+      action: action.to_uppercase(),
+      header,
+      body,
+    })
   }
+}
 
-  let mut first_part = Vec::new();
-  let mut cache: Vec<u8> = Vec::new();
-  let mut separator_index = 0;
-  let mut i: usize = 0;
+fn is_custom_action(action: &str) -> bool {
+  !action.is_empty()
+    && action.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
-  for byte in packet.clone() {
-    i += 1;
-    if byte == separator[separator_index] {
-      separator_index += 1;
-      cache.push(byte);
+pub type ActionHandler = Box<dyn FnMut(&CustomPacket) + Send + Sync>;
 
-      if separator_index >= separator.len() {
-        let second_part = packet.split_at(i).1.to_vec();
-        return Some((first_part, second_part));
-      }
-    } else {
-      if separator_index > 0 {
-        first_part.extend(cache.clone());
-        cache.clear();
-      }
-      first_part.push(byte);
-      separator_index = 0;
+/// Handlers for experimental actions, looked up before giving up on a packet
+/// with an unknown action
+#[derive(Default)]
+pub struct ActionRegistry {
+  handlers: HashMap<String, ActionHandler>,
+}
+
+impl ActionRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Registers `handler` for `action`, refusing built-in or malformed actions
+  pub fn register(&mut self, action: &str, handler: ActionHandler) -> bool {
+    if PacketAction::try_from_str(action).is_some() || !is_custom_action(action)
+    {
+      return false;
     }
+    self.handlers.insert(action.to_uppercase(), handler);
+    true
   }
 
-  None
+  /// Runs the handler registered for the packet's action, returning whether
+  /// there was one
+  pub fn dispatch(&mut self, packet: &CustomPacket) -> bool {
+    match self.handlers.get_mut(&packet.action) {
+      | Some(handler) => {
+        handler(packet);
+        true
+      },
+      | None => false,
+    }
+  }
 }
 
-impl Server {
-  pub fn build_data_packet(
-    id: &Uuid, port: &u16, separator: &str, data: &Vec<u8>,
-  ) -> Vec<u8> {
-    let id = id.to_string();
-    let packet = format!(
-      "{} {id} {port} {} {}{separator}",
-      PacketAction::DATA.value(),
-      hash_sha1(&data),
-      hash_sha512(&data),
-    );
-    let mut packet = packet.as_bytes().to_vec();
-    packet.extend(data);
-    packet
+#[derive(Debug, PartialEq)]
+pub enum AuthStatus {
+  Success,
+  Forbidden,
+  /// Sent to an authenticated client to have it send its AUTH packet again
+  Reauth,
+}
+
+impl AuthStatus {
+  pub fn from_string(string: &str) -> Option<AuthStatus> {
+    match string.to_lowercase().as_str() {
+      | "success" => Some(AuthStatus::Success),
+      | "forbidden" => Some(AuthStatus::Forbidden),
+      | "reauth" => Some(AuthStatus::Reauth),
+      | _ => None,
+    }
   }
 
-  pub fn close_connection_packet(id: &Uuid, separator: &String) -> Vec<u8> {
-    let id = id.to_string();
-    let packet = format!(
-      "{} {id}{separator}",
-      PacketAction::CLOSE.value()
-    );
-    packet.as_bytes().to_vec()
+  pub fn value(&self) -> String {
+    match self {
+      | AuthStatus::Success => "success".to_string(),
+      | AuthStatus::Forbidden => "forbidden".to_string(),
+      | AuthStatus::Reauth => "reauth".to_string(),
+    }
   }
+}
 
-  ///
-  /// Parses a packet from the client
-  ///
-  pub fn parse_packet(
-    packet: Vec<u8>, separator: &Vec<u8>,
-  ) -> Result<PacketType<Client>, ParseError> {
-    let (header, body) = split(&packet, separator)
-      .ok_or(ParseError::Header(ParseErrorType::Type))?;
-    let (action, p) = split(&header, &" ".as_bytes().to_vec()).ok_or(
-      ParseError::Header(ParseErrorType::Action),
+/// The body of an auth result packet
+#[derive(Debug, PartialEq)]
+pub struct AuthTryInfo {
+  pub status: AuthStatus,
+  pub name: Option<String>,
+  pub version: Option<String>,
+  /// Hashes the server puts in data packets, dual when not announced
+  pub integrity: Option<Integrity>,
+  /// Most data packets either side may have unacknowledged per connection,
+  /// no limit and no acknowledgements when not announced
+  pub window: Option<u64>,
+  /// Encoding the hashes of data packets are written in, both ways, the one
+  /// the client was set up with when not announced
+  pub encoding: Option<HashEncoding>,
+  /// Why the client was refused, when the server tells, like
+  /// [`crate::constants::SEPARATOR_MISMATCH`]
+  pub reason: Option<String>,
+}
+
+impl AuthTryInfo {
+  pub fn from_body(body: &Vec<u8>) -> Result<AuthTryInfo, ParseError> {
+    let body = String::from_utf8(body.to_owned()).ok().ok_or(
+      ParseError::Other(ParseErrorType::Status, None),
+    )?;
+    let mut fields = body.split(";");
+    let status = fields.next().and_then(AuthStatus::from_string).ok_or(
+      ParseError::Other(ParseErrorType::Status, None),
     )?;
+    let mut info = AuthTryInfo {
+      status,
+      name: None,
+      version: None,
+      integrity: None,
+      window: None,
+      encoding: None,
+      reason: None,
+    };
+    for field in fields {
+      match field.split_once("=") {
+        | Some(("name", name)) => info.name = Some(name.to_string()),
+        | Some(("v", version)) => info.version = Some(version.to_string()),
+        | Some(("integrity", integrity)) => {
+          info.integrity = Some(Integrity::from_string(integrity).ok_or(
+            ParseError::Other(ParseErrorType::Status, None),
+          )?)
+        },
+        | Some(("window", window)) => {
+          info.window = Some(
+            window.parse::<u64>().ok().filter(|window| *window > 0).ok_or(
+              ParseError::Other(ParseErrorType::Status, None),
+            )?,
+          )
+        },
+        | Some(("encoding", encoding)) => {
+          info.encoding = Some(
+            HashEncoding::from_string(encoding).ok_or(ParseError::Other(
+              ParseErrorType::Status,
+              None,
+            ))?,
+          )
+        },
+        | Some(("reason", reason)) => info.reason = Some(reason.to_string()),
+        | _ => (),
+      }
+    }
+    Ok(info)
+  }
 
-    let action =
-      PacketAction::from_string(String::from_utf8(action).ok().ok_or(
-        ParseError::Other(ParseErrorType::Action),
-      )?);
+  pub fn value(&self) -> String {
+    let mut value = self.status.value();
+    if let Some(name) = &self.name {
+      value.push_str(&format!(";name={name}"));
+    }
+    if let Some(version) = &self.version {
+      value.push_str(&format!(";v={version}"));
+    }
+    if let Some(integrity) = &self.integrity {
+      value.push_str(&format!(
+        ";integrity={}",
+        integrity.value()
+      ));
+    }
+    if let Some(window) = &self.window {
+      value.push_str(&format!(";window={window}"));
+    }
+    if let Some(encoding) = &self.encoding {
+      value.push_str(&format!(
+        ";encoding={}",
+        encoding.value()
+      ));
+    }
+    if let Some(reason) = &self.reason {
+      value.push_str(&format!(";reason={reason}"));
+    }
+    value
+  }
+}
 
-    match &action {
-      | PacketAction::DATA => {
-        let (id, p) = split(&p, &" ".as_bytes().to_vec())
-          .ok_or(ParseError::Header(ParseErrorType::ID))?;
-        let id = String::from_utf8(id)
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::ID))?;
+/// Why a string is not a valid [`Endpoint`] or [`Forward`]
+#[derive(Debug, PartialEq)]
+pub enum AddressError {
+  /// There is no `:` between the host and the port
+  MissingPort,
+  /// The host is empty, or holds a character the fields it is carried in are
+  /// split on
+  Host,
+  /// The port is not a number from 0 to 65535
+  Port,
+}
 
-        let id = Uuid::parse_str(&id)
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::ID))?;
-        let (sha1, sha512) = split(&p, &" ".as_bytes().to_vec())
-          .ok_or(ParseError::Header(ParseErrorType::Hash))?;
-        let sha1 = String::from_utf8(sha1)
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::Hash))?;
-        let sha512 = String::from_utf8(sha512)
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::Hash))?;
-        Ok(PacketType::Data(Packet {
-          action,
-          id,
-          port: (),
-          ports: (),
-          sha1,
-          sha512,
-          body,
-        }))
-      },
-      | PacketAction::AUTH => {
-        let ports = p;
-        let ports = String::from_utf8(ports)
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::Ports))?;
-        let ports = ports
-          .split(",")
-          .map(|port| {
-            port
-              .parse::<u16>()
-              .ok()
-              .ok_or(ParseError::Other(ParseErrorType::Ports))
-          })
-          .collect::<Result<Vec<u16>, ParseError>>()?;
-        Ok(PacketType::Auth(Packet {
-          action,
-          id: (),
-          port: (),
-          ports,
-          sha1: (),
-          sha512: (),
-          body,
-        }))
-      },
-      | PacketAction::CLOSE => {
-        let id: Uuid = Uuid::try_parse_ascii(p.as_slice())
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::ID))?;
-        Ok(PacketType::Close(Packet {
-          action,
-          id,
-          port: (),
-          ports: (),
-          sha1: (),
-          sha512: (),
-          body,
-        }))
+impl AddressError {
+  pub fn value(&self) -> String {
+    match self {
+      | AddressError::MissingPort => "The address has no port".to_string(),
+      | AddressError::Host => {
+        "The host is empty or holds a ' ', ',' or '='".to_string()
       },
+      | AddressError::Port => "The port is not a valid port".to_string(),
     }
   }
 }
 
-impl Client {
-  pub fn build_data_packet(
-    id: &Uuid, separator: &str, data: &Vec<u8>,
-  ) -> Vec<u8> {
-    let id = id.to_string();
-    let packet = format!(
-      "{} {id} {} {}{separator}",
-      PacketAction::DATA.value(),
-      hash_sha1(&data),
-      hash_sha512(&data),
-    );
-    let mut packet = packet.as_bytes().to_vec();
-    packet.extend(data);
-    packet
+impl Display for AddressError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.value())
   }
+}
 
-  pub fn close_connection_packet(id: &Uuid, separator: &String) -> Vec<u8> {
-    let id = id.to_string();
-    let packet = format!(
-      "{} {id} 0{separator}",
-      PacketAction::CLOSE.value()
-    );
-    packet.as_bytes().to_vec()
+/// A host and port to connect to, written `{host}:{port}`
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Endpoint {
+  pub host: String,
+  pub port: u16,
+}
+
+impl FromStr for Endpoint {
+  type Err = AddressError;
+
+  fn from_str(endpoint: &str) -> Result<Endpoint, AddressError> {
+    let (host, port) =
+      endpoint.rsplit_once(":").ok_or(AddressError::MissingPort)?;
+    // These separate the fields the endpoint is carried in.
+    if host.is_empty() || host.contains([' ', ',', '=']) {
+      return Err(AddressError::Host);
+    }
+    Ok(Endpoint {
+      host: host.to_string(),
+      port: port.parse::<u16>().map_err(|_| AddressError::Port)?,
+    })
   }
+}
 
-  pub fn build_auth_packet(
-    auth: &String, ports: &Vec<u16>, separator: &String,
-  ) -> Vec<u8> {
-    let ports_string = ports
-      .iter()
-      .map(|port| port.to_string())
-      .collect::<Vec<String>>()
-      .join(",");
-    let packet = format!(
-      "{} {ports_string}{separator}{auth}",
-      PacketAction::AUTH.value()
-    );
-    packet.as_bytes().to_vec()
+impl Endpoint {
+  pub fn value(&self) -> String {
+    format!("{}:{}", self.host, self.port)
   }
+}
 
-  ///
-  /// Parses a packet from the server
-  ///
-  pub fn parse_packet(
-    packet: Vec<u8>, separator: &Vec<u8>,
-  ) -> Result<PacketType<Server>, ParseError> {
-    let (header, body) = split(&packet, separator)
-      .ok_or(ParseError::Header(ParseErrorType::Type))?;
-    let (action, p) = split(&header, &" ".as_bytes().to_vec()).ok_or(
-      ParseError::Header(ParseErrorType::Action),
-    )?;
+/// A port the client asks the server to listen on, along with where the
+/// client forwards what arrives on it when that is not the same port on the
+/// client's own target: `{port}` or `{port}={host}:{port}`
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Forward {
+  pub port: u16,
+  pub upstream: Option<Endpoint>,
+}
 
-    let action =
-      PacketAction::from_string(String::from_utf8(action).ok().ok_or(
-        ParseError::Other(ParseErrorType::Action),
-      )?);
+impl FromStr for Forward {
+  type Err = AddressError;
 
-    match &action {
-      | PacketAction::DATA => {
-        let (id, p) = split(&p, &" ".as_bytes().to_vec())
-          .ok_or(ParseError::Header(ParseErrorType::ID))?;
-        let id = String::from_utf8(id)
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::ID))?;
-        let id = Uuid::parse_str(&id)
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::ID))?;
-        let (port, p) = split(&p, &" ".as_bytes().to_vec())
-          .ok_or(ParseError::Header(ParseErrorType::Port))?;
-        let port = String::from_utf8(port)
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::Port))?
-          .parse::<u16>()
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::Port))?;
-        let (sha1, sha512) = split(&p, &" ".as_bytes().to_vec())
-          .ok_or(ParseError::Header(ParseErrorType::Hash))?;
-        let sha1 = String::from_utf8(sha1)
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::Hash))?;
-        let sha512 = String::from_utf8(sha512)
-          .ok()
-          .ok_or(ParseError::Other(ParseErrorType::Hash))?;
-        Ok(PacketType::Data(Packet {
-          action,
-          id,
-          port,
-          ports: (),
+  fn from_str(forward: &str) -> Result<Forward, AddressError> {
+    let (port, upstream) = match forward.split_once("=") {
+      | Some((port, upstream)) => (port, Some(upstream.parse()?)),
+      | None => (forward, None),
+    };
+    Ok(Forward {
+      port: port.parse::<u16>().map_err(|_| AddressError::Port)?,
+      upstream,
+    })
+  }
+}
+
+impl Forward {
+  pub fn value(&self) -> String {
+    match &self.upstream {
+      | Some(upstream) => format!("{}={}", self.port, upstream.value()),
+      | None => self.port.to_string(),
+    }
+  }
+}
+
+impl From<u16> for Forward {
+  fn from(port: u16) -> Self {
+    Forward {
+      port,
+      upstream: None,
+    }
+  }
+}
+
+/// Why a list of forwards is not a valid port set
+#[derive(Debug, PartialEq)]
+pub enum PortSetError {
+  /// Port 0 can't be listened on by number
+  Zero,
+  /// The port was asked for more than once
+  Duplicate(u16),
+}
+
+impl PortSetError {
+  pub fn value(&self) -> String {
+    match self {
+      | PortSetError::Zero => "Port 0 can't be forwarded".to_string(),
+      | PortSetError::Duplicate(port) => {
+        format!("Port {port} is forwarded more than once")
+      },
+    }
+  }
+}
+
+/// The forwards of an auth request, each on a distinct, nonzero port, in the
+/// order they were asked for
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Default)]
+pub struct PortSet(Vec<Forward>);
+
+impl PortSet {
+  pub fn new(forwards: Vec<Forward>) -> Result<PortSet, PortSetError> {
+    let mut seen = HashSet::with_capacity(forwards.len());
+    for forward in &forwards {
+      if forward.port == 0 {
+        return Err(PortSetError::Zero);
+      }
+      if !seen.insert(forward.port) {
+        return Err(PortSetError::Duplicate(forward.port));
+      }
+    }
+    Ok(PortSet(forwards))
+  }
+
+  pub fn from_ports(ports: &Vec<u16>) -> Result<PortSet, PortSetError> {
+    PortSet::new(ports.iter().map(|port| Forward::from(*port)).collect())
+  }
+
+  pub fn forwards(&self) -> &Vec<Forward> {
+    &self.0
+  }
+
+  pub fn ports(&self) -> Vec<u16> {
+    self.0.iter().map(|forward| forward.port).collect()
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+}
+
+/// The body of an open packet
+#[derive(Debug, PartialEq, Clone)]
+pub struct OpenInfo {
+  pub peer: Option<SocketAddr>,
+  pub local: Option<SocketAddr>,
+  /// Where the client asked for this port to be forwarded, if it did
+  pub upstream: Option<Endpoint>,
+}
+
+impl OpenInfo {
+  pub fn from_body(body: &Vec<u8>) -> Result<OpenInfo, ParseError> {
+    let body = String::from_utf8(body.to_owned()).ok().ok_or(
+      ParseError::Other(ParseErrorType::Address, None),
+    )?;
+    let mut fields = body.split(" ");
+    let (peer, local) = match (fields.next(), fields.next()) {
+      | (Some(peer), Some(local)) => (peer, local),
+      | _ => {
+        return Err(ParseError::Other(
+          ParseErrorType::Address,
+          None,
+        ))
+      },
+    };
+    let upstream = match fields.next() {
+      | Some(upstream) => Some(upstream.parse::<Endpoint>().ok().ok_or(
+        ParseError::Other(ParseErrorType::Address, None),
+      )?),
+      | None => None,
+    };
+    let parse = |address: &str| match address {
+      | "unknown" => Ok(None),
+      | address => address.parse::<SocketAddr>().map(Some).ok().ok_or(
+        ParseError::Other(ParseErrorType::Address, None),
+      ),
+    };
+    Ok(OpenInfo {
+      peer: parse(peer)?,
+      local: parse(local)?,
+      upstream,
+    })
+  }
+
+  pub fn value(&self) -> String {
+    let format = |address: &Option<SocketAddr>| match address {
+      | Some(address) => address.to_string(),
+      | None => String::from("unknown"),
+    };
+    let mut value = format!(
+      "{} {}",
+      format(&self.peer),
+      format(&self.local)
+    );
+    if let Some(upstream) = &self.upstream {
+      value.push_str(&format!(" {}", upstream.value()));
+    }
+    value
+  }
+
+  /// Builds the PROXY protocol v1 header for this connection, falling back to
+  /// `UNKNOWN` when an address is missing or the two are of different families
+  pub fn proxy_v1_header(&self) -> Vec<u8> {
+    let header = match (self.peer, self.local) {
+      | (Some(SocketAddr::V4(peer)), Some(SocketAddr::V4(local))) => format!(
+        "PROXY TCP4 {} {} {} {}\r\n",
+        peer.ip(),
+        local.ip(),
+        peer.port(),
+        local.port()
+      ),
+      | (Some(SocketAddr::V6(peer)), Some(SocketAddr::V6(local))) => format!(
+        "PROXY TCP6 {} {} {} {}\r\n",
+        peer.ip(),
+        local.ip(),
+        peer.port(),
+        local.port()
+      ),
+      | _ => String::from("PROXY UNKNOWN\r\n"),
+    };
+    header.as_bytes().to_vec()
+  }
+}
+
+/// How the hashes in a data packet header are written
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashEncoding {
+  Hex,
+  Base64,
+  /// The digests themselves, half the size of hex. Once parsed, they are
+  /// kept as hex.
+  Raw,
+}
+
+impl HashEncoding {
+  pub fn from_string(encoding: &str) -> Option<HashEncoding> {
+    match encoding {
+      | "hex" => Some(HashEncoding::Hex),
+      | "base64" => Some(HashEncoding::Base64),
+      | "raw" => Some(HashEncoding::Raw),
+      | _ => None,
+    }
+  }
+
+  pub fn value(&self) -> String {
+    match self {
+      | HashEncoding::Hex => "hex".to_string(),
+      | HashEncoding::Base64 => "base64".to_string(),
+      | HashEncoding::Raw => "raw".to_string(),
+    }
+  }
+
+  pub fn encode(&self, digest: &[u8]) -> String {
+    match self {
+      | HashEncoding::Hex | HashEncoding::Raw => {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+      },
+      | HashEncoding::Base64 => STANDARD.encode(digest),
+    }
+  }
+
+  /// How long a `digest_len` byte hash is once written in a packet header
+  fn written_len(&self, digest_len: usize) -> usize {
+    match self {
+      | HashEncoding::Hex => digest_len * 2,
+      | HashEncoding::Base64 => digest_len.div_ceil(3) * 4,
+      | HashEncoding::Raw => digest_len,
+    }
+  }
+
+  /// A hash as it is written in a packet header
+  fn write(&self, digest: &[u8]) -> Vec<u8> {
+    match self {
+      | HashEncoding::Raw => digest.to_vec(),
+      | encoding => encoding.encode(digest).into_bytes(),
+    }
+  }
+
+  /// A hash read from a packet header, as text
+  fn read(&self, field: Vec<u8>) -> Vec<u8> {
+    match self {
+      | HashEncoding::Raw => self.encode(&field).into_bytes(),
+      | _ => field,
+    }
+  }
+
+  /// Compares an encoded hash against the expected one, ignoring case for hex
+  pub fn matches(&self, received: &str, expected: &str) -> bool {
+    match self {
+      | HashEncoding::Hex | HashEncoding::Raw => {
+        received.eq_ignore_ascii_case(expected)
+      },
+      | HashEncoding::Base64 => received == expected,
+    }
+  }
+
+  /// Whether `byte` can show up in a hash written in this encoding. Raw
+  /// hashes can hold any byte, so their header is found by length instead,
+  /// but the ids and ports before them are still written in hex digits.
+  pub fn reserves(&self, byte: u8) -> bool {
+    match self {
+      | HashEncoding::Hex | HashEncoding::Raw => byte.is_ascii_hexdigit(),
+      | HashEncoding::Base64 => {
+        byte.is_ascii_alphanumeric() || b"+/=".contains(&byte)
+      },
+    }
+  }
+}
+
+/// Finds the first byte of `separator` that can also show up inside a packet
+/// header, between fields, in its action name or within an id or a hash
+/// written in `encoding`, where it would end the header early
+pub fn separator_collision(
+  separator: &[u8], encoding: HashEncoding,
+) -> Option<u8> {
+  separator.iter().copied().find(|byte| {
+    *byte == b' '
+      || *byte == b'-'
+      || *byte == b'_'
+      || byte.is_ascii_alphabetic()
+      || encoding.reserves(*byte)
+  })
+}
+
+/// Checks that `id`, as written in a packet header, holds neither a space
+/// nor any byte of `separator`, either of which would end it early
+pub fn check_id(id: &str, separator: &[u8]) -> Result<(), ParseError> {
+  match id.bytes().position(|byte| byte == b' ' || separator.contains(&byte)) {
+    | Some(at) => Err(ParseError::at(
+      ParseErrorType::ID,
+      id.as_bytes(),
+      at,
+    )),
+    | None => Ok(()),
+  }
+}
+
+/// Writes `id` for a packet header. Canonical UUIDs always pass
+/// [`check_id`] for a separator that passed [`separator_collision`].
+fn header_id(id: &Uuid, separator: &[u8]) -> String {
+  let id = id.to_string();
+  debug_assert!(
+    check_id(&id, separator).is_ok(),
+    "{id:?} would end the packet header early"
+  );
+  id
+}
+
+/// Puts a packet together: its header fields, the separator, then its body
+fn join(fields: String, separator: &[u8], body: &[u8]) -> Vec<u8> {
+  let mut packet = fields.into_bytes();
+  packet.extend(separator);
+  packet.extend(body);
+  packet
+}
+
+/// Why a string can't be used as a separator
+#[derive(Debug, PartialEq)]
+pub enum SeparatorError {
+  Empty,
+  /// The separator holds this byte, which packet headers use too
+  Reserved(u8),
+}
+
+impl SeparatorError {
+  pub fn value(&self) -> String {
+    match self {
+      | SeparatorError::Empty => "The separator can't be empty".to_string(),
+      | SeparatorError::Reserved(byte) => format!(
+        "The separator can't contain {:?}, packet headers use it too",
+        *byte as char
+      ),
+    }
+  }
+}
+
+impl Display for SeparatorError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.value())
+  }
+}
+
+/// What ends a packet header, checked once when it is made so packets built
+/// and parsed with it can't be cut short by their own fields. It is only ever
+/// handled as bytes, so it need not be valid UTF-8.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Separator(Vec<u8>);
+
+impl Separator {
+  /// Checks `separator` against the bytes every header uses: spaces, the
+  /// letters and underscores of action names, and the hyphens and hex digits
+  /// of ids and ports.
+  /// Base64 hashes reserve more, see [`Separator::check`].
+  pub fn new(separator: &str) -> Result<Separator, SeparatorError> {
+    Separator::from_bytes(separator.as_bytes().to_vec())
+  }
+
+  /// Like [`Separator::new`], for separators that aren't text
+  pub fn from_bytes(separator: Vec<u8>) -> Result<Separator, SeparatorError> {
+    if separator.is_empty() {
+      return Err(SeparatorError::Empty);
+    }
+    match separator_collision(&separator, HashEncoding::Hex) {
+      | Some(byte) => Err(SeparatorError::Reserved(byte)),
+      | None => Ok(Separator(separator)),
+    }
+  }
+
+  /// Checks that no hash written in `encoding` can hold a byte of the
+  /// separator
+  pub fn check(&self, encoding: HashEncoding) -> Result<(), SeparatorError> {
+    match separator_collision(&self.0, encoding) {
+      | Some(byte) => Err(SeparatorError::Reserved(byte)),
+      | None => Ok(()),
+    }
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+impl Default for Separator {
+  /// `\u0000`, which no header can hold
+  fn default() -> Self {
+    Separator(vec![0x0])
+  }
+}
+
+impl Deref for Separator {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+impl AsRef<[u8]> for Separator {
+  fn as_ref(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+impl Display for Separator {
+  /// Escapes bytes that aren't printable ASCII, so it can be logged
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.0.escape_ascii())
+  }
+}
+
+impl TryFrom<&str> for Separator {
+  type Error = SeparatorError;
+
+  fn try_from(separator: &str) -> Result<Self, Self::Error> {
+    Separator::new(separator)
+  }
+}
+
+impl TryFrom<String> for Separator {
+  type Error = SeparatorError;
+
+  fn try_from(separator: String) -> Result<Self, Self::Error> {
+    Separator::new(&separator)
+  }
+}
+
+impl TryFrom<Vec<u8>> for Separator {
+  type Error = SeparatorError;
+
+  fn try_from(separator: Vec<u8>) -> Result<Self, Self::Error> {
+    Separator::from_bytes(separator)
+  }
+}
+
+/// How the separator or the auth secret is written in the settings: as a
+/// string, or as its bytes when it has characters most editors don't show or
+/// isn't text at all
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ArrOrStr {
+  ARR(Vec<u8>),
+  STR(String),
+}
+
+impl ArrOrStr {
+  /// Picks the byte form for bytes that aren't UTF-8 or hold control
+  /// characters, such as the default separator `\u0000`, which are too easy
+  /// to delete without noticing
+  pub fn from_bytes(bytes: &[u8]) -> ArrOrStr {
+    match std::str::from_utf8(bytes) {
+      | Ok(string) if !string.chars().any(char::is_control) => {
+        ArrOrStr::STR(string.to_string())
+      },
+      | _ => ArrOrStr::ARR(bytes.to_vec()),
+    }
+  }
+
+  pub fn into_bytes(self) -> Vec<u8> {
+    match self {
+      | ArrOrStr::ARR(bytes) => bytes,
+      | ArrOrStr::STR(string) => string.into_bytes(),
+    }
+  }
+
+  /// The separator this names, if it can be used as one
+  pub fn into_separator(self) -> Result<Separator, SeparatorError> {
+    Separator::from_bytes(self.into_bytes())
+  }
+}
+
+/// Writes a separator setting, see [`ArrOrStr::from_bytes`]
+pub fn serialize_separator<S: Serializer>(
+  separator: &Separator, serializer: S,
+) -> Result<S::Ok, S::Error> {
+  ArrOrStr::from_bytes(separator).serialize(serializer)
+}
+
+/// Reads a separator setting written either as a string or as its bytes
+pub fn deserialize_separator<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> Result<Separator, D::Error> {
+  ArrOrStr::deserialize(deserializer)?
+    .into_separator()
+    .map_err(de::Error::custom)
+}
+
+/// Writes a setting that is bytes rather than text, like the auth secret,
+/// see [`ArrOrStr::from_bytes`]
+pub fn serialize_bytes<S: Serializer>(
+  bytes: &[u8], serializer: S,
+) -> Result<S::Ok, S::Error> {
+  ArrOrStr::from_bytes(bytes).serialize(serializer)
+}
+
+/// Reads a setting written either as a string or as its bytes
+pub fn deserialize_bytes<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> Result<Vec<u8>, D::Error> {
+  Ok(ArrOrStr::deserialize(deserializer)?.into_bytes())
+}
+
+/// Which hashes a data packet header carries
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrity {
+  /// Both SHA1 and SHA512: `{sha1} {sha512}`
+  Dual,
+  /// Only SHA512, for environments where SHA1 is not allowed: `{sha512}`
+  Sha512Only,
+}
+
+impl Integrity {
+  pub fn from_string(integrity: &str) -> Option<Integrity> {
+    match integrity {
+      | "dual" => Some(Integrity::Dual),
+      | "sha512_only" => Some(Integrity::Sha512Only),
+      | _ => None,
+    }
+  }
+
+  pub fn value(&self) -> String {
+    match self {
+      | Integrity::Dual => "dual".to_string(),
+      | Integrity::Sha512Only => "sha512_only".to_string(),
+    }
+  }
+
+  /// The hash fields of a data packet header carrying `data`
+  fn hashes(&self, data: &Vec<u8>, encoding: HashEncoding) -> Vec<u8> {
+    let sha1 = match self {
+      | Integrity::Dual => Some(Sha1::digest(data).to_vec()),
+      | Integrity::Sha512Only => None,
+    };
+    self.write(
+      sha1.as_deref(),
+      &Sha512::digest(data),
+      encoding,
+    )
+  }
+
+  /// Writes the hash fields of a data packet header from the digests
+  fn write(
+    &self, sha1: Option<&[u8]>, sha512: &[u8], encoding: HashEncoding,
+  ) -> Vec<u8> {
+    let mut hashes = Vec::new();
+    if let Some(sha1) = sha1 {
+      hashes.extend(encoding.write(sha1));
+      hashes.push(b' ');
+    }
+    hashes.extend(encoding.write(sha512));
+    hashes
+  }
+
+  /// How long the hash fields of a data packet header are in `encoding`
+  fn written_len(&self, encoding: HashEncoding) -> usize {
+    let sha512 = encoding.written_len(Sha512::output_size());
+    match self {
+      | Integrity::Dual => {
+        encoding.written_len(Sha1::output_size()) + 1 + sha512
+      },
+      | Integrity::Sha512Only => sha512,
+    }
+  }
+
+  /// How long the hash fields of a data packet header are with raw hashes
+  fn raw_len(&self) -> usize {
+    match self {
+      | Integrity::Dual => Sha1::output_size() + 1 + Sha512::output_size(),
+      | Integrity::Sha512Only => Sha512::output_size(),
+    }
+  }
+
+  /// Splits the hash fields of a data packet header into SHA1 and SHA512,
+  /// leaving SHA1 empty when it is not carried
+  fn split(
+    &self, fields: &Vec<u8>, encoding: HashEncoding,
+  ) -> Option<(Vec<u8>, Vec<u8>)> {
+    if encoding == HashEncoding::Raw {
+      if fields.len() != self.raw_len() {
+        return None;
+      }
+      let sha512 = fields[fields.len() - Sha512::output_size()..].to_vec();
+      return match self {
+        | Integrity::Dual if fields[Sha1::output_size()] != b' ' => None,
+        | Integrity::Dual => Some((
+          fields[..Sha1::output_size()].to_vec(),
+          sha512,
+        )),
+        | Integrity::Sha512Only => Some((Vec::new(), sha512)),
+      };
+    }
+    match self {
+      | Integrity::Dual => split(fields, &" ".as_bytes().to_vec()),
+      | Integrity::Sha512Only => Some((Vec::new(), fields.to_owned())),
+    }
+  }
+}
+
+/// Compares two secrets in time that depends only on their lengths, not on
+/// where they first differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub fn hash_sha1(data: &Vec<u8>) -> String {
+  hash_sha1_with(data, HashEncoding::Hex)
+}
+
+pub fn hash_sha1_with(data: &Vec<u8>, encoding: HashEncoding) -> String {
+  let mut sha1 = Sha1::new();
+  sha1.update(data);
+  let result_sha1 = sha1.finalize();
+  encoding.encode(&result_sha1)
+}
+
+pub fn hash_sha512(data: &Vec<u8>) -> String {
+  hash_sha512_with(data, HashEncoding::Hex)
+}
+
+pub fn hash_sha512_with(data: &Vec<u8>, encoding: HashEncoding) -> String {
+  let mut sha512 = Sha512::new();
+  sha512.update(data);
+  let result_sha512 = sha512.finalize();
+  encoding.encode(&result_sha512)
+}
+
+/// Hashes everything left in `reader`, a chunk at a time
+fn hash_reader<R: Read>(
+  reader: &mut R, encoding: HashEncoding, integrity: Integrity,
+) -> io::Result<Vec<u8>> {
+  let mut sha1 = match integrity {
+    | Integrity::Dual => Some(Sha1::new()),
+    | Integrity::Sha512Only => None,
+  };
+  let mut sha512 = Sha512::new();
+  let mut buffer = [0u8; 8192];
+  loop {
+    match reader.read(&mut buffer) {
+      | Ok(0) => break,
+      | Ok(read) => {
+        if let Some(sha1) = &mut sha1 {
+          sha1.update(&buffer[..read]);
+        }
+        sha512.update(&buffer[..read]);
+      },
+      | Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+      | Err(err) => return Err(err),
+    }
+  }
+  let sha1 = sha1.map(|sha1| sha1.finalize().to_vec());
+  Ok(integrity.write(
+    sha1.as_deref(),
+    &sha512.finalize(),
+    encoding,
+  ))
+}
+
+/// Copies a data packet's body into `writer` after its header, reading the
+/// body twice, once to hash it and once to copy it, rather than holding it
+fn write_data<W: Write, R: Read + Seek>(
+  writer: &mut W, fields: &str, separator: &[u8], body: &mut R,
+  encoding: HashEncoding, integrity: Integrity,
+) -> io::Result<u64> {
+  let start = body.stream_position()?;
+  let hashes = hash_reader(body, encoding, integrity)?;
+  body.seek(SeekFrom::Start(start))?;
+  write!(
+    writer,
+    "{} {fields} ",
+    PacketAction::DATA.value()
+  )?;
+  writer.write_all(&hashes)?;
+  writer.write_all(separator)?;
+  io::copy(body, writer)
+}
+
+/// Reads a packet header off `reader`, up to and including the separator,
+/// a buffer at a time. Only the header is consumed, so the body is left in
+/// `reader`. `fields` is how many fields come between the action and the
+/// hashes of a data packet.
+fn read_header<R: BufRead>(
+  reader: &mut R, separator: &[u8], options: &ParseOptions, fields: usize,
+) -> Result<Vec<u8>, ParseError> {
+  let max_header_bytes = options.max_header_bytes;
+  let mut header = Vec::new();
+  loop {
+    let buffer = match reader.fill_buf() {
+      | Ok([]) => return Err(ParseError::Header(ParseErrorType::Type)),
+      | Ok(buffer) => buffer,
+      | Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+      | Err(_) => return Err(ParseError::Header(ParseErrorType::Type)),
+    };
+    let mut used = 0;
+    for byte in buffer {
+      if header.len() >= max_header_bytes + separator.len() {
+        return Err(ParseError::Header(
+          ParseErrorType::Length,
+        ));
+      }
+      header.push(*byte);
+      used += 1;
+      if options.header_read(&header, separator, fields) {
+        reader.consume(used);
+        return Ok(header);
+      }
+    }
+    reader.consume(used);
+  }
+}
+
+/// The body of a data packet still on its stream, hashed as it is read so it
+/// can be checked against the header once read through
+pub struct BodyReader<R: Read> {
+  inner: R,
+  sha1: Option<Sha1>,
+  sha512: Sha512,
+  expected_sha1: String,
+  expected_sha512: String,
+  encoding: HashEncoding,
+}
+
+impl<R: Read> BodyReader<R> {
+  fn new(
+    inner: R, sha1: &str, sha512: &str, encoding: HashEncoding,
+    integrity: Integrity,
+  ) -> BodyReader<R> {
+    BodyReader {
+      inner,
+      sha1: match integrity {
+        | Integrity::Dual => Some(Sha1::new()),
+        | Integrity::Sha512Only => None,
+      },
+      sha512: Sha512::new(),
+      expected_sha1: sha1.to_string(),
+      expected_sha512: sha512.to_string(),
+      encoding,
+    }
+  }
+
+  /// Checks what was read so far against the hashes from the header
+  pub fn verify(self) -> Result<(), ParseError> {
+    let sha1_matches = match self.sha1 {
+      | Some(sha1) => self.encoding.matches(
+        &self.expected_sha1,
+        &self.encoding.encode(&sha1.finalize()),
+      ),
+      | None => true,
+    };
+    let sha512 = self.encoding.encode(&self.sha512.finalize());
+    if sha1_matches && self.encoding.matches(&self.expected_sha512, &sha512) {
+      Ok(())
+    } else {
+      Err(ParseError::Other(
+        ParseErrorType::Hash,
+        None,
+      ))
+    }
+  }
+}
+
+impl<R: Read> Read for BodyReader<R> {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    let read = self.inner.read(buffer)?;
+    if let Some(sha1) = &mut self.sha1 {
+      sha1.update(&buffer[..read]);
+    }
+    self.sha512.update(&buffer[..read]);
+    Ok(read)
+  }
+}
+
+pub fn split(packet: &Vec<u8>, separator: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+  if separator.is_empty() || packet.is_empty() {
+    return None;
+  }
+
+  // Checks every offset rather than resuming after a partial match, which
+  // would skip a separator starting inside the bytes that were matched.
+  let start =
+    packet.windows(separator.len()).position(|window| window == separator)?;
+  Some((
+    packet[..start].to_vec(),
+    packet[start + separator.len()..].to_vec(),
+  ))
+}
+
+/// Like [`split`], but only looks for the separator within the first
+/// `max_header_bytes` of the packet, so a peer that never sends one can't make
+/// the parser scan and copy the whole packet looking for it.
+pub fn split_header(
+  packet: &Vec<u8>, separator: &[u8], max_header_bytes: usize,
+) -> Result<(Vec<u8>, Vec<u8>), ParseError> {
+  let limit = packet.len().min(max_header_bytes + separator.len());
+  match split(&packet[..limit].to_vec(), separator) {
+    | Some((header, _)) => {
+      let body = packet[header.len() + separator.len()..].to_vec();
+      Ok((header, body))
+    },
+    | None if limit < packet.len() => Err(ParseError::Header(
+      ParseErrorType::Length,
+    )),
+    | None => Err(ParseError::Header(ParseErrorType::Type)),
+  }
+}
+
+/// Limits applied while parsing a packet
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+  /// Longest header, in bytes, accepted before the separator
+  pub max_header_bytes: usize,
+  /// Rejects packets carrying data their format has no room for, like a body
+  /// after a CLOSE, instead of silently ignoring it
+  pub strict: bool,
+  /// Encoding the hashes of data packets are expected in
+  pub hash_encoding: HashEncoding,
+  /// Hashes data packets are expected to carry
+  pub integrity: Integrity,
+}
+
+impl Default for ParseOptions {
+  fn default() -> Self {
+    Self {
+      max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+      strict: false,
+      hash_encoding: DEFAULT_HASH_ENCODING,
+      integrity: DEFAULT_INTEGRITY,
+    }
+  }
+}
+
+impl ParseOptions {
+  /// Where the hashes of a data packet start when they are raw, after the
+  /// action and `fields` more fields, each followed by a space. None for
+  /// other packets, or when they are not raw.
+  fn raw_hashes_at(&self, packet: &[u8], fields: usize) -> Option<usize> {
+    let action = format!("{} ", PacketAction::DATA.value());
+    if self.hash_encoding != HashEncoding::Raw
+      || !packet.starts_with(action.as_bytes())
+    {
+      return None;
+    }
+    packet
+      .iter()
+      .take(self.max_header_bytes)
+      .enumerate()
+      .filter(|(_, byte)| **byte == b' ')
+      .nth(fields)
+      .map(|(at, _)| at + 1)
+  }
+
+  /// Splits `packet` into its header and body. Raw hashes can hold any byte,
+  /// the separator included, so the header of a data packet carrying them is
+  /// found by length instead of by looking for the separator.
+  fn split_header(
+    &self, packet: &Vec<u8>, separator: &[u8], fields: usize,
+  ) -> Result<(Vec<u8>, Vec<u8>), ParseError> {
+    let hashes_at = match self.raw_hashes_at(packet, fields) {
+      | Some(hashes_at) => hashes_at,
+      | None => return split_header(packet, separator, self.max_header_bytes),
+    };
+    let end = hashes_at + self.integrity.raw_len();
+    match packet.get(end..end + separator.len()) {
+      | Some(found) if found == separator => Ok((
+        packet[..end].to_vec(),
+        packet[end + separator.len()..].to_vec(),
+      )),
+      | Some(_) => Err(ParseError::Header(ParseErrorType::Hash)),
+      | None => Err(ParseError::Header(ParseErrorType::Type)),
+    }
+  }
+
+  /// Whether `header` holds a whole packet header, separator included, see
+  /// [`ParseOptions::split_header`]
+  fn header_read(
+    &self, header: &[u8], separator: &[u8], fields: usize,
+  ) -> bool {
+    match self.raw_hashes_at(header, fields) {
+      | Some(hashes_at) => {
+        header.len() >= hashes_at + self.integrity.raw_len() + separator.len()
+      },
+      | None => !separator.is_empty() && header.ends_with(separator),
+    }
+  }
+
+  fn expect_empty(
+    &self, data: &Vec<u8>, error: ParseError,
+  ) -> Result<(), ParseError> {
+    if self.strict && !data.is_empty() {
+      return Err(error);
+    }
+    Ok(())
+  }
+
+  fn expect_hashes(
+    &self, body: &Vec<u8>, sha1: &str, sha512: &str, error: ParseError,
+  ) -> Result<(), ParseError> {
+    let encoding = self.hash_encoding;
+    let sha1_matches = match self.integrity {
+      | Integrity::Dual => {
+        encoding.matches(sha1, &hash_sha1_with(body, encoding))
+      },
+      | Integrity::Sha512Only => true,
+    };
+    if sha1_matches
+      && encoding.matches(
+        sha512,
+        &hash_sha512_with(body, encoding),
+      )
+    {
+      Ok(())
+    } else {
+      Err(error)
+    }
+  }
+}
+
+impl Server {
+  pub fn build_data_packet(
+    id: &Uuid, port: &u16, separator: &Separator, data: &Vec<u8>,
+  ) -> Vec<u8> {
+    Server::build_data_packet_with(
+      id, port, separator, data, DEFAULT_HASH_ENCODING, DEFAULT_INTEGRITY,
+    )
+  }
+
+  pub fn build_data_packet_with(
+    id: &Uuid, port: &u16, separator: &Separator, data: &Vec<u8>,
+    encoding: HashEncoding, integrity: Integrity,
+  ) -> Vec<u8> {
+    let id = header_id(id, separator);
+    let mut packet = Vec::with_capacity(Server::data_packet_len(
+      port,
+      data.len(),
+      encoding,
+      integrity,
+      separator.len(),
+    ));
+    packet.extend(
+      format!(
+        "{} {id} {port} ",
+        PacketAction::DATA.value()
+      )
+      .bytes(),
+    );
+    packet.extend(integrity.hashes(&data, encoding));
+    packet.extend(separator.as_bytes());
+    packet.extend(data);
+    packet
+  }
+
+  /// Writes a data packet into `writer` without holding the body in memory
+  pub fn write_data_packet<W: Write, R: Read + Seek>(
+    writer: &mut W, id: &Uuid, port: &u16, separator: &Separator, body: &mut R,
+    encoding: HashEncoding, integrity: Integrity,
+  ) -> io::Result<u64> {
+    write_data(
+      writer,
+      &format!("{} {port}", header_id(id, separator)),
+      separator,
+      body,
+      encoding,
+      integrity,
+    )
+  }
+
+  pub fn close_connection_packet(id: &Uuid, separator: &Separator) -> Vec<u8> {
+    let id = header_id(id, separator);
+    join(
+      format!("{} {id}", PacketAction::CLOSE.value()),
+      separator,
+      &[],
+    )
+  }
+
+  pub fn build_noop_packet(separator: &Separator) -> Vec<u8> {
+    join(
+      PacketAction::NOOP.value(),
+      separator,
+      &[],
+    )
+  }
+
+  pub fn build_ack_packet(
+    id: &Uuid, sequence: u64, separator: &Separator,
+  ) -> Vec<u8> {
+    let id = header_id(id, separator);
+    join(
+      format!("{} {id}", PacketAction::ACK.value()),
+      separator,
+      sequence.to_string().as_bytes(),
+    )
+  }
+
+  pub fn build_open_packet(
+    id: &Uuid, port: &u16, info: &OpenInfo, separator: &Separator,
+  ) -> Vec<u8> {
+    let id = header_id(id, separator);
+    join(
+      format!(
+        "{} {id} {port}",
+        PacketAction::OPEN.value()
+      ),
+      separator,
+      info.value().as_bytes(),
+    )
+  }
+
+  pub fn build_auth_try_packet(
+    info: &AuthTryInfo, separator: &Separator,
+  ) -> Vec<u8> {
+    join(
+      PacketAction::AUTHTRY.value(),
+      separator,
+      info.value().as_bytes(),
+    )
+  }
+
+  /// The exact length of a data packet for `port` carrying `body_len` bytes,
+  /// as built by [`Server::build_data_packet_with`]
+  pub fn data_packet_len(
+    port: &u16, body_len: usize, encoding: HashEncoding, integrity: Integrity,
+    separator_len: usize,
+  ) -> usize {
+    format!("{} ", PacketAction::DATA.value()).len()
+      + Hyphenated::LENGTH
+      + format!(" {port} ").len()
+      + integrity.written_len(encoding)
+      + separator_len
+      + body_len
+  }
+
+  /// The exact length of a close packet, see [`Server::data_packet_len`]
+  pub fn close_packet_len(separator_len: usize) -> usize {
+    format!("{} ", PacketAction::CLOSE.value()).len()
+      + Hyphenated::LENGTH
+      + separator_len
+  }
+
+  /// The exact length of a noop packet, see [`Server::data_packet_len`]
+  pub fn noop_packet_len(separator_len: usize) -> usize {
+    PacketAction::NOOP.value().len() + separator_len
+  }
+
+  /// The exact length of an ack packet, see [`Server::data_packet_len`]
+  pub fn ack_packet_len(sequence: u64, separator_len: usize) -> usize {
+    format!("{} ", PacketAction::ACK.value()).len()
+      + Hyphenated::LENGTH
+      + separator_len
+      + sequence.to_string().len()
+  }
+
+  /// The separator a client announced in its auth packet, read without
+  /// knowing it: the hex after the length runs up to the separator, which
+  /// can't hold hex digits. None when there is no announcement, or it is not
+  /// followed by the separator it names.
+  pub fn announced_separator(packet: &[u8]) -> Option<Separator> {
+    let action = format!("{} ", PacketAction::AUTH.value());
+    let rest = packet.strip_prefix(action.as_bytes())?;
+    let digits = rest.iter().take_while(|byte| byte.is_ascii_digit()).count();
+    let rest = rest[digits..].strip_prefix(b" ")?;
+    let hex = rest.iter().take_while(|byte| byte.is_ascii_hexdigit()).count();
+    if digits == 0 || hex == 0 || hex % 2 != 0 {
+      return None;
+    }
+    let separator = rest[..hex]
+      .chunks(2)
+      .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+      .collect::<Option<Vec<u8>>>()?;
+    if !rest[hex..].starts_with(&separator) {
+      return None;
+    }
+    Separator::try_from(separator).ok()
+  }
+
+  ///
+  /// Parses a packet from the client
+  ///
+  pub fn parse_packet(
+    packet: Vec<u8>, separator: &Separator,
+  ) -> Result<PacketType<Client>, ParseError> {
+    Server::parse_packet_with(
+      packet,
+      separator,
+      &ParseOptions::default(),
+    )
+  }
+
+  ///
+  /// Parses a packet from the client with the given limits
+  ///
+  pub fn parse_packet_with(
+    packet: Vec<u8>, separator: &Separator, options: &ParseOptions,
+  ) -> Result<PacketType<Client>, ParseError> {
+    Server::parse(packet, separator, options, true)
+  }
+
+  ///
+  /// Reads a data packet from the client off `reader`, stopping after
+  /// the header: the returned packet has an empty body, which is left in the
+  /// returned reader instead
+  ///
+  pub fn read_data_packet<R: BufRead>(
+    mut reader: R, separator: &Separator, options: &ParseOptions,
+  ) -> Result<(Packet<Client, Data>, BodyReader<R>), ParseError> {
+    let header = read_header(
+      &mut reader,
+      separator.as_bytes(),
+      options,
+      1,
+    )?;
+    match Server::parse(header, separator, options, false)? {
+      | PacketType::Data(packet) => {
+        let body = BodyReader::new(
+          reader, &packet.sha1, &packet.sha512, options.hash_encoding,
+          options.integrity,
+        );
+        Ok((packet, body))
+      },
+      | _ => Err(ParseError::Other(
+        ParseErrorType::Action,
+        None,
+      )),
+    }
+  }
+
+  fn parse(
+    packet: Vec<u8>, separator: &Separator, options: &ParseOptions,
+    verify_hashes: bool,
+  ) -> Result<PacketType<Client>, ParseError> {
+    let (header, body) =
+      options.split_header(&packet, separator.as_bytes(), 1)?;
+    let header_len = header.len();
+    let body_at = packet.len() - body.len();
+    // Every field is a suffix of the header until it is split off, so its
+    // offset is whatever of the header comes before it
+    let at = |rest: &Vec<u8>| header_len - rest.len();
+    let fail = |error, offset| ParseError::at(error, &packet, offset);
+    let (action, p) =
+      split(&header, &" ".as_bytes().to_vec()).unwrap_or((header, Vec::new()));
+
+    let action = String::from_utf8(action)
+      .ok()
+      .ok_or_else(|| fail(ParseErrorType::Action, 0))?;
+    let action = match PacketAction::try_from_str(&action) {
+      | Some(action) => action,
+      | None => {
+        return CustomPacket::new(action, p, body)
+          .map(PacketType::Custom)
+          .map_err(|_| fail(ParseErrorType::Action, 0))
+      },
+    };
+
+    match &action {
+      | PacketAction::DATA => {
+        let id_at = at(&p);
+        let (id, p) = split(&p, &" ".as_bytes().to_vec())
+          .ok_or(ParseError::Header(ParseErrorType::ID))?;
+        let id = String::from_utf8(id)
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::ID, id_at))?;
+
+        let id = Uuid::parse_str(&id)
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::ID, id_at))?;
+        let sha1_at = at(&p);
+        let (sha1, sha512) = options
+          .integrity
+          .split(&p, options.hash_encoding)
+          .ok_or(ParseError::Header(ParseErrorType::Hash))?;
+        let sha512_at = at(&sha512);
+        let sha1 = String::from_utf8(options.hash_encoding.read(sha1))
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::Hash, sha1_at))?;
+        let sha512 = String::from_utf8(options.hash_encoding.read(sha512))
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::Hash, sha512_at))?;
+        if verify_hashes {
+          options.expect_hashes(
+            &body,
+            &sha1,
+            &sha512,
+            fail(ParseErrorType::Hash, sha1_at),
+          )?;
+        }
+        Ok(PacketType::Data(Packet {
+          action,
+          id,
+          port: (),
+          ports: (),
+          sha1,
+          sha512,
+          body,
+        }))
+      },
+      | PacketAction::AUTH => {
+        // Whatever separator the client announced after the length is this
+        // one, or the header could not have been split off
+        let ports_len = match p.iter().position(|byte| *byte == b' ') {
+          | Some(at) => p[..at].to_vec(),
+          | None => p,
+        };
+        let ports_len = String::from_utf8(ports_len)
+          .ok()
+          .ok_or(ParseError::Header(
+            ParseErrorType::Ports,
+          ))?
+          .parse::<usize>()
+          .ok()
+          .ok_or(ParseError::Header(
+            ParseErrorType::Ports,
+          ))?;
+        if ports_len > body.len() {
+          return Err(fail(ParseErrorType::Ports, body_at));
+        }
+        let (ports, body) = body.split_at(ports_len);
+        let ports = String::from_utf8(ports.to_vec())
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::Ports, body_at))?;
+        let mut port_at = body_at;
+        let ports = if ports.is_empty() {
+          Vec::new()
+        } else {
+          ports
+            .split(",")
+            .map(|port| {
+              let offset = port_at;
+              port_at += port.len() + 1;
+              port
+                .parse::<Forward>()
+                .map_err(|_| fail(ParseErrorType::Ports, offset))
+            })
+            .collect::<Result<Vec<Forward>, ParseError>>()?
+        };
+        let ports = PortSet::new(ports)
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::Ports, body_at))?;
+        let body = body.to_vec();
+        Ok(PacketType::Auth(Packet {
+          action,
+          id: (),
+          port: (),
+          ports,
+          sha1: (),
+          sha512: (),
+          body,
+        }))
+      },
+      | PacketAction::CLOSE => {
+        let id: Uuid = Uuid::try_parse_ascii(p.as_slice())
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::ID, at(&p)))?;
+        options.expect_empty(
+          &body,
+          fail(ParseErrorType::Trailing, body_at),
+        )?;
+        Ok(PacketType::Close(Packet {
+          action,
+          id,
+          port: (),
+          ports: (),
+          sha1: (),
+          sha512: (),
+          body,
+        }))
+      },
+      | PacketAction::NOOP => {
+        options.expect_empty(
+          &body,
+          fail(ParseErrorType::Trailing, body_at),
+        )?;
+        Ok(PacketType::Noop(Packet {
+          action,
+          id: (),
+          port: (),
+          ports: (),
+          sha1: (),
+          sha512: (),
+          body,
+        }))
+      },
+      | PacketAction::ACK => {
+        let id: Uuid = Uuid::try_parse_ascii(p.as_slice())
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::ID, at(&p)))?;
+        parse_sequence(&body)
+          .ok_or_else(|| fail(ParseErrorType::Sequence, body_at))?;
+        Ok(PacketType::Ack(Packet {
+          action,
+          id,
+          port: (),
+          ports: (),
+          sha1: (),
+          sha512: (),
+          body,
+        }))
+      },
+      | _ => Err(fail(ParseErrorType::Action, 0)),
+    }
+  }
+}
+
+impl Client {
+  pub fn build_data_packet(
+    id: &Uuid, separator: &Separator, data: &Vec<u8>,
+  ) -> Vec<u8> {
+    Client::build_data_packet_with(
+      id, separator, data, DEFAULT_HASH_ENCODING, DEFAULT_INTEGRITY,
+    )
+  }
+
+  pub fn build_data_packet_with(
+    id: &Uuid, separator: &Separator, data: &Vec<u8>, encoding: HashEncoding,
+    integrity: Integrity,
+  ) -> Vec<u8> {
+    let id = header_id(id, separator);
+    let mut packet = Vec::with_capacity(Client::data_packet_len(
+      data.len(),
+      encoding,
+      integrity,
+      separator.len(),
+    ));
+    packet.extend(format!("{} {id} ", PacketAction::DATA.value()).bytes());
+    packet.extend(integrity.hashes(&data, encoding));
+    packet.extend(separator.as_bytes());
+    packet.extend(data);
+    packet
+  }
+
+  /// Writes a data packet into `writer` without holding the body in memory
+  pub fn write_data_packet<W: Write, R: Read + Seek>(
+    writer: &mut W, id: &Uuid, separator: &Separator, body: &mut R,
+    encoding: HashEncoding, integrity: Integrity,
+  ) -> io::Result<u64> {
+    write_data(
+      writer,
+      &header_id(id, separator),
+      separator,
+      body,
+      encoding,
+      integrity,
+    )
+  }
+
+  pub fn close_connection_packet(id: &Uuid, separator: &Separator) -> Vec<u8> {
+    let id = header_id(id, separator);
+    join(
+      format!("{} {id}", PacketAction::CLOSE.value()),
+      separator,
+      &[],
+    )
+  }
+
+  pub fn build_noop_packet(separator: &Separator) -> Vec<u8> {
+    join(
+      PacketAction::NOOP.value(),
+      separator,
+      &[],
+    )
+  }
+
+  pub fn build_ack_packet(
+    id: &Uuid, sequence: u64, separator: &Separator,
+  ) -> Vec<u8> {
+    let id = header_id(id, separator);
+    join(
+      format!("{} {id}", PacketAction::ACK.value()),
+      separator,
+      sequence.to_string().as_bytes(),
+    )
+  }
+
+  /// The exact length of a data packet carrying `body_len` bytes, as built
+  /// by [`Client::build_data_packet_with`]
+  pub fn data_packet_len(
+    body_len: usize, encoding: HashEncoding, integrity: Integrity,
+    separator_len: usize,
+  ) -> usize {
+    format!("{} ", PacketAction::DATA.value()).len()
+      + Hyphenated::LENGTH
+      + 1
+      + integrity.written_len(encoding)
+      + separator_len
+      + body_len
+  }
+
+  /// The exact length of a close packet, see [`Client::data_packet_len`]
+  pub fn close_packet_len(separator_len: usize) -> usize {
+    format!("{} ", PacketAction::CLOSE.value()).len()
+      + Hyphenated::LENGTH
+      + separator_len
+  }
+
+  /// The exact length of a noop packet, see [`Client::data_packet_len`]
+  pub fn noop_packet_len(separator_len: usize) -> usize {
+    PacketAction::NOOP.value().len() + separator_len
+  }
+
+  /// The exact length of an ack packet, see [`Client::data_packet_len`]
+  pub fn ack_packet_len(sequence: u64, separator_len: usize) -> usize {
+    format!("{} ", PacketAction::ACK.value()).len()
+      + Hyphenated::LENGTH
+      + separator_len
+      + sequence.to_string().len()
+  }
+
+  pub fn build_auth_packet(
+    auth: &[u8], ports: &Vec<u16>, separator: &Separator,
+  ) -> Vec<u8> {
+    Client::build_auth_packet_with_forwards(
+      auth,
+      &ports.iter().map(|port| Forward::from(*port)).collect(),
+      separator,
+    )
+  }
+
+  /// Builds an auth packet asking for `forwards`, each of which may name the
+  /// upstream the client sends its port to
+  pub fn build_auth_packet_with_forwards(
+    auth: &[u8], forwards: &Vec<Forward>, separator: &Separator,
+  ) -> Vec<u8> {
+    let ports_string =
+      forwards.iter().map(Forward::value).collect::<Vec<String>>().join(",");
+    // The separator is announced ahead of itself, so a server set up with
+    // another one can still tell what went wrong
+    join(
+      format!(
+        "{} {} {}",
+        PacketAction::AUTH.value(),
+        ports_string.len(),
+        HashEncoding::Hex.encode(separator.as_bytes())
+      ),
+      separator,
+      &[ports_string.as_bytes(), auth].concat(),
+    )
+  }
+
+  ///
+  /// Parses a packet from the server
+  ///
+  pub fn parse_packet(
+    packet: Vec<u8>, separator: &Separator,
+  ) -> Result<PacketType<Server>, ParseError> {
+    Client::parse_packet_with(
+      packet,
+      separator,
+      &ParseOptions::default(),
+    )
+  }
+
+  ///
+  /// Parses a packet from the server with the given limits
+  ///
+  pub fn parse_packet_with(
+    packet: Vec<u8>, separator: &Separator, options: &ParseOptions,
+  ) -> Result<PacketType<Server>, ParseError> {
+    Client::parse(packet, separator, options, true)
+  }
+
+  ///
+  /// Reads a data packet from the server off `reader`, stopping after
+  /// the header: the returned packet has an empty body, which is left in the
+  /// returned reader instead
+  ///
+  pub fn read_data_packet<R: BufRead>(
+    mut reader: R, separator: &Separator, options: &ParseOptions,
+  ) -> Result<(Packet<Server, Data>, BodyReader<R>), ParseError> {
+    let header = read_header(
+      &mut reader,
+      separator.as_bytes(),
+      options,
+      2,
+    )?;
+    match Client::parse(header, separator, options, false)? {
+      | PacketType::Data(packet) => {
+        let body = BodyReader::new(
+          reader, &packet.sha1, &packet.sha512, options.hash_encoding,
+          options.integrity,
+        );
+        Ok((packet, body))
+      },
+      | _ => Err(ParseError::Other(
+        ParseErrorType::Action,
+        None,
+      )),
+    }
+  }
+
+  fn parse(
+    packet: Vec<u8>, separator: &Separator, options: &ParseOptions,
+    verify_hashes: bool,
+  ) -> Result<PacketType<Server>, ParseError> {
+    let (header, body) =
+      options.split_header(&packet, separator.as_bytes(), 2)?;
+    let header_len = header.len();
+    let body_at = packet.len() - body.len();
+    // Every field is a suffix of the header until it is split off, so its
+    // offset is whatever of the header comes before it
+    let at = |rest: &Vec<u8>| header_len - rest.len();
+    let fail = |error, offset| ParseError::at(error, &packet, offset);
+    let (action, p) =
+      split(&header, &" ".as_bytes().to_vec()).unwrap_or((header, Vec::new()));
+
+    let action = String::from_utf8(action)
+      .ok()
+      .ok_or_else(|| fail(ParseErrorType::Action, 0))?;
+    let action = match PacketAction::try_from_str(&action) {
+      | Some(action) => action,
+      | None => {
+        return CustomPacket::new(action, p, body)
+          .map(PacketType::Custom)
+          .map_err(|_| fail(ParseErrorType::Action, 0))
+      },
+    };
+
+    match &action {
+      | PacketAction::DATA => {
+        let id_at = at(&p);
+        let (id, p) = split(&p, &" ".as_bytes().to_vec())
+          .ok_or(ParseError::Header(ParseErrorType::ID))?;
+        let id = String::from_utf8(id)
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::ID, id_at))?;
+        let id = Uuid::parse_str(&id)
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::ID, id_at))?;
+        let port_at = at(&p);
+        let (port, p) = split(&p, &" ".as_bytes().to_vec())
+          .ok_or(ParseError::Header(ParseErrorType::Port))?;
+        let port = String::from_utf8(port)
+          .ok()
+          .and_then(|port| port.parse::<u16>().ok())
+          .ok_or_else(|| fail(ParseErrorType::Port, port_at))?;
+        let sha1_at = at(&p);
+        let (sha1, sha512) = options
+          .integrity
+          .split(&p, options.hash_encoding)
+          .ok_or(ParseError::Header(ParseErrorType::Hash))?;
+        let sha512_at = at(&sha512);
+        let sha1 = String::from_utf8(options.hash_encoding.read(sha1))
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::Hash, sha1_at))?;
+        let sha512 = String::from_utf8(options.hash_encoding.read(sha512))
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::Hash, sha512_at))?;
+        if verify_hashes {
+          options.expect_hashes(
+            &body,
+            &sha1,
+            &sha512,
+            fail(ParseErrorType::Hash, sha1_at),
+          )?;
+        }
+        Ok(PacketType::Data(Packet {
+          action,
+          id,
+          port,
+          ports: (),
           sha1,
           sha512,
           body,
         }))
       },
       | PacketAction::CLOSE => {
+        let id_at = at(&p);
         let id = String::from_utf8(p)
           .ok()
-          .ok_or(ParseError::Other(ParseErrorType::ID))?;
+          .ok_or_else(|| fail(ParseErrorType::ID, id_at))?;
         let id = Uuid::parse_str(&id)
           .ok()
-          .ok_or(ParseError::Other(ParseErrorType::ID))?;
+          .ok_or_else(|| fail(ParseErrorType::ID, id_at))?;
+        options.expect_empty(
+          &body,
+          fail(ParseErrorType::Trailing, body_at),
+        )?;
         Ok(PacketType::Close(Packet {
           action,
           id,
@@ -458,9 +2302,82 @@ impl Client {
           body,
         }))
       },
-      | _ => Err(ParseError::Other(
-        ParseErrorType::Action,
-      )),
+      | PacketAction::AUTHTRY => {
+        options.expect_empty(
+          &p,
+          ParseError::Header(ParseErrorType::Trailing),
+        )?;
+        Ok(PacketType::AuthTry(Packet {
+          action,
+          id: (),
+          port: 0,
+          ports: (),
+          sha1: (),
+          sha512: (),
+          body,
+        }))
+      },
+      | PacketAction::NOOP => {
+        options.expect_empty(
+          &body,
+          fail(ParseErrorType::Trailing, body_at),
+        )?;
+        Ok(PacketType::Noop(Packet {
+          action,
+          id: (),
+          port: 0,
+          ports: (),
+          sha1: (),
+          sha512: (),
+          body,
+        }))
+      },
+      | PacketAction::OPEN => {
+        let id_at = at(&p);
+        let (id, port) = split(&p, &" ".as_bytes().to_vec())
+          .ok_or(ParseError::Header(ParseErrorType::Port))?;
+        let port_at = at(&port);
+        let id = String::from_utf8(id)
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::ID, id_at))?;
+        let id = Uuid::parse_str(&id)
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::ID, id_at))?;
+        let port = String::from_utf8(port)
+          .ok()
+          .and_then(|port| port.parse::<u16>().ok())
+          .ok_or_else(|| fail(ParseErrorType::Port, port_at))?;
+        Ok(PacketType::Open(Packet {
+          action,
+          id,
+          port,
+          ports: (),
+          sha1: (),
+          sha512: (),
+          body,
+        }))
+      },
+      | PacketAction::ACK => {
+        let id_at = at(&p);
+        let id = String::from_utf8(p)
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::ID, id_at))?;
+        let id = Uuid::parse_str(&id)
+          .ok()
+          .ok_or_else(|| fail(ParseErrorType::ID, id_at))?;
+        parse_sequence(&body)
+          .ok_or_else(|| fail(ParseErrorType::Sequence, body_at))?;
+        Ok(PacketType::Ack(Packet {
+          action,
+          id,
+          port: 0,
+          ports: (),
+          sha1: (),
+          sha512: (),
+          body,
+        }))
+      },
+      | _ => Err(fail(ParseErrorType::Action, 0)),
     }
   }
 }
@@ -472,7 +2389,7 @@ pub struct Warning {
 
 impl Warning {
   pub fn warn(&mut self, msg: String) {
-    self.warns += 1;
+    self.warns = self.warns.saturating_add(1);
     if self.warns < self.total {
       let remaining = self.total - self.warns;
       if remaining > 1 {
@@ -491,6 +2408,11 @@ impl Warning {
       total,
     }
   }
+
+  /// How many times this was raised, whether or not it was logged
+  pub fn warns(&self) -> u8 {
+    self.warns
+  }
 }
 
 impl Clone for Warning {
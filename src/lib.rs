@@ -1,4 +1,10 @@
 pub mod constants;
 pub mod functions;
 pub mod logging;
+pub mod memory;
+pub mod protocol;
+pub mod replay;
+pub mod settings;
 mod tests;
+pub mod websocket;
+pub mod window;
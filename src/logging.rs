@@ -1,22 +1,328 @@
 use std::{
-  fs::{metadata, rename, File},
+  cell::Cell,
+  fmt::{Debug, Display, Formatter},
+  fs::{create_dir, metadata, rename, File},
+  io::Error,
   path::Path,
+  sync::{Arc, Mutex, RwLock},
 };
 
 use chrono::{DateTime, Local, NaiveDateTime};
+use log::{Log, Metadata, Record};
+use once_cell::sync::Lazy;
 use simplelog::{
-  Color, ColorChoice, CombinedLogger, ConfigBuilder, Level, LevelFilter,
-  TermLogger, TerminalMode, WriteLogger,
+  trace, warn, Color, ColorChoice, CombinedLogger, Config, ConfigBuilder,
+  Level, LevelFilter, SharedLogger, TermLogger, TerminalMode, WriteLogger,
 };
+use syslog::{Facility, Formatter3164, LoggerBackend};
+use uuid::Uuid;
 
-use super::constants::{LOG_FILE, LOG_PATH};
+use super::{
+  constants::{LOG_FILE, LOG_PATH, TRACE_BODY_BYTES},
+  functions::{Environment, Packet, PacketTrait, PacketType, ParseError},
+};
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct LoggerSettings {
   pub level: LevelFilter,
   pub file_level: LevelFilter,
+  /// Where else logs go, besides the terminal and the log file
+  pub sinks: Vec<LogSink>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum LogSink {
+  /// The local syslog daemon, tagging records with `facility`
+  Syslog { facility: Facility, level: LevelFilter },
+}
+
+impl LogSink {
+  fn level(&self) -> LevelFilter {
+    match self {
+      | LogSink::Syslog {
+        level,
+        ..
+      } => *level,
+    }
+  }
+}
+
+// `Facility` doesn't implement `PartialEq`, but its discriminants are the
+// facility codes themselves.
+impl PartialEq for LogSink {
+  fn eq(&self, other: &Self) -> bool {
+    match (self, other) {
+      | (
+        LogSink::Syslog {
+          facility,
+          level,
+        },
+        LogSink::Syslog {
+          facility: other_facility,
+          level: other_level,
+        },
+      ) => *facility as i32 == *other_facility as i32 && level == other_level,
+    }
+  }
+}
+
+/// A level shared between the logger and whoever wants to change it later
+#[derive(Clone, Debug)]
+pub struct LevelHandle {
+  level: Arc<RwLock<LevelFilter>>,
+}
+
+impl LevelHandle {
+  pub fn new(level: LevelFilter) -> Self {
+    Self {
+      level: Arc::new(RwLock::new(level)),
+    }
+  }
+
+  pub fn get(&self) -> LevelFilter {
+    match self.level.read() {
+      | Ok(level) => *level,
+      | Err(err) => *err.into_inner(),
+    }
+  }
+
+  pub fn set(&self, level: LevelFilter) {
+    match self.level.write() {
+      | Ok(mut current) => *current = level,
+      | Err(err) => *err.into_inner() = level,
+    }
+  }
+
+  /// Makes the level one step more verbose, returning the new level
+  pub fn raise(&self) -> LevelFilter {
+    let level = match self.get() {
+      | LevelFilter::Off => LevelFilter::Error,
+      | LevelFilter::Error => LevelFilter::Warn,
+      | LevelFilter::Warn => LevelFilter::Info,
+      | LevelFilter::Info => LevelFilter::Debug,
+      | LevelFilter::Debug | LevelFilter::Trace => LevelFilter::Trace,
+    };
+    self.set(level);
+    level
+  }
+
+  /// Makes the level one step less verbose, returning the new level
+  pub fn lower(&self) -> LevelFilter {
+    let level = match self.get() {
+      | LevelFilter::Trace => LevelFilter::Debug,
+      | LevelFilter::Debug => LevelFilter::Info,
+      | LevelFilter::Info => LevelFilter::Warn,
+      | LevelFilter::Warn => LevelFilter::Error,
+      | LevelFilter::Error | LevelFilter::Off => LevelFilter::Off,
+    };
+    self.set(level);
+    level
+  }
+}
+
+/// Wraps a logger so records are filtered against a [`LevelHandle`] instead
+/// of the level the inner logger was built with, which lets the level change
+/// after the global logger has been installed.
+pub struct DynamicLogger {
+  inner: Box<dyn SharedLogger>,
+  handle: LevelHandle,
+}
+
+impl DynamicLogger {
+  pub fn new(inner: Box<dyn SharedLogger>, handle: LevelHandle) -> Box<Self> {
+    Box::new(Self {
+      inner,
+      handle,
+    })
+  }
+}
+
+impl Log for DynamicLogger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    metadata.level() <= self.handle.get() && self.inner.enabled(metadata)
+  }
+
+  fn log(&self, record: &Record) {
+    if self.enabled(record.metadata()) {
+      self.inner.log(record);
+    }
+  }
+
+  fn flush(&self) {
+    self.inner.flush();
+  }
+}
+
+impl SharedLogger for DynamicLogger {
+  // `CombinedLogger` only looks at this once, when it is created, and drops
+  // anything above it, so the filtering is left to `enabled` and the global
+  // max level instead.
+  fn level(&self) -> LevelFilter {
+    LevelFilter::Trace
+  }
+
+  fn config(&self) -> Option<&Config> {
+    self.inner.config()
+  }
+
+  fn as_log(self: Box<Self>) -> Box<dyn Log> {
+    Box::new(*self)
+  }
+}
+
+/// A short random id for one control connection, so the log lines of that
+/// session and of its slave listeners can be told apart from the others
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SessionId(u32);
+
+impl SessionId {
+  pub fn new() -> Self {
+    SessionId(Uuid::new_v4().as_fields().0)
+  }
+}
+
+impl Default for SessionId {
+  fn default() -> Self {
+    SessionId::new()
+  }
+}
+
+impl Display for SessionId {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:08x}", self.0)
+  }
+}
+
+thread_local! {
+  static SESSION: Cell<Option<SessionId>> = const { Cell::new(None) };
+}
+
+/// The session records logged on this thread belong to, if any
+pub fn current_session() -> Option<SessionId> {
+  SESSION.with(Cell::get)
+}
+
+/// Restores the session this thread logged in before [`enter_session`] once
+/// dropped
+pub struct SessionGuard {
+  outer: Option<SessionId>,
+}
+
+impl Drop for SessionGuard {
+  fn drop(&mut self) {
+    SESSION.with(|current| current.set(self.outer));
+  }
+}
+
+/// Prefixes every record logged on this thread with `session` until the
+/// returned guard is dropped
+pub fn enter_session(session: Option<SessionId>) -> SessionGuard {
+  SessionGuard {
+    outer: SESSION.with(|current| current.replace(session)),
+  }
+}
+
+/// Runs `f` with every record it logs on this thread prefixed with `session`
+pub fn in_session<T>(session: Option<SessionId>, f: impl FnOnce() -> T) -> T {
+  let _session = enter_session(session);
+  f()
+}
+
+/// Prefixes each record with the session it was logged in, see
+/// [`in_session`], before handing it to the logger it wraps
+pub struct SessionLogger {
+  inner: Box<dyn Log>,
+}
+
+impl SessionLogger {
+  pub fn new(inner: Box<dyn Log>) -> Box<Self> {
+    Box::new(Self {
+      inner,
+    })
+  }
+}
+
+impl Log for SessionLogger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    self.inner.enabled(metadata)
+  }
+
+  fn log(&self, record: &Record) {
+    match current_session() {
+      | Some(session) => self.inner.log(
+        &Record::builder()
+          .args(format_args!(
+            "[{session}] {}",
+            record.args()
+          ))
+          .metadata(record.metadata().clone())
+          .target(record.target())
+          .module_path(record.module_path())
+          .file(record.file())
+          .line(record.line())
+          .build(),
+      ),
+      | None => self.inner.log(record),
+    }
+  }
+
+  fn flush(&self) {
+    self.inner.flush();
+  }
+}
+
+static TERMINAL_LEVEL: Lazy<LevelHandle> =
+  Lazy::new(|| LevelHandle::new(LevelFilter::Info));
+
+static FILE_LEVEL: Lazy<LevelHandle> =
+  Lazy::new(|| LevelHandle::new(LevelFilter::Debug));
+
+static SINKS: Lazy<RwLock<Vec<LogSink>>> =
+  Lazy::new(|| RwLock::new(Vec::new()));
+
+fn sinks() -> Vec<LogSink> {
+  match SINKS.read() {
+    | Ok(sinks) => sinks.clone(),
+    | Err(err) => err.into_inner().clone(),
+  }
+}
+
+/// Returns the levels the logger is currently using
+pub fn current_log_levels() -> LoggerSettings {
+  LoggerSettings {
+    level: TERMINAL_LEVEL.get(),
+    file_level: FILE_LEVEL.get(),
+    sinks: sinks(),
+  }
+}
+
+fn update_max_level() {
+  let sinks = sinks().iter().map(LogSink::level).max();
+  log::set_max_level(
+    TERMINAL_LEVEL
+      .get()
+      .max(FILE_LEVEL.get())
+      .max(sinks.unwrap_or(LevelFilter::Off)),
+  );
+}
+
+/// Makes the terminal output one step more verbose
+pub fn raise_log_level() -> LevelFilter {
+  let level = TERMINAL_LEVEL.raise();
+  update_max_level();
+  level
+}
+
+/// Makes the terminal output one step less verbose
+pub fn lower_log_level() -> LevelFilter {
+  let level = TERMINAL_LEVEL.lower();
+  update_max_level();
+  level
 }
 
 pub fn init_logger(settings: LoggerSettings) -> () {
+  TERMINAL_LEVEL.set(settings.level);
+  FILE_LEVEL.set(settings.file_level);
   let config = ConfigBuilder::new()
     .set_level_color(Level::Trace, Some(Color::Magenta))
     .set_level_color(Level::Debug, Some(Color::Cyan))
@@ -25,38 +331,295 @@ pub fn init_logger(settings: LoggerSettings) -> () {
     .set_level_color(Level::Error, Some(Color::Red))
     .build();
 
-  if !Path::new(&LOG_PATH).exists() {
-    std::fs::create_dir(&LOG_PATH).unwrap();
+  let mut loggers: Vec<Box<dyn SharedLogger>> = vec![DynamicLogger::new(
+    TermLogger::new(
+      LevelFilter::Trace,
+      config.clone(),
+      TerminalMode::Mixed,
+      ColorChoice::Auto,
+    ),
+    TERMINAL_LEVEL.clone(),
+  )];
+
+  let mut file_error = None;
+  if settings.file_level != LevelFilter::Off {
+    match open_log_file(LOG_PATH) {
+      | Ok(file) => loggers.push(WriteLogger::new(
+        settings.file_level, config, file,
+      )),
+      | Err(err) => {
+        FILE_LEVEL.set(LevelFilter::Off);
+        file_error = Some(err);
+      },
+    }
+  }
+
+  let mut sinks = Vec::new();
+  let mut sink_errors = Vec::new();
+  for sink in settings.sinks {
+    match sink {
+      | LogSink::Syslog {
+        facility,
+        level,
+      } => match SyslogLogger::new(facility, level) {
+        | Ok(logger) => {
+          loggers.push(logger);
+          sinks.push(sink);
+        },
+        | Err(err) => sink_errors.push(err),
+      },
+    }
+  }
+  match SINKS.write() {
+    | Ok(mut current) => *current = sinks,
+    | Err(err) => *err.into_inner() = sinks,
+  }
+
+  log::set_boxed_logger(SessionLogger::new(CombinedLogger::new(
+    loggers,
+  )))
+  .unwrap();
+  update_max_level();
+
+  if let Some(err) = file_error {
+    warn!(
+      "Unable to write logs to {LOG_PATH} ({err}), logging to terminal only"
+    );
+  }
+  for err in sink_errors {
+    warn!("Unable to connect to syslog ({err}), not logging there");
+  }
+}
+
+/// The lines logged at startup saying where logs go at which level, none
+/// when `quiet`
+pub fn startup_banner(
+  level: LevelFilter, file_level: LevelFilter, quiet: bool,
+) -> Vec<&'static str> {
+  if quiet {
+    return Vec::new();
+  }
+  let mut banner = vec![match level {
+    | LevelFilter::Trace => "TRACE calls logging to terminal",
+    | LevelFilter::Debug => "DEBUG calls logging to terminal",
+    | LevelFilter::Info => "INFO calls logging to terminal",
+    | LevelFilter::Warn => "WARN calls logging to terminal",
+    | LevelFilter::Error => "ERROR calls logging to terminal",
+    | LevelFilter::Off => "Disabled logging to terminal",
+  }];
+  match file_level {
+    | LevelFilter::Trace => banner.push("TRACE calls logging to file"),
+    | LevelFilter::Debug => banner.push("DEBUG calls logging to file"),
+    | LevelFilter::Off => banner.push("Disabled logging to file"),
+    | _ => (),
   }
+  banner
+}
+
+/// Parses a syslog facility name, such as `daemon` or `local0`, for the
+/// command line
+pub fn parse_facility(name: &str) -> Result<Facility, String> {
+  name.parse().map_err(|_| format!("unknown syslog facility {name}"))
+}
+
+/// Sends each record to syslog as one message
+pub struct SyslogLogger {
+  level: LevelFilter,
+  logger: Mutex<syslog::Logger<LoggerBackend, Formatter3164>>,
+}
 
-  let latest_log_path: String = format!("{}/{}", &LOG_PATH, &LOG_FILE);
+impl SyslogLogger {
+  /// Connects to the local syslog daemon
+  pub fn new(
+    facility: Facility, level: LevelFilter,
+  ) -> syslog::Result<Box<Self>> {
+    let logger = syslog::unix(Self::formatter(facility))?;
+    Ok(Self::with_logger(logger, level))
+  }
+
+  /// Connects to a syslog daemon listening on the unix socket at `path`
+  pub fn at<P: AsRef<Path>>(
+    path: P, facility: Facility, level: LevelFilter,
+  ) -> syslog::Result<Box<Self>> {
+    let logger = syslog::unix_custom(Self::formatter(facility), path)?;
+    Ok(Self::with_logger(logger, level))
+  }
+
+  fn formatter(facility: Facility) -> Formatter3164 {
+    Formatter3164 {
+      facility,
+      ..Formatter3164::default()
+    }
+  }
+
+  fn with_logger(
+    logger: syslog::Logger<LoggerBackend, Formatter3164>, level: LevelFilter,
+  ) -> Box<Self> {
+    Box::new(Self {
+      level,
+      logger: Mutex::new(logger),
+    })
+  }
+}
+
+impl Log for SyslogLogger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    metadata.level() <= self.level
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+    let message = record.args().to_string();
+    let mut logger = match self.logger.lock() {
+      | Ok(logger) => logger,
+      | Err(err) => err.into_inner(),
+    };
+    // Nowhere is left to report a failed write to, so it is dropped.
+    let _ = match record.level() {
+      | Level::Error => logger.err(message),
+      | Level::Warn => logger.warning(message),
+      | Level::Info => logger.info(message),
+      | Level::Debug | Level::Trace => logger.debug(message),
+    };
+  }
+
+  fn flush(&self) {}
+}
+
+impl SharedLogger for SyslogLogger {
+  fn level(&self) -> LevelFilter {
+    self.level
+  }
+
+  fn config(&self) -> Option<&Config> {
+    None
+  }
+
+  fn as_log(self: Box<Self>) -> Box<dyn Log> {
+    Box::new(*self)
+  }
+}
+
+/// Creates `log_path` if needed, moves the previous latest log out of the way
+/// and opens a fresh one
+pub fn open_log_file(log_path: &str) -> Result<File, Error> {
+  if !Path::new(log_path).exists() {
+    create_dir(log_path)?;
+  }
+
+  let latest_log_path: String = format!("{}/{}", log_path, &LOG_FILE);
   if Path::new(&latest_log_path).exists() {
-    let metadata = metadata(&latest_log_path).unwrap();
-    let created: DateTime<Local> = metadata.created().unwrap().into();
+    let metadata = metadata(&latest_log_path)?;
+    let created: DateTime<Local> =
+      metadata.created().or_else(|_| metadata.modified())?.into();
     let datetime: NaiveDateTime = created.naive_local();
     rename(
       &latest_log_path,
       format!(
         "{}/{}.log",
-        &LOG_PATH,
+        log_path,
         datetime.format("%Y-%m-%d-%H-%M-%S").to_string()
       ),
-    )
-    .unwrap();
+    )?;
   }
 
-  CombinedLogger::init(vec![
-    TermLogger::new(
-      settings.level,
-      config.clone(),
-      TerminalMode::Mixed,
-      ColorChoice::Auto,
-    ),
-    WriteLogger::new(
-      settings.file_level,
-      config,
-      File::create(&latest_log_path).unwrap(),
-    ),
-  ])
-  .unwrap();
+  File::create(&latest_log_path)
+}
+
+/// Which way a packet went, for packet traces
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+  Received,
+  Sent,
+}
+
+/// A header field for a packet trace, left out for the fields this kind of
+/// packet doesn't have, which are `()`
+fn trace_field(name: &str, value: &dyn Debug) -> String {
+  match format!("{value:?}") {
+    | value if value == "()" => String::new(),
+    | value => format!(" {name}={value}"),
+  }
+}
+
+/// The id and port of `packet` for a packet trace
+fn trace_ids<Env: Environment, Subset: PacketTrait>(
+  packet: &Packet<Env, Subset>,
+) -> String {
+  format!(
+    "{}{}",
+    trace_field("id", &packet.id),
+    trace_field("port", &packet.port)
+  )
+}
+
+/// The action of `packet`, followed by its id and port when it has them
+pub fn packet_label<Env: Environment>(packet: &PacketType<Env>) -> String {
+  let ids = match packet {
+    | PacketType::Data(packet) => trace_ids(packet),
+    | PacketType::Auth(packet) => trace_ids(packet),
+    | PacketType::AuthTry(packet) => trace_ids(packet),
+    | PacketType::Close(packet) => trace_ids(packet),
+    | PacketType::Noop(packet) => trace_ids(packet),
+    | PacketType::Open(packet) => trace_ids(packet),
+    | PacketType::Ack(packet) => trace_ids(packet),
+    | PacketType::Custom(_) => String::new(),
+  };
+  format!("{}{ids}", packet.action())
+}
+
+/// Describes `packet` for a packet trace: its action, id and port, and a hex
+/// dump of the start of its body. Formats nothing and returns None when
+/// `level` leaves trace out.
+pub fn packet_trace<Env: Environment>(
+  direction: Direction, packet: &PacketType<Env>, level: LevelFilter,
+) -> Option<String> {
+  if level < LevelFilter::Trace {
+    return None;
+  }
+  let header = packet_label(packet);
+  let body = packet.body();
+  let mut dump = body
+    .iter()
+    .take(TRACE_BODY_BYTES)
+    .map(|byte| format!("{byte:02x}"))
+    .collect::<Vec<String>>()
+    .join(" ");
+  if body.len() > TRACE_BODY_BYTES {
+    dump.push_str(" ...");
+  }
+  let arrow = match direction {
+    | Direction::Received => "<-",
+    | Direction::Sent => "->",
+  };
+  Some(format!(
+    "{arrow} {header} body[{}]: {dump}",
+    body.len()
+  ))
+}
+
+/// Logs `packet` at trace level, when trace is enabled anywhere
+pub fn log_packet<Env: Environment>(
+  direction: Direction, packet: &PacketType<Env>,
+) {
+  if let Some(line) = packet_trace(direction, packet, log::max_level()) {
+    trace!("{line}");
+  }
+}
+
+/// Logs a packet about to be sent, which only exists as bytes, by parsing it
+/// back with `parse` the way the other end will. Skipped, parse and all,
+/// unless trace is enabled.
+pub fn log_sent_packet<Env: Environment>(
+  packet: &Vec<u8>,
+  parse: impl FnOnce(Vec<u8>) -> Result<PacketType<Env>, ParseError>,
+) {
+  if log::max_level() < LevelFilter::Trace {
+    return;
+  }
+  if let Ok(packet) = parse(packet.to_owned()) {
+    log_packet(Direction::Sent, &packet);
+  }
 }
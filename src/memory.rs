@@ -0,0 +1,101 @@
+//! In-memory stand-ins for sockets, used to drive the handlers without real
+//! file descriptors
+
+use hydrogen::Stream as HydrogenStream;
+use std::{
+  collections::VecDeque,
+  io::{Error, ErrorKind},
+  os::unix::io::{AsRawFd, RawFd},
+  sync::{Arc, Mutex, MutexGuard},
+};
+
+#[derive(Default)]
+struct Buffers {
+  incoming: VecDeque<u8>,
+  outgoing: VecDeque<u8>,
+  shutdown: bool,
+  eof: bool,
+}
+
+/// A stream following the `recv`/`send`/`shutdown` contract of a hydrogen
+/// stream, backed by two byte queues. Clones share the same queues, so a test
+/// can keep one to feed and inspect what the code under test does with the
+/// other.
+#[derive(Clone)]
+pub struct MemoryStream {
+  buffers: Arc<Mutex<Buffers>>,
+  fd: RawFd,
+}
+
+impl MemoryStream {
+  /// Creates an empty stream reporting `fd` as its file descriptor, which is
+  /// never used for I/O
+  pub fn new(fd: RawFd) -> Self {
+    Self {
+      buffers: Arc::new(Mutex::new(Buffers::default())),
+      fd,
+    }
+  }
+
+  fn buffers(&self) -> MutexGuard<'_, Buffers> {
+    match self.buffers.lock() {
+      | Ok(buffers) => buffers,
+      | Err(err) => err.into_inner(),
+    }
+  }
+
+  /// Queues bytes to be returned by the next `recv`
+  pub fn feed(&self, data: &[u8]) {
+    self.buffers().incoming.extend(data);
+  }
+
+  /// Takes everything written with `send` so far
+  pub fn take_sent(&self) -> Vec<u8> {
+    self.buffers().outgoing.drain(..).collect()
+  }
+
+  /// Makes `recv` report a clean hang-up once the queued bytes are read
+  pub fn hang_up(&self) {
+    self.buffers().eof = true;
+  }
+
+  pub fn is_shutdown(&self) -> bool {
+    self.buffers().shutdown
+  }
+}
+
+impl HydrogenStream for MemoryStream {
+  fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+    let mut buffers = self.buffers();
+    if buffers.shutdown {
+      return Err(Error::from(ErrorKind::NotConnected));
+    }
+    if buffers.eof && buffers.incoming.is_empty() {
+      return Err(Error::from(ErrorKind::UnexpectedEof));
+    }
+    Ok(vec![buffers
+      .incoming
+      .drain(..)
+      .collect()])
+  }
+
+  fn send(&mut self, buf: &[u8]) -> Result<(), Error> {
+    let mut buffers = self.buffers();
+    if buffers.shutdown {
+      return Err(Error::from(ErrorKind::BrokenPipe));
+    }
+    buffers.outgoing.extend(buf);
+    Ok(())
+  }
+
+  fn shutdown(&mut self) -> Result<(), Error> {
+    self.buffers().shutdown = true;
+    Ok(())
+  }
+}
+
+impl AsRawFd for MemoryStream {
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd
+  }
+}
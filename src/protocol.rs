@@ -0,0 +1,34 @@
+//! The packet format spoken between the client and the server, for programs
+//! that want to embed it. Everything here is re-exported from the modules the
+//! binaries use, so there is only one copy of the protocol.
+//!
+//! ```
+//! use proxy_router::protocol::{Client, PacketType, Separator, Server};
+//! use uuid::Uuid;
+//!
+//! let separator = Separator::default();
+//! let id = Uuid::new_v4();
+//! let packet = Client::build_data_packet(&id, &separator, &"hi".into());
+//!
+//! match Server::parse_packet(packet, &separator) {
+//!   | Ok(PacketType::Data(packet)) => {
+//!     assert_eq!(packet.id, id);
+//!     assert_eq!(packet.body_as_str(), Ok("hi"));
+//!   },
+//!   | _ => panic!("Packet should parse as a data packet"),
+//! }
+//! ```
+
+pub use crate::{
+  constants::ConnectionId,
+  functions::{
+    check_id, constant_time_eq, hash_sha1, hash_sha1_with, hash_sha512,
+    hash_sha512_with, separator_collision, split, split_header, Ack,
+    ActionHandler, ActionRegistry, AddressError, ArrOrStr, Auth, AuthStatus,
+    AuthTry, AuthTryInfo, BodyReader, Client, Close, CustomPacket, Data,
+    Endpoint, Environment, ErrorContext, Forward, HashEncoding, Integrity,
+    Noop, Open, OpenInfo, Packet, PacketAction, PacketTrait, PacketType,
+    ParseError, ParseErrorType, ParseOptions, PortSet, PortSetError, Separator,
+    SeparatorError, Server,
+  },
+};
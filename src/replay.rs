@@ -0,0 +1,81 @@
+//! Runs captured traffic back through the parser, for regression tests
+//! against real traffic and for looking over a fuzz corpus. Packets carry no
+//! length of their own, so a capture frames each one with its length, as a
+//! 4 byte big endian integer, ahead of it.
+
+use std::collections::BTreeMap;
+
+use crate::functions::{
+  Environment, PacketType, ParseError, ParseOptions, Separator,
+};
+
+/// Bytes of the length ahead of each packet in a capture
+const FRAME_LENGTH_BYTES: usize = 4;
+
+/// The `parse_packet_with` of one side, reading packets sent by `Env`
+pub type Parser<Env> =
+  fn(Vec<u8>, &Separator, &ParseOptions) -> Result<PacketType<Env>, ParseError>;
+
+/// What came out of replaying a capture
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+  /// Packets that parsed, counted by action
+  pub actions: BTreeMap<String, usize>,
+  /// Packets that did not parse, by where their frame starts in the capture
+  pub errors: Vec<(usize, ParseError)>,
+  /// Where the capture ends with a frame cut short, if it does
+  pub truncated_at: Option<usize>,
+}
+
+impl ReplayReport {
+  /// Packets that parsed, whatever their action
+  pub fn parsed(&self) -> usize {
+    self.actions.values().sum()
+  }
+}
+
+/// Frames `packet` the way [`replay`] reads it back
+pub fn frame(packet: &[u8]) -> Vec<u8> {
+  let mut framed = (packet.len() as u32).to_be_bytes().to_vec();
+  framed.extend(packet);
+  framed
+}
+
+/// Splits a capture of framed packets into its packets and runs each through
+/// `parser`, the `parse_packet_with` of the side that received them, like
+/// `Server::parse_packet_with` for packets sent by the client. `options` should
+/// match what the capture was taken with, hash encoding included.
+pub fn replay<Env: Environment>(
+  bytes: &[u8], separator: &Separator, options: &ParseOptions,
+  parser: Parser<Env>,
+) -> ReplayReport {
+  let mut report = ReplayReport::default();
+  let mut offset = 0;
+  while offset < bytes.len() {
+    let start = offset + FRAME_LENGTH_BYTES;
+    let length = match bytes.get(offset..start) {
+      | Some(length) => {
+        u32::from_be_bytes([length[0], length[1], length[2], length[3]])
+      },
+      | None => {
+        report.truncated_at = Some(offset);
+        break;
+      },
+    };
+    let packet = match bytes.get(start..start + length as usize) {
+      | Some(packet) => packet.to_vec(),
+      | None => {
+        report.truncated_at = Some(offset);
+        break;
+      },
+    };
+    match parser(packet, separator, options) {
+      | Ok(packet) => {
+        *report.actions.entry(packet.action()).or_default() += 1;
+      },
+      | Err(err) => report.errors.push((offset, err)),
+    }
+    offset = start + length as usize;
+  }
+  report
+}
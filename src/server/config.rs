@@ -1,17 +1,42 @@
 use std::{
+  collections::HashMap,
   fs::File,
-  io::{BufReader, BufWriter, Read, Write},
+  io::{BufWriter, Write},
+  process::exit,
   time::{SystemTime, UNIX_EPOCH},
 };
 
 use once_cell::sync::Lazy;
-use proxy_router::constants::{
-  ConfigFile, Runtime, DEFAULT_THREAD_COUNT, SETTING_FILE_PATH,
+use proxy_router::{
+  constants::{
+    auth_from_env, prompt_auth, resolve_auth, ConfigFile, Runtime,
+    DEFAULT_AUTH_BLOCK_SECS, DEFAULT_AUTH_FAILURE_WINDOW_SECS,
+    DEFAULT_HASH_ENCODING, DEFAULT_INTEGRITY, DEFAULT_MAX_HEADER_BYTES,
+    DEFAULT_REAUTH_TIMEOUT_SECS, DEFAULT_THREAD_COUNT, SETTING_FILE_PATH,
+  },
+  functions::{
+    deserialize_bytes, deserialize_separator, serialize_bytes,
+    serialize_separator, HashEncoding, Integrity, Separator,
+  },
+  settings::{load_settings, LoadError, Settings},
+  websocket::Transport,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::{from_reader, to_string_pretty, Error};
+use serde_json::to_string_pretty;
 use simplelog::{debug, error, info, trace, warn};
 
+/// What to do with a data packet for a connection that is not open, like one
+/// that arrived after the connection was closed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownIdPolicy {
+  /// Drop it without telling the client
+  #[default]
+  Drop,
+  /// Drop it and send a close back, so the client stops sending for it
+  Close,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Address {
   pub port: u16,
@@ -32,22 +57,168 @@ impl ThreadType for Runtime {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config<T: ThreadType> {
-  pub separator: String,
-  pub listen: Address,
-  pub auth: String,
+  /// Written as the separator's bytes when it has control characters, and
+  /// read back from either that or a plain string
+  #[serde(
+    serialize_with = "serialize_separator",
+    deserialize_with = "deserialize_separator"
+  )]
+  pub separator: Separator,
+  /// Where the control connections from clients are accepted
+  #[serde(alias = "listen")]
+  pub control_listen: Address,
+  /// Host forwarded ports are bound on, unless `forward_bind_ports` names
+  /// another for the port; the control listener's host when unset
+  pub forward_bind: Option<String>,
+  /// Host to bind on, by forwarded port
+  #[serde(default)]
+  pub forward_bind_ports: HashMap<u16, String>,
+  /// Written as a string when it is printable text, and as its bytes
+  /// otherwise, so a secret need not be valid UTF-8
+  #[serde(
+    serialize_with = "serialize_bytes",
+    deserialize_with = "deserialize_bytes"
+  )]
+  pub auth: Vec<u8>,
+  /// Lets `auth` be empty, so any client sending an empty AUTH gets in;
+  /// refused at load time otherwise
+  #[serde(default)]
+  pub allow_empty_auth: bool,
   pub threads: T::THREAD,
+  /// Connections expected open at once: each listener preallocates this many
+  /// slots, and the connection table is sized for it up front
   pub concurrency: usize,
+  pub server_name: Option<String>,
+  /// Longest packet header accepted from a client, in bytes
+  #[serde(default = "default_max_header_bytes")]
+  pub max_header_bytes: usize,
+  /// Connections open for longer than this are closed, even while active
+  pub max_connection_lifetime_secs: Option<u64>,
+  /// How the hashes in data packets are written, `hex`, `base64` or `raw`;
+  /// announced to the client when it authenticates
+  #[serde(default = "default_hash_encoding")]
+  pub hash_encoding: HashEncoding,
+  /// Hashes carried by data packets, `dual` (SHA1 and SHA512) or
+  /// `sha512_only` where SHA1 is not allowed; announced to the client when it
+  /// authenticates
+  #[serde(default = "default_integrity")]
+  pub integrity: Integrity,
+  /// Most ports one session may listen on; the rest of its AUTH request is
+  /// refused, and far larger requests get the connection closed. Unlimited
+  /// when unset
+  pub max_ports_per_session: Option<usize>,
+  /// Control connections kept open at once; the ones past it are refused
+  /// with a `capacity` reason. Unlimited when unset
+  pub max_connections: Option<usize>,
+  /// Bytes the WebSocket transport may hold in partial frames across all
+  /// control connections before new control connections are refused, until
+  /// those frames complete. Control connections on the TCP transport are not
+  /// counted. Unlimited when unset
+  pub max_total_buffer_bytes: Option<usize>,
+  /// Failed AUTH attempts from one IP, within `auth_failure_window_secs`,
+  /// before that IP is refused for `auth_block_secs`; unlimited when unset
+  pub max_auth_failures: Option<u32>,
+  #[serde(default = "default_auth_failure_window_secs")]
+  pub auth_failure_window_secs: u64,
+  #[serde(default = "default_auth_block_secs")]
+  pub auth_block_secs: u64,
+  /// How long a client asked to authenticate again has to send its AUTH
+  /// packet before it is dropped
+  #[serde(default = "default_reauth_timeout_secs")]
+  pub reauth_timeout_secs: u64,
+  /// Close a downstream connection, and tell the client, once data written to
+  /// it has waited this long for the other end to read it
+  pub write_timeout_ms: Option<u64>,
+  /// Set SO_REUSEPORT on every listener, so a new instance can bind the same
+  /// ports while this one drains
+  #[serde(default)]
+  pub reuse_port: bool,
+  /// On SIGTERM, stop accepting and wait up to this long for the open
+  /// connections to close before exiting; exit right away when unset
+  pub drain_timeout_secs: Option<u64>,
+  /// How long to wait, by forwarded port, between the client authenticating
+  /// and accepting connections on that port, for upstreams that need a
+  /// moment once the tunnel is up
+  #[serde(default)]
+  pub warmup_ms: HashMap<u16, u64>,
+  /// Most data packets a connection may have in flight each way before its
+  /// sender waits for them to be acknowledged; announced to the client when
+  /// it authenticates. No acknowledgements are sent when unset
+  pub data_window: Option<u64>,
+  /// Warn when handling a single packet from the client, from parsing it to
+  /// writing it downstream, takes longer than this
+  pub slow_packet_ms: Option<u64>,
+  /// Log a line for every closed connection with its id, port, peer, bytes
+  /// each way, duration and why it closed
+  #[serde(default)]
+  pub access_log: bool,
+  /// What to do with data for a connection that is not open, `drop` or
+  /// `close`
+  #[serde(default)]
+  pub unknown_id: UnknownIdPolicy,
+  /// How control connections are carried, plain TCP or WebSocket messages
+  /// upgraded from a request for `path`
+  #[serde(default)]
+  pub transport: Transport,
+}
+
+fn default_max_header_bytes() -> usize {
+  DEFAULT_MAX_HEADER_BYTES
+}
+
+fn default_hash_encoding() -> HashEncoding {
+  DEFAULT_HASH_ENCODING
+}
+
+fn default_integrity() -> Integrity {
+  DEFAULT_INTEGRITY
+}
+
+fn default_auth_failure_window_secs() -> u64 {
+  DEFAULT_AUTH_FAILURE_WINDOW_SECS
+}
+
+fn default_auth_block_secs() -> u64 {
+  DEFAULT_AUTH_BLOCK_SECS
+}
+
+fn default_reauth_timeout_secs() -> u64 {
+  DEFAULT_REAUTH_TIMEOUT_SECS
 }
 
 pub static DEFAULT_SETTINGS: Lazy<Config<ConfigFile>> = Lazy::new(|| Config {
-  auth: String::from("CH4ng3M3!"),
-  separator: String::from("\u{0000}"),
-  listen: Address {
+  auth: b"CH4ng3M3!".to_vec(),
+  allow_empty_auth: false,
+  separator: Separator::default(),
+  control_listen: Address {
     port: 65535,
     host: String::from("0.0.0.0"),
   },
+  forward_bind: None,
+  forward_bind_ports: HashMap::new(),
   threads: None,
   concurrency: 1024,
+  server_name: None,
+  max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+  hash_encoding: DEFAULT_HASH_ENCODING,
+  integrity: DEFAULT_INTEGRITY,
+  max_connection_lifetime_secs: None,
+  max_auth_failures: None,
+  max_ports_per_session: None,
+  max_connections: None,
+  max_total_buffer_bytes: None,
+  auth_failure_window_secs: DEFAULT_AUTH_FAILURE_WINDOW_SECS,
+  auth_block_secs: DEFAULT_AUTH_BLOCK_SECS,
+  reauth_timeout_secs: DEFAULT_REAUTH_TIMEOUT_SECS,
+  write_timeout_ms: None,
+  reuse_port: false,
+  drain_timeout_secs: None,
+  warmup_ms: HashMap::new(),
+  data_window: None,
+  slow_packet_ms: None,
+  access_log: false,
+  unknown_id: UnknownIdPolicy::Drop,
+  transport: Transport::Tcp,
 });
 
 fn save_default() -> Result<(), ()> {
@@ -88,43 +259,31 @@ fn save_default() -> Result<(), ()> {
   }
 }
 
-fn backup_settings(mut reader: BufReader<File>) -> Result<(), ()> {
-  let mut settings: String = String::new();
-  match reader.read_to_string(&mut settings) {
-    | Ok(_) => {
-      let backup_file: Result<File, std::io::Error> = File::create(format!(
-        "{}-invalid-{}.json",
-        SETTING_FILE_PATH.strip_suffix(".json").unwrap(),
-        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
-      ));
-      debug!(
-        "Backup file name: {}",
-        format!(
-          "{}-invalid-{}.json",
-          SETTING_FILE_PATH.strip_suffix(".json").unwrap(),
-          SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
-        )
-      );
-      trace!("Backup file contents: {}", settings);
-      match backup_file {
-        | Ok(mut backup_file) => {
-          match backup_file.write_all(&settings.as_bytes()) {
-            | Ok(_) => {
-              info!("Settings file backed up!");
-              return Result::Ok(());
-            },
-            | Err(e) => {
-              error!(
-                "Failed to write to settings backup file: {}",
-                e
-              );
-              return Result::Err(());
-            },
-          }
+fn backup_settings(settings: &String) -> Result<(), ()> {
+  let backup_file: Result<File, std::io::Error> = File::create(format!(
+    "{}-invalid-{}.json",
+    SETTING_FILE_PATH.strip_suffix(".json").unwrap(),
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+  ));
+  debug!(
+    "Backup file name: {}",
+    format!(
+      "{}-invalid-{}.json",
+      SETTING_FILE_PATH.strip_suffix(".json").unwrap(),
+      SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    )
+  );
+  trace!("Backup file contents: {}", settings);
+  match backup_file {
+    | Ok(mut backup_file) => {
+      match backup_file.write_all(&settings.as_bytes()) {
+        | Ok(_) => {
+          info!("Settings file backed up!");
+          return Result::Ok(());
         },
         | Err(e) => {
           error!(
-            "Failed to create settings backup file: {}",
+            "Failed to write to settings backup file: {}",
             e
           );
           return Result::Err(());
@@ -132,14 +291,19 @@ fn backup_settings(mut reader: BufReader<File>) -> Result<(), ()> {
       }
     },
     | Err(e) => {
-      error!("Failed to read settings file: {}", e);
+      error!(
+        "Failed to create settings backup file: {}",
+        e
+      );
       return Result::Err(());
     },
   }
 }
 
-fn file_to_runtime(config: Config<ConfigFile>) -> Config<Runtime> {
-  let threads: usize = match config.threads {
+/// Picks the thread count, falling back to the available parallelism when
+/// none is given
+fn resolve_threads(threads: Option<usize>) -> usize {
+  match threads {
     | Some(threads) => threads,
     | _ => match std::thread::available_parallelism() {
       | Ok(threads) => {
@@ -151,47 +315,230 @@ fn file_to_runtime(config: Config<ConfigFile>) -> Config<Runtime> {
         DEFAULT_THREAD_COUNT
       },
     },
+  }
+}
+
+/// Overrides the thread count from the settings file with the one given on
+/// the command line, if any, where 0 means the available parallelism
+pub fn override_threads(config: &mut Config<Runtime>, threads: Option<usize>) {
+  match threads {
+    | Some(0) => config.threads = resolve_threads(None),
+    | Some(threads) => config.threads = threads,
+    | None => (),
+  }
+}
+
+/// Overrides the auth secret from the settings file with the one in the
+/// environment, or with one typed at the terminal when `ask`, refusing to
+/// start if that leaves it empty without `allow_empty_auth`
+pub fn override_auth(config: &mut Config<Runtime>, ask: bool) {
+  let prompted = match ask {
+    | true => match prompt_auth() {
+      | Ok(auth) => Some(auth),
+      | Err(err) => {
+        error!("Failed to read the auth secret: {err}");
+        exit(1);
+      },
+    },
+    | false => None,
   };
+  config.auth = resolve_auth(
+    std::mem::take(&mut config.auth),
+    auth_from_env(),
+    prompted,
+  );
+  if config.auth.is_empty() && !config.allow_empty_auth {
+    error!("The auth secret is empty, set allow_empty_auth to run without one");
+    exit(1);
+  }
+}
+
+/// Reads the auth secret at `path` again, then the one in the environment
+/// over it as at startup, so a SIGHUP picks up a changed secret. `None` keeps
+/// the running one, when the settings can't be loaded or leave it empty
+/// without `allow_empty_auth`.
+pub fn reload_auth(path: &str) -> Option<Vec<u8>> {
+  let settings = match load_settings::<Config<ConfigFile>>(path) {
+    | Ok(settings) => settings,
+    | Err(_) => {
+      error!("Failed to reload {path}, keeping the current auth secret");
+      return None;
+    },
+  };
+  let auth = resolve_auth(settings.auth, auth_from_env(), None);
+  if auth.is_empty() && !settings.allow_empty_auth {
+    error!("The reloaded auth secret is empty, keeping the current one");
+    return None;
+  }
+  Some(auth)
+}
+
+pub fn file_to_runtime(config: Config<ConfigFile>) -> Config<Runtime> {
+  let threads = resolve_threads(config.threads);
   Config {
     auth: config.auth,
+    allow_empty_auth: config.allow_empty_auth,
     concurrency: config.concurrency,
-    listen: config.listen,
+    control_listen: config.control_listen,
+    forward_bind: config.forward_bind,
+    forward_bind_ports: config.forward_bind_ports,
     separator: config.separator,
     threads,
+    server_name: config.server_name,
+    max_header_bytes: config.max_header_bytes,
+    hash_encoding: config.hash_encoding,
+    integrity: config.integrity,
+    max_connection_lifetime_secs: config.max_connection_lifetime_secs,
+    max_auth_failures: config.max_auth_failures,
+    max_ports_per_session: config.max_ports_per_session,
+    max_connections: config.max_connections,
+    max_total_buffer_bytes: config.max_total_buffer_bytes,
+    auth_failure_window_secs: config.auth_failure_window_secs,
+    auth_block_secs: config.auth_block_secs,
+    reauth_timeout_secs: config.reauth_timeout_secs,
+    write_timeout_ms: config.write_timeout_ms,
+    reuse_port: config.reuse_port,
+    drain_timeout_secs: config.drain_timeout_secs,
+    warmup_ms: config.warmup_ms,
+    data_window: match config.data_window {
+      | Some(0) => {
+        warn!(
+          "A data window of 0 would stall every connection, leaving it unset"
+        );
+        None
+      },
+      | data_window => data_window,
+    },
+    slow_packet_ms: config.slow_packet_ms,
+    access_log: config.access_log,
+    unknown_id: config.unknown_id,
+    transport: config.transport,
+  }
+}
+
+/// The inverse of [`file_to_runtime`], for writing edited settings back. The
+/// thread count is kept as resolved, and the separator is written as a string
+/// or as its bytes just like the defaults are.
+#[allow(dead_code)]
+pub fn runtime_to_file(config: Config<Runtime>) -> Config<ConfigFile> {
+  Config {
+    auth: config.auth,
+    allow_empty_auth: config.allow_empty_auth,
+    concurrency: config.concurrency,
+    control_listen: config.control_listen,
+    forward_bind: config.forward_bind,
+    forward_bind_ports: config.forward_bind_ports,
+    separator: config.separator,
+    threads: Some(config.threads),
+    server_name: config.server_name,
+    max_header_bytes: config.max_header_bytes,
+    hash_encoding: config.hash_encoding,
+    integrity: config.integrity,
+    max_connection_lifetime_secs: config.max_connection_lifetime_secs,
+    max_auth_failures: config.max_auth_failures,
+    max_ports_per_session: config.max_ports_per_session,
+    max_connections: config.max_connections,
+    max_total_buffer_bytes: config.max_total_buffer_bytes,
+    auth_failure_window_secs: config.auth_failure_window_secs,
+    auth_block_secs: config.auth_block_secs,
+    reauth_timeout_secs: config.reauth_timeout_secs,
+    write_timeout_ms: config.write_timeout_ms,
+    reuse_port: config.reuse_port,
+    drain_timeout_secs: config.drain_timeout_secs,
+    warmup_ms: config.warmup_ms,
+    data_window: config.data_window,
+    slow_packet_ms: config.slow_packet_ms,
+    access_log: config.access_log,
+    unknown_id: config.unknown_id,
+    transport: config.transport,
+  }
+}
+
+/// Why server settings that parsed can't be used
+pub enum SettingsError {
+  /// `auth` is empty without `allow_empty_auth`
+  EmptyAuth,
+  /// `server_name` holds a `;` or `=`, which would be read as more fields of
+  /// the AUTHTRY it is sent in
+  ServerName(String),
+}
+
+impl Settings for Config<ConfigFile> {
+  type Error = SettingsError;
+
+  const RENAMED_FIELDS: &'static [(&'static str, &'static str)] =
+    &[("listen", "control_listen")];
+
+  fn defaults() -> &'static Self {
+    &DEFAULT_SETTINGS
+  }
+
+  fn separator(&self) -> &Separator {
+    &self.separator
+  }
+
+  fn hash_encoding(&self) -> HashEncoding {
+    self.hash_encoding
+  }
+
+  fn check(&self) -> Result<(), SettingsError> {
+    if self.auth.is_empty() && !self.allow_empty_auth {
+      return Err(SettingsError::EmptyAuth);
+    }
+    match &self.server_name {
+      | Some(name) if name.contains([';', '=']) => Err(
+        SettingsError::ServerName(name.to_owned()),
+      ),
+      | _ => Ok(()),
+    }
   }
 }
 
 pub fn get_settings() -> Config<Runtime> {
   let settings: Config<ConfigFile> = DEFAULT_SETTINGS.clone();
-  let file: Result<File, std::io::Error> = File::open(SETTING_FILE_PATH);
-  match file {
-    | Ok(file) => {
-      let reader: BufReader<File> = BufReader::new(file);
-      let settings_from_files: Result<Config<ConfigFile>, Error> =
-        from_reader(reader);
-      match settings_from_files {
-        | Ok(settings_from_files) => {
-          trace!("{:?}", settings_from_files);
-
-          return file_to_runtime(settings_from_files);
+  match load_settings(SETTING_FILE_PATH) {
+    | Ok(settings_from_files) => {
+      trace!("{:?}", settings_from_files);
+      return file_to_runtime(settings_from_files);
+    },
+    | Err(LoadError::Invalid(contents, e)) => {
+      error!("Failed to deserialize settings: {}", e);
+      warn!("Using default settings");
+      match backup_settings(&contents) {
+        | Ok(_) => {
+          save_default().unwrap();
         },
-        | Err(e) => {
-          error!("Failed to deserialize settings: {}", e);
-          warn!("Using default settings");
-          match backup_settings(BufReader::new(
-            File::open(SETTING_FILE_PATH).unwrap(),
-          )) {
-            | Ok(_) => {
-              save_default().unwrap();
-            },
-            | Err(_) => {
-              error!("Failed to backup settings");
-            },
-          }
+        | Err(_) => {
+          error!("Failed to backup settings");
         },
       }
     },
-    | Err(e) => {
+    | Err(LoadError::Separator(e)) => {
+      // Falling back to the default separator would only leave this side
+      // unable to talk to the other one, so refuse to start instead.
+      error!("{}", e.value());
+      exit(1);
+    },
+    | Err(LoadError::Check(SettingsError::EmptyAuth)) => {
+      // Any client could authenticate with an empty secret, so only start
+      // when that was asked for.
+      error!(
+        "The auth secret is empty, set allow_empty_auth to run without one"
+      );
+      exit(1);
+    },
+    | Err(LoadError::Check(SettingsError::ServerName(name))) => {
+      error!("The server name {name:?} can't hold a ';' or a '='");
+      exit(1);
+    },
+    | Err(LoadError::NotAFile(e)) => {
+      error!(
+        "Failed to open settings file: {} ({SETTING_FILE_PATH} is not a regular file, leaving it alone)",
+        e
+      );
+      exit(1);
+    },
+    | Err(LoadError::Read(e)) => {
       error!("Failed to open settings file: {}", e);
       warn!("Using default settings");
       save_default().unwrap();
@@ -199,3 +546,34 @@ pub fn get_settings() -> Config<Runtime> {
   }
   file_to_runtime(settings)
 }
+
+/// Reads the soft limit on open file descriptors, or `None` when it is
+/// unlimited or can't be read
+pub fn soft_fd_limit() -> Option<u64> {
+  let mut limit = libc::rlimit {
+    rlim_cur: 0,
+    rlim_max: 0,
+  };
+  if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+    return None;
+  }
+  if limit.rlim_cur == libc::RLIM_INFINITY {
+    None
+  } else {
+    Some(limit.rlim_cur as u64)
+  }
+}
+
+/// Checks that `concurrency` connections fit in the soft file descriptor
+/// limit, warning otherwise since going over it only shows up later as
+/// failed accepts
+pub fn check_fd_limit(concurrency: usize, soft_limit: Option<u64>) -> bool {
+  match soft_limit {
+    | Some(limit) if concurrency as u64 > limit => {
+      warn!("Concurrency ({concurrency}) is above the open file limit ({limit}), connections past it will fail to be accepted");
+      warn!("Raise the limit (e.g. `ulimit -n {concurrency}`) or lower the concurrency setting");
+      false
+    },
+    | _ => true,
+  }
+}
@@ -0,0 +1,61 @@
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use simplelog::warn;
+use tokio::time::Instant;
+
+/// Counts failed AUTH attempts per source IP and refuses an IP for a while
+/// once it fails too often within the window
+pub struct AuthLimiter {
+  max_failures: u32,
+  window: Duration,
+  block: Duration,
+  failures: HashMap<IpAddr, Vec<Instant>>,
+  blocked: HashMap<IpAddr, Instant>,
+}
+
+impl AuthLimiter {
+  pub fn new(max_failures: u32, window: Duration, block: Duration) -> Self {
+    AuthLimiter {
+      max_failures,
+      window,
+      block,
+      failures: HashMap::new(),
+      blocked: HashMap::new(),
+    }
+  }
+
+  /// Whether `ip` is still refused at `now`, forgetting blocks that expired
+  pub fn is_blocked(&mut self, ip: &IpAddr, now: Instant) -> bool {
+    match self.blocked.get(ip) {
+      | Some(until) if *until > now => true,
+      | Some(_) => {
+        self.blocked.remove(ip);
+        false
+      },
+      | None => false,
+    }
+  }
+
+  /// Records a failed attempt from `ip`, returning whether it is now blocked
+  pub fn record_failure(&mut self, ip: IpAddr, now: Instant) -> bool {
+    let window = self.window;
+    let failures = self.failures.entry(ip).or_default();
+    failures.retain(|at| now.duration_since(*at) < window);
+    failures.push(now);
+    if failures.len() < self.max_failures as usize {
+      return false;
+    }
+    self.failures.remove(&ip);
+    self.blocked.insert(ip, now + self.block);
+    warn!(
+      "Blocking {ip} for {}s after {} failed auth attempts",
+      self.block.as_secs(),
+      self.max_failures
+    );
+    true
+  }
+
+  pub fn record_success(&mut self, ip: &IpAddr) {
+    self.failures.remove(ip);
+  }
+}
@@ -1,23 +1,34 @@
 mod config;
+mod limiter;
 mod slave;
 mod socket;
+mod tests;
 
-use proxy_router::logging::{init_logger, LoggerSettings};
+use proxy_router::{
+  constants::{ignore_sigpipe, ConfigFile, SETTING_FILE_PATH},
+  logging::{
+    init_logger, lower_log_level, parse_facility, raise_log_level,
+    startup_banner, LogSink, LoggerSettings,
+  },
+  settings,
+};
 
 use clap::{value_parser, Arg, ArgAction, Command};
 use signal_hook::{
-  consts::{SIGINT, SIGTERM},
+  consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2},
   iterator::Signals,
 };
 #[allow(unused_imports)]
 use simplelog::{debug, error, info, trace, warn};
-use std::{process::exit, thread};
+use std::{process::exit, thread, time::Duration};
+use syslog::Facility;
 
 #[tokio::main]
 async fn main() {
   let mut logger_settings = LoggerSettings {
     level: simplelog::LevelFilter::Info,
     file_level: simplelog::LevelFilter::Debug,
+    sinks: Vec::new(),
   };
 
   let level: simplelog::LevelFilter;
@@ -88,6 +99,28 @@ async fn main() {
         .conflicts_with_all(&["trace", "debug", "error", "warn", "info"])
         .help("Sets the logging level to off"),
     )
+    .arg(
+      Arg::new("threads")
+        .long("threads")
+        .value_name("N")
+        .value_parser(value_parser!(usize))
+        .help("Overrides the number of threads from the settings file, 0 uses the available parallelism"),
+    )
+    .arg(
+      Arg::new("quiet")
+        .long("quiet")
+        .num_args(0)
+        .action(ArgAction::SetTrue)
+        .conflicts_with("trace")
+        .help("Leaves out the startup lines saying where logs go"),
+    )
+    .arg(
+      Arg::new("ask-auth")
+        .long("ask-auth")
+        .num_args(0)
+        .action(ArgAction::SetTrue)
+        .help("Asks for the auth secret on the terminal, overriding the settings file and the PROXY_ROUTER_AUTH environment variable"),
+    )
     .arg(
       Arg::new("trace-file")
         .long("trace-file")
@@ -104,8 +137,32 @@ async fn main() {
         .conflicts_with("trace-file")
         .help("Disables the log file"),
     )
+    .arg(
+      Arg::new("syslog")
+        .long("syslog")
+        .value_name("FACILITY")
+        .value_parser(parse_facility)
+        .help("Also sends INFO and above to syslog under FACILITY, such as daemon or local0"),
+    )
+    .subcommand(
+      Command::new("init")
+        .about("Writes the default settings file and exits")
+        .arg(
+          Arg::new("force")
+            .long("force")
+            .num_args(0)
+            .action(ArgAction::SetTrue)
+            .help("Overwrites the settings file if it already exists"),
+        ),
+    )
     .get_matches();
 
+  if let Some(init) = matches.subcommand_matches("init") {
+    exit(settings::init::<
+      config::Config<ConfigFile>,
+    >(init.get_flag("force")));
+  }
+
   if matches.get_flag("trace") {
     logger_settings.level = simplelog::LevelFilter::Trace;
     level = simplelog::LevelFilter::Trace;
@@ -135,26 +192,42 @@ async fn main() {
     file_level = simplelog::LevelFilter::Debug;
   }
 
+  if let Some(facility) = matches.get_one::<Facility>("syslog") {
+    logger_settings.sinks.push(LogSink::Syslog {
+      facility: *facility,
+      level: simplelog::LevelFilter::Info,
+    });
+  }
+
   init_logger(logger_settings);
 
-  match level {
-    | simplelog::LevelFilter::Trace => info!("TRACE calls logging to terminal"),
-    | simplelog::LevelFilter::Debug => info!("DEBUG calls logging to terminal"),
-    | simplelog::LevelFilter::Info => info!("INFO calls logging to terminal"),
-    | simplelog::LevelFilter::Warn => info!("WARN calls logging to terminal"),
-    | simplelog::LevelFilter::Error => info!("ERROR calls logging to terminal"),
-    | simplelog::LevelFilter::Off => info!("Disabled logging to terminal"),
+  for line in startup_banner(
+    level,
+    file_level,
+    matches.get_flag("quiet"),
+  ) {
+    info!("{line}");
   }
 
-  match file_level {
-    | simplelog::LevelFilter::Trace => info!("TRACE calls logging to file"),
-    | simplelog::LevelFilter::Debug => info!("DEBUG calls logging to file"),
-    | simplelog::LevelFilter::Off => info!("Disabled logging to file"),
-    | _ => (),
-  }
+  let mut config = config::get_settings();
+  config::override_threads(
+    &mut config,
+    matches.get_one::<usize>("threads").copied(),
+  );
+  let ask_auth = matches.get_flag("ask-auth");
+  config::override_auth(&mut config, ask_auth);
+  config::check_fd_limit(
+    config.concurrency,
+    config::soft_fd_limit(),
+  );
+  let master = socket::MasterListener::new(&config);
+  let shutdown = master.shutdown_handle();
+  let reauth = master.reauth_handle();
+  let drain_timeout = config.drain_timeout_secs.map(Duration::from_secs);
 
+  ignore_sigpipe();
   let mut signals: signal_hook::iterator::SignalsInfo =
-    Signals::new(&[SIGINT, SIGTERM]).unwrap();
+    Signals::new(&[SIGINT, SIGTERM, SIGUSR1, SIGUSR2, SIGHUP]).unwrap();
 
   thread::spawn(move || {
     for sig in signals.forever() {
@@ -162,12 +235,44 @@ async fn main() {
       match sig {
         | SIGINT => warn!("Received SIGINT"),
         | SIGTERM => warn!("Received SIGTERM"),
+        | SIGUSR1 => {
+          warn!(
+            "Terminal log level raised to {}",
+            raise_log_level()
+          );
+          continue;
+        },
+        | SIGUSR2 => {
+          warn!(
+            "Terminal log level lowered to {}",
+            lower_log_level()
+          );
+          continue;
+        },
+        | SIGHUP => {
+          // A secret typed in at startup can't be asked for again here.
+          if !ask_auth {
+            if let Some(auth) = config::reload_auth(SETTING_FILE_PATH) {
+              reauth.set_auth(auth);
+              info!("Reloaded the auth secret");
+            }
+          }
+          warn!(
+            "Asked {} client(s) to authenticate again",
+            reauth.reauth()
+          );
+          continue;
+        },
         | _ => unreachable!(),
       }
+      // hydrogen::begin never returns, so drain from here before exiting.
+      match (sig, drain_timeout) {
+        | (SIGTERM, Some(timeout)) => shutdown.drain(timeout),
+        | _ => shutdown.shutdown(),
+      }
       exit(0);
     }
   });
 
-  let config = config::get_settings();
-  socket::MasterListener::start(&config);
+  master.start();
 }
@@ -1,17 +1,34 @@
 use hydrogen::{HydrogenSocket, Stream as HydrogenStream};
+#[cfg(test)]
+use proxy_router::memory::MemoryStream;
 use proxy_router::{
-  constants::Stream,
-  functions::{Server, Warning},
+  constants::{peer_label, IdSource, Stream, WINDOW_BACKLOG_BYTES},
+  functions::{
+    Client, Endpoint, HashEncoding, Integrity, OpenInfo, ParseOptions,
+    Separator, Server, Warning,
+  },
+  logging::{enter_session, in_session, log_sent_packet, SessionId},
+  websocket::{close_frame, server_frame},
+  window::Window,
 };
-use simplelog::{debug, error, info};
+use simplelog::{debug, error, info, warn};
 use std::{
   cell::UnsafeCell,
-  collections::HashMap,
-  io::Error,
-  net::TcpStream,
-  os::{fd::FromRawFd, unix::io::RawFd},
-  sync::{Arc, Mutex},
+  collections::{HashMap, VecDeque},
+  io::{Error, ErrorKind},
+  net::{SocketAddr, TcpStream},
+  os::{
+    fd::FromRawFd,
+    unix::io::{AsRawFd, RawFd},
+  },
+  sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex, MutexGuard,
+  },
+  thread,
+  time::Duration,
 };
+use tokio::time::Instant;
 use uuid::Uuid;
 
 #[derive(Clone, Debug)]
@@ -22,32 +39,412 @@ pub struct Address {
 
 #[derive(Clone)]
 pub struct ServerConfig {
-  pub separator: String,
+  pub separator: Separator,
   pub listen: Address,
   pub threads: usize,
   pub concurrency: usize,
-  pub socket: Arc<Mutex<HydrogenSocket>>,
+  pub socket: Control,
   pub connections: Arc<Mutex<HashMap<Uuid, SenderPacket>>>,
+  pub handle: SlaveHandle,
+  pub ids: IdSource,
+  pub hash_encoding: HashEncoding,
+  pub integrity: Integrity,
+  /// Where the client asked for this port to be forwarded, passed back to it
+  /// with every new connection
+  pub upstream: Option<Endpoint>,
+  pub reuse_port: bool,
+  pub write_timeout: Option<Duration>,
+  /// Waited out before the listener starts accepting
+  pub warmup: Option<Duration>,
+  /// Data packets each connection may have unacknowledged by the client
+  pub window: Option<u64>,
+  /// Log a summary line for every connection once it closes
+  pub access_log: bool,
+  /// The session of the control connection that spawned this listener,
+  /// which its log lines are prefixed with too
+  pub session: Option<SessionId>,
+}
+
+/// Ties a slave listener to the control connection that spawned it, so the
+/// listener and its downstream sockets can be torn down once that control
+/// connection goes away
+#[derive(Clone, Default)]
+pub struct SlaveHandle {
+  closed: Arc<AtomicBool>,
+  listener: Arc<Mutex<Option<RawFd>>>,
+  ids: Arc<Mutex<Vec<Uuid>>>,
+}
+
+impl SlaveHandle {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn is_closed(&self) -> bool {
+    self.closed.load(Ordering::SeqCst)
+  }
+
+  /// Records the listening fd, shutting it down right away if the control
+  /// connection already went away before the listener came up
+  pub fn set_listener(&self, fd: RawFd) {
+    match self.listener.lock() {
+      | Ok(mut listener) => {
+        *listener = Some(fd);
+        if self.is_closed() {
+          shutdown_fd(fd);
+        }
+      },
+      | Err(err) => error!("Failed while aquiring lock from listener: {err}"),
+    }
+  }
+
+  pub fn add(&self, id: Uuid) {
+    match self.ids.lock() {
+      | Ok(mut ids) => ids.push(id),
+      | Err(err) => error!("Failed while aquiring lock from ids: {err}"),
+    }
+  }
+
+  pub fn remove(&self, id: &Uuid) {
+    match self.ids.lock() {
+      | Ok(mut ids) => ids.retain(|other| other != id),
+      | Err(err) => error!("Failed while aquiring lock from ids: {err}"),
+    }
+  }
+
+  /// Stops accepting on the listener, leaving the downstream sockets it
+  /// already accepted alone
+  pub fn stop_accepting(&self) {
+    self.closed.store(true, Ordering::SeqCst);
+    match self.listener.lock() {
+      | Ok(listener) => {
+        if let Some(fd) = *listener {
+          shutdown_fd(fd);
+        }
+      },
+      | Err(err) => error!("Failed while aquiring lock from listener: {err}"),
+    }
+  }
+
+  /// Stops accepting on the listener, then shuts down every downstream socket
+  /// it accepted and tells the client each one is gone
+  pub fn close(
+    &self, connections: &Mutex<HashMap<Uuid, SenderPacket>>,
+    separator: &Separator,
+  ) {
+    self.stop_accepting();
+    let ids = match self.ids.lock() {
+      | Ok(mut ids) => ids.drain(..).collect::<Vec<Uuid>>(),
+      | Err(err) => {
+        error!("Failed while aquiring lock from ids: {err}");
+        return;
+      },
+    };
+    // Closing takes the control lock, which is held while the connections
+    // are looked up for data, so the connections are let go of first.
+    let closing = match connections.lock() {
+      | Ok(mut connections) => ids
+        .iter()
+        .filter_map(|id| connections.remove(id))
+        .collect::<Vec<SenderPacket>>(),
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        return;
+      },
+    };
+    for connection in closing {
+      connection.close(separator, "control connection closed");
+    }
+  }
+}
+
+// Shutting down a listening socket makes the pending accept fail and refuses
+// any new connection, without closing the fd hydrogen still owns.
+fn shutdown_fd(fd: RawFd) {
+  if unsafe { libc::shutdown(fd, libc::SHUT_RDWR) } != 0 {
+    debug!(
+      "Failed to shutdown listener ({fd}): {}",
+      Error::last_os_error()
+    );
+  }
+}
+
+/// The control connection a downstream connection reports back to
+pub trait ControlSocket: AsRawFd + Send {
+  fn send(&mut self, buf: &[u8]);
+  fn shutdown(&mut self) -> Result<(), Error>;
+}
+
+impl ControlSocket for HydrogenSocket {
+  fn send(&mut self, buf: &[u8]) {
+    HydrogenSocket::send(self, buf);
+  }
+
+  fn shutdown(&mut self) -> Result<(), Error> {
+    HydrogenSocket::shutdown(self)
+  }
+}
+
+#[cfg(test)]
+impl ControlSocket for MemoryStream {
+  fn send(&mut self, buf: &[u8]) {
+    if let Err(err) = HydrogenStream::send(self, buf) {
+      error!("Failed to write to control socket: {err}");
+    }
+  }
+
+  fn shutdown(&mut self) -> Result<(), Error> {
+    HydrogenStream::shutdown(self)
+  }
+}
+
+/// A control connection using the WebSocket transport, which sends each
+/// packet as one binary message on the socket it wraps
+pub struct WebSocketControl<S>(pub S);
+
+impl<S: AsRawFd> AsRawFd for WebSocketControl<S> {
+  fn as_raw_fd(&self) -> RawFd {
+    self.0.as_raw_fd()
+  }
+}
+
+impl<S: ControlSocket> ControlSocket for WebSocketControl<S> {
+  fn send(&mut self, buf: &[u8]) {
+    self.0.send(&server_frame(buf));
+  }
+
+  fn shutdown(&mut self) -> Result<(), Error> {
+    self.0.send(&close_frame());
+    self.0.shutdown()
+  }
+}
+
+pub type Control = Arc<Mutex<dyn ControlSocket>>;
+
+/// Totals for a forwarded connection, shared by the master and the slave
+/// listener that accepted it, for the summary logged once it closes
+pub struct ConnectionStats {
+  pub port: u16,
+  pub peer: Option<SocketAddr>,
+  pub opened: Instant,
+  /// Bytes read from the downstream and sent on to the client
+  pub bytes_in: AtomicU64,
+  /// Bytes from the client written to the downstream
+  pub bytes_out: AtomicU64,
+  /// Why the server closed the connection, if it was the server
+  reason: Mutex<Option<String>>,
+}
+
+impl ConnectionStats {
+  pub fn new(port: u16, peer: Option<SocketAddr>) -> Self {
+    Self {
+      port,
+      peer,
+      opened: Instant::now(),
+      bytes_in: AtomicU64::new(0),
+      bytes_out: AtomicU64::new(0),
+      reason: Mutex::new(None),
+    }
+  }
+
+  /// Records why the connection is being closed, keeping the first reason
+  pub fn set_reason(&self, reason: &str) {
+    let mut current = match self.reason.lock() {
+      | Ok(current) => current,
+      | Err(err) => err.into_inner(),
+    };
+    current.get_or_insert_with(|| reason.to_string());
+  }
+
+  /// The summary line for connection `id`, closed for `reason` unless an
+  /// earlier one was recorded
+  pub fn summary(&self, id: &Uuid, reason: &str) -> String {
+    let recorded = match self.reason.lock() {
+      | Ok(recorded) => recorded.to_owned(),
+      | Err(err) => err.into_inner().to_owned(),
+    };
+    format!(
+      "Connection closed: id={id} port={} peer={} bytes_in={} bytes_out={} duration_ms={} reason={:?}",
+      self.port,
+      peer_label(&self.peer),
+      self.bytes_in.load(Ordering::Relaxed),
+      self.bytes_out.load(Ordering::Relaxed),
+      self.opened.elapsed().as_millis(),
+      recorded.as_deref().unwrap_or(reason)
+    )
+  }
 }
 
 pub struct SenderPacket {
-  pub socket: Arc<Mutex<Stream>>,
+  pub socket: Arc<Mutex<dyn HydrogenStream>>,
   pub fd: RawFd,
   pub uuid: Uuid,
+  pub control: Control,
+  pub peer: Option<SocketAddr>,
+  pub accepted: Instant,
+  /// Data packets sent to the client and not acknowledged yet, when the
+  /// server has a data window
+  pub window: Option<Arc<Window>>,
+  /// Data packets received from the client so far
+  pub received: u64,
+  pub stats: Arc<ConnectionStats>,
+}
+
+impl SenderPacket {
+  /// Shuts down the downstream socket and tells the client it is gone,
+  /// recording `reason` for the connection's summary
+  pub fn close(&self, separator: &Separator, reason: &str) {
+    self.stats.set_reason(reason);
+    self.release();
+    match self.socket.lock() {
+      | Ok(mut socket) => match socket.shutdown() {
+        | Ok(_) => debug!("Closed connection: {}", self.uuid),
+        | Err(err) => {
+          error!(
+            "Failed to close connection ({}): {err}",
+            self.uuid
+          )
+        },
+      },
+      | Err(err) => error!("Failed to aquire lock for socket: {err}"),
+    }
+    match self.control.lock() {
+      | Ok(mut control) => control.send(
+        Server::close_connection_packet(&self.uuid, separator).as_slice(),
+      ),
+      | Err(err) => error!("Failed to aquire lock for control socket: {err}"),
+    }
+  }
+
+  /// Lets go of a slave thread waiting on the window to send more data
+  pub fn release(&self) {
+    if let Some(window) = &self.window {
+      window.close();
+    }
+  }
+}
+
+/// Lets another process bind the same port while this one still listens, so
+/// a restarted server can take over the port before the old one exits
+pub fn set_reuse_port(fd: RawFd) {
+  let enable: libc::c_int = 1;
+  let result = unsafe {
+    libc::setsockopt(
+      fd,
+      libc::SOL_SOCKET,
+      libc::SO_REUSEPORT,
+      &enable as *const libc::c_int as *const libc::c_void,
+      std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+    )
+  };
+  if result != 0 {
+    warn!(
+      "Failed to set SO_REUSEPORT on listener ({fd}): {}",
+      Error::last_os_error()
+    );
+  }
+}
+
+/// A data packet for the client, waiting on its connection's window
+struct Outgoing {
+  packet: Vec<u8>,
+  /// Bytes read from the downstream that went into it
+  len: usize,
+  stats: Arc<ConnectionStats>,
+}
+
+impl Outgoing {
+  fn send(&self, socket: &Control) -> bool {
+    match socket.lock() {
+      | Ok(mut master_socket) => {
+        master_socket.send(self.packet.as_slice());
+        self.stats.bytes_in.fetch_add(self.len as u64, Ordering::Relaxed);
+        true
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock from socket: {err}");
+        false
+      },
+    }
+  }
+}
+
+/// Data packets read from a downstream once its window filled up, sent in
+/// order as acknowledgements make room
+#[derive(Default)]
+struct Queued {
+  packets: VecDeque<Outgoing>,
+  /// Bytes read from the downstream that went into `packets`
+  bytes: usize,
+}
+
+impl Queued {
+  fn push(&mut self, outgoing: Outgoing) {
+    self.bytes += outgoing.len;
+    self.packets.push_back(outgoing);
+  }
+
+  fn pop(&mut self) -> Option<Outgoing> {
+    let outgoing = self.packets.pop_front()?;
+    self.bytes -= outgoing.len;
+    Some(outgoing)
+  }
+
+  fn clear(&mut self) {
+    self.packets.clear();
+    self.bytes = 0;
+  }
+}
+
+type Backlog = Arc<Mutex<Queued>>;
+
+fn lock_backlog(backlog: &Backlog) -> MutexGuard<'_, Queued> {
+  match backlog.lock() {
+    | Ok(queued) => queued,
+    | Err(err) => err.into_inner(),
+  }
+}
+
+/// Sends what `backlog` holds as `window` makes room, until it runs dry or
+/// the connection `id` is closed
+fn drain_backlog(
+  id: &Uuid, window: &Window, backlog: &Backlog, socket: &Control,
+) {
+  loop {
+    if !window.reserve_blocking() {
+      debug!("Dropping data for {id}, it was closed while waiting");
+      lock_backlog(backlog).clear();
+      return;
+    }
+    let mut queued = lock_backlog(backlog);
+    if let Some(outgoing) = queued.pop() {
+      outgoing.send(socket);
+    }
+    if queued.packets.is_empty() {
+      return;
+    }
+  }
 }
 
 // The following will be our server that handles all reported events
 pub struct SlaveListener {
-  connections: HashMap<RawFd, Uuid>,
+  connections: HashMap<RawFd, (Uuid, Arc<ConnectionStats>)>,
+  /// Data packets read before a connection's window filled up, by fd
+  backlogs: HashMap<RawFd, Backlog>,
   config: ServerConfig,
-  socket: Arc<Mutex<HydrogenSocket>>,
+  socket: Control,
   warn: Warning,
 }
 
 impl hydrogen::Handler for SlaveListener {
-  fn on_server_created(&mut self, _: RawFd) {
+  fn on_server_created(&mut self, fd: RawFd) {
+    let _session = enter_session(self.config.session);
     // Do any secific flag/option setting on the underlying listening fd.
     // This will be the fd that accepts all incoming connections.
+    self.config.handle.set_listener(fd);
+    if self.config.reuse_port {
+      set_reuse_port(fd);
+    }
     info!("Server created");
     info!(
       "Listening on: {}:{}",
@@ -64,14 +461,32 @@ impl hydrogen::Handler for SlaveListener {
   fn on_new_connection(
     &mut self, fd: RawFd,
   ) -> Arc<UnsafeCell<dyn HydrogenStream>> {
+    let _session = enter_session(self.config.session);
     // With the passed fd, create your type that implements `hydrogen::Stream`
     // and return it.
 
     // For example:
     let tcp_stream = unsafe { TcpStream::from_raw_fd(fd) };
-    let stream = Stream::from_tcp_stream(tcp_stream);
-    self.connections.insert(fd, stream.id);
-    info!("New connection: {}", stream.id);
+    let mut stream = Stream::with_id(tcp_stream, (self.config.ids)());
+    stream.write_timeout = self.config.write_timeout;
+    if self.config.handle.is_closed() {
+      info!("Refusing connection {fd}, control connection is gone");
+      match stream.shutdown() {
+        | Ok(_) => (),
+        | Err(err) => error!("Error shutting down connection: {err}"),
+      }
+      return Arc::new(UnsafeCell::new(stream));
+    }
+    let stats = Arc::new(ConnectionStats::new(
+      self.config.listen.port, stream.peer,
+    ));
+    self.connections.insert(fd, (stream.id, Arc::clone(&stats)));
+    self.config.handle.add(stream.id);
+    info!(
+      "New connection: {} from {}",
+      stream.id,
+      stream.peer_label()
+    );
     match self.config.connections.lock() {
       | Ok(mut connections) => {
         connections.insert(
@@ -80,6 +495,15 @@ impl hydrogen::Handler for SlaveListener {
             socket: Arc::new(Mutex::new(stream.to_owned())),
             fd: fd.to_owned(),
             uuid: stream.id.to_owned(),
+            control: self.socket.clone(),
+            peer: stream.peer,
+            accepted: Instant::now(),
+            window: self
+              .config
+              .window
+              .map(|window| Arc::new(Window::new(window))),
+            received: 0,
+            stats,
           },
         );
       },
@@ -91,38 +515,144 @@ impl hydrogen::Handler for SlaveListener {
         );
       },
     }
+    // Lets the client tell the upstream who is really connecting, for
+    // targets that speak the PROXY protocol.
+    let packet = Server::build_open_packet(
+      &stream.id,
+      &self.config.listen.port,
+      &OpenInfo {
+        peer: stream.peer,
+        local: stream.local,
+        upstream: self.config.upstream.clone(),
+      },
+      &self.config.separator,
+    );
+    match self.socket.lock() {
+      | Ok(mut master_socket) => master_socket.send(packet.as_slice()),
+      | Err(err) => {
+        error!("Failed while aquiring lock from socket: {err}");
+      },
+    }
     Arc::new(UnsafeCell::new(stream))
   }
 
   fn on_data_received(&mut self, socket: HydrogenSocket, buffer: Vec<u8>) {
+    let _session = enter_session(self.config.session);
     // Called when a complete, consumer defined, chunk of data has been read.
-    match self.connections.get(&socket.arc_connection.fd) {
-      | Some(id) => {
+    self.forward(socket.arc_connection.fd, buffer);
+  }
+
+  fn on_connection_removed(&mut self, fd: RawFd, err: Error) {
+    let _session = enter_session(self.config.session);
+    // Called when a connection has been removed from the watch list, with the
+    // `std::io::Error` as the reason removed.
+    if self.config.access_log {
+      if let Some(summary) = self.summary(fd, &err) {
+        info!("{summary}");
+      }
+    }
+    self.backlogs.remove(&fd);
+    let uuid = match self.connections.remove(&fd) {
+      | Some((uuid, _)) => uuid,
+      | None => {
+        info!("Unknown connection removed: {}", err);
+        return;
+      },
+    };
+    if err.kind() == ErrorKind::UnexpectedEof {
+      info!("{uuid} closed by peer");
+    } else {
+      error!("{uuid} removed: {err}");
+    }
+    self.config.handle.remove(&uuid);
+
+    // The master drops the entry itself when the client asked for the close,
+    // so only a connection still registered here needs the client told.
+    let registered = match self.config.connections.lock() {
+      | Ok(mut connections) => {
+        let count = connections.len();
+        connections.retain(|_, connection| {
+          if connection.fd == fd {
+            connection.release();
+          }
+          connection.fd != fd
+        });
+        connections.len() != count
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        self.warn.warn(
+          "This may result in a hanging connection or a broken pipe"
+            .to_string(),
+        );
+        return;
+      },
+    };
+    if registered {
+      let packet =
+        Server::close_connection_packet(&uuid, &self.config.separator);
+      match self.socket.lock() {
+        | Ok(mut master_socket) => master_socket.send(packet.as_slice()),
+        | Err(err) => error!("Failed while aquiring lock from socket: {err}"),
+      }
+    }
+  }
+}
+
+impl SlaveListener {
+  pub fn new(config: &ServerConfig) -> Self {
+    SlaveListener {
+      connections: HashMap::new(),
+      backlogs: HashMap::new(),
+      config: config.to_owned(),
+      socket: Arc::clone(&config.socket),
+      warn: Warning::new(5),
+    }
+  }
+
+  /// Sends data read from the downstream `fd` on to the client
+  pub fn forward(&mut self, fd: RawFd, buffer: Vec<u8>) {
+    match self.connections.get(&fd) {
+      | Some((id, stats)) => {
+        let (id, stats) = (id.to_owned(), Arc::clone(stats));
         debug!("Received data from {id}");
-        let packet = Server::build_data_packet(
+        let packet = Server::build_data_packet_with(
           &id.to_owned(),
           &self.config.listen.port,
           &self.config.separator,
           &buffer,
+          self.config.hash_encoding,
+          self.config.integrity,
         );
-        match self.socket.lock() {
-          | Ok(master_socket) => {
-            master_socket.send(packet.as_slice());
-          },
-          | Err(err) => {
-            error!("Failed while aquiring lock from socket: {err}");
-            self.warn.warn(
-              "This may result in a hanging connection or a broken pipe"
-                .to_string(),
-            );
-          },
+        log_sent_packet(&packet, |packet| {
+          Client::parse_packet_with(
+            packet,
+            &self.config.separator,
+            &ParseOptions {
+              hash_encoding: self.config.hash_encoding,
+              integrity: self.config.integrity,
+              ..ParseOptions::default()
+            },
+          )
+        });
+        let outgoing = Outgoing {
+          packet,
+          len: buffer.len(),
+          stats,
+        };
+        let sent = match self.window(&id) {
+          | Some(window) => self.send_windowed(fd, id, window, outgoing),
+          | None => outgoing.send(&self.socket),
+        };
+        if !sent {
+          self.warn.warn(
+            "This may result in a hanging connection or a broken pipe"
+              .to_string(),
+          );
         }
       },
       | None => {
-        error!(
-          "Unknown connection: {}",
-          socket.arc_connection.fd
-        );
+        error!("Unknown connection: {fd}");
         self.warn.warn(
           "This may result in a hanging connection or a broken pipe"
             .to_string(),
@@ -131,43 +661,108 @@ impl hydrogen::Handler for SlaveListener {
     }
   }
 
-  fn on_connection_removed(&mut self, fd: RawFd, err: Error) {
-    // Called when a connection has been removed from the watch list, with the
-    // `std::io::Error` as the reason removed.
-    match self.connections.get(&fd) {
-      | Some(uuid) => {
-        info!("{uuid} removed: {err}");
-        self.connections.remove(&fd);
-      },
-      | None => {
-        info!("Unknown connection removed: {}", err);
+  /// Sends `outgoing` as soon as `window` has room for it, after whatever the
+  /// downstream `fd` already has waiting. The downstream keeps being read
+  /// meanwhile, with a full window waited on from a thread of its own so
+  /// this listener's other connections are still served, until more than
+  /// [`WINDOW_BACKLOG_BYTES`] wait and it is closed instead.
+  fn send_windowed(
+    &mut self, fd: RawFd, id: Uuid, window: Arc<Window>, outgoing: Outgoing,
+  ) -> bool {
+    let backlog = Arc::clone(self.backlogs.entry(fd).or_default());
+    let mut queued = lock_backlog(&backlog);
+    if queued.packets.is_empty() && window.try_reserve() {
+      return outgoing.send(&self.socket);
+    }
+    if queued.bytes + outgoing.len > WINDOW_BACKLOG_BYTES {
+      queued.clear();
+      drop(queued);
+      warn!("{id} sent more than the client takes in, closing it");
+      self.close(&id, "window backlog full");
+      return true;
+    }
+    queued.push(outgoing);
+    // Otherwise a thread is already sending what is waiting.
+    let started = queued.packets.len() > 1;
+    drop(queued);
+    if !started {
+      let socket = Arc::clone(&self.socket);
+      let session = self.config.session;
+      thread::spawn(move || {
+        in_session(session, || {
+          drain_backlog(&id, &window, &backlog, &socket)
+        })
+      });
+    }
+    true
+  }
+
+  /// The summary line for the downstream `fd`, removed because of `err`
+  /// unless the server closed it first
+  pub fn summary(&self, fd: RawFd, err: &Error) -> Option<String> {
+    let (id, stats) = self.connections.get(&fd)?;
+    let reason = match err.kind() {
+      | ErrorKind::UnexpectedEof => String::from("closed by peer"),
+      | _ => err.to_string(),
+    };
+    Some(stats.summary(id, &reason))
+  }
+
+  /// Closes the connection `id` on both ends, recording `reason`
+  fn close(&self, id: &Uuid, reason: &str) {
+    let connection = match self.config.connections.lock() {
+      | Ok(mut connections) => connections.remove(id),
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        None
       },
+    };
+    if let Some(connection) = connection {
+      connection.close(&self.config.separator, reason);
     }
+  }
+
+  fn window(&self, id: &Uuid) -> Option<Arc<Window>> {
     match self.config.connections.lock() {
-      | Ok(mut connections) => {
-        connections.retain(|_, v| v.fd != fd);
-      },
+      | Ok(connections) => connections
+        .get(id)
+        .and_then(|connection| connection.window.as_ref().map(Arc::clone)),
       | Err(err) => {
         error!("Failed while aquiring lock from connections: {err}");
-        self.warn.warn(
-          "This may result in a hanging connection or a broken pipe"
-            .to_string(),
-        );
+        None
       },
     }
   }
-}
 
-impl SlaveListener {
+  /// Waits out the port's warmup, returning false if the listener was closed
+  /// in the meantime and should not start at all
+  pub fn warm_up(config: &ServerConfig) -> bool {
+    if let Some(warmup) = config.warmup {
+      info!(
+        "Waiting {}ms before accepting on port {}",
+        warmup.as_millis(),
+        config.listen.port
+      );
+      thread::sleep(warmup);
+    }
+    if config.handle.is_closed() {
+      debug!(
+        "Port {} was closed before it started",
+        config.listen.port
+      );
+      return false;
+    }
+    true
+  }
+
   pub fn begin(config: &ServerConfig) -> () {
+    let _session = enter_session(config.session);
     let config: ServerConfig = config.to_owned();
+    if !SlaveListener::warm_up(&config) {
+      return;
+    }
     hydrogen::begin(
-      Box::new(SlaveListener {
-        connections: HashMap::new(),
-        config: config.to_owned(),
-        socket: Arc::clone(&config.socket),
-        warn: Warning::new(5),
-      }),
+      Box::new(SlaveListener::new(&config)),
       hydrogen::Config {
         addr: config.listen.addr,
         port: config.listen.port,
@@ -1,42 +1,297 @@
 use hydrogen::{HydrogenSocket, Stream as HydrogenStream};
 use proxy_router::{
-  constants::{Runtime, Stream},
-  functions::{PacketType, Server, Warning},
+  constants::{
+    peer_label, random_ids, ConnectionId, IdSource, Runtime, Stream,
+    AT_CAPACITY, DRAIN_POLL_MS, OVER_CAPACITY_GRACE_MS, PENDING_RETRY_MS,
+    PORT_LIMIT_CLOSE_FACTOR, SEPARATOR_MISMATCH,
+  },
+  functions::{
+    constant_time_eq, Ack, ActionHandler, ActionRegistry, Auth, AuthStatus,
+    AuthTryInfo, Client, Close, CustomPacket, Data, Integrity, Noop, Packet,
+    PacketAction, PacketType, ParseError, ParseOptions, Separator, Server,
+    Warning,
+  },
+  logging::{
+    current_session, enter_session, log_packet, packet_label, Direction,
+    SessionId,
+  },
+  websocket::{
+    accept_upgrade, close_frame, pong_frame, rejection, unavailable,
+    BufferBudget, Decoder, Message, Transport,
+  },
 };
-use simplelog::{debug, error, info};
+use simplelog::{debug, error, info, warn, LevelFilter};
 use std::{
   cell::UnsafeCell,
-  collections::HashMap,
-  io::Error,
-  net::TcpStream,
-  os::{
-    fd::FromRawFd,
-    unix::io::{AsRawFd, RawFd},
+  collections::{HashMap, HashSet},
+  io::{self, Error, ErrorKind},
+  net::{IpAddr, TcpStream},
+  os::{fd::FromRawFd, unix::io::RawFd},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
   },
-  sync::{Arc, Mutex},
+  thread,
+  time::Duration,
 };
+use tokio::time::{sleep, sleep_until, Instant};
 use uuid::Uuid;
 
-use crate::slave::SenderPacket;
+use crate::{
+  config::UnknownIdPolicy,
+  limiter::AuthLimiter,
+  slave::{Control, ControlSocket, SenderPacket, WebSocketControl},
+};
 
-use super::slave::{Address, ServerConfig, SlaveListener};
+use super::slave::{
+  set_reuse_port, Address, ServerConfig, SlaveHandle, SlaveListener,
+};
 
 // The following will be our server that handles all reported events
 pub struct MasterListener {
   config: crate::config::Config<Runtime>,
-  was_authed: bool,
   warn: Warning,
+  /// Raised for packets that took longer than `slow_packet_ms` to handle
+  slow: Warning,
   connections: Arc<Mutex<HashMap<Uuid, SenderPacket>>>,
+  slaves: Arc<Mutex<HashMap<RawFd, Vec<SlaveHandle>>>>,
+  listener: SlaveHandle,
+  ids: IdSource,
+  actions: ActionRegistry,
+  limiter: Option<AuthLimiter>,
+  /// Source IPs of the control connections, by fd
+  peers: HashMap<RawFd, IpAddr>,
+  /// Control connections counted against `max_connections`
+  open: HashSet<RawFd>,
+  /// Control connections accepted past `max_connections`, refused as soon as
+  /// they send something, so the refusal goes out in a form they can read.
+  /// Those that stay silent are closed by [`close_silent`] once the deadline
+  /// they are kept with passes.
+  over_capacity: Silent,
+  /// The control connections that have authenticated, by fd
+  authed: Arc<Mutex<HashMap<RawFd, AuthSession>>>,
+  /// The secret AUTH packets are checked against, starting as the configured
+  /// one and replaced through [`ReauthHandle::set_auth`]
+  auth: Arc<Mutex<Vec<u8>>>,
+  /// State of the control connections using the WebSocket transport, by fd
+  websockets: HashMap<RawFd, WebSocketSession>,
+  /// Shared by the decoders in `websockets`, refusing new connections while
+  /// it holds `max_total_buffer_bytes`
+  buffer_budget: Option<BufferBudget>,
+  /// The session each control connection's log lines are prefixed with, by
+  /// fd
+  sessions: HashMap<RawFd, SessionId>,
+}
+
+/// Control connections accepted past `max_connections`, with when they are
+/// closed unless they send something first, by fd
+type Silent = Arc<Mutex<HashMap<RawFd, Instant>>>;
+
+/// A control connection that has authenticated
+struct AuthSession {
+  control: Control,
+  /// Set while the connection owes the AUTH asked for by
+  /// [`ReauthHandle::reauth`], cleared once it arrives in time
+  reauth_pending: Option<Arc<AtomicBool>>,
+}
+
+/// A control connection on the WebSocket transport, before and after its
+/// upgrade
+#[derive(Default)]
+struct WebSocketSession {
+  upgraded: bool,
+  /// The upgrade request read so far
+  request: Vec<u8>,
+  decoder: Decoder,
+}
+
+/// Stops a running [`MasterListener`] from another thread: the control port
+/// stops accepting, and every slave listener is closed along with the
+/// connections it forwarded
+#[derive(Clone)]
+pub struct ShutdownHandle {
+  listener: SlaveHandle,
+  slaves: Arc<Mutex<HashMap<RawFd, Vec<SlaveHandle>>>>,
+  connections: Arc<Mutex<HashMap<Uuid, SenderPacket>>>,
+  separator: Separator,
+}
+
+/// Sends the authenticated control connections back through AUTH from
+/// another thread
+#[derive(Clone)]
+pub struct ReauthHandle {
+  authed: Arc<Mutex<HashMap<RawFd, AuthSession>>>,
+  auth: Arc<Mutex<Vec<u8>>>,
+  separator: Separator,
+  timeout: Duration,
+}
+
+impl ReauthHandle {
+  /// Replaces the secret AUTH packets are checked against, including the ones
+  /// answering [`ReauthHandle::reauth`]
+  pub fn set_auth(&self, auth: Vec<u8>) {
+    match self.auth.lock() {
+      | Ok(mut current) => *current = auth,
+      | Err(err) => error!("Failed while aquiring lock for auth: {err}"),
+    }
+  }
+
+  /// Asks every authenticated control connection to AUTH again, dropping
+  /// those whose AUTH doesn't arrive within `reauth_timeout_secs` or is wrong.
+  /// Their slave listeners and connections are kept, and their packets
+  /// handled, meanwhile. Returns how many were asked.
+  pub fn reauth(&self) -> usize {
+    let mut authed = match self.authed.lock() {
+      | Ok(authed) => authed,
+      | Err(err) => {
+        error!("Failed while aquiring lock for sessions: {err}");
+        return 0;
+      },
+    };
+    let info = AuthTryInfo {
+      status: AuthStatus::Reauth,
+      name: None,
+      version: None,
+      integrity: None,
+      window: None,
+      encoding: None,
+      reason: None,
+    };
+    let packet = Server::build_auth_try_packet(&info, &self.separator);
+    let mut asked = 0;
+    for session in authed.values_mut() {
+      if session.reauth_pending.is_some() {
+        continue;
+      }
+      let pending = Arc::new(AtomicBool::new(true));
+      session.reauth_pending = Some(Arc::clone(&pending));
+      let control = Arc::clone(&session.control);
+      match control.lock() {
+        | Ok(mut socket) => {
+          info!(
+            "Asking connection {} to authenticate again",
+            socket.as_raw_fd()
+          );
+          socket.send(packet.as_slice());
+        },
+        | Err(err) => {
+          error!("Failed to aquire lock for control socket: {err}")
+        },
+      }
+      asked += 1;
+
+      let timeout = self.timeout;
+      thread::spawn(move || {
+        thread::sleep(timeout);
+        if !pending.load(Ordering::SeqCst) {
+          return;
+        }
+        match control.lock() {
+          | Ok(mut socket) => {
+            warn!(
+              "Connection {} did not authenticate again in time, closing it",
+              socket.as_raw_fd()
+            );
+            if let Err(err) = socket.shutdown() {
+              error!("Error shutting down connection: {err}");
+            }
+          },
+          | Err(err) => {
+            error!("Failed to aquire lock for control socket: {err}")
+          },
+        }
+      });
+    }
+    asked
+  }
+}
+
+impl ShutdownHandle {
+  #[cfg(test)]
+  pub fn is_closed(&self) -> bool {
+    self.listener.is_closed()
+  }
+
+  /// Stops the control port and every slave listener from accepting, leaving
+  /// the connections already forwarded open
+  pub fn stop_accepting(&self) {
+    info!("No longer accepting connections");
+    self.listener.stop_accepting();
+    match self.slaves.lock() {
+      | Ok(slaves) => {
+        for handle in slaves.values().flatten() {
+          handle.stop_accepting();
+        }
+      },
+      | Err(err) => error!("Failed while aquiring lock for slaves: {err}"),
+    }
+  }
+
+  /// How many forwarded connections are still open
+  pub fn open_connections(&self) -> usize {
+    match self.connections.lock() {
+      | Ok(connections) => connections.len(),
+      | Err(err) => {
+        error!("Failed while aquiring lock from connections: {err}");
+        0
+      },
+    }
+  }
+
+  /// Stops accepting, then gives the forwarded connections up to `timeout` to
+  /// close on their own before shutting down
+  pub fn drain(&self, timeout: Duration) {
+    self.stop_accepting();
+    let deadline = std::time::Instant::now() + timeout;
+    let mut open = self.open_connections();
+    info!(
+      "Draining {open} connection(s) for up to {}s",
+      timeout.as_secs()
+    );
+    while open > 0 && std::time::Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(DRAIN_POLL_MS));
+      open = self.open_connections();
+    }
+    if open > 0 {
+      warn!("Drain timed out with {open} connection(s) still open");
+    }
+    self.shutdown();
+  }
+
+  pub fn shutdown(&self) {
+    info!("Shutting down, no longer accepting connections");
+    self.listener.close(&self.connections, &self.separator);
+    let handles = match self.slaves.lock() {
+      | Ok(mut slaves) => {
+        slaves.drain().flat_map(|(_, handles)| handles).collect::<Vec<_>>()
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock for slaves: {err}");
+        return;
+      },
+    };
+    info!(
+      "Closing {} slave listener(s)",
+      handles.len()
+    );
+    for handle in handles {
+      handle.close(&self.connections, &self.separator);
+    }
+  }
 }
 
 impl hydrogen::Handler for MasterListener {
-  fn on_server_created(&mut self, _: RawFd) {
+  fn on_server_created(&mut self, fd: RawFd) {
     // Do any secific flag/option setting on the underlying listening fd.
     // This will be the fd that accepts all incoming connections.
+    self.listener.set_listener(fd);
+    if self.config.reuse_port {
+      set_reuse_port(fd);
+    }
     info!("Server created");
     info!(
       "Listening on: {}:{}",
-      self.config.listen.host, self.config.listen.port
+      self.config.control_listen.host, self.config.control_listen.port
     );
     info!("Max threads: {}", self.config.threads);
     info!(
@@ -49,182 +304,1178 @@ impl hydrogen::Handler for MasterListener {
   fn on_new_connection(
     &mut self, fd: RawFd,
   ) -> Arc<UnsafeCell<dyn HydrogenStream>> {
+    let session = SessionId::new();
+    self.sessions.insert(fd, session);
+    let _session = enter_session(Some(session));
     // With the passed fd, create your type that implements `hydrogen::Stream`
     // and return it.
 
     // For example:
     let tcp_stream = unsafe { TcpStream::from_raw_fd(fd) };
-    let stream = Stream::from_tcp_stream(tcp_stream);
-    info!("New connection: {fd}");
+    let mut stream = Stream::from_tcp_stream(tcp_stream);
+    if self.listener.is_closed() {
+      info!("Refusing connection {fd}, shutting down");
+      match stream.shutdown() {
+        | Ok(_) => (),
+        | Err(err) => error!("Error shutting down connection: {err}"),
+      }
+      return Arc::new(UnsafeCell::new(stream));
+    }
+    if let Some(peer) = stream.peer {
+      let blocked = match &mut self.limiter {
+        | Some(limiter) => limiter.is_blocked(&peer.ip(), Instant::now()),
+        | None => false,
+      };
+      if blocked {
+        info!("Refusing connection {fd} from {peer}, too many failed auths");
+        match stream.shutdown() {
+          | Ok(_) => (),
+          | Err(err) => error!("Error shutting down connection: {err}"),
+        }
+        return Arc::new(UnsafeCell::new(stream));
+      }
+      self.peers.insert(fd, peer.ip());
+    }
+    if let Some(max) = self.config.max_connections {
+      if self.open.len() >= max {
+        info!("Refusing connection {fd}, already at {max} connection(s)");
+        let deadline =
+          Instant::now() + Duration::from_millis(OVER_CAPACITY_GRACE_MS);
+        match self.over_capacity.lock() {
+          | Ok(mut over_capacity) => {
+            over_capacity.insert(fd, deadline);
+          },
+          | Err(err) => {
+            error!("Failed while aquiring lock for over capacity: {err}")
+          },
+        }
+        return Arc::new(UnsafeCell::new(stream));
+      }
+    }
+    if self.buffer_budget.as_ref().is_some_and(BufferBudget::is_spent) {
+      info!("Refusing connection {fd}, WebSocket buffers are full");
+      if let Err(err) = stream.send(&unavailable()) {
+        debug!("Failed to refuse connection {fd}: {err}");
+      }
+      match stream.shutdown() {
+        | Ok(_) => (),
+        | Err(err) => error!("Error shutting down connection: {err}"),
+      }
+      return Arc::new(UnsafeCell::new(stream));
+    }
+    self.open.insert(fd);
+    info!(
+      "New connection: {fd} from {}",
+      stream.peer_label()
+    );
     Arc::new(UnsafeCell::new(stream))
   }
 
-  fn on_data_received(&mut self, mut socket: HydrogenSocket, buffer: Vec<u8>) {
+  fn on_data_received(&mut self, socket: HydrogenSocket, buffer: Vec<u8>) {
     // Called when a complete, consumer defined, chunk of data has been read.
-    if !self.was_authed {
-      let packet = Server::parse_packet(
+    let session = self.sessions.get(&socket.arc_connection.fd).copied();
+    let _session = enter_session(session);
+    self.receive(socket, buffer);
+  }
+
+  fn on_connection_removed(&mut self, fd: RawFd, err: Error) {
+    let session = self.sessions.remove(&fd);
+    let _session = enter_session(session);
+    // Called when a connection has been removed from the watch list, with the
+    // `std::io::Error` as the reason removed.
+    debug!("{fd} removed: {err}");
+    self.peers.remove(&fd);
+    self.open.remove(&fd);
+    match self.over_capacity.lock() {
+      | Ok(mut over_capacity) => {
+        over_capacity.remove(&fd);
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock for over capacity: {err}")
+      },
+    }
+    self.websockets.remove(&fd);
+    self.forget_session(fd);
+    self.close_slaves(fd);
+  }
+}
+
+/// Trims `ports` to at most `max` of them, or returns None when far more
+/// were asked for than any honest client would
+pub fn limit_ports(ports: Vec<u16>, max: Option<usize>) -> Option<Vec<u16>> {
+  let max = match max {
+    | Some(max) => max,
+    | None => return Some(ports),
+  };
+  if ports.len() > max.saturating_mul(PORT_LIMIT_CLOSE_FACTOR) {
+    return None;
+  }
+  if ports.len() > max {
+    warn!(
+      "Rejected {} ports from auth request, over the limit of {max}",
+      ports.len() - max
+    );
+  }
+  Some(ports.into_iter().take(max).collect())
+}
+
+/// Closes every connection once it has been open for `lifetime`, whether it
+/// is still active or not. Sleeps until the oldest remaining connection is due
+/// instead of polling.
+pub async fn reap_expired(
+  connections: Arc<Mutex<HashMap<Uuid, SenderPacket>>>, lifetime: Duration,
+  separator: Separator,
+) {
+  loop {
+    let now = Instant::now();
+    let mut next = now + lifetime;
+    let expired = match connections.lock() {
+      | Ok(mut connections) => {
+        let ids = connections
+          .iter()
+          .filter(|(_, connection)| connection.accepted + lifetime <= now)
+          .map(|(id, _)| id.to_owned())
+          .collect::<Vec<Uuid>>();
+        for connection in connections.values() {
+          let deadline = connection.accepted + lifetime;
+          if deadline > now && deadline < next {
+            next = deadline;
+          }
+        }
+        ids
+          .iter()
+          .filter_map(|id| connections.remove(id))
+          .collect::<Vec<SenderPacket>>()
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock for connections: {err}");
+        Vec::new()
+      },
+    };
+    for connection in expired {
+      info!(
+        "Connection {} ({}) reached its max lifetime, closing",
+        connection.uuid,
+        peer_label(&connection.peer)
+      );
+      connection.close(&separator, "max lifetime reached");
+    }
+    sleep_until(next).await;
+  }
+}
+
+/// Shuts down the control connections in `silent` once their deadline
+/// passes, sleeping until the next one is due. Entries are added with a
+/// deadline [`OVER_CAPACITY_GRACE_MS`] out, never earlier than the wake-up
+/// already planned.
+pub async fn close_silent(silent: Silent) {
+  let grace = Duration::from_millis(OVER_CAPACITY_GRACE_MS);
+  loop {
+    let now = Instant::now();
+    let mut next = now + grace;
+    match silent.lock() {
+      // Shut down with the lock held, so the fd can't be removed and handed
+      // to another connection meanwhile.
+      | Ok(mut silent) => silent.retain(|fd, deadline| {
+        if *deadline > now {
+          next = next.min(*deadline);
+          return true;
+        }
+        if unsafe { libc::shutdown(*fd, libc::SHUT_RDWR) } == 0 {
+          debug!("Closed connection {fd}, it never sent anything");
+        }
+        false
+      }),
+      | Err(err) => {
+        error!("Failed while aquiring lock for over capacity: {err}")
+      },
+    }
+    sleep_until(next).await;
+  }
+}
+
+/// Whether `buffer` holds a DATA packet, going by its action
+fn is_data(buffer: &[u8]) -> bool {
+  let action = PacketAction::DATA.value();
+  buffer.len() > action.len()
+    && buffer[..action.len()].eq_ignore_ascii_case(action.as_bytes())
+    && buffer[action.len()] == b' '
+}
+
+/// Pushes out what downstream writes left waiting, which nothing else does
+/// for a connection that gets no more data, and closes the connections whose
+/// peer stopped reading for longer than the write timeout
+pub fn flush_pending(
+  connections: &Mutex<HashMap<Uuid, SenderPacket>>, separator: &Separator,
+) {
+  let stuck = match connections.lock() {
+    | Ok(mut connections) => {
+      let ids = connections
+        .iter()
+        .filter(
+          |(_, connection)| match connection.socket.lock() {
+            | Ok(mut socket) => matches!(
+              socket.send(&[]),
+              Err(err) if err.kind() == ErrorKind::TimedOut
+            ),
+            | Err(_) => false,
+          },
+        )
+        .map(|(id, _)| id.to_owned())
+        .collect::<Vec<Uuid>>();
+      ids
+        .iter()
+        .filter_map(|id| connections.remove(id))
+        .collect::<Vec<SenderPacket>>()
+    },
+    | Err(err) => {
+      error!("Failed while aquiring lock for connections: {err}");
+      Vec::new()
+    },
+  };
+  for connection in stuck {
+    warn!(
+      "Write to {} timed out, closing it",
+      connection.uuid
+    );
+    connection.close(separator, "write timed out");
+  }
+}
+
+/// Runs [`flush_pending`] every [`PENDING_RETRY_MS`]
+pub async fn retry_pending(
+  connections: Arc<Mutex<HashMap<Uuid, SenderPacket>>>, separator: Separator,
+) {
+  loop {
+    flush_pending(&connections, &separator);
+    sleep(Duration::from_millis(PENDING_RETRY_MS)).await;
+  }
+}
+
+impl MasterListener {
+  pub fn new(config: &crate::config::Config<Runtime>) -> Self {
+    MasterListener {
+      config: config.to_owned(),
+      warn: Warning::new(5),
+      slow: Warning::new(5),
+      connections: Arc::new(Mutex::new(HashMap::with_capacity(
+        config.concurrency,
+      ))),
+      slaves: Arc::new(Mutex::new(HashMap::new())),
+      listener: SlaveHandle::new(),
+      ids: random_ids(),
+      actions: ActionRegistry::new(),
+      limiter: config.max_auth_failures.map(|max_failures| {
+        AuthLimiter::new(
+          max_failures,
+          Duration::from_secs(config.auth_failure_window_secs),
+          Duration::from_secs(config.auth_block_secs),
+        )
+      }),
+      peers: HashMap::new(),
+      open: HashSet::new(),
+      over_capacity: Arc::new(Mutex::new(HashMap::new())),
+      authed: Arc::new(Mutex::new(HashMap::new())),
+      auth: Arc::new(Mutex::new(config.auth.to_owned())),
+      websockets: HashMap::new(),
+      buffer_budget: config.max_total_buffer_bytes.map(BufferBudget::new),
+      sessions: HashMap::new(),
+    }
+  }
+
+  #[cfg(test)]
+  pub fn with_ids(
+    config: &crate::config::Config<Runtime>, ids: IdSource,
+  ) -> Self {
+    MasterListener {
+      ids,
+      ..MasterListener::new(config)
+    }
+  }
+
+  /// The host a forwarded `port` is bound on
+  pub fn forward_host(&self, port: u16) -> String {
+    self
+      .config
+      .forward_bind_ports
+      .get(&port)
+      .or(self.config.forward_bind.as_ref())
+      .unwrap_or(&self.config.control_listen.host)
+      .to_owned()
+  }
+
+  /// Builds the config for a slave listener on `port` that forwards to
+  /// `control`
+  pub fn slave_config(&self, port: u16, control: &Control) -> ServerConfig {
+    ServerConfig {
+      separator: self.config.separator.clone(),
+      listen: Address {
+        port,
+        addr: self.forward_host(port),
+      },
+      threads: self.config.threads,
+      concurrency: self.config.concurrency,
+      socket: Arc::clone(control),
+      connections: Arc::clone(&self.connections),
+      handle: SlaveHandle::new(),
+      ids: Arc::clone(&self.ids),
+      hash_encoding: self.config.hash_encoding,
+      integrity: self.config.integrity,
+      upstream: None,
+      reuse_port: self.config.reuse_port,
+      write_timeout: self.config.write_timeout_ms.map(Duration::from_millis),
+      warmup: self
+        .config
+        .warmup_ms
+        .get(&port)
+        .map(|warmup| Duration::from_millis(*warmup)),
+      window: self.config.data_window,
+      access_log: self.config.access_log,
+      session: current_session(),
+    }
+  }
+
+  /// Routes packets with the custom `action` from authenticated clients to
+  /// `handler`, returning false for built-in or malformed actions
+  #[allow(dead_code)]
+  pub fn register_action(
+    &mut self, action: &str, handler: ActionHandler,
+  ) -> bool {
+    self.actions.register(action, handler)
+  }
+
+  /// Shuts down the downstream connection `id` and tells the client it is
+  /// gone, returning whether such a connection existed
+  #[allow(dead_code)]
+  pub fn close_connection(&self, id: ConnectionId) -> bool {
+    let connection = match self.connections.lock() {
+      | Ok(mut connections) => connections.remove(&id),
+      | Err(err) => {
+        error!("Failed while aquiring lock for connections: {err}");
+        return false;
+      },
+    };
+    let connection = match connection {
+      | Some(connection) => connection,
+      | None => return false,
+    };
+
+    connection.close(&self.config.separator, "kicked");
+    info!(
+      "Kicked connection: {id} ({})",
+      peer_label(&connection.peer)
+    );
+    true
+  }
+
+  /// Whether the control connection `fd` was accepted past `max_connections`
+  fn is_over_capacity(&self, fd: RawFd) -> bool {
+    match self.over_capacity.lock() {
+      | Ok(over_capacity) => over_capacity.contains_key(&fd),
+      | Err(err) => {
+        error!("Failed while aquiring lock for over capacity: {err}");
+        false
+      },
+    }
+  }
+
+  #[cfg(test)]
+  pub fn over_capacity(&self) -> Silent {
+    Arc::clone(&self.over_capacity)
+  }
+
+  /// Whether the control connection `fd` has authenticated, even if it owes
+  /// a reauth
+  fn is_authed(&self, fd: RawFd) -> bool {
+    match self.authed.lock() {
+      | Ok(authed) => authed.contains_key(&fd),
+      | Err(err) => {
+        error!("Failed while aquiring lock for sessions: {err}");
+        false
+      },
+    }
+  }
+
+  /// Whether the control connection `fd` owes the AUTH asked for by
+  /// [`ReauthHandle::reauth`]
+  fn owes_reauth(&self, fd: RawFd) -> bool {
+    match self.authed.lock() {
+      | Ok(authed) => {
+        authed.get(&fd).is_some_and(|session| session.reauth_pending.is_some())
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock for sessions: {err}");
+        false
+      },
+    }
+  }
+
+  /// Handles a chunk read from the control connection `socket`, unwrapping it
+  /// from WebSocket messages first when that is the configured transport
+  pub fn receive<S: ControlSocket + Clone + 'static>(
+    &mut self, mut socket: S, buffer: Vec<u8>,
+  ) {
+    let path = match &self.config.transport {
+      | Transport::Tcp => {
+        return self.dispatch(Arc::new(Mutex::new(socket)), buffer)
+      },
+      | Transport::WebSocket {
+        path,
+      } => path.to_owned(),
+    };
+    let fd = socket.as_raw_fd();
+    let budget = &self.buffer_budget;
+    let session =
+      self.websockets.entry(fd).or_insert_with(|| WebSocketSession {
+        decoder: match budget {
+          | Some(budget) => Decoder::with_budget(budget.clone()),
+          | None => Decoder::new(),
+        },
+        ..WebSocketSession::default()
+      });
+    let mut bytes = buffer;
+    if !session.upgraded {
+      session.request.extend(bytes);
+      let upgrade = match accept_upgrade(&session.request, &path) {
+        | Ok(None) if session.request.len() > self.config.max_header_bytes => {
+          Err(String::from("Upgrade request too long"))
+        },
+        | Ok(None) => return,
+        | Ok(Some(upgrade)) => Ok(upgrade),
+        | Err(err) => Err(err.value()),
+      };
+      match upgrade {
+        | Ok((response, consumed)) => {
+          socket.send(&response);
+          session.upgraded = true;
+          bytes = session.request.split_off(consumed);
+          session.request = Vec::new();
+          debug!("Connection {fd} upgraded to WebSocket");
+        },
+        | Err(err) => {
+          error!("{err} from connection {fd}. Closing connection.");
+          socket.send(&rejection());
+          if let Err(err) = socket.shutdown() {
+            error!("Error shutting down connection: {err}");
+          }
+          self.websockets.remove(&fd);
+          return;
+        },
+      }
+    }
+    let messages = match session.decoder.push(&bytes) {
+      | Ok(messages) => messages,
+      | Err(err) => {
+        error!("{err} from connection {fd}. Closing connection.");
+        socket.send(&close_frame());
+        if let Err(err) = socket.shutdown() {
+          error!("Error shutting down connection: {err}");
+        }
+        // Gives what its decoder held back to the budget right away
+        self.websockets.remove(&fd);
+        return;
+      },
+    };
+    for message in messages {
+      match message {
+        | Message::Binary(packet) => self.dispatch(
+          Arc::new(Mutex::new(WebSocketControl(
+            socket.clone(),
+          ))),
+          packet,
+        ),
+        | Message::Ping(payload) => socket.send(&pong_frame(&payload)),
+        | Message::Close => {
+          debug!("Connection {fd} closed its WebSocket");
+          socket.send(&close_frame());
+          if let Err(err) = socket.shutdown() {
+            error!("Error shutting down connection: {err}");
+          }
+          return;
+        },
+      }
+    }
+  }
+
+  /// Handles a chunk read from a control connection, parsing it and passing
+  /// it to the matching [`PacketHandler`] method. Takes the connection as a
+  /// [`Control`] rather than a `HydrogenSocket` so it can be driven without a
+  /// real socket.
+  pub fn dispatch(&mut self, control: Control, buffer: Vec<u8>) {
+    let mut socket = match control.lock() {
+      | Ok(socket) => socket,
+      | Err(err) => {
+        error!("Failed to aquire lock for control socket: {err}");
+        return;
+      },
+    };
+    if self.is_over_capacity(socket.as_raw_fd()) {
+      let info = AuthTryInfo {
+        status: AuthStatus::Forbidden,
+        name: None,
+        version: None,
+        integrity: None,
+        window: None,
+        encoding: None,
+        reason: Some(AT_CAPACITY.to_string()),
+      };
+      let separator = Server::announced_separator(&buffer)
+        .unwrap_or_else(|| self.config.separator.to_owned());
+      socket.send(Server::build_auth_try_packet(&info, &separator).as_slice());
+      match socket.shutdown() {
+        | Ok(_) => info!("Shutdown connection"),
+        | Err(err) => error!("Error shutting down connection: {err}"),
+      }
+      return;
+    }
+    let authed = self.is_authed(socket.as_raw_fd());
+    if !authed {
+      if let Some(theirs) = Server::announced_separator(&buffer) {
+        if theirs != self.config.separator {
+          error!(
+            "Connection {} uses separator {theirs:?}, not ours. Closing connection.",
+            socket.as_raw_fd()
+          );
+          let info = AuthTryInfo {
+            status: AuthStatus::Forbidden,
+            name: None,
+            version: None,
+            integrity: None,
+            window: None,
+            encoding: None,
+            reason: Some(SEPARATOR_MISMATCH.to_string()),
+          };
+          // Written with the client's separator, the only one it can read
+          socket.send(Server::build_auth_try_packet(&info, &theirs).as_slice());
+          match socket.shutdown() {
+            | Ok(_) => info!("Shutdown connection"),
+            | Err(err) => error!("Error shutting down connection: {err}"),
+          }
+          return;
+        }
+      }
+    }
+    let started = Instant::now();
+    // A DATA body is checked and passed on where it was read, rather than
+    // copied out of the buffer first.
+    let streamed = authed && is_data(&buffer);
+    let (packet, body) = if streamed {
+      match self.parse_data(&buffer) {
+        | Ok((packet, body)) => (Ok(PacketType::Data(packet)), body),
+        | Err(err) => (Err(err), &[][..]),
+      }
+    } else {
+      let packet = Server::parse_packet_with(
         buffer,
-        &self.config.separator.as_bytes().to_vec(),
+        &self.config.separator,
+        &self.parse_options(),
       );
+      (packet, &[][..])
+    };
+    let packet = match packet {
+      | Ok(packet) => packet,
+      | Err(err) => {
+        error!("Error parsing packet: {}", err.value());
+        self.warn.warn(
+          "This may result in a hanging connection or a broken pipe"
+            .to_string(),
+        );
+        return;
+      },
+    };
+    log_packet(Direction::Received, &packet);
+    let socket = &mut *socket;
+    let label = self.config.slow_packet_ms.map(|_| packet_label(&packet));
+    if !authed {
       match packet {
-        | Ok(packet) => {
-          match packet {
-            | PacketType::Auth(packet) => {
-              if self.config.auth.as_bytes().to_vec() == packet.body {
-                self.was_authed = true;
-                info!(
-                  "Authenticated connection: {}",
-                  socket.as_raw_fd()
-                );
-                for port in packet.ports {
-                  SlaveListener::begin(&ServerConfig {
-                    separator: self.config.separator.clone(),
-                    listen: Address {
-                      port,
-                      addr: self.config.listen.host.clone(),
-                    },
-                    threads: self.config.threads,
-                    concurrency: self.config.concurrency,
-                    socket: Arc::new(Mutex::new(socket.clone())),
-                    connections: Arc::clone(&self.connections),
-                  });
-                }
-              }
-            },
-            | _ => {
-              error!("Expected a auth packet, got something else. Closing connection.");
-              match socket.shutdown() {
-                | Ok(_) => info!("Shutdown connection"),
-                | Err(err) => error!("Error shutting down connection: {err}"),
-              }
-            },
+        | PacketType::Auth(packet) => {
+          self.handle_auth(&control, socket, packet)
+        },
+        | _ => {
+          error!(
+            "Expected a auth packet, got something else. Closing connection."
+          );
+          match socket.shutdown() {
+            | Ok(_) => info!("Shutdown connection"),
+            | Err(err) => error!("Error shutting down connection: {err}"),
           }
         },
+      }
+      if let Some(label) = label {
+        self.check_slow(started, label);
+      }
+      return;
+    }
+    match packet {
+      | PacketType::Data(mut packet) => {
+        let parsed = std::mem::take(&mut packet.body);
+        let body = if streamed {
+          body
+        } else {
+          &parsed
+        };
+        self.handle_data(socket, packet, body)
+      },
+      | PacketType::Close(packet) => self.handle_close(socket, packet),
+      | PacketType::Ack(packet) => self.handle_ack(packet),
+      | PacketType::Noop(packet) => self.handle_noop(packet),
+      | PacketType::Custom(packet) => self.handle_custom(packet),
+      // Traffic keeps flowing while a reauth is owed, only the AUTH itself is
+      // checked.
+      | PacketType::Auth(packet) if self.owes_reauth(socket.as_raw_fd()) => {
+        self.handle_auth(&control, socket, packet)
+      },
+      | _ => {
+        error!(
+          "Expected a data packet, got something else. Closing connection. (fd: {})",
+          socket.as_raw_fd()
+        );
+        match socket.shutdown() {
+          | Ok(_) => info!("Shutdown connection"),
+          | Err(err) => error!("Error shutting down connection: {err}"),
+        }
+      },
+    }
+    if let Some(label) = label {
+      self.check_slow(started, label);
+    }
+  }
+
+  /// Warns when handling the packet labelled `label`, which began at
+  /// `started`, took longer than `slow_packet_ms`
+  fn check_slow(&mut self, started: Instant, label: String) {
+    let threshold = match self.config.slow_packet_ms {
+      | Some(threshold) => Duration::from_millis(threshold),
+      | None => return,
+    };
+    let elapsed = started.elapsed();
+    if elapsed > threshold {
+      self.slow.warn(format!(
+        "Handling {label} took {}ms, longer than {}ms",
+        elapsed.as_millis(),
+        threshold.as_millis()
+      ));
+    }
+  }
+
+  #[cfg(test)]
+  pub fn slow_packets(&self) -> u8 {
+    self.slow.warns()
+  }
+
+  /// Feeds the outcome of an AUTH attempt on `fd` to the limiter, if any
+  fn record_auth(&mut self, fd: RawFd, authed: bool) {
+    let (limiter, ip) = match (&mut self.limiter, self.peers.get(&fd)) {
+      | (Some(limiter), Some(ip)) => (limiter, ip.to_owned()),
+      | _ => return,
+    };
+    if authed {
+      limiter.record_success(&ip);
+    } else {
+      limiter.record_failure(ip, Instant::now());
+    }
+  }
+
+  #[cfg(test)]
+  pub fn connections_capacity(&self) -> usize {
+    self.connections.lock().unwrap().capacity()
+  }
+
+  /// Ids of the connections currently being forwarded
+  #[cfg(test)]
+  pub fn connection_ids(&self) -> Vec<ConnectionId> {
+    self.connections.lock().unwrap().keys().copied().collect()
+  }
+
+  #[cfg(test)]
+  pub fn connections(&self) -> Arc<Mutex<HashMap<Uuid, SenderPacket>>> {
+    Arc::clone(&self.connections)
+  }
+
+  #[cfg(test)]
+  pub fn insert_connection(&self, connection: SenderPacket) {
+    self.connections.lock().unwrap().insert(connection.uuid, connection);
+  }
+
+  /// Parses the header of the DATA packet in `buffer` and checks its body
+  /// against it in place, returning the packet without its body and the body
+  /// as a slice of `buffer`
+  fn parse_data<'a>(
+    &self, buffer: &'a [u8],
+  ) -> Result<(Packet<Client, Data>, &'a [u8]), ParseError> {
+    let (mut packet, mut reader) = Server::read_data_packet(
+      buffer,
+      &self.config.separator,
+      &self.parse_options(),
+    )?;
+    // Reading a slice can't fail, and a short read fails the check anyway.
+    let len = io::copy(&mut reader, &mut io::sink()).unwrap_or_default();
+    reader.verify()?;
+    let body = &buffer[buffer.len() - len as usize..];
+    // Only copied out for a trace, which shows the start of the body
+    if log::max_level() >= LevelFilter::Trace {
+      packet.body = body.to_vec();
+    }
+    Ok((packet, body))
+  }
+
+  fn parse_options(&self) -> ParseOptions {
+    ParseOptions {
+      max_header_bytes: self.config.max_header_bytes,
+      hash_encoding: self.config.hash_encoding,
+      integrity: self.config.integrity,
+      ..ParseOptions::default()
+    }
+  }
+
+  /// Tears down every slave listener spawned by the control connection `fd`,
+  /// so their ports stop accepting connections that could go nowhere
+  fn close_slaves(&mut self, fd: RawFd) {
+    let handles = match self.slaves.lock() {
+      | Ok(mut slaves) => slaves.remove(&fd),
+      | Err(err) => {
+        error!("Failed while aquiring lock for slaves: {err}");
+        None
+      },
+    };
+    if let Some(handles) = handles {
+      info!(
+        "Control connection {fd} dropped, closing {} slave listener(s)",
+        handles.len()
+      );
+      for handle in handles {
+        handle.close(
+          &self.connections, &self.config.separator,
+        );
+      }
+    }
+  }
+
+  /// Forgets the auth state of the control connection `fd`, calling off any
+  /// reauth it still owes
+  fn forget_session(&mut self, fd: RawFd) {
+    let session = match self.authed.lock() {
+      | Ok(mut authed) => authed.remove(&fd),
+      | Err(err) => {
+        error!("Failed while aquiring lock for sessions: {err}");
+        None
+      },
+    };
+    if let Some(pending) = session.and_then(|session| session.reauth_pending) {
+      pending.store(false, Ordering::SeqCst);
+    }
+  }
+
+  pub fn reauth_handle(&self) -> ReauthHandle {
+    ReauthHandle {
+      authed: Arc::clone(&self.authed),
+      auth: Arc::clone(&self.auth),
+      separator: self.config.separator.to_owned(),
+      timeout: Duration::from_secs(self.config.reauth_timeout_secs),
+    }
+  }
+
+  pub fn shutdown_handle(&self) -> ShutdownHandle {
+    ShutdownHandle {
+      listener: self.listener.clone(),
+      slaves: Arc::clone(&self.slaves),
+      connections: Arc::clone(&self.connections),
+      separator: self.config.separator.to_owned(),
+    }
+  }
+
+  pub fn start(self) {
+    let config = self.config.to_owned();
+    let master = self;
+    if let Some(secs) = config.max_connection_lifetime_secs {
+      info!("Max connection lifetime: {secs}s");
+      tokio::spawn(reap_expired(
+        Arc::clone(&master.connections),
+        Duration::from_secs(secs),
+        config.separator.to_owned(),
+      ));
+    }
+    if config.max_connections.is_some() {
+      tokio::spawn(close_silent(Arc::clone(
+        &master.over_capacity,
+      )));
+    }
+    tokio::spawn(retry_pending(
+      Arc::clone(&master.connections),
+      config.separator.to_owned(),
+    ));
+    hydrogen::begin(
+      Box::new(master),
+      hydrogen::Config {
+        addr: config.control_listen.host,
+        port: config.control_listen.port,
+        max_threads: config.threads,
+        pre_allocated: config.concurrency,
+      },
+    );
+  }
+}
+
+/// What the server does with each kind of packet a client sends, apart from
+/// parsing it, so each can be driven on its own. [`MasterListener::dispatch`]
+/// parses packets and hands them to the matching method.
+pub trait PacketHandler {
+  /// Checks the secret in an AUTH packet, starting the slave listeners for
+  /// the ports it asks for when it matches and closing `socket` when not
+  fn handle_auth(
+    &mut self, control: &Control, socket: &mut dyn ControlSocket,
+    packet: Packet<Client, Auth>,
+  );
+
+  /// Writes `body`, the body of a DATA packet, to the connection it names.
+  /// The body is passed apart from the packet so it can be left in the
+  /// buffer it was read into.
+  fn handle_data(
+    &mut self, socket: &mut dyn ControlSocket, packet: Packet<Client, Data>,
+    body: &[u8],
+  );
+
+  /// Closes the connection a CLOSE packet names
+  fn handle_close(
+    &mut self, socket: &mut dyn ControlSocket, packet: Packet<Client, Close>,
+  );
+
+  /// Moves the data window of the connection an ACK packet names
+  fn handle_ack(&mut self, packet: Packet<Client, Ack>);
+
+  /// NOOP packets only keep the connection alive
+  fn handle_noop(&mut self, _packet: Packet<Client, Noop>) {}
+
+  /// Passes a packet with an action of its own to the registered handler
+  fn handle_custom(&mut self, packet: CustomPacket);
+}
+
+impl PacketHandler for MasterListener {
+  fn handle_auth(
+    &mut self, control: &Control, socket: &mut dyn ControlSocket,
+    packet: Packet<Client, Auth>,
+  ) {
+    // An empty secret only matches when the server opted into one.
+    let authed = (!packet.body.is_empty() || self.config.allow_empty_auth)
+      && match self.auth.lock() {
+        | Ok(auth) => constant_time_eq(&auth, &packet.body),
         | Err(err) => {
-          error!("Error parsing packet: {}", err.value());
-          self.warn.warn(
-            "This may result in a hanging connection or a broken pipe"
-              .to_string(),
+          error!("Failed while aquiring lock for auth: {err}");
+          false
+        },
+      };
+    self.record_auth(socket.as_raw_fd(), authed);
+    if authed {
+      let requested = packet.ports.len();
+      let mut upstreams = HashMap::new();
+      for forward in packet.ports.forwards() {
+        if let Some(upstream) = &forward.upstream {
+          upstreams.entry(forward.port).or_insert_with(|| upstream.to_owned());
+        }
+      }
+      let ports = match limit_ports(
+        packet.ports.ports(),
+        self.config.max_ports_per_session,
+      ) {
+        | Some(ports) => ports,
+        | None => {
+          error!(
+            "Connection {} asked for {requested} ports. Closing connection.",
+            socket.as_raw_fd()
+          );
+          let info = AuthTryInfo {
+            status: AuthStatus::Forbidden,
+            name: None,
+            version: None,
+            integrity: None,
+            window: None,
+            encoding: None,
+            reason: None,
+          };
+          socket.send(
+            Server::build_auth_try_packet(&info, &self.config.separator)
+              .as_slice(),
           );
+          match socket.shutdown() {
+            | Ok(_) => info!("Shutdown connection"),
+            | Err(err) => {
+              error!("Error shutting down connection: {err}")
+            },
+          }
+          return;
+        },
+      };
+      let previous = match self.authed.lock() {
+        | Ok(mut authed) => authed.insert(
+          socket.as_raw_fd(),
+          AuthSession {
+            control: Arc::clone(control),
+            reauth_pending: None,
+          },
+        ),
+        | Err(err) => {
+          error!("Failed while aquiring lock for sessions: {err}");
+          None
+        },
+      };
+      if let Some(pending) = previous.and_then(|session| session.reauth_pending)
+      {
+        pending.store(false, Ordering::SeqCst);
+      }
+      info!(
+        "Authenticated connection: {}",
+        socket.as_raw_fd()
+      );
+      // Clients assume dual hashes when nothing is announced, so
+      // that is left out for clients that predate the field
+      let integrity = match self.config.integrity {
+        | Integrity::Dual => None,
+        | integrity => Some(integrity),
+      };
+      // Always announced, so the client follows it whatever it was
+      // set up with
+      let encoding = Some(self.config.hash_encoding);
+      let info = match &self.config.server_name {
+        | Some(name) => AuthTryInfo {
+          status: AuthStatus::Success,
+          name: Some(name.to_owned()),
+          version: Some(env!("CARGO_PKG_VERSION").to_string()),
+          integrity,
+          window: self.config.data_window,
+          encoding,
+          reason: None,
+        },
+        | None => AuthTryInfo {
+          status: AuthStatus::Success,
+          name: None,
+          version: None,
+          integrity,
+          window: self.config.data_window,
+          encoding,
+          reason: None,
+        },
+      };
+      socket.send(
+        Server::build_auth_try_packet(&info, &self.config.separator).as_slice(),
+      );
+      let running = match self.slaves.lock() {
+        | Ok(slaves) => slaves.contains_key(&socket.as_raw_fd()),
+        | Err(err) => {
+          error!("Failed while aquiring lock for slaves: {err}");
+          false
+        },
+      };
+      if running {
+        info!("Authenticated again, keeping the slave listeners");
+        return;
+      }
+      let mut handles = Vec::new();
+      for port in ports {
+        let mut config = self.slave_config(port, control);
+        config.upstream = upstreams.remove(&port);
+        handles.push(config.handle.clone());
+        thread::spawn(move || SlaveListener::begin(&config));
+      }
+      match self.slaves.lock() {
+        | Ok(mut slaves) => {
+          slaves.insert(socket.as_raw_fd(), handles);
+        },
+        | Err(err) => {
+          error!("Failed while aquiring lock for slaves: {err}")
         },
       }
     } else {
-      let packet = Server::parse_packet(
-        buffer,
-        &self.config.separator.as_bytes().to_vec(),
+      error!(
+        "Wrong auth from connection: {}. Closing connection.",
+        socket.as_raw_fd()
       );
-      match packet {
-        | Ok(packet) => {
-          match packet {
-            | PacketType::Data(packet) => match self.connections.lock() {
-              | Ok(connections) => match connections.get(&packet.id) {
-                | Some(stream) => match stream.socket.lock() {
-                  | Ok(mut socket) => match socket.send(&packet.body) {
-                    | Ok(_) => debug!(
-                      "Wrote data to socket: {}",
-                      socket.as_raw_fd()
-                    ),
-                    | Err(err) => error!(
-                      "Failed to write data to socket ({}): {err}",
-                      socket.as_raw_fd()
-                    ),
-                  },
-                  | Err(err) => {
-                    error!("Failed to aquire lock for socket: {err}");
-                    self.warn.warn("This may result in a hanging connection or a broken pipe".to_string());
-                  },
-                },
-                | None => debug!(
-                  "Failed to find connection for socket: {}",
-                  packet.id
-                ),
-              },
-              | Err(err) => {
-                error!("Failed while aquiring lock for connections: {err}");
-                self.warn.warn(
-                  "This may result in a hanging connection or a broken pipe"
-                    .to_string(),
+      let info = AuthTryInfo {
+        status: AuthStatus::Forbidden,
+        name: None,
+        version: None,
+        integrity: None,
+        window: None,
+        encoding: None,
+        reason: None,
+      };
+      socket.send(
+        Server::build_auth_try_packet(&info, &self.config.separator).as_slice(),
+      );
+      match socket.shutdown() {
+        | Ok(_) => info!("Shutdown connection"),
+        | Err(err) => error!("Error shutting down connection: {err}"),
+      }
+    }
+  }
+
+  fn handle_data(
+    &mut self, socket: &mut dyn ControlSocket, packet: Packet<Client, Data>,
+    body: &[u8],
+  ) {
+    match self.connections.lock() {
+      | Ok(mut connections) => match connections.get_mut(&packet.id) {
+        | Some(stream) => match Arc::clone(&stream.socket).lock() {
+          | Ok(mut downstream) => {
+            // Bytes a write would have blocked on are kept by the stream and
+            // pushed out later, so they count as accepted.
+            let sent = match downstream.send(body) {
+              | Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+              | result => result,
+            };
+            match sent {
+              | Ok(_) => {
+                debug!(
+                  "Wrote data to socket: {}",
+                  downstream.as_raw_fd()
                 );
+                stream
+                  .stats
+                  .bytes_out
+                  .fetch_add(body.len() as u64, Ordering::Relaxed);
+                if self.config.data_window.is_some() {
+                  stream.received += 1;
+                  socket.send(
+                    Server::build_ack_packet(
+                      &packet.id, stream.received, &self.config.separator,
+                    )
+                    .as_slice(),
+                  );
+                }
               },
-            },
-            | PacketType::Close(packet) => match self.connections.lock() {
-              | Ok(connections) => match connections.get(&packet.id) {
-                | Some(connection) => match connection.socket.lock() {
-                  | Ok(mut socket) => match socket.shutdown() {
-                    | Ok(_) => debug!(
-                      "Closed connection: {}",
-                      socket.as_raw_fd()
-                    ),
-                    | Err(err) => error!("Failed to close connection: {err}"),
-                  },
-                  | Err(err) => error!(
-                    "Failed to find connection for socket ({}): {err}",
-                    socket.as_raw_fd()
-                  ),
-                },
-                | None => error!(
-                  "Failed to find connection for socket: {}",
-                  socket.as_raw_fd()
-                ),
+              | Err(err) if err.kind() == ErrorKind::TimedOut => {
+                warn!(
+                  "Write to {} timed out ({err}), closing it",
+                  packet.id
+                );
+                if let Err(err) = downstream.shutdown() {
+                  error!("Failed to close connection: {err}");
+                }
+                drop(downstream);
+                if let Some(connection) = connections.remove(&packet.id) {
+                  connection.stats.set_reason("write timed out");
+                  connection.release();
+                }
+                socket.send(
+                  Server::close_connection_packet(
+                    &packet.id, &self.config.separator,
+                  )
+                  .as_slice(),
+                );
               },
+              | Err(err) => error!(
+                "Failed to write data to socket ({}): {err}",
+                downstream.as_raw_fd()
+              ),
+            }
+          },
+          | Err(err) => {
+            error!("Failed to aquire lock for socket: {err}");
+            self.warn.warn(
+              "This may result in a hanging connection or a broken pipe"
+                .to_string(),
+            );
+          },
+        },
+        | None => match self.config.unknown_id {
+          | UnknownIdPolicy::Drop => debug!(
+            "Failed to find connection for socket: {}",
+            packet.id
+          ),
+          | UnknownIdPolicy::Close => {
+            debug!(
+              "Failed to find connection for socket: {}, closing it",
+              packet.id
+            );
+            socket.send(
+              Server::close_connection_packet(
+                &packet.id, &self.config.separator,
+              )
+              .as_slice(),
+            );
+          },
+        },
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock for connections: {err}");
+        self.warn.warn(
+          "This may result in a hanging connection or a broken pipe"
+            .to_string(),
+        );
+      },
+    }
+  }
+
+  fn handle_close(
+    &mut self, socket: &mut dyn ControlSocket, packet: Packet<Client, Close>,
+  ) {
+    match self.connections.lock() {
+      | Ok(mut connections) => match connections.remove(&packet.id) {
+        | Some(connection) => {
+          connection.stats.set_reason("closed by client");
+          connection.release();
+          match connection.socket.lock() {
+            | Ok(mut socket) => match socket.shutdown() {
+              | Ok(_) => debug!(
+                "Closed connection: {} ({})",
+                socket.as_raw_fd(),
+                peer_label(&connection.peer)
+              ),
               | Err(err) => {
-                error!("Failed while aquiring lock for connections: {err}");
-                self.warn.warn(
-                  "This may result in a hanging connection or a broken pipe"
-                    .to_string(),
-                );
+                error!("Failed to close connection: {err}")
               },
             },
-            | _ => {
-              error!(
-              "Expected a data packet, got something else. Closing connection. (fd: {})",
+            | Err(err) => error!(
+              "Failed to find connection for socket ({}): {err}",
               socket.as_raw_fd()
-            );
-              match socket.shutdown() {
-                | Ok(_) => {
-                  info!("Shutdown connection");
-                },
-                | Err(err) => {
-                  error!("Error shutting down connection: {err}");
-                },
-              }
-            },
+            ),
           }
         },
-        | Err(err) => {
-          error!("Error parsing packet: {}", err.value());
-          self.warn.warn(
-            "This may result in a hanging connection or a broken pipe"
-              .to_string(),
-          );
-        },
-      }
+        | None => error!(
+          "Failed to find connection for socket: {}",
+          socket.as_raw_fd()
+        ),
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock for connections: {err}");
+        self.warn.warn(
+          "This may result in a hanging connection or a broken pipe"
+            .to_string(),
+        );
+      },
     }
   }
 
-  fn on_connection_removed(&mut self, fd: RawFd, err: Error) {
-    // Called when a connection has been removed from the watch list, with the
-    // `std::io::Error` as the reason removed.
-    debug!("{fd} removed: {err}");
+  fn handle_ack(&mut self, packet: Packet<Client, Ack>) {
+    match self.connections.lock() {
+      | Ok(connections) => match connections
+        .get(&packet.id)
+        .and_then(|connection| connection.window.as_ref())
+      {
+        | Some(window) => {
+          if !window.ack(packet.sequence()) {
+            debug!(
+              "Ignoring out of order ack for {}",
+              packet.id
+            );
+          }
+        },
+        | None => debug!(
+          "Failed to find window for connection: {}",
+          packet.id
+        ),
+      },
+      | Err(err) => {
+        error!("Failed while aquiring lock for connections: {err}")
+      },
+    }
   }
-}
 
-impl MasterListener {
-  pub fn start(config: &crate::config::Config<Runtime>) {
-    let config = config.to_owned();
-    hydrogen::begin(
-      Box::new(MasterListener {
-        config: config.to_owned(),
-        was_authed: false,
-        warn: Warning::new(5),
-        connections: Arc::new(Mutex::new(HashMap::new())),
-      }),
-      hydrogen::Config {
-        addr: config.listen.host,
-        port: config.listen.port,
-        max_threads: config.threads,
-        pre_allocated: config.concurrency,
-      },
-    );
+  fn handle_custom(&mut self, packet: CustomPacket) {
+    if !self.actions.dispatch(&packet) {
+      error!(
+        "No handler for packet action: {}",
+        packet.action
+      );
+    }
   }
 }
@@ -0,0 +1,410 @@
+#[allow(unused_imports)]
+use crate::config::{
+  check_fd_limit, file_to_runtime, override_threads, reload_auth,
+  runtime_to_file, Config, SettingsError, DEFAULT_SETTINGS,
+};
+#[allow(unused_imports)]
+use proxy_router::{
+  constants::ConfigFile,
+  functions::{HashEncoding, Separator, SeparatorError},
+  settings::{
+    defaulted_fields, init_settings, load_settings, InitError, LoadError,
+  },
+};
+#[allow(unused_imports)]
+use serde_json::{json, to_string_pretty};
+#[allow(unused_imports)]
+use std::{env::temp_dir, fs, os::unix::fs::symlink, process};
+
+#[cfg(test)]
+fn settings_path(name: &str) -> String {
+  let path = temp_dir().join(format!(
+    "proxy-{name}-{}.json",
+    process::id()
+  ));
+  path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn concurrency_within_fd_limit() {
+  assert!(check_fd_limit(1024, Some(4096)));
+  assert!(check_fd_limit(1024, Some(1024)));
+}
+
+#[test]
+fn concurrency_over_fd_limit() {
+  assert!(!check_fd_limit(1024, Some(256)));
+}
+
+#[test]
+fn concurrency_without_fd_limit() {
+  assert!(check_fd_limit(usize::MAX, None));
+}
+
+#[test]
+fn init_creates_settings() {
+  let path = settings_path("init-create");
+  let _ = fs::remove_file(&path);
+  assert!(init_settings::<Config<ConfigFile>>(&path, false).is_ok());
+  let result = load_settings::<Config<ConfigFile>>(&path);
+  fs::remove_file(&path).unwrap();
+  assert!(result.is_ok());
+}
+
+#[test]
+fn init_refuses_to_overwrite() {
+  let path = settings_path("init-refuse");
+  fs::write(&path, "keep me").unwrap();
+  let result = init_settings::<Config<ConfigFile>>(&path, false);
+  let contents = fs::read_to_string(&path).unwrap();
+  fs::remove_file(&path).unwrap();
+  assert!(matches!(result, Err(InitError::Exists)));
+  assert_eq!(contents, "keep me");
+}
+
+#[test]
+fn init_force_overwrites() {
+  let path = settings_path("init-force");
+  fs::write(&path, "replace me").unwrap();
+  assert!(init_settings::<Config<ConfigFile>>(&path, true).is_ok());
+  let result = load_settings::<Config<ConfigFile>>(&path);
+  fs::remove_file(&path).unwrap();
+  assert!(result.is_ok());
+}
+
+#[cfg(test)]
+fn load_with_separator(
+  name: &str, separator: &str, encoding: HashEncoding,
+) -> Option<u8> {
+  let path = settings_path(name);
+  // Written by hand, as no Separator can hold the ones rejected outright
+  let mut settings = json!(DEFAULT_SETTINGS.clone());
+  settings["separator"] = json!(separator);
+  settings["hash_encoding"] = json!(encoding);
+  fs::write(
+    &path,
+    to_string_pretty(&settings).unwrap(),
+  )
+  .unwrap();
+  let result = load_settings::<Config<ConfigFile>>(&path);
+  fs::remove_file(&path).unwrap();
+  match result {
+    | Ok(_) => None,
+    | Err(LoadError::Separator(SeparatorError::Reserved(byte))) => Some(byte),
+    | Err(_) => panic!("Settings should only be rejected for the separator"),
+  }
+}
+
+#[test]
+fn separator_rejects_space() {
+  assert_eq!(
+    load_with_separator(
+      "separator-space",
+      " ",
+      HashEncoding::Hex
+    ),
+    Some(b' ')
+  );
+}
+
+#[test]
+fn separator_rejects_hex_digit() {
+  assert_eq!(
+    load_with_separator("separator-hex", "a", HashEncoding::Hex),
+    Some(b'a')
+  );
+  // Not part of the hex charset, but base64 hashes can contain it.
+  assert_eq!(
+    load_with_separator(
+      "separator-base64",
+      "+",
+      HashEncoding::Base64
+    ),
+    Some(b'+')
+  );
+  assert_eq!(
+    load_with_separator("separator-ok", "+", HashEncoding::Hex),
+    None
+  );
+}
+
+#[test]
+fn omitted_fields_are_reported_as_defaulted() {
+  let contents = r#"{
+    "separator": "\u0000",
+    "listen": { "port": 65535, "host": "0.0.0.0" },
+    "auth": "CH4ng3M3!",
+    "concurrency": 1024,
+    "threads": null,
+    "reauth_timeout_secs": 5
+  }"#;
+  let defaulted = defaulted_fields::<Config<ConfigFile>>(contents);
+  let field = |name: &str| {
+    defaulted
+      .iter()
+      .find(|(field, _)| field == name)
+      .map(|(_, value)| value.to_owned())
+  };
+
+  assert_eq!(
+    field("max_header_bytes"),
+    Some(json!(DEFAULT_SETTINGS.max_header_bytes))
+  );
+  assert_eq!(
+    field("auth_block_secs"),
+    Some(json!(DEFAULT_SETTINGS.auth_block_secs))
+  );
+  for given in [
+    "separator", "listen", "auth", "concurrency", "threads",
+    "reauth_timeout_secs",
+  ] {
+    assert_eq!(field(given), None, "{given} was set");
+  }
+  assert!(defaulted_fields::<Config<ConfigFile>>(
+    &to_string_pretty(&DEFAULT_SETTINGS.clone()).unwrap()
+  )
+  .is_empty());
+}
+
+#[test]
+fn threads_flag_overrides_settings() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.threads = Some(3);
+
+  let mut config = file_to_runtime(settings.clone());
+  override_threads(&mut config, None);
+  assert_eq!(config.threads, 3);
+
+  let mut config = file_to_runtime(settings.clone());
+  override_threads(&mut config, Some(8));
+  assert_eq!(config.threads, 8);
+
+  // 0 picks the same count as leaving threads out of the settings.
+  let mut config = file_to_runtime(settings);
+  override_threads(&mut config, Some(0));
+  assert_eq!(
+    config.threads,
+    file_to_runtime(DEFAULT_SETTINGS.clone()).threads
+  );
+}
+
+#[test]
+fn directory_settings_left_alone() {
+  let path = settings_path("load-directory");
+  fs::create_dir_all(&path).unwrap();
+  let result = load_settings::<Config<ConfigFile>>(&path);
+  let still_directory = fs::metadata(&path).unwrap().is_dir();
+  fs::remove_dir(&path).unwrap();
+  assert!(matches!(
+    result,
+    Err(LoadError::NotAFile(_))
+  ));
+  assert!(still_directory);
+}
+
+#[test]
+fn dangling_symlink_settings_left_alone() {
+  let path = settings_path("load-symlink");
+  let target = settings_path("load-symlink-target");
+  let _ = fs::remove_file(&path);
+  symlink(&target, &path).unwrap();
+  let result = load_settings::<Config<ConfigFile>>(&path);
+  fs::remove_file(&path).unwrap();
+  assert!(matches!(
+    result,
+    Err(LoadError::NotAFile(_))
+  ));
+
+  // A path with nothing at all is still just missing.
+  assert!(matches!(
+    load_settings::<Config<ConfigFile>>(&path),
+    Err(LoadError::Read(_))
+  ));
+}
+
+#[test]
+fn control_separator_saved_as_bytes() {
+  let path = settings_path("separator-bytes");
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.separator = Separator::new("\u{0001}\u{0000}").unwrap();
+  let contents = to_string_pretty(&settings).unwrap();
+  assert!(contents.contains("\"separator\": [\n    1,\n    0\n  ]"));
+
+  fs::write(&path, &contents).unwrap();
+  let loaded = load_settings::<Config<ConfigFile>>(&path);
+  fs::remove_file(&path).unwrap();
+  match loaded {
+    | Ok(loaded) => assert_eq!(loaded.separator, settings.separator),
+    | _ => panic!("Settings with a byte separator should load"),
+  }
+}
+
+#[test]
+fn separator_loads_from_string_or_bytes() {
+  let path = settings_path("separator-forms");
+  let mut settings = json!(DEFAULT_SETTINGS.clone());
+  for (separator, expected) in [
+    (json!("\u{0000}"), "\u{0000}"),
+    (json!([0]), "\u{0000}"),
+    (json!("||"), "||"),
+    (json!([124, 124]), "||"),
+  ] {
+    settings["separator"] = separator;
+    fs::write(&path, settings.to_string()).unwrap();
+    let loaded = load_settings::<Config<ConfigFile>>(&path);
+    fs::remove_file(&path).unwrap();
+    match loaded {
+      | Ok(loaded) => assert_eq!(
+        loaded.separator.as_bytes(),
+        expected.as_bytes()
+      ),
+      | _ => panic!("Separator {expected:?} should load"),
+    }
+  }
+
+  // Printable separators are still written as a string.
+  let mut printable = DEFAULT_SETTINGS.clone();
+  printable.separator = Separator::new("||").unwrap();
+  assert!(to_string_pretty(&printable)
+    .unwrap()
+    .contains("\"separator\": \"||\""));
+}
+
+#[test]
+fn runtime_settings_written_back() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.threads = Some(3);
+  settings.server_name = Some(String::from("edge"));
+  settings.warmup_ms.insert(25565, 500);
+  let mut config = file_to_runtime(settings.clone());
+  config.concurrency = 64;
+  settings.concurrency = 64;
+
+  let written = runtime_to_file(config);
+  assert_eq!(
+    to_string_pretty(&written).unwrap(),
+    to_string_pretty(&settings).unwrap()
+  );
+  // The default separator is a control character, so it stays in byte form.
+  assert_eq!(
+    serde_json::to_value(&written).unwrap()["separator"],
+    json!([0])
+  );
+}
+
+#[test]
+fn listen_loads_as_control_listen() {
+  let path = settings_path("listen-renamed");
+  let mut settings = json!(DEFAULT_SETTINGS.clone());
+  let settings = settings.as_object_mut().unwrap();
+  settings.remove("control_listen");
+  settings.insert(
+    String::from("listen"),
+    json!({ "port": 25565, "host": "127.0.0.1" }),
+  );
+  let contents = json!(settings).to_string();
+  fs::write(&path, &contents).unwrap();
+  let loaded = load_settings::<Config<ConfigFile>>(&path);
+  fs::remove_file(&path).unwrap();
+  match loaded {
+    | Ok(loaded) => {
+      assert_eq!(loaded.control_listen.port, 25565);
+      assert_eq!(loaded.control_listen.host, "127.0.0.1");
+    },
+    | _ => panic!("Settings with listen should load"),
+  }
+  assert!(defaulted_fields::<Config<ConfigFile>>(&contents).is_empty());
+}
+
+#[cfg(test)]
+fn load_with_auth(name: &str, auth: &str, allow_empty_auth: bool) -> bool {
+  let path = settings_path(name);
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.auth = auth.as_bytes().to_vec();
+  settings.allow_empty_auth = allow_empty_auth;
+  fs::write(
+    &path,
+    to_string_pretty(&settings).unwrap(),
+  )
+  .unwrap();
+  let result = load_settings::<Config<ConfigFile>>(&path);
+  fs::remove_file(&path).unwrap();
+  match result {
+    | Ok(_) => true,
+    | Err(LoadError::Check(SettingsError::EmptyAuth)) => false,
+    | Err(_) => panic!("Settings should only be rejected for the auth"),
+  }
+}
+
+#[test]
+fn empty_auth_refused() {
+  assert!(!load_with_auth("auth-empty", "", false));
+  assert!(load_with_auth(
+    "auth-set", "secret", false
+  ));
+}
+
+#[test]
+fn empty_auth_allowed_explicitly() {
+  assert!(load_with_auth(
+    "auth-empty-allowed", "", true
+  ));
+}
+
+#[test]
+fn auth_reloaded_from_settings() {
+  let path = settings_path("auth-reload");
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.auth = b"rotated".to_vec();
+  fs::write(
+    &path,
+    to_string_pretty(&settings).unwrap(),
+  )
+  .unwrap();
+  let reloaded = reload_auth(&path);
+
+  settings.auth = Vec::new();
+  fs::write(
+    &path,
+    to_string_pretty(&settings).unwrap(),
+  )
+  .unwrap();
+  let emptied = reload_auth(&path);
+  fs::remove_file(&path).unwrap();
+
+  assert_eq!(reloaded, Some(b"rotated".to_vec()));
+  // The running secret is kept rather than letting anyone in.
+  assert_eq!(emptied, None);
+  assert_eq!(reload_auth(&path), None);
+}
+
+#[test]
+fn server_name_with_field_delimiters_refused() {
+  let path = settings_path("server-name");
+  for name in ["edge;window=0", "edge=1"] {
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.server_name = Some(String::from(name));
+    fs::write(
+      &path,
+      to_string_pretty(&settings).unwrap(),
+    )
+    .unwrap();
+    let loaded = load_settings::<Config<ConfigFile>>(&path);
+    let refused = match &loaded {
+      | Err(LoadError::Check(SettingsError::ServerName(refused))) => refused,
+      | _ => panic!("{name} should be refused"),
+    };
+    assert_eq!(refused, name);
+  }
+
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.server_name = Some(String::from("edge-1"));
+  fs::write(
+    &path,
+    to_string_pretty(&settings).unwrap(),
+  )
+  .unwrap();
+  let loaded = load_settings::<Config<ConfigFile>>(&path);
+  fs::remove_file(&path).unwrap();
+  assert!(loaded.is_ok());
+}
@@ -0,0 +1,45 @@
+#[allow(unused_imports)]
+use crate::limiter::AuthLimiter;
+#[allow(unused_imports)]
+use std::{net::IpAddr, time::Duration};
+#[allow(unused_imports)]
+use tokio::time::Instant;
+
+#[test]
+fn failures_block_until_expiry() {
+  let ip: IpAddr = "203.0.113.7".parse().unwrap();
+  let other: IpAddr = "203.0.113.8".parse().unwrap();
+  let mut limiter = AuthLimiter::new(
+    3,
+    Duration::from_secs(60),
+    Duration::from_secs(300),
+  );
+  let start = Instant::now();
+
+  assert!(!limiter.record_failure(ip, start));
+  assert!(!limiter.record_failure(ip, start + Duration::from_secs(1)));
+  assert!(!limiter.is_blocked(&ip, start + Duration::from_secs(1)));
+  assert!(limiter.record_failure(ip, start + Duration::from_secs(2)));
+
+  assert!(limiter.is_blocked(&ip, start + Duration::from_secs(3)));
+  assert!(!limiter.is_blocked(&other, start + Duration::from_secs(3)));
+  assert!(limiter.is_blocked(&ip, start + Duration::from_secs(301)));
+  assert!(!limiter.is_blocked(&ip, start + Duration::from_secs(302)));
+}
+
+#[test]
+fn failures_outside_window_are_forgotten() {
+  let ip: IpAddr = "203.0.113.7".parse().unwrap();
+  let mut limiter = AuthLimiter::new(
+    2,
+    Duration::from_secs(60),
+    Duration::from_secs(300),
+  );
+  let start = Instant::now();
+
+  assert!(!limiter.record_failure(ip, start));
+  assert!(!limiter.record_failure(ip, start + Duration::from_secs(61)));
+
+  limiter.record_success(&ip);
+  assert!(!limiter.record_failure(ip, start + Duration::from_secs(62)));
+}
@@ -0,0 +1,4 @@
+mod config;
+mod limiter;
+mod slave;
+mod socket;
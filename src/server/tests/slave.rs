@@ -0,0 +1,382 @@
+#[allow(unused_imports)]
+use crate::{
+  config::{file_to_runtime, DEFAULT_SETTINGS},
+  slave::{ConnectionStats, Control, SenderPacket, SlaveHandle, SlaveListener},
+  socket::MasterListener,
+};
+#[allow(unused_imports)]
+use hydrogen::Handler;
+#[allow(unused_imports)]
+use proxy_router::{
+  constants::{Stream, WINDOW_BACKLOG_BYTES},
+  functions::{Client, PacketType, Separator, Server},
+  memory::MemoryStream,
+};
+#[allow(unused_imports)]
+use std::{
+  collections::HashMap,
+  io::{Error, ErrorKind, Read, Write},
+  net::{TcpListener, TcpStream},
+  os::unix::io::{AsRawFd, IntoRawFd, RawFd},
+  sync::{Arc, Mutex},
+  thread,
+  time::Duration,
+};
+#[allow(unused_imports)]
+use tokio::time::Instant;
+#[allow(unused_imports)]
+use uuid::Uuid;
+
+#[test]
+fn closed_handle_stops_accepting() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+  let handle = SlaveHandle::new();
+  handle.set_listener(listener.as_raw_fd());
+  assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+
+  handle.close(
+    &Mutex::new(HashMap::new()),
+    &Separator::default(),
+  );
+  assert!(handle.is_closed());
+  assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn listener_set_after_close_is_shut_down() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+  let handle = SlaveHandle::new();
+  handle.close(
+    &Mutex::new(HashMap::new()),
+    &Separator::default(),
+  );
+
+  handle.set_listener(listener.as_raw_fd());
+  assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn closed_handle_shuts_down_downstreams() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut downstream =
+    TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let stream = Stream::from_tcp_stream(accepted);
+  let id = stream.id;
+
+  let handle = SlaveHandle::new();
+  handle.add(id);
+  let connections = Mutex::new(HashMap::new());
+  connections.lock().unwrap().insert(
+    id,
+    SenderPacket {
+      fd: stream.as_raw_fd(),
+      uuid: id,
+      peer: stream.peer,
+      accepted: Instant::now(),
+      window: None,
+      received: 0,
+      stats: Arc::new(ConnectionStats::new(8080, None)),
+      socket: Arc::new(Mutex::new(stream)),
+      control: Arc::new(Mutex::new(MemoryStream::new(-1))),
+    },
+  );
+
+  handle.close(&connections, &Separator::default());
+  assert!(connections.lock().unwrap().is_empty());
+  let mut buffer = [0u8; 16];
+  assert_eq!(downstream.read(&mut buffer).unwrap(), 0);
+}
+
+/// Accepts one downstream through `slave`, returning its fd, the id announced
+/// to the client and the downstream's end of the connection
+#[cfg(test)]
+fn accept_downstream(
+  slave: &mut SlaveListener, control: &MemoryStream, separator: &Separator,
+) -> (RawFd, Uuid, TcpStream) {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let downstream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let fd = listener.accept().unwrap().0.into_raw_fd();
+  slave.on_new_connection(fd);
+  let id = match Client::parse_packet(control.take_sent(), &separator) {
+    | Ok(PacketType::Open(packet)) => packet.id,
+    | _ => panic!("Expected an open packet"),
+  };
+  (fd, id, downstream)
+}
+
+#[test]
+fn downstream_hang_up_and_error_send_close() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  let mut slave = SlaveListener::new(&master.slave_config(8080, &handle));
+
+  let (fd, id, _downstream) =
+    accept_downstream(&mut slave, &control, &separator);
+  slave.on_connection_removed(
+    fd,
+    Error::from(ErrorKind::UnexpectedEof),
+  );
+  assert_eq!(
+    control.take_sent(),
+    Server::close_connection_packet(&id, &separator)
+  );
+
+  let (fd, id, _downstream) =
+    accept_downstream(&mut slave, &control, &separator);
+  slave.on_connection_removed(
+    fd,
+    Error::from(ErrorKind::ConnectionReset),
+  );
+  assert_eq!(
+    control.take_sent(),
+    Server::close_connection_packet(&id, &separator)
+  );
+}
+
+#[test]
+fn closed_listener_sends_close_for_each_downstream() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  let slave_config = master.slave_config(8080, &handle);
+  let mut slave = SlaveListener::new(&slave_config);
+
+  let (_, first, mut first_downstream) =
+    accept_downstream(&mut slave, &control, &separator);
+  let (_, second, mut second_downstream) =
+    accept_downstream(&mut slave, &control, &separator);
+
+  slave_config.handle.close(&slave_config.connections, &separator);
+  let mut expected = Server::close_connection_packet(&first, &separator);
+  expected.extend(Server::close_connection_packet(
+    &second, &separator,
+  ));
+  assert_eq!(control.take_sent(), expected);
+  assert!(slave_config.connections.lock().unwrap().is_empty());
+  let mut buffer = [0u8; 16];
+  assert_eq!(
+    first_downstream.read(&mut buffer).unwrap(),
+    0
+  );
+  assert_eq!(
+    second_downstream.read(&mut buffer).unwrap(),
+    0
+  );
+}
+
+#[test]
+fn close_from_client_not_echoed() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  control.take_sent();
+  let mut slave = SlaveListener::new(&master.slave_config(8080, &handle));
+
+  let (fd, id, _downstream) =
+    accept_downstream(&mut slave, &control, &separator);
+  master.dispatch(
+    Arc::clone(&handle),
+    Server::close_connection_packet(&id, &separator),
+  );
+  slave.on_connection_removed(fd, Error::from(ErrorKind::BrokenPipe));
+  assert!(control.take_sent().is_empty());
+}
+
+#[test]
+fn full_window_does_not_hold_up_the_listener() {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  config.data_window = Some(1);
+  let separator = config.separator.clone();
+  let master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  let slave_config = master.slave_config(8080, &handle);
+  let mut slave = SlaveListener::new(&slave_config);
+  let (fd, id, _downstream) =
+    accept_downstream(&mut slave, &control, &separator);
+
+  slave.forward(fd, "first".into());
+  let started = Instant::now();
+  slave.forward(fd, "second".into());
+  assert!(started.elapsed() < Duration::from_millis(100));
+  let first =
+    Server::build_data_packet(&id, &8080, &separator, &"first".into());
+  assert_eq!(control.take_sent(), first);
+
+  // The acknowledgement lets the one waiting go out.
+  let window = slave_config.connections.lock().unwrap()[&id]
+    .window
+    .as_ref()
+    .map(Arc::clone)
+    .unwrap();
+  assert!(window.ack(1));
+  thread::sleep(Duration::from_millis(50));
+  assert_eq!(
+    control.take_sent(),
+    Server::build_data_packet(&id, &8080, &separator, &"second".into())
+  );
+}
+
+#[test]
+fn ack_sends_data_read_while_window_full() {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  config.data_window = Some(1);
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  control.take_sent();
+  let mut slave = SlaveListener::new(&master.slave_config(8080, &handle));
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut downstream =
+    TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let fd = listener.accept().unwrap().0.into_raw_fd();
+  let stream = slave.on_new_connection(fd);
+  let id = match Client::parse_packet(control.take_sent(), &separator) {
+    | Ok(PacketType::Open(packet)) => packet.id,
+    | _ => panic!("Expected an open packet"),
+  };
+  // Reads once for each time the downstream turns readable, as the epoll
+  // loop does
+  let readable = |slave: &mut SlaveListener| {
+    thread::sleep(Duration::from_millis(50));
+    for buffer in unsafe { &mut *stream.get() }.recv().unwrap() {
+      slave.forward(fd, buffer);
+    }
+  };
+
+  downstream.write_all(b"first").unwrap();
+  readable(&mut slave);
+  downstream.write_all(b"second").unwrap();
+  readable(&mut slave);
+  assert_eq!(
+    control.take_sent(),
+    Server::build_data_packet(&id, &8080, &separator, &"first".into())
+  );
+
+  // Nothing more is sent, so the ACK alone has to get the rest out.
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_ack_packet(&id, 1, &separator),
+  );
+  thread::sleep(Duration::from_millis(50));
+  assert_eq!(
+    control.take_sent(),
+    Server::build_data_packet(&id, &8080, &separator, &"second".into())
+  );
+}
+
+#[test]
+fn overfull_window_backlog_closes_downstream() {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  config.data_window = Some(1);
+  let separator = config.separator.clone();
+  let master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  let slave_config = master.slave_config(8080, &handle);
+  let mut slave = SlaveListener::new(&slave_config);
+  let (fd, id, mut downstream) =
+    accept_downstream(&mut slave, &control, &separator);
+
+  slave.forward(fd, "first".into());
+  control.take_sent();
+  slave.forward(fd, vec![0u8; WINDOW_BACKLOG_BYTES + 1]);
+  assert_eq!(
+    control.take_sent(),
+    Server::close_connection_packet(&id, &separator)
+  );
+  assert!(slave_config.connections.lock().unwrap().is_empty());
+  let mut buffer = [0u8; 16];
+  assert_eq!(downstream.read(&mut buffer).unwrap(), 0);
+}
+
+#[test]
+fn warmup_delays_accepting() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.warmup_ms.insert(8080, 200);
+  let config = file_to_runtime(settings);
+  let master = MasterListener::new(&config);
+  let handle: Control = Arc::new(Mutex::new(MemoryStream::new(3)));
+  assert_eq!(
+    master.slave_config(8081, &handle).warmup,
+    None
+  );
+
+  let slave_config = master.slave_config(8080, &handle);
+  let started = std::time::Instant::now();
+  assert!(SlaveListener::warm_up(&slave_config));
+  assert!(started.elapsed() >= Duration::from_millis(200));
+
+  // The client went away while the port was warming up.
+  slave_config.handle.stop_accepting();
+  assert!(!SlaveListener::warm_up(&slave_config));
+}
+
+#[test]
+fn closed_connection_summarized() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.access_log = true;
+  let config = file_to_runtime(settings);
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  control.take_sent();
+  let mut slave = SlaveListener::new(&master.slave_config(8080, &handle));
+
+  let (fd, id, downstream) =
+    accept_downstream(&mut slave, &control, &separator);
+  slave.forward(fd, "ping".into());
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_data_packet(&id, &separator, &"hello!".into()),
+  );
+  master.dispatch(
+    Arc::clone(&handle),
+    Server::close_connection_packet(&id, &separator),
+  );
+
+  let error = Error::from(ErrorKind::BrokenPipe);
+  let summary = slave.summary(fd, &error).unwrap();
+  let peer = downstream.local_addr().unwrap();
+  for field in [
+    format!("id={id}"),
+    String::from("port=8080"),
+    format!("peer={peer}"),
+    String::from("bytes_in=4"),
+    String::from("bytes_out=6"),
+    String::from("duration_ms="),
+    String::from("reason=\"closed by client\""),
+  ] {
+    assert!(
+      summary.contains(&field),
+      "{field} missing from {summary}"
+    );
+  }
+  slave.on_connection_removed(fd, error);
+  assert!(slave.summary(fd, &Error::from(ErrorKind::BrokenPipe)).is_none());
+}
@@ -0,0 +1,1511 @@
+#[allow(unused_imports)]
+use crate::{
+  config::{file_to_runtime, UnknownIdPolicy, DEFAULT_SETTINGS},
+  slave::{ConnectionStats, Control, SenderPacket, SlaveListener},
+  socket::{
+    close_silent, flush_pending, limit_ports, reap_expired, MasterListener,
+    PacketHandler,
+  },
+};
+#[allow(unused_imports)]
+use hydrogen::Handler;
+#[allow(unused_imports)]
+use proxy_router::{
+  constants::{IdSource, Stream, AT_CAPACITY, OVER_CAPACITY_GRACE_MS},
+  functions::{
+    AuthStatus, AuthTryInfo, Client, HashEncoding, Integrity, PacketType,
+    ParseOptions, Separator, Server,
+  },
+  memory::MemoryStream,
+  websocket::{
+    client_frame, close_frame, upgrade_request, Decoder, Message, Transport,
+    WebSocketError,
+  },
+  window::Window,
+};
+#[allow(unused_imports)]
+use std::{
+  collections::{HashMap, VecDeque},
+  io::{Error, ErrorKind, Read},
+  net::{TcpListener, TcpStream},
+  os::unix::io::{AsRawFd, IntoRawFd},
+  sync::atomic::{AtomicUsize, Ordering},
+  sync::{Arc, Mutex},
+  thread,
+  time::Duration,
+};
+#[allow(unused_imports)]
+use tokio::{
+  net::TcpSocket,
+  time::{sleep, Instant},
+};
+#[allow(unused_imports)]
+use uuid::Uuid;
+
+#[test]
+fn limit_ports_trims_to_max() {
+  assert_eq!(
+    limit_ports(vec![80, 443], Some(2)),
+    Some(vec![80, 443])
+  );
+  assert_eq!(
+    limit_ports(vec![80, 443, 8080], Some(2)),
+    Some(vec![80, 443])
+  );
+  assert_eq!(
+    limit_ports(vec![80, 443, 8080], None),
+    Some(vec![80, 443, 8080])
+  );
+  assert_eq!(
+    limit_ports((1..=9).collect(), Some(2)),
+    None
+  );
+}
+
+#[test]
+fn auth_within_port_limit() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.max_ports_per_session = Some(2);
+  let config = file_to_runtime(settings);
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+
+  master.dispatch(
+    Arc::new(Mutex::new(control.clone())),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  assert!(control.take_sent().starts_with("AUTHTRY\u{0000}success".as_bytes()));
+  assert!(!control.is_shutdown());
+}
+
+#[test]
+fn auth_far_over_port_limit_closes() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.max_ports_per_session = Some(1);
+  let config = file_to_runtime(settings);
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+
+  master.dispatch(
+    Arc::new(Mutex::new(control.clone())),
+    Client::build_auth_packet(
+      &config.auth,
+      &(20000..20005).collect(),
+      &separator,
+    ),
+  );
+  let info = AuthTryInfo {
+    status: AuthStatus::Forbidden,
+    name: None,
+    version: None,
+    integrity: None,
+    window: None,
+    encoding: None,
+    reason: None,
+  };
+  assert_eq!(
+    control.take_sent(),
+    Server::build_auth_try_packet(&info, &separator)
+  );
+  assert!(control.is_shutdown());
+}
+
+#[test]
+fn connections_sized_for_concurrency() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.concurrency = 4096;
+  let master = MasterListener::new(&file_to_runtime(settings));
+  assert!(master.connections_capacity() >= 4096);
+}
+
+#[test]
+fn close_connection_by_id() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let master = MasterListener::new(&config);
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut downstream =
+    TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let stream = Stream::from_tcp_stream(accepted);
+  let id = stream.id;
+  let control = MemoryStream::new(-1);
+  master.insert_connection(SenderPacket {
+    fd: stream.as_raw_fd(),
+    uuid: id,
+    peer: stream.peer,
+    accepted: Instant::now(),
+    window: None,
+    received: 0,
+    stats: Arc::new(ConnectionStats::new(8080, None)),
+    socket: Arc::new(Mutex::new(stream)),
+    control: Arc::new(Mutex::new(control.clone())),
+  });
+
+  assert!(master.close_connection(id));
+  assert_eq!(
+    control.take_sent(),
+    Server::close_connection_packet(&id, &config.separator)
+  );
+  let mut buffer = [0u8; 16];
+  assert_eq!(downstream.read(&mut buffer).unwrap(), 0);
+
+  assert!(!master.close_connection(id));
+  assert!(!master.close_connection(Uuid::new_v4()));
+}
+
+#[tokio::test(start_paused = true)]
+async fn connection_closed_at_max_lifetime() {
+  let separator = Separator::default();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut downstream =
+    TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let stream = Stream::from_tcp_stream(accepted);
+  let id = stream.id;
+  let control = MemoryStream::new(-1);
+  let connections = Arc::new(Mutex::new(HashMap::new()));
+  connections.lock().unwrap().insert(
+    id,
+    SenderPacket {
+      fd: stream.as_raw_fd(),
+      uuid: id,
+      peer: stream.peer,
+      accepted: Instant::now(),
+      window: None,
+      received: 0,
+      stats: Arc::new(ConnectionStats::new(8080, None)),
+      socket: Arc::new(Mutex::new(stream)),
+      control: Arc::new(Mutex::new(control.clone())),
+    },
+  );
+  tokio::spawn(reap_expired(
+    Arc::clone(&connections),
+    Duration::from_secs(30),
+    separator.clone(),
+  ));
+
+  sleep(Duration::from_secs(29)).await;
+  assert!(connections.lock().unwrap().contains_key(&id));
+  assert!(control.take_sent().is_empty());
+
+  sleep(Duration::from_secs(1)).await;
+  // Let the reaper, woken by the same tick, run first.
+  tokio::task::yield_now().await;
+  assert!(!connections.lock().unwrap().contains_key(&id));
+  assert_eq!(
+    control.take_sent(),
+    Server::close_connection_packet(&id, &separator)
+  );
+  let mut buffer = [0u8; 16];
+  assert_eq!(downstream.read(&mut buffer).unwrap(), 0);
+}
+
+#[test]
+fn auth_then_data_through_memory_streams() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  let info = AuthTryInfo {
+    status: AuthStatus::Success,
+    name: None,
+    version: None,
+    integrity: None,
+    window: None,
+    encoding: Some(HashEncoding::Hex),
+    reason: None,
+  };
+  assert_eq!(
+    control.take_sent(),
+    Server::build_auth_try_packet(&info, &separator)
+  );
+
+  let downstream = MemoryStream::new(4);
+  let id = Uuid::new_v4();
+  master.insert_connection(SenderPacket {
+    socket: Arc::new(Mutex::new(downstream.clone())),
+    fd: downstream.as_raw_fd(),
+    uuid: id,
+    control: Arc::clone(&handle),
+    peer: None,
+    accepted: Instant::now(),
+    window: None,
+    received: 0,
+    stats: Arc::new(ConnectionStats::new(8080, None)),
+  });
+
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_data_packet(&id, &separator, &"Hello".into()),
+  );
+  assert_eq!(
+    downstream.take_sent(),
+    "Hello".as_bytes().to_vec()
+  );
+
+  // Built with the server's builder, the client's one still adds a port.
+  master.dispatch(
+    Arc::clone(&handle),
+    Server::close_connection_packet(&id, &separator),
+  );
+  assert!(downstream.is_shutdown());
+  assert!(!control.is_shutdown());
+}
+
+#[test]
+fn sha512_only_announced_and_expected() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.integrity = Integrity::Sha512Only;
+  let config = file_to_runtime(settings);
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  assert_eq!(
+    control.take_sent(),
+    "AUTHTRY\u{0000}success;integrity=sha512_only;encoding=hex"
+      .as_bytes()
+      .to_vec()
+  );
+
+  let downstream = MemoryStream::new(4);
+  let id = Uuid::new_v4();
+  master.insert_connection(SenderPacket {
+    socket: Arc::new(Mutex::new(downstream.clone())),
+    fd: downstream.as_raw_fd(),
+    uuid: id,
+    control: Arc::clone(&handle),
+    peer: None,
+    accepted: Instant::now(),
+    window: None,
+    received: 0,
+    stats: Arc::new(ConnectionStats::new(8080, None)),
+  });
+
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_data_packet(&id, &separator, &"Hello".into()),
+  );
+  assert!(downstream.take_sent().is_empty());
+
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_data_packet_with(
+      &id,
+      &separator,
+      &"Hello".into(),
+      HashEncoding::Hex,
+      Integrity::Sha512Only,
+    ),
+  );
+  assert_eq!(
+    downstream.take_sent(),
+    "Hello".as_bytes().to_vec()
+  );
+}
+
+#[test]
+fn wrong_auth_through_memory_stream() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+
+  master.dispatch(
+    Arc::new(Mutex::new(control.clone())),
+    Client::build_auth_packet(b"wrong", &vec![], &separator),
+  );
+  let info = AuthTryInfo {
+    status: AuthStatus::Forbidden,
+    name: None,
+    version: None,
+    integrity: None,
+    window: None,
+    encoding: None,
+    reason: None,
+  };
+  assert_eq!(
+    control.take_sent(),
+    Server::build_auth_try_packet(&info, &separator)
+  );
+  assert!(control.is_shutdown());
+}
+
+#[test]
+fn mismatched_separator_refused_in_clients() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let theirs = Separator::new("||").unwrap();
+
+  master.dispatch(
+    Arc::new(Mutex::new(control.clone())),
+    Client::build_auth_packet(&config.auth, &vec![8080], &theirs),
+  );
+  match Client::parse_packet(control.take_sent(), &theirs) {
+    | Ok(PacketType::AuthTry(packet)) => {
+      let info = AuthTryInfo::from_body(&packet.body).unwrap();
+      assert_eq!(info.status, AuthStatus::Forbidden);
+      assert_eq!(
+        info.reason,
+        Some(String::from("separator"))
+      );
+    },
+    | _ => panic!("Expected an auth result in the client's separator"),
+  }
+  assert!(control.is_shutdown());
+}
+
+#[test]
+fn matching_separator_authenticates() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+
+  master.dispatch(
+    Arc::new(Mutex::new(control.clone())),
+    Client::build_auth_packet(&config.auth, &vec![], &config.separator),
+  );
+  match Client::parse_packet(control.take_sent(), &config.separator) {
+    | Ok(PacketType::AuthTry(packet)) => {
+      let info = AuthTryInfo::from_body(&packet.body).unwrap();
+      assert_eq!(info.status, AuthStatus::Success);
+      assert_eq!(info.reason, None);
+    },
+    | _ => panic!("Expected an auth result"),
+  }
+  assert!(!control.is_shutdown());
+}
+
+#[test]
+fn data_before_auth_through_memory_stream() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+
+  master.dispatch(
+    Arc::new(Mutex::new(control.clone())),
+    Client::build_data_packet(
+      &Uuid::new_v4(),
+      &config.separator,
+      &"Hello".into(),
+    ),
+  );
+  assert!(control.take_sent().is_empty());
+  assert!(control.is_shutdown());
+}
+
+#[cfg(test)]
+fn sequence(ids: Vec<Uuid>) -> IdSource {
+  let ids = Mutex::new(VecDeque::from(ids));
+  Arc::new(move || ids.lock().unwrap().pop_front().unwrap())
+}
+
+#[test]
+fn data_routed_by_sequenced_id() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let first = Uuid::from_u128(1);
+  let second = Uuid::from_u128(2);
+  let mut master =
+    MasterListener::with_ids(&config, sequence(vec![first, second]));
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut slave = SlaveListener::new(&master.slave_config(8080, &handle));
+  let mut downstreams = Vec::new();
+  for _ in 0..2 {
+    let downstream =
+      TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    slave.on_new_connection(accepted.into_raw_fd());
+    downstreams.push(downstream);
+  }
+
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_data_packet(&second, &separator, &"Hello".into()),
+  );
+  let mut buffer = [0u8; 5];
+  downstreams[1].set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+  downstreams[1].read_exact(&mut buffer).unwrap();
+  assert_eq!(&buffer, "Hello".as_bytes());
+
+  downstreams[0].set_nonblocking(true).unwrap();
+  match downstreams[0].read(&mut buffer) {
+    | Err(err) => assert_eq!(err.kind(), ErrorKind::WouldBlock),
+    | Ok(read) => panic!("First connection got {read} bytes"),
+  }
+}
+
+#[test]
+fn shutdown_reaches_master_listener() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let mut master = MasterListener::new(&config);
+  let shutdown = master.shutdown_handle();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap();
+  master.on_server_created(listener.as_raw_fd());
+
+  // Accepted before the signal, handed to the handler after it.
+  let mut late = TcpStream::connect(address).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+
+  shutdown.shutdown();
+  assert!(shutdown.is_closed());
+  assert!(TcpStream::connect(address).is_err());
+
+  master.on_new_connection(accepted.into_raw_fd());
+  let mut buffer = [0u8; 1];
+  late.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+  assert_eq!(late.read(&mut buffer).unwrap(), 0);
+}
+
+#[test]
+fn custom_action_reaches_handler() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let pings = Arc::new(AtomicUsize::new(0));
+  let counter = Arc::clone(&pings);
+  assert!(master.register_action(
+    "PING",
+    Box::new(move |packet| {
+      assert_eq!(packet.body, "hi".as_bytes());
+      counter.fetch_add(1, Ordering::SeqCst);
+    })
+  ));
+
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  master.dispatch(
+    Arc::clone(&handle),
+    [b"PING", separator.as_bytes(), b"hi"].concat(),
+  );
+  assert_eq!(pings.load(Ordering::SeqCst), 1);
+
+  // Unregistered actions are logged and dropped, the connection stays up.
+  master.dispatch(
+    Arc::clone(&handle),
+    [b"PONG", separator.as_bytes()].concat(),
+  );
+  assert!(!control.is_shutdown());
+}
+
+#[test]
+fn failed_auths_block_source_ip() {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  config.max_auth_failures = Some(2);
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+  let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let fd = listener.accept().unwrap().0.into_raw_fd();
+  master.on_new_connection(fd);
+  for _ in 0..2 {
+    let control: Control = Arc::new(Mutex::new(MemoryStream::new(fd)));
+    master.dispatch(
+      control,
+      Client::build_auth_packet(b"wrong", &vec![], &separator),
+    );
+  }
+
+  let mut refused = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  master.on_new_connection(listener.accept().unwrap().0.into_raw_fd());
+  let mut buffer = [0u8; 1];
+  refused.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+  assert_eq!(refused.read(&mut buffer).unwrap(), 0);
+}
+
+#[cfg(test)]
+fn open_connection(master: &MasterListener) -> (Uuid, TcpStream) {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let downstream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let stream = Stream::from_tcp_stream(accepted);
+  let id = stream.id;
+  master.insert_connection(SenderPacket {
+    fd: stream.as_raw_fd(),
+    uuid: id,
+    peer: stream.peer,
+    accepted: Instant::now(),
+    window: None,
+    received: 0,
+    stats: Arc::new(ConnectionStats::new(8080, None)),
+    socket: Arc::new(Mutex::new(stream)),
+    control: Arc::new(Mutex::new(MemoryStream::new(-1))),
+  });
+  (id, downstream)
+}
+
+#[test]
+fn stop_accepting_keeps_connections() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let mut master = MasterListener::new(&config);
+  let shutdown = master.shutdown_handle();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap();
+  master.on_server_created(listener.as_raw_fd());
+  let (id, _downstream) = open_connection(&master);
+
+  shutdown.stop_accepting();
+  assert!(shutdown.is_closed());
+  assert!(TcpStream::connect(address).is_err());
+  assert_eq!(shutdown.open_connections(), 1);
+  assert!(master.close_connection(id));
+  assert_eq!(shutdown.open_connections(), 0);
+}
+
+#[tokio::test]
+async fn reuse_port_hands_off_while_draining() {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  config.reuse_port = true;
+  let mut master = MasterListener::new(&config);
+  let shutdown = master.shutdown_handle();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap();
+  master.on_server_created(listener.as_raw_fd());
+
+  // The next instance binds the same port while this one is still up.
+  let socket = TcpSocket::new_v4().unwrap();
+  socket.set_reuseport(true).unwrap();
+  socket.bind(address).unwrap();
+  let next = socket.listen(16).unwrap();
+
+  // A connection that never closes holds the drain until its deadline.
+  let _downstream = open_connection(&master);
+  let started = std::time::Instant::now();
+  shutdown.drain(Duration::from_millis(200));
+  assert!(started.elapsed() >= Duration::from_millis(200));
+  assert!(shutdown.is_closed());
+
+  let _client = TcpStream::connect(address).unwrap();
+  assert!(next.accept().await.is_ok());
+}
+
+#[test]
+fn reauth_requires_auth_again() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let reauth = master.reauth_handle();
+  assert_eq!(reauth.reauth(), 0);
+
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  let auth = Client::build_auth_packet(&config.auth, &vec![], &separator);
+  master.dispatch(Arc::clone(&handle), auth.clone());
+  control.take_sent();
+
+  assert_eq!(reauth.reauth(), 1);
+  let info = AuthTryInfo {
+    status: AuthStatus::Reauth,
+    name: None,
+    version: None,
+    integrity: None,
+    window: None,
+    encoding: None,
+    reason: None,
+  };
+  assert_eq!(
+    control.take_sent(),
+    Server::build_auth_try_packet(&info, &separator)
+  );
+
+  // Other packets are still handled while the AUTH is owed.
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_data_packet(
+      &Uuid::new_v4(),
+      &separator,
+      &"Hello".into(),
+    ),
+  );
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_noop_packet(&separator),
+  );
+  assert!(!control.is_shutdown());
+
+  // Sending AUTH again in time restores the session.
+  master.dispatch(Arc::clone(&handle), auth);
+  assert!(!control.is_shutdown());
+
+  // A wrong one drops the connection.
+  assert_eq!(reauth.reauth(), 1);
+  control.take_sent();
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(b"wrong", &vec![], &separator),
+  );
+  assert!(control.is_shutdown());
+}
+
+#[test]
+fn reauth_checks_the_new_secret() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let reauth = master.reauth_handle();
+  let first = MemoryStream::new(3);
+  let old = Client::build_auth_packet(&config.auth, &vec![], &separator);
+  master.dispatch(
+    Arc::new(Mutex::new(first.clone())),
+    old.clone(),
+  );
+
+  reauth.set_auth(b"rotated".to_vec());
+  assert_eq!(reauth.reauth(), 1);
+  master.dispatch(Arc::new(Mutex::new(first.clone())), old);
+  assert!(first.is_shutdown());
+
+  let second = MemoryStream::new(4);
+  master.dispatch(
+    Arc::new(Mutex::new(second.clone())),
+    Client::build_auth_packet(b"rotated", &vec![], &separator),
+  );
+  assert!(!second.is_shutdown());
+}
+
+#[test]
+fn reauth_times_out() {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  config.reauth_timeout_secs = 0;
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  master.dispatch(
+    Arc::new(Mutex::new(control.clone())),
+    Client::build_auth_packet(&config.auth, &vec![], &config.separator),
+  );
+
+  assert_eq!(master.reauth_handle().reauth(), 1);
+  thread::sleep(Duration::from_millis(100));
+  assert!(control.is_shutdown());
+}
+
+#[test]
+fn auth_is_kept_per_control_connection() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let first = MemoryStream::new(3);
+  let second = MemoryStream::new(4);
+  let third = MemoryStream::new(5);
+  let auth = Client::build_auth_packet(&config.auth, &vec![], &separator);
+  master.dispatch(
+    Arc::new(Mutex::new(first.clone())),
+    auth.clone(),
+  );
+  master.dispatch(
+    Arc::new(Mutex::new(second.clone())),
+    auth,
+  );
+
+  // Another connection being authenticated doesn't let this one skip AUTH.
+  master.dispatch(
+    Arc::new(Mutex::new(third.clone())),
+    Client::build_data_packet(
+      &Uuid::new_v4(),
+      &separator,
+      &"Hello".into(),
+    ),
+  );
+  assert!(third.is_shutdown());
+
+  // Nor does one of them dropping de-auth the other.
+  master.on_connection_removed(
+    3,
+    Error::new(ErrorKind::ConnectionReset, "gone"),
+  );
+  second.take_sent();
+  master.dispatch(
+    Arc::new(Mutex::new(second.clone())),
+    Client::build_noop_packet(&separator),
+  );
+  assert!(!second.is_shutdown());
+  assert_eq!(master.reauth_handle().reauth(), 1);
+}
+
+#[test]
+fn stuck_downstream_write_times_out() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  control.take_sent();
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut downstream =
+    TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let mut stream = Stream::from_tcp_stream(accepted);
+  stream.write_timeout = Some(Duration::from_millis(50));
+  let id = stream.id;
+  master.insert_connection(SenderPacket {
+    fd: stream.as_raw_fd(),
+    uuid: id,
+    peer: stream.peer,
+    accepted: Instant::now(),
+    window: None,
+    received: 0,
+    stats: Arc::new(ConnectionStats::new(8080, None)),
+    socket: Arc::new(Mutex::new(stream)),
+    control: Arc::clone(&handle),
+  });
+
+  // The downstream never reads, so its buffers fill up and stay full.
+  let data = Client::build_data_packet(&id, &separator, &vec![0u8; 1 << 20]);
+  let mut sent = Vec::new();
+  for _ in 0..64 {
+    master.dispatch(Arc::clone(&handle), data.clone());
+    sent = control.take_sent();
+    if !sent.is_empty() {
+      break;
+    }
+    thread::sleep(Duration::from_millis(60));
+  }
+  assert_eq!(
+    sent,
+    Server::close_connection_packet(&id, &separator)
+  );
+  assert!(!master.close_connection(id));
+  // Only what the socket buffers took reaches the other end before EOF.
+  downstream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+  let mut buffer = Vec::new();
+  assert!(downstream.read_to_end(&mut buffer).is_ok());
+}
+
+#[test]
+fn stuck_downstream_closed_without_more_data() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  control.take_sent();
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let _downstream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let mut stream = Stream::from_tcp_stream(accepted);
+  stream.write_timeout = Some(Duration::from_millis(50));
+  let id = stream.id;
+  master.insert_connection(SenderPacket {
+    fd: stream.as_raw_fd(),
+    uuid: id,
+    peer: stream.peer,
+    accepted: Instant::now(),
+    window: None,
+    received: 0,
+    stats: Arc::new(ConnectionStats::new(8080, None)),
+    socket: Arc::new(Mutex::new(stream)),
+    control: Arc::clone(&handle),
+  });
+
+  // More than the socket buffers take, and then nothing more.
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_data_packet(&id, &separator, &vec![0u8; 16 << 20]),
+  );
+  assert!(control.take_sent().is_empty());
+  assert_eq!(master.connection_ids(), vec![id]);
+
+  thread::sleep(Duration::from_millis(60));
+  flush_pending(&master.connections(), &separator);
+  assert_eq!(
+    control.take_sent(),
+    Server::close_connection_packet(&id, &separator)
+  );
+  assert!(master.connection_ids().is_empty());
+}
+
+#[test]
+fn data_window_acks_and_advances() {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  config.data_window = Some(2);
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  let info = AuthTryInfo {
+    status: AuthStatus::Success,
+    name: None,
+    version: None,
+    integrity: None,
+    window: Some(2),
+    encoding: Some(HashEncoding::Hex),
+    reason: None,
+  };
+  assert_eq!(
+    control.take_sent(),
+    Server::build_auth_try_packet(&info, &separator)
+  );
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut downstream =
+    TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let stream = Stream::from_tcp_stream(accepted);
+  let id = stream.id;
+  let window = Arc::new(Window::new(2));
+  master.insert_connection(SenderPacket {
+    fd: stream.as_raw_fd(),
+    uuid: id,
+    peer: stream.peer,
+    accepted: Instant::now(),
+    window: Some(Arc::clone(&window)),
+    received: 0,
+    stats: Arc::new(ConnectionStats::new(8080, None)),
+    socket: Arc::new(Mutex::new(stream)),
+    control: Arc::clone(&handle),
+  });
+
+  // Data from the client is acknowledged once written downstream.
+  for sequence in 1..=2 {
+    master.dispatch(
+      Arc::clone(&handle),
+      Client::build_data_packet(&id, &separator, &"Hello".into()),
+    );
+    assert_eq!(
+      control.take_sent(),
+      Server::build_ack_packet(&id, sequence, &separator)
+    );
+  }
+  let mut buffer = [0u8; 16];
+  downstream.read_exact(&mut buffer[..10]).unwrap();
+  assert_eq!(&buffer[..10], b"HelloHello");
+
+  // Data toward the client only moves on as the client acknowledges it.
+  assert!(window.try_reserve());
+  assert!(window.try_reserve());
+  assert!(!window.try_reserve());
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_ack_packet(&id, 1, &separator),
+  );
+  assert_eq!(window.in_flight(), 1);
+  assert!(window.try_reserve());
+
+  // Closing the connection lets go of anything waiting on the window.
+  assert!(master.close_connection(id));
+  assert!(!window.try_reserve());
+}
+
+#[test]
+fn slow_packets_are_reported() {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  config.slow_packet_ms = Some(10);
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  assert!(master.register_action(
+    "STALL",
+    Box::new(|_| thread::sleep(Duration::from_millis(30)))
+  ));
+
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_noop_packet(&separator),
+  );
+  assert_eq!(master.slow_packets(), 0);
+
+  master.dispatch(
+    Arc::clone(&handle),
+    [b"STALL", separator.as_bytes()].concat(),
+  );
+  assert_eq!(master.slow_packets(), 1);
+}
+
+#[test]
+fn slow_auth_is_reported() {
+  let mut config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  // Anything at all counts as slow
+  config.slow_packet_ms = Some(0);
+  let mut master = MasterListener::new(&config);
+  master.dispatch(
+    Arc::new(Mutex::new(MemoryStream::new(3))),
+    Client::build_auth_packet(&config.auth, &vec![], &config.separator),
+  );
+  assert_eq!(master.slow_packets(), 1);
+}
+
+#[test]
+fn close_forgets_connection() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+
+  let ids = [Uuid::new_v4(), Uuid::new_v4()];
+  for (fd, id) in ids.iter().enumerate() {
+    let downstream = MemoryStream::new(4 + fd as i32);
+    master.insert_connection(SenderPacket {
+      socket: Arc::new(Mutex::new(downstream.clone())),
+      fd: downstream.as_raw_fd(),
+      uuid: *id,
+      control: Arc::clone(&handle),
+      peer: None,
+      accepted: Instant::now(),
+      window: None,
+      received: 0,
+      stats: Arc::new(ConnectionStats::new(8080, None)),
+    });
+  }
+  let mut registered = master.connection_ids();
+  registered.sort();
+  let mut expected = ids.to_vec();
+  expected.sort();
+  assert_eq!(registered, expected);
+
+  master.dispatch(
+    Arc::clone(&handle),
+    Server::close_connection_packet(&ids[0], &separator),
+  );
+  assert_eq!(master.connection_ids(), vec![ids[1]]);
+}
+
+#[test]
+fn forwards_bind_apart_from_control() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.control_listen.host = String::from("127.0.0.1");
+  settings.forward_bind = Some(String::from("127.0.0.2"));
+  settings.forward_bind_ports =
+    HashMap::from([(8081, String::from("127.0.0.3"))]);
+  let master = MasterListener::new(&file_to_runtime(settings));
+  let handle: Control = Arc::new(Mutex::new(MemoryStream::new(3)));
+  assert_eq!(
+    master.slave_config(8080, &handle).listen.addr,
+    "127.0.0.2"
+  );
+  assert_eq!(
+    master.slave_config(8081, &handle).listen.addr,
+    "127.0.0.3"
+  );
+
+  // Both can hold the same port, each on its own address.
+  let control = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+  let port = control.local_addr().unwrap().port();
+  let forward = master.slave_config(port, &handle).listen;
+  let forward =
+    TcpListener::bind((forward.addr.as_str(), forward.port)).unwrap();
+  assert_eq!(
+    forward.local_addr().unwrap().to_string(),
+    format!("127.0.0.2:{port}")
+  );
+
+  // Without a forward_bind, forwards are bound where the control port is.
+  let master = MasterListener::new(&file_to_runtime(
+    DEFAULT_SETTINGS.clone(),
+  ));
+  assert_eq!(
+    master.slave_config(8080, &handle).listen.addr,
+    DEFAULT_SETTINGS.control_listen.host
+  );
+}
+
+#[test]
+fn unknown_id_data_follows_policy() {
+  for policy in [UnknownIdPolicy::Drop, UnknownIdPolicy::Close] {
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.unknown_id = policy;
+    let config = file_to_runtime(settings);
+    let separator = config.separator.clone();
+    let mut master = MasterListener::new(&config);
+    let control = MemoryStream::new(3);
+    let handle: Control = Arc::new(Mutex::new(control.clone()));
+    master.dispatch(
+      Arc::clone(&handle),
+      Client::build_auth_packet(&config.auth, &vec![], &separator),
+    );
+    control.take_sent();
+
+    let id = Uuid::new_v4();
+    master.dispatch(
+      Arc::clone(&handle),
+      Client::build_data_packet(&id, &separator, &"late".into()),
+    );
+    let expected = match policy {
+      | UnknownIdPolicy::Drop => Vec::new(),
+      | UnknownIdPolicy::Close => {
+        Server::close_connection_packet(&id, &separator)
+      },
+    };
+    assert_eq!(
+      control.take_sent(),
+      expected,
+      "{policy:?}"
+    );
+    assert!(!control.is_shutdown());
+  }
+}
+
+#[test]
+fn websocket_transport_authenticates() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.transport = Transport::WebSocket {
+    path: String::from("/proxy"),
+  };
+  let config = file_to_runtime(settings);
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+
+  let request = upgrade_request(
+    "localhost", "/proxy", "dGhlIHNhbXBsZSBub25jZQ==",
+  );
+  let (head, rest) = request.split_at(10);
+  master.receive(control.clone(), head.to_vec());
+  assert!(control.take_sent().is_empty());
+  // The first packet may ride along with the end of the upgrade request.
+  let mut bytes = rest.to_vec();
+  bytes.extend(client_frame(
+    &Client::build_auth_packet(&config.auth, &vec![], &separator),
+  ));
+  master.receive(control.clone(), bytes);
+
+  let sent = control.take_sent();
+  let end = sent.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+  assert!(sent.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+  assert!(String::from_utf8_lossy(&sent[..end])
+    .contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+  let messages = Decoder::new().push(&sent[end + 4..]).unwrap();
+  match messages.as_slice() {
+    | [Message::Binary(packet)] => {
+      assert!(packet.starts_with("AUTHTRY\u{0000}success".as_bytes()))
+    },
+    | messages => panic!("expected one message, got {messages:?}"),
+  }
+  assert!(!control.is_shutdown());
+}
+
+#[test]
+fn websocket_transport_refuses_other_paths() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.transport = Transport::WebSocket {
+    path: String::from("/proxy"),
+  };
+  let config = file_to_runtime(settings);
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+
+  master.receive(
+    control.clone(),
+    upgrade_request(
+      "localhost", "/", "dGhlIHNhbXBsZSBub25jZQ==",
+    ),
+  );
+  assert!(control.take_sent().starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+  assert!(control.is_shutdown());
+}
+
+#[test]
+fn websocket_buffers_are_bounded_globally() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.transport = Transport::WebSocket {
+    path: String::from("/proxy"),
+  };
+  settings.max_total_buffer_bytes = Some(100_000);
+  let config = file_to_runtime(settings);
+  let mut master = MasterListener::new(&config);
+  let frame = client_frame(&vec![7u8; 60_000]);
+  let controls: Vec<MemoryStream> = (3..6).map(MemoryStream::new).collect();
+  for control in &controls {
+    master.receive(
+      control.clone(),
+      upgrade_request(
+        "localhost", "/proxy", "dGhlIHNhbXBsZSBub25jZQ==",
+      ),
+    );
+    assert!(control.take_sent().starts_with(b"HTTP/1.1 101"));
+    master.receive(
+      control.clone(),
+      frame[..40_000].to_vec(),
+    );
+  }
+
+  // The third partial frame goes past the limit, but the connections already
+  // open are kept.
+  for control in &controls {
+    assert!(control.take_sent().is_empty());
+    assert!(!control.is_shutdown());
+  }
+
+  // New ones are refused until those frames complete.
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let _stream =
+    master.on_new_connection(listener.accept().unwrap().0.into_raw_fd());
+  let mut response = Vec::new();
+  client.read_to_end(&mut response).unwrap();
+  assert!(response.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+}
+
+#[test]
+fn refuses_control_connections_at_capacity() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.max_connections = Some(1);
+  let config = file_to_runtime(settings);
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut fds = Vec::new();
+  let mut clients = Vec::new();
+  for _ in 0..2 {
+    clients.push(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+    let fd = listener.accept().unwrap().0.into_raw_fd();
+    master.on_new_connection(fd);
+    fds.push(fd);
+  }
+
+  let full = MemoryStream::new(fds[1]);
+  master.dispatch(
+    Arc::new(Mutex::new(full.clone())),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  let sent = full.take_sent();
+  let info = match Client::parse_packet(sent, &separator) {
+    | Ok(PacketType::AuthTry(packet)) => {
+      AuthTryInfo::from_body(&packet.body).unwrap()
+    },
+    | _ => panic!("expected an AUTHTRY"),
+  };
+  assert_eq!(info.status, AuthStatus::Forbidden);
+  assert_eq!(
+    info.reason.as_deref(),
+    Some(AT_CAPACITY)
+  );
+  assert!(full.is_shutdown());
+
+  // The connection under the limit is served as usual.
+  let control = MemoryStream::new(fds[0]);
+  master.dispatch(
+    Arc::new(Mutex::new(control.clone())),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+  assert!(control.take_sent().starts_with("AUTHTRY\u{0000}success".as_bytes()));
+}
+
+#[tokio::test]
+async fn silent_connections_at_capacity_are_closed() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.max_connections = Some(1);
+  let mut master = MasterListener::new(&file_to_runtime(settings));
+  tokio::spawn(close_silent(master.over_capacity()));
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut clients = Vec::new();
+  // Kept, as dropping them would close the connections
+  let mut streams = Vec::new();
+  let mut fds = Vec::new();
+  for _ in 0..3 {
+    clients.push(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+    let fd = listener.accept().unwrap().0.into_raw_fd();
+    streams.push(master.on_new_connection(fd));
+    fds.push(fd);
+  }
+  // One that goes away by itself is forgotten.
+  master.on_connection_removed(
+    fds[2],
+    Error::new(ErrorKind::ConnectionReset, "gone"),
+  );
+  assert!(!master.over_capacity().lock().unwrap().contains_key(&fds[2]));
+
+  sleep(Duration::from_millis(
+    OVER_CAPACITY_GRACE_MS + 100,
+  ))
+  .await;
+  let mut buffer = [0u8; 16];
+  clients[1].set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  assert_eq!(clients[1].read(&mut buffer).unwrap(), 0);
+  assert!(master.over_capacity().lock().unwrap().is_empty());
+
+  // The connection under the limit is left alone.
+  clients[0].set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+  assert!(clients[0].read(&mut buffer).is_err());
+}
+
+#[test]
+fn empty_auth_body_refused() {
+  let mut settings = DEFAULT_SETTINGS.clone();
+  settings.auth = Vec::new();
+  let separator = settings.separator.clone();
+  let auth = Client::build_auth_packet(&[], &vec![], &separator);
+
+  let mut master = MasterListener::new(&file_to_runtime(settings.clone()));
+  let control = MemoryStream::new(3);
+  master.dispatch(
+    Arc::new(Mutex::new(control.clone())),
+    auth.clone(),
+  );
+  assert!(control
+    .take_sent()
+    .starts_with("AUTHTRY\u{0000}forbidden".as_bytes()));
+
+  settings.allow_empty_auth = true;
+  let mut master = MasterListener::new(&file_to_runtime(settings));
+  let control = MemoryStream::new(3);
+  master.dispatch(
+    Arc::new(Mutex::new(control.clone())),
+    auth,
+  );
+  assert!(control.take_sent().starts_with("AUTHTRY\u{0000}success".as_bytes()));
+}
+
+/// Parses `packet` the way the master does, for driving its handlers directly
+#[cfg(test)]
+fn parsed(
+  config: &crate::config::Config<proxy_router::constants::Runtime>,
+  packet: Vec<u8>,
+) -> PacketType<Client> {
+  let options = ParseOptions {
+    hash_encoding: config.hash_encoding,
+    integrity: config.integrity,
+    ..ParseOptions::default()
+  };
+  Server::parse_packet_with(packet, &config.separator, &options).unwrap()
+}
+
+#[test]
+fn auth_handler_answers_directly() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+
+  let packet = match parsed(
+    &config,
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  ) {
+    | PacketType::Auth(packet) => packet,
+    | _ => panic!("expected an auth packet"),
+  };
+  master.handle_auth(&handle, &mut control.clone(), packet);
+  let info = AuthTryInfo {
+    status: AuthStatus::Success,
+    name: None,
+    version: None,
+    integrity: None,
+    window: None,
+    encoding: Some(HashEncoding::Hex),
+    reason: None,
+  };
+  assert_eq!(
+    control.take_sent(),
+    Server::build_auth_try_packet(&info, &separator)
+  );
+  assert!(!control.is_shutdown());
+
+  let control = MemoryStream::new(4);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  let packet = match parsed(
+    &config,
+    Client::build_auth_packet(b"wrong", &vec![], &separator),
+  ) {
+    | PacketType::Auth(packet) => packet,
+    | _ => panic!("expected an auth packet"),
+  };
+  master.handle_auth(&handle, &mut control.clone(), packet);
+  assert!(control.is_shutdown());
+}
+
+#[test]
+fn data_and_close_handlers_reach_connection() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let downstream = MemoryStream::new(4);
+  let id = Uuid::new_v4();
+  master.insert_connection(SenderPacket {
+    socket: Arc::new(Mutex::new(downstream.clone())),
+    fd: downstream.as_raw_fd(),
+    uuid: id,
+    control: Arc::new(Mutex::new(control.clone())),
+    peer: None,
+    accepted: Instant::now(),
+    window: None,
+    received: 0,
+    stats: Arc::new(ConnectionStats::new(8080, None)),
+  });
+
+  // Handlers leave checking auth to the dispatcher.
+  match parsed(
+    &config,
+    Client::build_data_packet(&id, &separator, &"Hello".into()),
+  ) {
+    | PacketType::Data(packet) => {
+      let body = packet.body.clone();
+      master.handle_data(&mut control.clone(), packet, &body)
+    },
+    | _ => panic!("expected a data packet"),
+  }
+  assert_eq!(
+    downstream.take_sent(),
+    b"Hello".to_vec()
+  );
+
+  match parsed(
+    &config,
+    Server::close_connection_packet(&id, &separator),
+  ) {
+    | PacketType::Close(packet) => {
+      master.handle_close(&mut control.clone(), packet)
+    },
+    | _ => panic!("expected a close packet"),
+  }
+  assert!(downstream.is_shutdown());
+  assert!(master.connection_ids().is_empty());
+  assert!(!control.is_shutdown());
+}
+
+#[test]
+fn ack_noop_and_custom_handlers() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let downstream = MemoryStream::new(4);
+  let id = Uuid::new_v4();
+  let window = Arc::new(Window::new(2));
+  master.insert_connection(SenderPacket {
+    socket: Arc::new(Mutex::new(downstream.clone())),
+    fd: downstream.as_raw_fd(),
+    uuid: id,
+    control: Arc::new(Mutex::new(control.clone())),
+    peer: None,
+    accepted: Instant::now(),
+    window: Some(Arc::clone(&window)),
+    received: 0,
+    stats: Arc::new(ConnectionStats::new(8080, None)),
+  });
+
+  assert!(window.try_reserve());
+  assert!(window.try_reserve());
+  match parsed(
+    &config,
+    Client::build_ack_packet(&id, 2, &separator),
+  ) {
+    | PacketType::Ack(packet) => master.handle_ack(packet),
+    | _ => panic!("expected an ack packet"),
+  }
+  assert_eq!(window.in_flight(), 0);
+
+  match parsed(
+    &config,
+    Client::build_noop_packet(&separator),
+  ) {
+    | PacketType::Noop(packet) => master.handle_noop(packet),
+    | _ => panic!("expected a noop packet"),
+  }
+
+  let pings = Arc::new(AtomicUsize::new(0));
+  let counter = Arc::clone(&pings);
+  assert!(master.register_action(
+    "PING",
+    Box::new(move |_| {
+      counter.fetch_add(1, Ordering::SeqCst);
+    })
+  ));
+  match parsed(
+    &config,
+    [b"PING", separator.as_bytes()].concat(),
+  ) {
+    | PacketType::Custom(packet) => master.handle_custom(packet),
+    | _ => panic!("expected a custom packet"),
+  }
+  assert_eq!(pings.load(Ordering::SeqCst), 1);
+  assert!(control.take_sent().is_empty());
+  assert!(!control.is_shutdown());
+}
+
+#[test]
+fn data_body_checked_where_it_was_read() {
+  let config = file_to_runtime(DEFAULT_SETTINGS.clone());
+  let separator = config.separator.clone();
+  let mut master = MasterListener::new(&config);
+  let control = MemoryStream::new(3);
+  let handle: Control = Arc::new(Mutex::new(control.clone()));
+  master.dispatch(
+    Arc::clone(&handle),
+    Client::build_auth_packet(&config.auth, &vec![], &separator),
+  );
+
+  let downstream = MemoryStream::new(4);
+  let id = Uuid::new_v4();
+  master.insert_connection(SenderPacket {
+    socket: Arc::new(Mutex::new(downstream.clone())),
+    fd: downstream.as_raw_fd(),
+    uuid: id,
+    control: Arc::clone(&handle),
+    peer: None,
+    accepted: Instant::now(),
+    window: None,
+    received: 0,
+    stats: Arc::new(ConnectionStats::new(8080, None)),
+  });
+
+  // A body that no longer matches its hashes is never written.
+  let mut corrupted =
+    Client::build_data_packet(&id, &separator, &"Hello".into());
+  *corrupted.last_mut().unwrap() ^= 1;
+  master.dispatch(Arc::clone(&handle), corrupted);
+  assert!(downstream.take_sent().is_empty());
+
+  // Lowercase actions take the same path.
+  let mut packet = Client::build_data_packet(&id, &separator, &"Hello".into());
+  packet[..4].make_ascii_lowercase();
+  master.dispatch(Arc::clone(&handle), packet);
+  assert_eq!(
+    downstream.take_sent(),
+    b"Hello".to_vec()
+  );
+  assert!(!control.is_shutdown());
+}
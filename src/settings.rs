@@ -0,0 +1,163 @@
+//! Reading and writing the settings file, the same for the client and the
+//! server apart from the settings each of them keeps in it
+
+use std::{
+  fs::{read_to_string, symlink_metadata, OpenOptions},
+  io::{ErrorKind, Write},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{from_str, to_string_pretty, to_value, Error, Value};
+use simplelog::debug;
+
+use crate::{
+  constants::SETTING_FILE_PATH,
+  functions::{ArrOrStr, HashEncoding, Separator, SeparatorError},
+};
+
+/// The settings one side keeps in its settings file
+pub trait Settings: Serialize + DeserializeOwned + 'static {
+  /// Why settings that parsed, separator included, still can't be used
+  type Error;
+
+  /// Settings still read under the name they had before, as (old, new)
+  const RENAMED_FIELDS: &'static [(&'static str, &'static str)] = &[];
+
+  /// Filled in for what the file leaves out, and written by `init`
+  fn defaults() -> &'static Self;
+
+  fn separator(&self) -> &Separator;
+
+  fn hash_encoding(&self) -> HashEncoding;
+
+  /// Checks what only this side's settings hold, once the separator passed
+  fn check(&self) -> Result<(), Self::Error>;
+}
+
+pub enum LoadError<E> {
+  Read(std::io::Error),
+  /// Something other than a regular file is at the path, such as a directory
+  /// or a dangling symlink, so it must not be replaced with the defaults
+  NotAFile(std::io::Error),
+  /// The file was read but is not valid settings, along with exactly what
+  /// was read so it can be reported and backed up as-is
+  Invalid(String, Error),
+  /// The separator can't be used, as is or with the hash encoding
+  Separator(SeparatorError),
+  /// [`Settings::check`] refused the settings
+  Check(E),
+}
+
+/// Why the separator in the settings `contents` can't be used, if that is
+/// what keeps them from parsing
+fn separator_error(contents: &str) -> Option<SeparatorError> {
+  let given = from_str::<Value>(contents).ok()?;
+  ArrOrStr::deserialize(given.get("separator")?).ok()?.into_separator().err()
+}
+
+/// Reads the settings file once and parses what was read, so a file swapped
+/// out mid-load can't make the parse and the error report disagree
+pub fn load_settings<S: Settings>(
+  path: &str,
+) -> Result<S, LoadError<S::Error>> {
+  let contents =
+    read_to_string(path).map_err(|e| match symlink_metadata(path) {
+      | Ok(metadata) if !metadata.is_file() => LoadError::NotAFile(e),
+      | _ => LoadError::Read(e),
+    })?;
+  let settings: S = match from_str(&contents) {
+    | Ok(settings) => settings,
+    | Err(e) => {
+      return Err(match separator_error(&contents) {
+        | Some(err) => LoadError::Separator(err),
+        | None => LoadError::Invalid(contents, e),
+      })
+    },
+  };
+  for (field, value) in defaulted_fields::<S>(&contents) {
+    debug!("{field} is not set, using the default ({value})");
+  }
+  settings
+    .separator()
+    .check(settings.hash_encoding())
+    .map_err(LoadError::Separator)?;
+  settings.check().map_err(LoadError::Check)?;
+  Ok(settings)
+}
+
+/// Settings left out of `contents`, along with the defaults they were filled
+/// in with
+pub fn defaulted_fields<S: Settings>(contents: &str) -> Vec<(String, Value)> {
+  let given = match from_str::<Value>(contents) {
+    | Ok(Value::Object(given)) => given,
+    | _ => return Vec::new(),
+  };
+  match to_value(S::defaults()) {
+    | Ok(Value::Object(defaults)) => defaults
+      .into_iter()
+      .filter(|(field, _)| {
+        !given.contains_key(field)
+          && !S::RENAMED_FIELDS
+            .iter()
+            .any(|(old, new)| new == field && given.contains_key(*old))
+      })
+      .collect(),
+    | _ => Vec::new(),
+  }
+}
+
+pub enum InitError {
+  /// The file is already there and `force` was not given
+  Exists,
+  Serialize(Error),
+  Write(std::io::Error),
+}
+
+/// Writes the default settings to `path`, leaving an existing file alone
+/// unless `force` is set
+pub fn init_settings<S: Settings>(
+  path: &str, force: bool,
+) -> Result<(), InitError> {
+  let settings =
+    to_string_pretty(S::defaults()).map_err(InitError::Serialize)?;
+  let file = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .create_new(!force)
+    .open(path);
+  let mut file = match file {
+    | Ok(file) => file,
+    | Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+      return Err(InitError::Exists)
+    },
+    | Err(e) => return Err(InitError::Write(e)),
+  };
+  file.write_all(settings.as_bytes()).map_err(InitError::Write)
+}
+
+/// Runs the `init` subcommand, returning the exit code. Runs before the
+/// logger is set up, so nothing is logged or rotated, and reports to the
+/// terminal instead.
+pub fn init<S: Settings>(force: bool) -> i32 {
+  match init_settings::<S>(SETTING_FILE_PATH, force) {
+    | Ok(_) => {
+      println!("Default settings written to {SETTING_FILE_PATH}");
+      0
+    },
+    | Err(InitError::Exists) => {
+      eprintln!(
+        "{SETTING_FILE_PATH} already exists, pass --force to overwrite it"
+      );
+      1
+    },
+    | Err(InitError::Serialize(e)) => {
+      eprintln!("Failed to serialize default settings: {e}");
+      1
+    },
+    | Err(InitError::Write(e)) => {
+      eprintln!("Failed to write {SETTING_FILE_PATH}: {e}");
+      1
+    },
+  }
+}
@@ -0,0 +1,232 @@
+#[allow(unused_imports)]
+use crate::constants::{
+  ignore_sigpipe, peer_label, resolve_auth, PendingWrite, Stream,
+};
+#[allow(unused_imports)]
+use hydrogen::Stream as HydrogenStream;
+#[allow(unused_imports)]
+use std::{
+  io::{BufWriter, Error, ErrorKind, Read, Write},
+  net::{Shutdown, TcpListener, TcpStream},
+  thread::sleep,
+  time::Duration,
+};
+
+#[test]
+fn stream_records_peer_address() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let stream = Stream::from_tcp_stream(accepted);
+
+  assert_eq!(
+    stream.peer,
+    Some(client.local_addr().unwrap())
+  );
+  assert_eq!(
+    stream.peer_label(),
+    client.local_addr().unwrap().to_string()
+  );
+  assert_eq!(stream.clone().peer, stream.peer);
+}
+
+#[test]
+fn peer_label_without_address() {
+  assert_eq!(peer_label(&None), "unknown peer");
+}
+
+/// Accepts at most `per_write` bytes per call and `budget` bytes in total
+/// before reporting `WouldBlock`, like a full nonblocking socket
+#[cfg(test)]
+struct ChunkedWriter {
+  written: Vec<u8>,
+  per_write: usize,
+  budget: usize,
+}
+
+#[cfg(test)]
+impl Write for ChunkedWriter {
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    if self.budget == 0 {
+      return Err(Error::from(ErrorKind::WouldBlock));
+    }
+    let bytes = buf.len().min(self.per_write).min(self.budget);
+    self.written.extend_from_slice(&buf[..bytes]);
+    self.budget -= bytes;
+    Ok(bytes)
+  }
+
+  fn flush(&mut self) -> Result<(), Error> {
+    Ok(())
+  }
+}
+
+#[test]
+fn pending_write_keeps_unwritten_tail() {
+  let mut writer = ChunkedWriter {
+    written: Vec::new(),
+    per_write: 3,
+    budget: 10,
+  };
+  let mut pending = PendingWrite::default();
+  let first: Vec<u8> = (0..16).collect();
+  let second: Vec<u8> = (16..32).collect();
+
+  let err = pending.write(&mut writer, &first).unwrap_err();
+  assert_eq!(err.kind(), ErrorKind::WouldBlock);
+  assert_eq!(writer.written, first[..10].to_vec());
+  assert!(!pending.is_empty());
+
+  writer.budget = 8;
+  let err = pending.write(&mut writer, &second).unwrap_err();
+  assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+  writer.budget = usize::MAX;
+  pending.write(&mut writer, &[]).unwrap();
+  assert!(pending.is_empty());
+  assert_eq!(writer.written, [first, second].concat());
+}
+
+#[test]
+fn stream_reports_hang_up_after_data() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let mut stream = Stream::from_tcp_stream(accepted);
+
+  client.write_all("Hello".as_bytes()).unwrap();
+  client.shutdown(Shutdown::Write).unwrap();
+  sleep(Duration::from_millis(50));
+
+  assert_eq!(
+    stream.recv().unwrap(),
+    vec!["Hello".as_bytes().to_vec()]
+  );
+  let err = stream.recv().unwrap_err();
+  assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn stream_write_times_out_on_stuck_peer() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let mut stream = Stream::from_tcp_stream(accepted);
+  stream.write_timeout = Some(Duration::from_millis(50));
+
+  // The client never reads, so once the socket buffers stop growing the
+  // unwritten tail is left waiting past the timeout.
+  let chunk = vec![0u8; 1 << 20];
+  let mut timed_out = false;
+  for _ in 0..64 {
+    match stream.send(&chunk) {
+      | Ok(_) => (),
+      | Err(err) if err.kind() == ErrorKind::WouldBlock => {
+        sleep(Duration::from_millis(60))
+      },
+      | Err(err) => {
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        timed_out = true;
+        break;
+      },
+    }
+  }
+  assert!(timed_out);
+}
+
+#[test]
+fn stream_clones_share_pending_write() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (accepted, _) = listener.accept().unwrap();
+  let mut stream = Stream::from_tcp_stream(accepted);
+  let mut writer = stream.clone();
+
+  // Written through the clone until the client's buffers are full.
+  let chunk = vec![7u8; 1 << 20];
+  let mut sent = 0;
+  loop {
+    sent += chunk.len();
+    match writer.send(&chunk) {
+      | Ok(_) => (),
+      | Err(err) => {
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        break;
+      },
+    }
+  }
+  drop(writer);
+
+  // The original pushes out the tail the clone left behind.
+  let reader = std::thread::spawn(move || {
+    let mut buffer = vec![0u8; 1 << 16];
+    let mut read = 0;
+    while read < sent {
+      read += client.read(&mut buffer).unwrap();
+    }
+    read
+  });
+  while let Err(err) = stream.send(&[]) {
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    sleep(Duration::from_millis(10));
+  }
+  assert_eq!(reader.join().unwrap(), sent);
+}
+
+#[test]
+fn write_to_closed_socket_fails() {
+  ignore_sigpipe();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  drop(listener.accept().unwrap());
+  // The first writes can still land in the buffer before the reset arrives.
+  let error = (0..100)
+    .find_map(|_| {
+      sleep(Duration::from_millis(1));
+      stream.write_all(b"lost").err()
+    })
+    .expect("Writing to a closed socket should fail");
+  assert!(matches!(
+    error.kind(),
+    ErrorKind::BrokenPipe | ErrorKind::ConnectionReset
+  ));
+}
+
+#[test]
+fn pending_write_flushes_to_peer() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  let (mut peer, _) = listener.accept().unwrap();
+  peer.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+  // Far larger than the write, so only a flush gets it out.
+  let mut writer = BufWriter::with_capacity(65536, stream);
+  let mut pending = PendingWrite::default();
+
+  pending.write(&mut writer, b"ping").unwrap();
+  let mut buffer = [0u8; 4];
+  peer.read_exact(&mut buffer).unwrap();
+  assert_eq!(&buffer, b"ping");
+}
+
+#[test]
+fn auth_resolution_precedence() {
+  let file = b"from file".to_vec();
+  let env = || Some(b"from env".to_vec());
+  let prompted = || Some(b"typed".to_vec());
+  assert_eq!(
+    resolve_auth(file.clone(), None, None),
+    file
+  );
+  assert_eq!(
+    resolve_auth(file.clone(), env(), None),
+    b"from env"
+  );
+  assert_eq!(
+    resolve_auth(file.clone(), None, prompted()),
+    b"typed"
+  );
+  assert_eq!(
+    resolve_auth(file, env(), prompted()),
+    b"typed"
+  );
+}
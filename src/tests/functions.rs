@@ -1,10 +1,19 @@
 #[allow(unused_imports)]
 use crate::functions::{
-  hash_sha1, hash_sha512, split, Client, Packet, PacketAction, PacketType,
-  Server,
+  check_id, constant_time_eq, hash_sha1, hash_sha1_with, hash_sha512,
+  hash_sha512_with, split, ActionRegistry, AddressError, ArrOrStr, AuthStatus,
+  AuthTryInfo, Client, CustomPacket, Endpoint, Forward, HashEncoding,
+  Integrity, OpenInfo, Packet, PacketAction, PacketType, ParseError,
+  ParseErrorType, ParseOptions, PortSet, PortSetError, Separator,
+  SeparatorError, Server,
 };
 #[allow(unused_imports)]
-use std::str::FromStr;
+use std::{
+  collections::HashSet,
+  io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+  net::SocketAddr,
+  str::FromStr,
+};
 #[allow(unused_imports)]
 use uuid::Uuid;
 
@@ -237,6 +246,34 @@ fn split_to_none() {
   }
 }
 
+#[test]
+fn split_partial_then_full_match() {
+  let packet: Vec<u8> = vec![0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0x2];
+  let separator: Vec<u8> = vec![0x0, 0x0, 0x0];
+  assert_eq!(
+    split(&packet, &separator),
+    Some((vec![0x0, 0x0, 0x1], vec![0x2]))
+  );
+}
+
+#[test]
+fn split_match_inside_partial_match() {
+  // The byte that breaks the partial match starts the real separator.
+  let packet: Vec<u8> = vec![0x9, 0x0, 0x0, 0x1, 0x2];
+  let separator: Vec<u8> = vec![0x0, 0x1];
+  assert_eq!(
+    split(&packet, &separator),
+    Some((vec![0x9, 0x0], vec![0x2]))
+  );
+
+  let packet: Vec<u8> = vec![0x0, 0x0, 0x0, 0x1, 0x2];
+  let separator: Vec<u8> = vec![0x0, 0x0, 0x1];
+  assert_eq!(
+    split(&packet, &separator),
+    Some((vec![0x0], vec![0x2]))
+  );
+}
+
 #[test]
 fn split_some_to_none() {
   println!(
@@ -283,14 +320,15 @@ fn split_some_to_none() {
 #[test]
 fn auth_packet() {
   let packet_test = Client::build_auth_packet(
-    &String::from("123"),
+    b"123",
     &vec![3000, 4000, 5000],
-    &String::from("\u{0000}"),
+    &Separator::default(),
   );
 
   let packet = vec![
-    0x41, 0x55, 0x54, 0x48, 0x20, 0x33, 0x30, 0x30, 0x30, 0x2C, 0x34, 0x30,
-    0x30, 0x30, 0x2C, 0x35, 0x30, 0x30, 0x30, 0x0, 0x31, 0x32, 0x33,
+    0x41, 0x55, 0x54, 0x48, 0x20, 0x31, 0x34, 0x20, 0x30, 0x30, 0x0, 0x33,
+    0x30, 0x30, 0x30, 0x2C, 0x34, 0x30, 0x30, 0x30, 0x2C, 0x35, 0x30, 0x30,
+    0x30, 0x31, 0x32, 0x33,
   ];
 
   assert_eq!(packet_test, packet);
@@ -301,7 +339,11 @@ fn data_packet_client() {
   let id = "8c95a08a-97d1-4330-b5bf-87866baae5de";
   let id = Uuid::from_str(id).unwrap();
   let data = vec![0x0, 0x01, 0x26, 0x42, 0xAF, 0xFF];
-  let packet_test = Client::build_data_packet(&id, "\u{0000}", &data.clone());
+  let packet_test = Client::build_data_packet(
+    &id,
+    &Separator::default(),
+    &data.clone(),
+  );
 
   let sha1_hash = hash_sha1(&data).as_bytes().to_vec();
   let sha512_hash = hash_sha512(&data).as_bytes().to_vec();
@@ -323,8 +365,12 @@ fn data_packet_server() {
   let id = "8c95a08a-97d1-4330-b5bf-87866baae5de";
   let id = Uuid::from_str(id).unwrap();
   let data = vec![0x0, 0x01, 0x26, 0x42, 0xAF, 0xFF];
-  let packet_test =
-    Server::build_data_packet(&id, &3000, "\u{0000}", &data.clone());
+  let packet_test = Server::build_data_packet(
+    &id,
+    &3000,
+    &Separator::default(),
+    &data.clone(),
+  );
 
   let sha1_hash = hash_sha1(&data).as_bytes().to_vec();
   let sha512_hash = hash_sha512(&data).as_bytes().to_vec();
@@ -399,7 +445,7 @@ fn parse_data_client() {
   let data = vec![0x0, 0x01, 0x26, 0x42, 0xAF, 0xFF];
   let sha1_hash = hash_sha1(&data);
   let sha512_hash = hash_sha512(&data);
-  let separator: Vec<u8> = vec![0x00];
+  let separator = Separator::default();
   let mut packet = PacketAction::DATA.value().as_bytes().to_vec();
   packet.extend(vec![0x20]);
   packet.extend(format!("{id}").as_bytes().to_vec());
@@ -409,7 +455,7 @@ fn parse_data_client() {
   packet.extend(sha1_hash.as_bytes().to_vec());
   packet.extend(vec![0x20]);
   packet.extend(sha512_hash.as_bytes().to_vec());
-  packet.extend(separator.clone());
+  packet.extend(separator.as_bytes());
   packet.extend(data.clone());
 
   match Client::parse_packet(packet.clone(), &separator) {
@@ -434,7 +480,7 @@ fn parse_auth_client() {
   let id = Uuid::from_str(id).unwrap();
   let ports: Vec<u16> = vec![6753, 11, 6, 9, 4, 2, 8];
   let data = vec![0x0, 0x01, 0x26, 0x42, 0xAF, 0xFF];
-  let separator: Vec<u8> = vec![0x00];
+  let separator = Separator::default();
   let mut packet = PacketAction::AUTH.value().as_bytes().to_vec();
   packet.extend(vec![0x20]);
   packet.extend(format!("{id}").as_bytes().to_vec());
@@ -448,7 +494,7 @@ fn parse_auth_client() {
       .as_bytes()
       .to_vec(),
   );
-  packet.extend(separator.clone());
+  packet.extend(separator.as_bytes());
   packet.extend(data.clone());
 
   match Client::parse_packet(packet.clone(), &separator) {
@@ -461,12 +507,12 @@ fn parse_auth_client() {
 fn parse_close_client() {
   let id = "8c95a08a-97d1-4330-b5bf-87866baae5de";
   let id = Uuid::from_str(id).unwrap();
-  let data = vec![];
-  let separator: Vec<u8> = vec![0x00];
+  let data: Vec<u8> = vec![];
+  let separator = Separator::default();
   let mut packet = PacketAction::CLOSE.value().as_bytes().to_vec();
   packet.extend(vec![0x20]);
   packet.extend(format!("{id}").as_bytes().to_vec());
-  packet.extend(separator.clone());
+  packet.extend(separator.as_bytes());
 
   match Client::parse_packet(packet.clone(), &separator) {
     | Ok(packet_test) => match packet_test {
@@ -491,7 +537,7 @@ fn parse_data_server() {
   let data = vec![0x0, 0x01, 0x26, 0x42, 0xAF, 0xFF];
   let sha1_hash = hash_sha1(&data);
   let sha512_hash = hash_sha512(&data);
-  let separator: Vec<u8> = vec![0x00];
+  let separator = Separator::default();
   let mut packet = PacketAction::DATA.value().as_bytes().to_vec();
   packet.extend(vec![0x20]);
   packet.extend(format!("{id}").as_bytes().to_vec());
@@ -499,7 +545,7 @@ fn parse_data_server() {
   packet.extend(sha1_hash.as_bytes().to_vec());
   packet.extend(vec![0x20]);
   packet.extend(sha512_hash.as_bytes().to_vec());
-  packet.extend(separator.clone());
+  packet.extend(separator.as_bytes());
   packet.extend(data.clone());
 
   match Server::parse_packet(packet.clone(), &separator) {
@@ -522,19 +568,14 @@ fn parse_data_server() {
 fn parse_auth_server() {
   let ports: Vec<u16> = vec![6753, 11, 6, 9, 4, 2, 8];
   let data = vec![0x0, 0x01, 0x26, 0x42, 0xAF, 0xFF];
-  let separator: Vec<u8> = vec![0x00];
+  let separator = Separator::default();
+  let ports_string =
+    ports.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(",");
   let mut packet = PacketAction::AUTH.value().as_bytes().to_vec();
   packet.extend(vec![0x20]);
-  packet.extend(
-    ports
-      .iter()
-      .map(|x| x.to_string())
-      .collect::<Vec<String>>()
-      .join(",")
-      .as_bytes()
-      .to_vec(),
-  );
-  packet.extend(separator.clone());
+  packet.extend(ports_string.len().to_string().as_bytes().to_vec());
+  packet.extend(separator.as_bytes());
+  packet.extend(ports_string.as_bytes().to_vec());
   packet.extend(data.clone());
 
   println!(
@@ -547,7 +588,10 @@ fn parse_auth_server() {
       | PacketType::Auth(packet_test) => {
         assert_eq!(packet_test.id, ());
         assert_eq!(packet_test.port, ());
-        assert_eq!(packet_test.ports, ports);
+        assert_eq!(
+          packet_test.ports,
+          PortSet::from_ports(&ports).unwrap()
+        );
         assert_eq!(packet_test.sha1, ());
         assert_eq!(packet_test.sha512, ());
         assert_eq!(packet_test.body, data);
@@ -562,12 +606,12 @@ fn parse_auth_server() {
 fn parse_close_server() {
   let id = "8c95a08a-97d1-4330-b5bf-87866baae5de";
   let id = Uuid::from_str(id).unwrap();
-  let separator: Vec<u8> = vec![0x00];
+  let separator = Separator::default();
   let data: Vec<u8> = vec![];
   let mut packet = PacketAction::CLOSE.value().as_bytes().to_vec();
   packet.extend(vec![0x20]);
   packet.extend(format!("{id}").as_bytes().to_vec());
-  packet.extend(separator.clone());
+  packet.extend(separator.as_bytes());
 
   match Server::parse_packet(packet.clone(), &separator) {
     | Ok(packet_test) => match packet_test {
@@ -588,12 +632,11 @@ fn parse_close_server() {
 #[test]
 fn build_to_parse_client_data() {
   let id = Uuid::new_v4();
-  let separator = "\u{0000}";
+  let separator = Separator::default();
   let data = vec![0x0, 0x01, 0x26, 0x42, 0xAF, 0xFF];
   let packet = Client::build_data_packet(&id, &separator, &data);
 
-  let packet =
-    Server::parse_packet(packet, &separator.as_bytes().to_vec()).unwrap();
+  let packet = Server::parse_packet(packet, &separator).unwrap();
 
   match packet {
     | PacketType::Data(packet) => {
@@ -610,19 +653,21 @@ fn build_to_parse_client_data() {
 
 #[test]
 fn build_to_parse_client_auth() {
-  let separator = "\u{0000}";
+  let separator = Separator::default();
   let auth = String::from("(*HN)PIu)*&(hBI");
   let ports: Vec<u16> = vec![6753, 11, 6, 9, 4, 2, 8];
-  let packet = Client::build_auth_packet(&auth, &ports, &separator.to_string());
+  let packet = Client::build_auth_packet(auth.as_bytes(), &ports, &separator);
 
-  let packet =
-    Server::parse_packet(packet, &separator.as_bytes().to_vec()).unwrap();
+  let packet = Server::parse_packet(packet, &separator).unwrap();
 
   match packet {
     | PacketType::Auth(packet) => {
       assert_eq!(packet.id, ());
       assert_eq!(packet.port, ());
-      assert_eq!(packet.ports, ports);
+      assert_eq!(
+        packet.ports,
+        PortSet::from_ports(&ports).unwrap()
+      );
       assert_eq!(packet.sha1, ());
       assert_eq!(packet.sha512, ());
       assert_eq!(packet.body, auth.as_bytes().to_vec());
@@ -631,40 +676,82 @@ fn build_to_parse_client_auth() {
   }
 }
 
-// #[test]
-// fn build_to_parse_client_close() {
-//   let id = Uuid::new_v4();
-//   println!("{id}");
-//   let separator = "\u{0000}";
-//   let data = vec![];
-//   let packet = Client::close_connection_packet(&id, &separator.to_string());
+#[test]
+fn build_to_parse_client_auth_many_ports() {
+  let separator = Separator::default();
+  let auth = String::from("CH4ng3M3!");
+  let ports: Vec<u16> = (1..=500).map(|port| port + 10000).collect();
+  let packet = Client::build_auth_packet(auth.as_bytes(), &ports, &separator);
+
+  let (header, _) = split(&packet, separator.as_bytes()).unwrap();
+  assert_eq!(
+    header,
+    "AUTH 2999 00".as_bytes().to_vec()
+  );
+
+  let packet = Server::parse_packet(packet, &separator).unwrap();
+
+  match packet {
+    | PacketType::Auth(packet) => {
+      assert_eq!(
+        packet.ports,
+        PortSet::from_ports(&ports).unwrap()
+      );
+      assert_eq!(packet.body, auth.as_bytes().to_vec());
+    },
+    | _ => panic!("Packet is not an auth packet"),
+  }
+}
+
+#[test]
+fn parse_auth_server_short_ports() {
+  let separator = Separator::default();
+  let mut packet = "AUTH 15".as_bytes().to_vec();
+  packet.extend(separator.as_bytes());
+  packet.extend("8080,8081".as_bytes().to_vec());
 
-//   let packet =
-//     Server::parse_packet(packet, &separator.as_bytes().to_vec()).unwrap();
+  match Server::parse_packet(packet, &separator) {
+    | Ok(_) => panic!("Packet should not be parsed"),
+    | _ => (),
+  }
+}
+
+#[test]
+fn build_to_parse_client_close() {
+  let id = Uuid::new_v4();
+  let separator = Separator::default();
+  let data: Vec<u8> = vec![];
+  let packet = Client::close_connection_packet(&id, &separator);
+  // Both sides write CLOSE the same way, with no port.
+  assert_eq!(
+    packet,
+    Server::close_connection_packet(&id, &separator)
+  );
+
+  let packet = Server::parse_packet(packet, &separator).unwrap();
 
-//   match packet {
-//     | PacketType::Close(packet) => {
-//       assert_eq!(packet.id, id);
-//       assert_eq!(packet.port, ());
-//       assert_eq!(packet.ports, ());
-//       assert_eq!(packet.sha1, ());
-//       assert_eq!(packet.sha512, ());
-//       assert_eq!(packet.body, data);
-//     },
-//     | _ => panic!("Packet is not a data packet"),
-//   }
-// }
+  match packet {
+    | PacketType::Close(packet) => {
+      assert_eq!(packet.id, id);
+      assert_eq!(packet.port, ());
+      assert_eq!(packet.ports, ());
+      assert_eq!(packet.sha1, ());
+      assert_eq!(packet.sha512, ());
+      assert_eq!(packet.body, data);
+    },
+    | _ => panic!("Packet is not a close packet"),
+  }
+}
 
 #[test]
 fn build_to_parse_server_data() {
   let id = Uuid::new_v4();
-  let separator = "\u{0000}";
+  let separator = Separator::default();
   let port: u16 = 6753;
   let data = vec![0x0, 0x01, 0x26, 0x42, 0xAF, 0xFF];
   let packet = Server::build_data_packet(&id, &port, &separator, &data);
 
-  let packet =
-    Client::parse_packet(packet, &separator.as_bytes().to_vec()).unwrap();
+  let packet = Client::parse_packet(packet, &separator).unwrap();
 
   match packet {
     | PacketType::Data(packet) => {
@@ -682,12 +769,11 @@ fn build_to_parse_server_data() {
 #[test]
 fn build_to_parse_server_close() {
   let id = Uuid::new_v4();
-  let separator = "\u{0000}";
+  let separator = Separator::default();
   let data: Vec<u8> = vec![];
-  let packet = Server::close_connection_packet(&id, &separator.to_string());
+  let packet = Server::close_connection_packet(&id, &separator);
 
-  let packet =
-    Client::parse_packet(packet, &separator.as_bytes().to_vec()).unwrap();
+  let packet = Client::parse_packet(packet, &separator).unwrap();
 
   match packet {
     | PacketType::Close(packet) => {
@@ -701,3 +787,1387 @@ fn build_to_parse_server_close() {
     | _ => panic!("Packet is not a data packet"),
   }
 }
+
+#[test]
+fn parse_auth_try_plain() {
+  let separator = Separator::default();
+  for (body, status) in
+    [("success", AuthStatus::Success), ("forbidden", AuthStatus::Forbidden)]
+  {
+    let mut packet = PacketAction::AUTHTRY.value().as_bytes().to_vec();
+    packet.extend(separator.as_bytes());
+    packet.extend(body.as_bytes().to_vec());
+
+    match Client::parse_packet(packet, &separator) {
+      | Ok(PacketType::AuthTry(packet)) => {
+        let info = AuthTryInfo::from_body(&packet.body).unwrap();
+        assert_eq!(info.status, status);
+        assert_eq!(info.name, None);
+        assert_eq!(info.version, None);
+      },
+      | Ok(_) => panic!("Packet is not an auth result packet"),
+      | Err(err) => panic!("{err}"),
+    }
+  }
+}
+
+#[test]
+fn parse_auth_try_extended() {
+  let separator = Separator::default();
+  let mut packet = "AUTHTRY".as_bytes().to_vec();
+  packet.extend(separator.as_bytes());
+  packet.extend("success;name=edge1;v=2".as_bytes().to_vec());
+
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::AuthTry(packet)) => {
+      let info = AuthTryInfo::from_body(&packet.body).unwrap();
+      assert_eq!(info.status, AuthStatus::Success);
+      assert_eq!(info.name, Some(String::from("edge1")));
+      assert_eq!(info.version, Some(String::from("2")));
+    },
+    | Ok(_) => panic!("Packet is not an auth result packet"),
+    | Err(err) => panic!("{err}"),
+  }
+}
+
+#[test]
+fn parse_auth_try_invalid_status() {
+  let body = "maybe;name=edge1".as_bytes().to_vec();
+  assert_eq!(
+    AuthTryInfo::from_body(&body).is_err(),
+    true
+  );
+}
+
+#[test]
+fn build_to_parse_server_auth_try() {
+  let separator = Separator::default();
+  let info = AuthTryInfo {
+    status: AuthStatus::Success,
+    name: Some(String::from("edge1")),
+    version: Some(String::from("0.0.1")),
+    integrity: None,
+    window: None,
+    encoding: None,
+    reason: None,
+  };
+  let packet = Server::build_auth_try_packet(&info, &separator);
+  assert_eq!(
+    packet,
+    "AUTHTRY\u{0000}success;name=edge1;v=0.0.1".as_bytes().to_vec()
+  );
+
+  let packet = Client::parse_packet(packet, &separator).unwrap();
+
+  match packet {
+    | PacketType::AuthTry(packet) => {
+      assert_eq!(
+        AuthTryInfo::from_body(&packet.body).unwrap(),
+        info
+      );
+    },
+    | _ => panic!("Packet is not an auth result packet"),
+  }
+}
+
+#[test]
+fn parse_long_header_server() {
+  let separator = Separator::default();
+  let mut packet = "DATA ".as_bytes().to_vec();
+  packet.extend(vec![b'a'; 1024 * 1024]);
+  packet.extend(separator.as_bytes());
+  packet.extend("Hello".as_bytes().to_vec());
+
+  match Server::parse_packet(packet, &separator) {
+    | Err(ParseError::Header(ParseErrorType::Length)) => (),
+    | _ => panic!("Packet should be rejected for its header length"),
+  }
+}
+
+#[test]
+fn parse_header_over_custom_limit() {
+  let id = Uuid::new_v4();
+  let separator = Separator::default();
+  let packet =
+    Server::build_data_packet(&id, &8080, &separator, &"Hello".into());
+  let options = ParseOptions {
+    max_header_bytes: 64,
+    ..ParseOptions::default()
+  };
+
+  match Client::parse_packet_with(packet.clone(), &separator, &options) {
+    | Err(ParseError::Header(ParseErrorType::Length)) => (),
+    | _ => panic!("Packet should be rejected for its header length"),
+  }
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(_)) => (),
+    | _ => panic!("Packet is not a data packet"),
+  }
+}
+
+#[test]
+fn build_to_parse_client_noop() {
+  let separator = Separator::default();
+  let packet = Client::build_noop_packet(&separator);
+  assert_eq!(
+    packet,
+    "NOOP\u{0000}".as_bytes().to_vec()
+  );
+
+  let packet = Server::parse_packet(packet, &separator).unwrap();
+
+  match packet {
+    | PacketType::Noop(packet) => {
+      assert_eq!(packet.action.value(), "NOOP");
+      assert_eq!(packet.body, Vec::<u8>::new());
+    },
+    | _ => panic!("Packet is not a noop packet"),
+  }
+}
+
+#[test]
+fn build_to_parse_server_noop() {
+  let separator = Separator::default();
+  let packet = Server::build_noop_packet(&separator);
+
+  let packet = Client::parse_packet(packet, &separator).unwrap();
+
+  match packet {
+    | PacketType::Noop(packet) => {
+      assert_eq!(packet.port, 0);
+      assert_eq!(packet.body, Vec::<u8>::new());
+    },
+    | _ => panic!("Packet is not a noop packet"),
+  }
+}
+
+#[test]
+fn parse_close_with_body_lenient() {
+  let id = Uuid::new_v4();
+  let separator = Separator::default();
+  let mut packet = Server::close_connection_packet(&id, &separator);
+  packet.extend("junk".as_bytes());
+
+  match Server::parse_packet(packet, &separator) {
+    | Ok(PacketType::Close(packet)) => {
+      assert_eq!(packet.id, id);
+      assert_eq!(packet.body, "junk".as_bytes().to_vec());
+    },
+    | _ => panic!("Packet is not a close packet"),
+  }
+}
+
+#[test]
+fn parse_close_with_body_strict() {
+  let id = Uuid::new_v4();
+  let separator = Separator::default();
+  let options = ParseOptions {
+    strict: true,
+    ..ParseOptions::default()
+  };
+  let packet = Server::close_connection_packet(&id, &separator);
+  let mut junk = packet.clone();
+  junk.extend("junk".as_bytes());
+
+  match Server::parse_packet_with(junk.clone(), &separator, &options) {
+    | Err(ParseError::Other(ParseErrorType::Trailing, _)) => (),
+    | _ => panic!("Packet should be rejected for its body"),
+  }
+  match Client::parse_packet_with(junk, &separator, &options) {
+    | Err(ParseError::Other(ParseErrorType::Trailing, _)) => (),
+    | _ => panic!("Packet should be rejected for its body"),
+  }
+  match Server::parse_packet_with(packet, &separator, &options) {
+    | Ok(PacketType::Close(packet)) => assert_eq!(packet.id, id),
+    | _ => panic!("Packet is not a close packet"),
+  }
+}
+
+#[test]
+fn parse_noop_and_auth_try_strict() {
+  let separator = Separator::default();
+  let options = ParseOptions {
+    strict: true,
+    ..ParseOptions::default()
+  };
+  let mut noop = Server::build_noop_packet(&separator);
+  noop.extend("junk".as_bytes());
+  match Client::parse_packet_with(noop, &separator, &options) {
+    | Err(ParseError::Other(ParseErrorType::Trailing, _)) => (),
+    | _ => panic!("Packet should be rejected for its body"),
+  }
+
+  let auth_try = "AUTHTRY junk\u{0000}success".as_bytes().to_vec();
+  match Client::parse_packet_with(auth_try, &separator, &options) {
+    | Err(ParseError::Header(ParseErrorType::Trailing)) => (),
+    | _ => panic!("Packet should be rejected for its header"),
+  }
+}
+
+#[test]
+fn proxy_v1_header_ipv4() {
+  let info = OpenInfo {
+    peer: Some(SocketAddr::from_str("192.168.0.1:56324").unwrap()),
+    local: Some(SocketAddr::from_str("10.0.0.1:443").unwrap()),
+    upstream: None,
+  };
+  assert_eq!(
+    String::from_utf8(info.proxy_v1_header()).unwrap(),
+    "PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\n"
+  );
+
+  let mixed = OpenInfo {
+    peer: Some(SocketAddr::from_str("[::1]:56324").unwrap()),
+    local: info.local,
+    upstream: None,
+  };
+  assert_eq!(
+    mixed.proxy_v1_header(),
+    "PROXY UNKNOWN\r\n".as_bytes()
+  );
+}
+
+#[test]
+fn open_packet_round_trip() {
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+  let info = OpenInfo {
+    peer: Some(SocketAddr::from_str("203.0.113.7:56324").unwrap()),
+    local: None,
+    upstream: None,
+  };
+  let packet = Server::build_open_packet(&id, &8080, &info, &separator);
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Open(packet)) => {
+      assert_eq!(packet.id, id);
+      assert_eq!(packet.port, 8080);
+      assert_eq!(
+        OpenInfo::from_body(&packet.body).unwrap(),
+        info
+      );
+    },
+    | _ => panic!("Packet should parse as an open packet"),
+  }
+}
+
+#[test]
+fn forward_parsing() {
+  assert_eq!("8080".parse(), Ok(Forward::from(8080)));
+  assert_eq!(
+    "8080=db.internal:5432".parse(),
+    Ok(Forward {
+      port: 8080,
+      upstream: Some(Endpoint {
+        host: String::from("db.internal"),
+        port: 5432,
+      }),
+    })
+  );
+  assert_eq!(
+    "443=[::1]:8443".parse::<Forward>().map(|forward| forward.upstream),
+    Ok(Some(Endpoint {
+      host: String::from("[::1]"),
+      port: 8443,
+    }))
+  );
+  for (invalid, err) in [
+    ("", AddressError::Port),
+    ("http", AddressError::Port),
+    ("8080=", AddressError::MissingPort),
+    (
+      "8080=db.internal",
+      AddressError::MissingPort,
+    ),
+    ("8080=:5432", AddressError::Host),
+    (
+      "8080=db.internal:99999",
+      AddressError::Port,
+    ),
+    (
+      "70000=db.internal:5432",
+      AddressError::Port,
+    ),
+  ] {
+    assert_eq!(
+      invalid.parse::<Forward>(),
+      Err(err),
+      "{invalid:?}"
+    );
+  }
+}
+
+#[test]
+fn build_to_parse_client_auth_with_upstreams() {
+  let separator = Separator::default();
+  let auth = String::from("CH4ng3M3!");
+  let forwards =
+    vec![Forward::from(25565), "8080=127.0.0.1:80".parse().unwrap()];
+  let packet = Client::build_auth_packet_with_forwards(
+    auth.as_bytes(),
+    &forwards,
+    &separator,
+  );
+  assert_eq!(
+    packet,
+    "AUTH 23 00\u{0000}25565,8080=127.0.0.1:80CH4ng3M3!".as_bytes().to_vec()
+  );
+
+  match Server::parse_packet(packet, &separator) {
+    | Ok(PacketType::Auth(packet)) => {
+      assert_eq!(packet.ports.forwards(), &forwards);
+      assert_eq!(packet.body, auth.as_bytes().to_vec());
+    },
+    | _ => panic!("Packet is not an auth packet"),
+  }
+}
+
+#[test]
+fn open_info_carries_upstream() {
+  let info = OpenInfo {
+    peer: Some(SocketAddr::from_str("203.0.113.7:56324").unwrap()),
+    local: None,
+    upstream: "127.0.0.1:80".parse().ok(),
+  };
+  assert_eq!(
+    info.value(),
+    "203.0.113.7:56324 unknown 127.0.0.1:80"
+  );
+  assert_eq!(
+    OpenInfo::from_body(&info.value().into()).unwrap(),
+    info
+  );
+  assert!(OpenInfo::from_body(&"unknown unknown 127.0.0.1".into()).is_err());
+}
+
+#[test]
+fn packet_actions_enumerated() {
+  assert_eq!(
+    PacketAction::all(),
+    &[
+      PacketAction::DATA,
+      PacketAction::CLOSE,
+      PacketAction::AUTH,
+      PacketAction::AUTHTRY,
+      PacketAction::NOOP,
+      PacketAction::OPEN,
+      PacketAction::ACK,
+    ]
+  );
+  for action in PacketAction::all() {
+    let token = action.value();
+    assert_eq!(
+      PacketAction::try_from_str(&token),
+      Some(*action)
+    );
+    assert_eq!(
+      PacketAction::try_from_str(&token.to_lowercase()),
+      Some(*action)
+    );
+  }
+  assert_eq!(
+    PacketAction::try_from_str("AuthTry"),
+    Some(PacketAction::AUTHTRY)
+  );
+  assert_eq!(PacketAction::try_from_str("PING"), None);
+}
+
+#[test]
+fn hash_encodings() {
+  let data: Vec<u8> = "abc".into();
+  assert_eq!(
+    hash_sha1_with(&data, HashEncoding::Hex),
+    "a9993e364706816aba3e25717850c26c9cd0d89d"
+  );
+  assert_eq!(
+    hash_sha1_with(&data, HashEncoding::Base64),
+    "qZk+NkcGgWq6PiVxeFDCbJzQ2J0="
+  );
+  assert_eq!(
+    hash_sha512(&data),
+    hash_sha512_with(&data, HashEncoding::Hex)
+  );
+}
+
+#[test]
+fn data_round_trip_base64_hashes() {
+  let id = Uuid::new_v4();
+  let separator = Separator::default();
+  let data: Vec<u8> = "Hello".into();
+  let options = ParseOptions {
+    hash_encoding: HashEncoding::Base64,
+    ..ParseOptions::default()
+  };
+
+  let packet = Server::build_data_packet_with(
+    &id,
+    &8080,
+    &separator,
+    &data,
+    HashEncoding::Base64,
+    Integrity::Dual,
+  );
+  match Client::parse_packet_with(packet.clone(), &separator, &options) {
+    | Ok(PacketType::Data(packet)) => {
+      assert_eq!(
+        packet.sha1,
+        hash_sha1_with(&data, HashEncoding::Base64)
+      );
+      assert_eq!(
+        packet.sha512,
+        hash_sha512_with(&data, HashEncoding::Base64)
+      );
+      assert_eq!(packet.body, data);
+    },
+    | _ => panic!("Packet should parse as a data packet"),
+  }
+  // Hashes in the wrong encoding don't match.
+  match Client::parse_packet(packet, &separator) {
+    | Err(ParseError::Other(ParseErrorType::Hash, _)) => (),
+    | _ => panic!("Packet should be rejected for its hashes"),
+  }
+
+  let packet = Client::build_data_packet_with(
+    &id,
+    &separator,
+    &data,
+    HashEncoding::Base64,
+    Integrity::Dual,
+  );
+  match Server::parse_packet_with(packet, &separator, &options) {
+    | Ok(PacketType::Data(packet)) => assert_eq!(packet.body, data),
+    | _ => panic!("Packet should parse as a data packet"),
+  }
+}
+
+#[test]
+fn empty_data_round_trip() {
+  let id = Uuid::new_v4();
+  let separator = Separator::default();
+  let sha1 = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+  let sha512 = "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e";
+
+  let packet = Client::build_data_packet(&id, &separator, &vec![]);
+  assert_eq!(
+    packet,
+    format!("DATA {id} {sha1} {sha512}\u{0000}").into_bytes()
+  );
+  match Server::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(packet)) => {
+      assert_eq!(packet.id, id);
+      assert_eq!(packet.sha1, sha1);
+      assert_eq!(packet.sha512, sha512);
+      assert!(packet.body.is_empty());
+    },
+    | _ => panic!("Packet should parse as a data packet"),
+  }
+
+  let packet = Server::build_data_packet(&id, &8080, &separator, &vec![]);
+  assert_eq!(
+    packet,
+    format!("DATA {id} 8080 {sha1} {sha512}\u{0000}").into_bytes()
+  );
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(packet)) => {
+      assert_eq!(packet.id, id);
+      assert_eq!(packet.port, 8080);
+      assert!(packet.body.is_empty());
+    },
+    | _ => panic!("Packet should parse as a data packet"),
+  }
+
+  // The empty-input hashes are still checked, against a body that is not
+  // empty.
+  let packet = format!("DATA {id} {sha1} {sha512}\u{0000}x").into_bytes();
+  match Server::parse_packet(packet, &separator) {
+    | Err(ParseError::Other(ParseErrorType::Hash, _)) => (),
+    | _ => panic!("Packet should be rejected for its hashes"),
+  }
+}
+
+#[test]
+fn data_round_trip_sha512_only() {
+  let id = Uuid::new_v4();
+  let separator = Separator::default();
+  let data: Vec<u8> = "Hello".into();
+  let options = ParseOptions {
+    integrity: Integrity::Sha512Only,
+    ..ParseOptions::default()
+  };
+
+  let packet = Server::build_data_packet_with(
+    &id,
+    &8080,
+    &separator,
+    &data,
+    HashEncoding::Hex,
+    Integrity::Sha512Only,
+  );
+  let mut expected =
+    format!("DATA {id} 8080 {}", hash_sha512(&data)).as_bytes().to_vec();
+  expected.extend(separator.as_bytes());
+  expected.extend(&data);
+  assert_eq!(packet, expected);
+  match Client::parse_packet_with(packet.clone(), &separator, &options) {
+    | Ok(PacketType::Data(packet)) => {
+      assert_eq!(packet.port, 8080);
+      assert!(packet.sha1.is_empty());
+      assert_eq!(packet.sha512, hash_sha512(&data));
+      assert_eq!(packet.body, data);
+    },
+    | _ => panic!("Packet should parse as a data packet"),
+  }
+  // A side still expecting both hashes finds one missing.
+  match Client::parse_packet(packet, &separator) {
+    | Err(ParseError::Header(ParseErrorType::Hash)) => (),
+    | _ => panic!("Packet should be rejected for its hashes"),
+  }
+
+  let packet = Client::build_data_packet_with(
+    &id,
+    &separator,
+    &data,
+    HashEncoding::Hex,
+    Integrity::Sha512Only,
+  );
+  let mut tampered = packet.clone();
+  tampered.push(b'!');
+  match Server::parse_packet_with(packet, &separator, &options) {
+    | Ok(PacketType::Data(packet)) => {
+      assert!(packet.sha1.is_empty());
+      assert_eq!(packet.body, data);
+    },
+    | _ => panic!("Packet should parse as a data packet"),
+  }
+  match Server::parse_packet_with(tampered, &separator, &options) {
+    | Err(ParseError::Other(ParseErrorType::Hash, _)) => (),
+    | _ => panic!("Packet should be rejected for its hashes"),
+  }
+}
+
+#[test]
+fn stream_sha512_only_data_packet() {
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+  let options = ParseOptions {
+    integrity: Integrity::Sha512Only,
+    ..ParseOptions::default()
+  };
+  let mut written = Vec::new();
+  Client::write_data_packet(
+    &mut written,
+    &id,
+    &separator,
+    &mut Cursor::new("Hello".as_bytes().to_vec()),
+    HashEncoding::Hex,
+    Integrity::Sha512Only,
+  )
+  .unwrap();
+  assert_eq!(
+    written,
+    Client::build_data_packet_with(
+      &id,
+      &separator,
+      &"Hello".into(),
+      HashEncoding::Hex,
+      Integrity::Sha512Only,
+    )
+  );
+
+  let (packet, mut body) = Server::read_data_packet(
+    Cursor::new(written),
+    &separator,
+    &options,
+  )
+  .unwrap();
+  assert_eq!(packet.id, id);
+  let mut read = Vec::new();
+  body.read_to_end(&mut read).unwrap();
+  assert_eq!(read, "Hello".as_bytes().to_vec());
+  assert!(body.verify().is_ok());
+}
+
+#[test]
+fn auth_try_announces_integrity() {
+  let info = AuthTryInfo {
+    status: AuthStatus::Success,
+    name: None,
+    version: None,
+    integrity: Some(Integrity::Sha512Only),
+    window: None,
+    encoding: None,
+    reason: None,
+  };
+  assert_eq!(
+    info.value(),
+    "success;integrity=sha512_only"
+  );
+  assert_eq!(
+    AuthTryInfo::from_body(&info.value().into()).unwrap(),
+    info
+  );
+  assert!(AuthTryInfo::from_body(&"success;integrity=md5".into()).is_err());
+}
+
+#[test]
+fn parse_custom_action() {
+  let separator = Separator::default();
+  match Server::parse_packet("ping 42\u{0000}hi".into(), &separator) {
+    | Ok(PacketType::Custom(packet)) => assert_eq!(
+      packet,
+      CustomPacket {
+        action: String::from("PING"),
+        header: "42".into(),
+        body: "hi".into(),
+      }
+    ),
+    | _ => panic!("Packet should parse as a custom packet"),
+  }
+  match Client::parse_packet("P!NG\u{0000}".into(), &separator) {
+    | Err(ParseError::Other(ParseErrorType::Action, _)) => (),
+    | _ => panic!("Malformed action should be rejected"),
+  }
+}
+
+#[test]
+fn packet_action_and_body() {
+  let separator = Separator::default();
+  let packet =
+    Server::parse_packet("ping 42\u{0000}hi".into(), &separator).unwrap();
+  assert_eq!(packet.action(), "PING");
+  assert_eq!(packet.body(), b"hi");
+
+  let id = Uuid::new_v4();
+  let packet = Server::parse_packet(
+    Client::build_data_packet(&id, &separator, &"Hello".into()),
+    &separator,
+  )
+  .unwrap();
+  assert_eq!(packet.action(), "DATA");
+  assert_eq!(packet.body(), b"Hello");
+}
+
+#[test]
+fn action_registry_dispatch() {
+  let mut registry = ActionRegistry::new();
+  assert!(!registry.register("DATA", Box::new(|_| ())));
+  assert!(!registry.register("P!NG", Box::new(|_| ())));
+  assert!(registry.register("ping", Box::new(|_| ())));
+
+  let packet = CustomPacket::new(
+    String::from("Ping"),
+    Vec::new(),
+    Vec::new(),
+  )
+  .unwrap();
+  assert!(registry.dispatch(&packet));
+  let packet = CustomPacket::new(
+    String::from("PONG"),
+    Vec::new(),
+    Vec::new(),
+  )
+  .unwrap();
+  assert!(!registry.dispatch(&packet));
+}
+
+#[test]
+fn constant_time_comparison() {
+  assert!(constant_time_eq(
+    b"CH4ng3M3!", b"CH4ng3M3!"
+  ));
+  assert!(!constant_time_eq(
+    b"CH4ng3M3!", b"CH4ng3M3?"
+  ));
+  assert!(!constant_time_eq(
+    b"CH4ng3M3!", b"CH4ng3"
+  ));
+  assert!(constant_time_eq(b"", b""));
+}
+
+#[test]
+fn packet_body_as_str() {
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+  let packet =
+    Server::build_data_packet(&id, &8080, &separator, &"Hello".into());
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(packet)) => {
+      assert_eq!(packet.body_as_str(), Ok("Hello"))
+    },
+    | _ => panic!("Packet should parse as a data packet"),
+  }
+
+  let packet = Server::build_data_packet(
+    &id,
+    &8080,
+    &separator,
+    &vec![0x48, 0xFF],
+  );
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(packet)) => assert!(packet.body_as_str().is_err()),
+    | _ => panic!("Packet should parse as a data packet"),
+  }
+}
+
+#[test]
+fn parse_error_offset() {
+  let separator = Separator::default();
+  let error = match Server::parse_packet(
+    "DATA not-a-uuid aa bb\u{0000}".into(),
+    &separator,
+  ) {
+    | Err(error) => error,
+    | Ok(_) => panic!("Packet with a bad id should be rejected"),
+  };
+  assert_eq!(
+    error.value(),
+    "Invalid packet: Invalid ID at byte 5 near \"not-a-uuid aa bb\""
+  );
+
+  let id = Uuid::new_v4();
+  let packet = format!("DATA {id} 80x aa bb\u{0000}");
+  match Client::parse_packet(packet.into(), &separator) {
+    | Err(ParseError::Other(ParseErrorType::Port, Some(context))) => {
+      assert_eq!(context.offset, 42);
+      assert!(context.context.starts_with(b"80x "));
+    },
+    | _ => panic!("Packet with a bad port should be rejected"),
+  }
+}
+
+#[test]
+fn equal_packets_compare_and_dedup() {
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+  let packet =
+    Server::build_data_packet(&id, &8080, &separator, &"hello".into());
+  let parse = |packet: &Vec<u8>| {
+    Client::parse_packet(packet.to_owned(), &separator).unwrap()
+  };
+  assert_eq!(parse(&packet), parse(&packet));
+
+  let other =
+    Server::build_data_packet(&id, &8080, &separator, &"world".into());
+  let packets: HashSet<PacketType<Server>> =
+    [&packet, &other, &packet].into_iter().map(parse).collect();
+  assert_eq!(packets.len(), 2);
+}
+
+/// A body made up on the fly, so nothing ever holds it whole
+#[cfg(test)]
+struct Pattern {
+  len: u64,
+  position: u64,
+}
+
+#[cfg(test)]
+impl Read for Pattern {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    let left = self.len.saturating_sub(self.position);
+    let read = left.min(buffer.len() as u64) as usize;
+    for (i, byte) in buffer[..read].iter_mut().enumerate() {
+      *byte = ((self.position + i as u64) % 251) as u8;
+    }
+    self.position += read as u64;
+    Ok(read)
+  }
+}
+
+#[cfg(test)]
+impl Seek for Pattern {
+  fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+    self.position = match position {
+      | SeekFrom::Start(offset) => offset,
+      | SeekFrom::End(offset) => (self.len as i64 + offset) as u64,
+      | SeekFrom::Current(offset) => (self.position as i64 + offset) as u64,
+    };
+    Ok(self.position)
+  }
+}
+
+/// Keeps the first bytes written and only counts the rest
+#[cfg(test)]
+struct HeadSink {
+  head: Vec<u8>,
+  written: u64,
+}
+
+#[cfg(test)]
+impl Write for HeadSink {
+  fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+    let keep = 256usize.saturating_sub(self.head.len()).min(buffer.len());
+    self.head.extend_from_slice(&buffer[..keep]);
+    self.written += buffer.len() as u64;
+    Ok(buffer.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[test]
+fn stream_large_data_packet() {
+  let len = 10 * 1024 * 1024;
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+  let mut sink = HeadSink {
+    head: Vec::new(),
+    written: 0,
+  };
+  let mut body = Pattern {
+    len,
+    position: 0,
+  };
+  let copied = Server::write_data_packet(
+    &mut sink,
+    &id,
+    &8080,
+    &separator,
+    &mut body,
+    HashEncoding::Hex,
+    Integrity::Dual,
+  )
+  .unwrap();
+  assert_eq!(copied, len);
+  let header = sink.head[..(sink.written - len) as usize].to_vec();
+
+  let reader = BufReader::new(Cursor::new(header).chain(Pattern {
+    len,
+    position: 0,
+  }));
+  let (packet, mut body) = Client::read_data_packet(
+    reader,
+    &separator,
+    &ParseOptions::default(),
+  )
+  .unwrap();
+  assert_eq!(packet.id, id);
+  assert_eq!(packet.port, 8080);
+  assert!(packet.body.is_empty());
+  assert_eq!(
+    io::copy(&mut body, &mut io::sink()).unwrap(),
+    len
+  );
+  assert!(body.verify().is_ok());
+}
+
+/// Counts the reads made on `inner`
+#[cfg(test)]
+struct CountingReader<R> {
+  inner: R,
+  reads: usize,
+}
+
+#[cfg(test)]
+impl<R: Read> Read for CountingReader<R> {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    self.reads += 1;
+    self.inner.read(buffer)
+  }
+}
+
+#[test]
+fn streamed_header_read_a_buffer_at_a_time() {
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+  let packet = Client::build_data_packet(&id, &separator, &"hello".into());
+  let mut reader = BufReader::new(CountingReader {
+    inner: Cursor::new(packet),
+    reads: 0,
+  });
+
+  let (packet, mut body) = Server::read_data_packet(
+    &mut reader,
+    &separator,
+    &ParseOptions::default(),
+  )
+  .unwrap();
+  assert_eq!(packet.id, id);
+  let mut read = Vec::new();
+  body.read_to_end(&mut read).unwrap();
+  assert_eq!(read, b"hello");
+  assert!(body.verify().is_ok());
+  // One read for the whole packet, and one finding the end
+  assert_eq!(reader.get_ref().reads, 2);
+}
+
+#[test]
+fn streamed_body_checked_against_header() {
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+  let mut packet = Client::build_data_packet(&id, &separator, &"hello".into());
+  packet.pop();
+
+  let (_, mut body) = Server::read_data_packet(
+    Cursor::new(packet),
+    &separator,
+    &ParseOptions::default(),
+  )
+  .unwrap();
+  let mut read = Vec::new();
+  body.read_to_end(&mut read).unwrap();
+  assert_eq!(read, "hell".as_bytes());
+  assert!(matches!(
+    body.verify(),
+    Err(ParseError::Other(
+      ParseErrorType::Hash,
+      _
+    ))
+  ));
+}
+
+#[test]
+fn port_set_accepts_distinct_ports() {
+  let forwards = vec![
+    Forward::from(25565),
+    "8080=db.internal:5432".parse().unwrap(),
+    Forward::from(443),
+  ];
+  let ports = PortSet::new(forwards.clone()).unwrap();
+  assert_eq!(ports.forwards(), &forwards);
+  assert_eq!(ports.ports(), vec![25565, 8080, 443]);
+  assert!(PortSet::new(Vec::new()).unwrap().is_empty());
+}
+
+#[test]
+fn port_set_rejects_duplicates() {
+  assert_eq!(
+    PortSet::from_ports(&vec![8080, 8081, 8080]),
+    Err(PortSetError::Duplicate(8080))
+  );
+  // Forwarding to different upstreams doesn't make the port distinct.
+  assert_eq!(
+    PortSet::new(vec![
+      Forward::from(8080),
+      "8080=db.internal:5432".parse().unwrap(),
+    ]),
+    Err(PortSetError::Duplicate(8080))
+  );
+}
+
+#[test]
+fn port_set_rejects_zero() {
+  assert_eq!(
+    PortSet::from_ports(&vec![8080, 0]),
+    Err(PortSetError::Zero)
+  );
+}
+
+#[test]
+fn parse_auth_rejects_invalid_port_set() {
+  let separator = Separator::default();
+  for ports in [vec![8080, 0, 8081], vec![8080, 8, 8080]] {
+    let packet = Client::build_auth_packet(b"CH4ng3M3!", &ports, &separator);
+    match Server::parse_packet(packet, &separator) {
+      | Err(ParseError::Other(ParseErrorType::Ports, Some(context))) => {
+        assert_eq!(
+          context.offset,
+          "AUTH 16 00".len() + separator.len()
+        )
+      },
+      | result => panic!("{ports:?} should be rejected, got {result:?}"),
+    }
+  }
+}
+
+#[test]
+fn build_to_parse_ack() {
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+
+  let packet = Client::build_ack_packet(&id, 42, &separator);
+  match Server::parse_packet(packet, &separator) {
+    | Ok(PacketType::Ack(packet)) => {
+      assert_eq!(packet.id, id);
+      assert_eq!(packet.sequence(), 42);
+    },
+    | _ => panic!("Packet should parse as an ack packet"),
+  }
+
+  let packet = Server::build_ack_packet(&id, 7, &separator);
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Ack(packet)) => {
+      assert_eq!(packet.id, id);
+      assert_eq!(packet.sequence(), 7);
+    },
+    | _ => panic!("Packet should parse as an ack packet"),
+  }
+
+  for sequence in ["", "-1", "4x", "99999999999999999999"] {
+    let packet =
+      [format!("ACK {id}").as_bytes(), &separator, sequence.as_bytes()]
+        .concat();
+    assert!(
+      matches!(
+        Server::parse_packet(packet, &separator),
+        Err(ParseError::Other(
+          ParseErrorType::Sequence,
+          _
+        ))
+      ),
+      "Sequence {sequence:?} should be rejected"
+    );
+  }
+}
+
+#[test]
+fn auth_try_window_round_trip() {
+  let info = AuthTryInfo {
+    status: AuthStatus::Success,
+    name: None,
+    version: None,
+    integrity: None,
+    window: Some(64),
+    encoding: None,
+    reason: None,
+  };
+  assert_eq!(info.value(), "success;window=64");
+  assert_eq!(
+    AuthTryInfo::from_body(&info.value().into()).unwrap(),
+    info
+  );
+  assert!(AuthTryInfo::from_body(&"success;window=0".into()).is_err());
+}
+
+#[test]
+fn data_round_trip_raw_hashes() {
+  let id = Uuid::new_v4();
+  let separator = Separator::default();
+  let options = ParseOptions {
+    hash_encoding: HashEncoding::Raw,
+    ..ParseOptions::default()
+  };
+  let build = |data: &Vec<u8>| {
+    Server::build_data_packet_with(
+      &id,
+      &8080,
+      &separator,
+      data,
+      HashEncoding::Raw,
+      Integrity::Dual,
+    )
+  };
+  let prefix = format!("DATA {id} 8080 ").len();
+  // A body whose hashes hold both the separator and a space of their own,
+  // besides the one between them, which the parser must not stop at.
+  let data = (0u32..)
+    .map(|i| format!("Hello\u{0000}{i}").into_bytes())
+    .find(|data| {
+      let packet = build(data);
+      let hashes = &packet[prefix..prefix + 85];
+      hashes.contains(&0) && hashes.iter().filter(|b| **b == b' ').count() > 1
+    })
+    .unwrap();
+
+  let packet = build(&data);
+  assert_eq!(
+    packet.len(),
+    prefix + 85 + separator.len() + data.len()
+  );
+  match Client::parse_packet_with(packet.clone(), &separator, &options) {
+    | Ok(PacketType::Data(packet)) => {
+      assert_eq!(packet.id, id);
+      assert_eq!(packet.port, 8080);
+      assert_eq!(packet.sha1, hash_sha1(&data));
+      assert_eq!(packet.sha512, hash_sha512(&data));
+      assert_eq!(packet.body, data);
+    },
+    | _ => panic!("Packet should parse as a data packet"),
+  }
+  // Read as hex, the header ends early and makes no sense.
+  assert!(Client::parse_packet(packet, &separator).is_err());
+
+  let packet = Client::build_data_packet_with(
+    &id,
+    &separator,
+    &data,
+    HashEncoding::Raw,
+    Integrity::Dual,
+  );
+  match Server::parse_packet_with(packet, &separator, &options) {
+    | Ok(PacketType::Data(packet)) => assert_eq!(packet.body, data),
+    | _ => panic!("Packet should parse as a data packet"),
+  }
+}
+
+#[test]
+fn raw_hashes_streamed() {
+  let id = Uuid::new_v4();
+  let separator = Separator::default();
+  let options = ParseOptions {
+    hash_encoding: HashEncoding::Raw,
+    integrity: Integrity::Sha512Only,
+    ..ParseOptions::default()
+  };
+  let data: Vec<u8> = "Hello\u{0000}world".into();
+  let mut written = Vec::new();
+  Client::write_data_packet(
+    &mut written,
+    &id,
+    &separator,
+    &mut Cursor::new(data.clone()),
+    HashEncoding::Raw,
+    Integrity::Sha512Only,
+  )
+  .unwrap();
+  assert_eq!(
+    written,
+    Client::build_data_packet_with(
+      &id,
+      &separator,
+      &data,
+      HashEncoding::Raw,
+      Integrity::Sha512Only,
+    )
+  );
+
+  let (packet, mut body) = Server::read_data_packet(
+    Cursor::new(written),
+    &separator,
+    &options,
+  )
+  .unwrap();
+  assert_eq!(packet.sha512, hash_sha512(&data));
+  let mut read = Vec::new();
+  body.read_to_end(&mut read).unwrap();
+  assert_eq!(read, data);
+  assert!(body.verify().is_ok());
+}
+
+#[test]
+fn auth_try_announces_encoding() {
+  let info = AuthTryInfo {
+    status: AuthStatus::Success,
+    name: None,
+    version: None,
+    integrity: None,
+    window: None,
+    encoding: Some(HashEncoding::Raw),
+    reason: None,
+  };
+  assert_eq!(info.value(), "success;encoding=raw");
+  assert_eq!(
+    AuthTryInfo::from_body(&info.value().into()).unwrap(),
+    info
+  );
+  assert!(AuthTryInfo::from_body(&"success;encoding=hexx".into()).is_err());
+}
+
+#[test]
+fn ids_checked_before_building() {
+  let separator = Separator::default();
+  assert!(check_id(&Uuid::new_v4().to_string(), &separator).is_ok());
+  match check_id("123e4567 e89b", &separator) {
+    | Err(ParseError::Other(ParseErrorType::ID, Some(context))) => {
+      assert_eq!(context.offset, 8)
+    },
+    | _ => panic!("An id with a space should be rejected"),
+  }
+  assert!(check_id("123e4567\u{0000}e89b", &separator).is_err());
+  assert!(check_id("123e4567-e89b", b"-").is_err());
+}
+
+#[test]
+fn auth_announces_separator() {
+  for separator in ["\u{0000}", "||", "\u{0000}\u{0001}"] {
+    let separator = Separator::new(separator).unwrap();
+    let packet =
+      Client::build_auth_packet(b"CH4ng3M3!", &vec![8080], &separator);
+    assert_eq!(
+      Server::announced_separator(&packet),
+      Some(separator)
+    );
+  }
+
+  // Clients that predate the announcement are still understood.
+  let separator = Separator::default();
+  let mut packet = "AUTH 4".as_bytes().to_vec();
+  packet.extend(separator.as_bytes());
+  packet.extend("8080CH4ng3M3!".as_bytes().to_vec());
+  assert_eq!(
+    Server::announced_separator(&packet),
+    None
+  );
+  match Server::parse_packet(packet, &separator) {
+    | Ok(PacketType::Auth(packet)) => {
+      assert_eq!(
+        packet.ports,
+        PortSet::from_ports(&vec![8080]).unwrap()
+      );
+      assert_eq!(
+        packet.body,
+        "CH4ng3M3!".as_bytes().to_vec()
+      );
+    },
+    | _ => panic!("Packet is not an auth packet"),
+  }
+}
+
+#[test]
+fn announced_separator_must_follow() {
+  for packet in [
+    "AUTH 4 7c7c\u{0000}8080".as_bytes(),
+    "AUTH 4 7c7\u{0000}8080".as_bytes(),
+    "AUTH  7c7c||8080".as_bytes(),
+    "DATA 4 7c7c||8080".as_bytes(),
+  ] {
+    assert_eq!(
+      Server::announced_separator(packet),
+      None
+    );
+  }
+  assert_eq!(
+    Server::announced_separator("AUTH 4 7c7c||8080".as_bytes()),
+    Some(Separator::new("||").unwrap())
+  );
+}
+
+#[test]
+fn separator_validated() {
+  assert_eq!(
+    Separator::new(""),
+    Err(SeparatorError::Empty)
+  );
+  assert_eq!(
+    Separator::new("| |"),
+    Err(SeparatorError::Reserved(b' '))
+  );
+  assert_eq!(
+    Separator::new("a"),
+    Err(SeparatorError::Reserved(b'a'))
+  );
+  // Would end a header inside its action name, as in "AUTHTRY"
+  assert_eq!(
+    Separator::new("T"),
+    Err(SeparatorError::Reserved(b'T'))
+  );
+  assert_eq!(
+    Separator::new("|_|"),
+    Err(SeparatorError::Reserved(b'_'))
+  );
+  let separator = Separator::try_from("||").unwrap();
+  assert_eq!(separator.as_bytes(), b"||");
+  assert!(separator.check(HashEncoding::Base64).is_ok());
+  assert_eq!(
+    Separator::new("|+|").unwrap().check(HashEncoding::Base64),
+    Err(SeparatorError::Reserved(b'+'))
+  );
+}
+
+#[test]
+fn binary_separator_and_auth_round_trip() {
+  let separator = Separator::try_from(vec![0xff, 0x00]).unwrap();
+  let auth = vec![0x80, 0x00, 0xff, b'!'];
+  let packet = Client::build_auth_packet(&auth, &vec![8080], &separator);
+  assert_eq!(
+    Server::announced_separator(&packet),
+    Some(separator.clone())
+  );
+  match Server::parse_packet(packet, &separator) {
+    | Ok(PacketType::Auth(packet)) => assert_eq!(packet.body, auth),
+    | _ => panic!("Packet is not an auth packet"),
+  }
+
+  let id = Uuid::new_v4();
+  let body = vec![0xff, 0xfe, 0x00];
+  let packet = Server::build_data_packet(&id, &8080, &separator, &body);
+  match Client::parse_packet(packet, &separator) {
+    | Ok(PacketType::Data(packet)) => assert_eq!(packet.body, body),
+    | _ => panic!("Packet is not a data packet"),
+  }
+
+  // Both settings are written as bytes, and read back unchanged.
+  let written = serde_json::to_string(&ArrOrStr::from_bytes(&auth)).unwrap();
+  assert_eq!(written, "[128,0,255,33]");
+  let read: ArrOrStr = serde_json::from_str(&written).unwrap();
+  assert_eq!(read.into_bytes(), auth);
+  let read: ArrOrStr = serde_json::from_str("[255,0]").unwrap();
+  assert_eq!(read.into_separator(), Ok(separator));
+}
+
+#[test]
+fn auth_try_carries_reason() {
+  let info = AuthTryInfo {
+    status: AuthStatus::Forbidden,
+    name: None,
+    version: None,
+    integrity: None,
+    window: None,
+    encoding: None,
+    reason: Some(String::from("separator")),
+  };
+  assert_eq!(
+    info.value(),
+    "forbidden;reason=separator"
+  );
+  assert_eq!(
+    AuthTryInfo::from_body(&info.value().into_bytes()).unwrap(),
+    info
+  );
+}
+
+#[test]
+fn packet_len_matches_built() {
+  let id = Uuid::new_v4();
+  for separator in ["\u{0000}", "||", "<#>"] {
+    let separator = Separator::new(separator).unwrap();
+    for encoding in [HashEncoding::Hex, HashEncoding::Base64, HashEncoding::Raw]
+    {
+      for integrity in [Integrity::Dual, Integrity::Sha512Only] {
+        for body in [Vec::new(), vec![7u8; 1], vec![0u8; 1500]] {
+          assert_eq!(
+            Server::build_data_packet_with(
+              &id, &8080, &separator, &body, encoding, integrity,
+            )
+            .len(),
+            Server::data_packet_len(
+              &8080,
+              body.len(),
+              encoding,
+              integrity,
+              separator.len(),
+            )
+          );
+          assert_eq!(
+            Client::build_data_packet_with(
+              &id, &separator, &body, encoding, integrity,
+            )
+            .len(),
+            Client::data_packet_len(
+              body.len(),
+              encoding,
+              integrity,
+              separator.len(),
+            )
+          );
+        }
+      }
+    }
+    assert_eq!(
+      Server::close_connection_packet(&id, &separator).len(),
+      Server::close_packet_len(separator.len())
+    );
+    assert_eq!(
+      Client::close_connection_packet(&id, &separator).len(),
+      Client::close_packet_len(separator.len())
+    );
+    assert_eq!(
+      Server::build_noop_packet(&separator).len(),
+      Server::noop_packet_len(separator.len())
+    );
+    assert_eq!(
+      Client::build_noop_packet(&separator).len(),
+      Client::noop_packet_len(separator.len())
+    );
+    for sequence in [0, 9, 10, u64::MAX] {
+      assert_eq!(
+        Server::build_ack_packet(&id, sequence, &separator).len(),
+        Server::ack_packet_len(sequence, separator.len())
+      );
+      assert_eq!(
+        Client::build_ack_packet(&id, sequence, &separator).len(),
+        Client::ack_packet_len(sequence, separator.len())
+      );
+    }
+  }
+}
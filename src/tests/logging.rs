@@ -0,0 +1,386 @@
+#[allow(unused_imports)]
+use crate::{
+  functions::{Client, Separator, Server},
+  logging::{
+    current_log_levels, enter_session, in_session, init_logger, open_log_file,
+    packet_trace, startup_banner, Direction, DynamicLogger, LevelHandle,
+    LogSink, LoggerSettings, SessionId, SessionLogger, SyslogLogger,
+  },
+};
+#[allow(unused_imports)]
+use log::{Level, Log, Metadata, Record};
+#[allow(unused_imports)]
+use simplelog::{Config, LevelFilter, SharedLogger};
+#[allow(unused_imports)]
+use std::{
+  env::temp_dir,
+  fs::{remove_dir_all, remove_file, File},
+  os::unix::net::UnixDatagram,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Barrier, Mutex,
+  },
+  thread,
+};
+#[allow(unused_imports)]
+use syslog::Facility;
+#[allow(unused_imports)]
+use uuid::Uuid;
+
+#[test]
+fn init_logger_stores_levels() {
+  let settings = LoggerSettings {
+    level: LevelFilter::Warn,
+    file_level: LevelFilter::Trace,
+    sinks: Vec::new(),
+  };
+  init_logger(settings.clone());
+  assert_eq!(current_log_levels(), settings);
+}
+
+#[test]
+fn quiet_leaves_out_startup_banner() {
+  assert_eq!(
+    startup_banner(
+      LevelFilter::Info,
+      LevelFilter::Debug,
+      false
+    ),
+    vec!["INFO calls logging to terminal", "DEBUG calls logging to file"]
+  );
+  assert!(startup_banner(
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    true
+  )
+  .is_empty());
+  assert!(startup_banner(LevelFilter::Off, LevelFilter::Off, true).is_empty());
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+struct CountingLogger {
+  count: Arc<AtomicUsize>,
+}
+
+#[cfg(test)]
+impl Log for CountingLogger {
+  fn enabled(&self, _: &Metadata) -> bool {
+    true
+  }
+
+  fn log(&self, _: &Record) {
+    self.count.fetch_add(1, Ordering::SeqCst);
+  }
+
+  fn flush(&self) {}
+}
+
+#[cfg(test)]
+impl SharedLogger for CountingLogger {
+  fn level(&self) -> LevelFilter {
+    LevelFilter::Trace
+  }
+
+  fn config(&self) -> Option<&Config> {
+    None
+  }
+
+  fn as_log(self: Box<Self>) -> Box<dyn Log> {
+    Box::new(*self)
+  }
+}
+
+#[test]
+fn dynamic_logger_follows_handle() {
+  let count = Arc::new(AtomicUsize::new(0));
+  let handle = LevelHandle::new(LevelFilter::Warn);
+  let logger = DynamicLogger::new(
+    Box::new(CountingLogger {
+      count: Arc::clone(&count),
+    }),
+    handle.clone(),
+  );
+  let record = |level: Level| {
+    logger
+      .log(&Record::builder().level(level).args(format_args!("Hello")).build())
+  };
+
+  record(Level::Info);
+  assert_eq!(count.load(Ordering::SeqCst), 0);
+
+  assert_eq!(handle.raise(), LevelFilter::Info);
+  record(Level::Info);
+  assert_eq!(count.load(Ordering::SeqCst), 1);
+
+  handle.set(LevelFilter::Off);
+  record(Level::Error);
+  assert_eq!(count.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn level_handle_stops_at_bounds() {
+  let handle = LevelHandle::new(LevelFilter::Debug);
+  assert_eq!(handle.raise(), LevelFilter::Trace);
+  assert_eq!(handle.raise(), LevelFilter::Trace);
+
+  let handle = LevelHandle::new(LevelFilter::Error);
+  assert_eq!(handle.lower(), LevelFilter::Off);
+  assert_eq!(handle.lower(), LevelFilter::Off);
+}
+
+#[test]
+fn open_log_file_fails_on_unwritable_path() {
+  // A path below a regular file can never be created, even as root.
+  let blocker = temp_dir().join(format!(
+    "proxy-router-{}",
+    std::process::id()
+  ));
+  File::create(&blocker).unwrap();
+  let log_path = blocker.join("logs");
+
+  assert!(open_log_file(log_path.to_str().unwrap()).is_err());
+  remove_file(&blocker).unwrap();
+}
+
+#[test]
+fn open_log_file_rotates_latest() {
+  let log_path = temp_dir().join(format!(
+    "proxy-router-logs-{}",
+    std::process::id()
+  ));
+  let log_path = log_path.to_str().unwrap();
+
+  assert!(open_log_file(log_path).is_ok());
+  assert!(open_log_file(log_path).is_ok());
+  assert_eq!(
+    std::fs::read_dir(log_path).unwrap().count(),
+    2
+  );
+  remove_dir_all(log_path).unwrap();
+}
+
+#[test]
+fn packet_trace_lists_header_and_body() {
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+  let packet = Client::parse_packet(
+    Server::build_data_packet(&id, &8080, &separator, &"Hello".into()),
+    &separator,
+  )
+  .unwrap();
+  assert_eq!(
+    packet_trace(
+      Direction::Received,
+      &packet,
+      LevelFilter::Trace
+    ),
+    Some(format!(
+      "<- DATA id={id} port=8080 body[5]: 48 65 6c 6c 6f"
+    ))
+  );
+
+  let packet = Server::parse_packet(
+    Client::build_data_packet(&id, &separator, &vec![0xab; 100]),
+    &separator,
+  )
+  .unwrap();
+  let dump = vec!["ab"; 64].join(" ");
+  assert_eq!(
+    packet_trace(
+      Direction::Sent,
+      &packet,
+      LevelFilter::Trace
+    ),
+    Some(format!(
+      "-> DATA id={id} body[100]: {dump} ..."
+    ))
+  );
+}
+
+#[test]
+fn packet_trace_skipped_below_trace() {
+  let separator = Separator::default();
+  let packet = Client::parse_packet(
+    Server::build_data_packet(
+      &Uuid::new_v4(),
+      &8080,
+      &separator,
+      &"Hello".into(),
+    ),
+    &separator,
+  )
+  .unwrap();
+  assert_eq!(
+    packet_trace(
+      Direction::Received,
+      &packet,
+      LevelFilter::Debug
+    ),
+    None
+  );
+  assert_eq!(
+    packet_trace(
+      Direction::Received,
+      &packet,
+      LevelFilter::Off
+    ),
+    None
+  );
+}
+
+#[test]
+fn syslog_sink_sends_records() {
+  let path = temp_dir().join(format!(
+    "proxy-syslog-{}",
+    Uuid::new_v4()
+  ));
+  let daemon = UnixDatagram::bind(&path).unwrap();
+  let logger = SyslogLogger::at(
+    &path,
+    Facility::LOG_LOCAL0,
+    LevelFilter::Info,
+  )
+  .unwrap();
+
+  logger.log(
+    &Record::builder()
+      .level(Level::Debug)
+      .args(format_args!("below the sink level"))
+      .build(),
+  );
+  logger.log(
+    &Record::builder()
+      .level(Level::Warn)
+      .args(format_args!("upstream went away"))
+      .build(),
+  );
+
+  let mut buffer = [0u8; 1024];
+  let received = daemon.recv(&mut buffer).unwrap();
+  let message = String::from_utf8_lossy(&buffer[..received]).to_string();
+  remove_file(&path).unwrap();
+  // local0 (16) * 8 + warning (4)
+  assert!(
+    message.starts_with("<132>"),
+    "{message}"
+  );
+  assert!(
+    message.ends_with(": upstream went away"),
+    "{message}"
+  );
+}
+
+#[test]
+fn syslog_sinks_compare_by_facility() {
+  let sink = |facility| LogSink::Syslog {
+    facility,
+    level: LevelFilter::Info,
+  };
+  assert_eq!(
+    sink(Facility::LOG_DAEMON),
+    sink(Facility::LOG_DAEMON)
+  );
+  assert_ne!(
+    sink(Facility::LOG_DAEMON),
+    sink(Facility::LOG_LOCAL0)
+  );
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct CapturingLogger {
+  lines: Arc<Mutex<Vec<String>>>,
+  targets: Arc<Mutex<Vec<String>>>,
+}
+
+#[cfg(test)]
+impl Log for CapturingLogger {
+  fn enabled(&self, _: &Metadata) -> bool {
+    true
+  }
+
+  fn log(&self, record: &Record) {
+    self.lines.lock().unwrap().push(record.args().to_string());
+    self.targets.lock().unwrap().push(record.target().to_string());
+  }
+
+  fn flush(&self) {}
+}
+
+#[test]
+fn concurrent_sessions_are_prefixed_apart() {
+  let capture = CapturingLogger::default();
+  let logger = Arc::new(SessionLogger::new(Box::new(
+    capture.clone(),
+  )));
+  let sessions = [SessionId::new(), SessionId::new()];
+  assert_ne!(sessions[0], sessions[1]);
+  // Both sessions log while the other one is in the middle of its own.
+  let barrier = Arc::new(Barrier::new(2));
+  let threads = sessions.map(|session| {
+    let logger = Arc::clone(&logger);
+    let barrier = Arc::clone(&barrier);
+    thread::spawn(move || {
+      in_session(Some(session), || {
+        barrier.wait();
+        logger.log(
+          &Record::builder()
+            .level(Level::Info)
+            .args(format_args!("New connection"))
+            .build(),
+        );
+      })
+    })
+  });
+  for thread in threads {
+    thread.join().unwrap();
+  }
+  logger.log(
+    &Record::builder().level(Level::Info).args(format_args!("Idle")).build(),
+  );
+
+  let mut lines = capture.lines.lock().unwrap().clone();
+  assert_eq!(lines.pop(), Some(String::from("Idle")));
+  lines.sort();
+  let mut expected: Vec<String> = sessions
+    .iter()
+    .map(|session| format!("[{session}] New connection"))
+    .collect();
+  expected.sort();
+  assert_eq!(lines, expected);
+  for session in sessions {
+    assert_eq!(session.to_string().len(), 8);
+  }
+}
+
+#[test]
+fn session_guard_prefixes_until_dropped() {
+  let capture = CapturingLogger::default();
+  let logger = SessionLogger::new(Box::new(capture.clone()));
+  let log = |message| {
+    logger.log(
+      &Record::builder()
+        .level(Level::Info)
+        .target("proxy_router::server")
+        .args(format_args!("{message}"))
+        .build(),
+    )
+  };
+  let session = SessionId::new();
+  {
+    let _session = enter_session(Some(session));
+    log("Authenticated");
+  }
+  log("Idle");
+
+  assert_eq!(
+    *capture.lines.lock().unwrap(),
+    vec![format!("[{session}] Authenticated"), String::from("Idle")]
+  );
+  // The prefixed record still reports where it came from.
+  assert_eq!(
+    *capture.targets.lock().unwrap(),
+    vec!["proxy_router::server"; 2]
+  );
+}
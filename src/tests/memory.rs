@@ -0,0 +1,52 @@
+#[allow(unused_imports)]
+use crate::memory::MemoryStream;
+#[allow(unused_imports)]
+use hydrogen::Stream as HydrogenStream;
+#[allow(unused_imports)]
+use std::{io::ErrorKind, os::unix::io::AsRawFd};
+
+#[test]
+fn memory_stream_round_trip() {
+  let stream = MemoryStream::new(7);
+  let mut handler = stream.clone();
+  assert_eq!(handler.as_raw_fd(), 7);
+
+  stream.feed("Hello".as_bytes());
+  assert_eq!(
+    handler.recv().unwrap(),
+    vec!["Hello".as_bytes().to_vec()]
+  );
+
+  handler.send("World".as_bytes()).unwrap();
+  assert_eq!(
+    stream.take_sent(),
+    "World".as_bytes().to_vec()
+  );
+  assert!(stream.take_sent().is_empty());
+}
+
+#[test]
+fn memory_stream_shutdown() {
+  let stream = MemoryStream::new(7);
+  let mut handler = stream.clone();
+  handler.shutdown().unwrap();
+
+  assert!(stream.is_shutdown());
+  assert!(handler.send("Hello".as_bytes()).is_err());
+  assert!(handler.recv().is_err());
+}
+
+#[test]
+fn memory_stream_hang_up() {
+  let stream = MemoryStream::new(7);
+  let mut handler = stream.clone();
+  stream.feed("Bye".as_bytes());
+  stream.hang_up();
+
+  assert_eq!(
+    handler.recv().unwrap(),
+    vec!["Bye".as_bytes().to_vec()]
+  );
+  let err = handler.recv().unwrap_err();
+  assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
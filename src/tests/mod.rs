@@ -1 +1,7 @@
+mod constants;
 mod functions;
+mod logging;
+mod memory;
+mod replay;
+mod websocket;
+mod window;
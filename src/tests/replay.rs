@@ -0,0 +1,103 @@
+#[allow(unused_imports)]
+use crate::{
+  functions::{
+    Client, HashEncoding, Integrity, ParseError, ParseErrorType, ParseOptions,
+    Separator, Server,
+  },
+  replay::{frame, replay},
+};
+#[allow(unused_imports)]
+use uuid::Uuid;
+
+#[test]
+fn replay_counts_actions() {
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+  let mut capture = Vec::new();
+  for packet in [
+    Client::build_auth_packet(b"secret", &vec![8080], &separator),
+    Client::build_data_packet(&id, &separator, &"hello".into()),
+    Client::build_data_packet(&id, &separator, &"world".into()),
+    Client::build_noop_packet(&separator),
+    b"PING extra\x00".to_vec(),
+  ] {
+    capture.extend(frame(&packet));
+  }
+  let bad_at = capture.len();
+  capture.extend(frame(b"DATA not-an-id\x00"));
+  capture.extend(frame(&Server::close_connection_packet(
+    &id, &separator,
+  )));
+
+  let report = replay(
+    &capture,
+    &separator,
+    &ParseOptions::default(),
+    Server::parse_packet_with,
+  );
+  assert_eq!(report.actions.get("AUTH"), Some(&1));
+  assert_eq!(report.actions.get("DATA"), Some(&2));
+  assert_eq!(report.actions.get("NOOP"), Some(&1));
+  assert_eq!(report.actions.get("PING"), Some(&1));
+  assert_eq!(report.actions.get("CLOSE"), Some(&1));
+  assert_eq!(report.parsed(), 6);
+  assert_eq!(report.errors.len(), 1);
+  assert_eq!(report.errors[0].0, bad_at);
+  assert!(report.truncated_at.is_none());
+}
+
+#[test]
+fn replay_reports_cut_frame() {
+  let separator = Separator::default();
+  let mut capture = frame(&Client::build_noop_packet(&separator));
+  let cut_at = capture.len();
+  let mut cut = frame(&Client::build_noop_packet(&separator));
+  cut.pop();
+  capture.extend(cut);
+
+  let report = replay(
+    &capture,
+    &separator,
+    &ParseOptions::default(),
+    Server::parse_packet_with,
+  );
+  assert_eq!(report.parsed(), 1);
+  assert_eq!(report.truncated_at, Some(cut_at));
+}
+
+#[test]
+fn replay_follows_hash_encoding() {
+  let separator = Separator::default();
+  let id = Uuid::new_v4();
+  let capture = frame(&Server::build_data_packet_with(
+    &id,
+    &8080,
+    &separator,
+    &"hello".into(),
+    HashEncoding::Base64,
+    Integrity::Dual,
+  ));
+  let options = ParseOptions {
+    hash_encoding: HashEncoding::Base64,
+    ..ParseOptions::default()
+  };
+
+  let report = replay(
+    &capture,
+    &separator,
+    &options,
+    Client::parse_packet_with,
+  );
+  assert_eq!(report.actions.get("DATA"), Some(&1));
+  assert!(report.errors.is_empty());
+
+  // Read as hex, the hashes don't check out.
+  let report = replay(
+    &capture,
+    &separator,
+    &ParseOptions::default(),
+    Client::parse_packet_with,
+  );
+  assert_eq!(report.parsed(), 0);
+  assert_eq!(report.errors.len(), 1);
+}
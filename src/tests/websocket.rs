@@ -0,0 +1,121 @@
+#[allow(unused_imports)]
+use crate::websocket::{
+  accept_key, accept_upgrade, check_upgrade, client_frame, new_key,
+  server_frame, upgrade_request, BufferBudget, Decoder, Message,
+  WebSocketError,
+};
+
+#[test]
+fn accept_key_matches_rfc() {
+  assert_eq!(
+    accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+    "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+  );
+}
+
+#[test]
+fn upgrade_round_trip() {
+  let key = new_key();
+  let request = upgrade_request("localhost:8080", "/proxy", &key);
+  assert_eq!(
+    accept_upgrade(&request[..request.len() - 2], "/proxy"),
+    Ok(None)
+  );
+  let (response, consumed) =
+    accept_upgrade(&request, "/proxy").unwrap().unwrap();
+  assert_eq!(consumed, request.len());
+  assert_eq!(
+    check_upgrade(&response, &key),
+    Ok(Some(response.len()))
+  );
+  assert!(matches!(
+    check_upgrade(&response, &new_key()),
+    Err(WebSocketError::Handshake(_))
+  ));
+  assert!(matches!(
+    accept_upgrade(&request, "/other"),
+    Err(WebSocketError::Handshake(_))
+  ));
+}
+
+#[test]
+fn decoder_reassembles_split_frames() {
+  let long = vec![7u8; 70_000];
+  let mut bytes = client_frame(b"AUTH");
+  bytes.extend(server_frame(&long));
+  bytes.extend(client_frame(&[]));
+  let mut decoder = Decoder::new();
+  let mut messages = Vec::new();
+  for chunk in bytes.chunks(1000) {
+    messages.extend(decoder.push(chunk).unwrap());
+  }
+  assert_eq!(
+    messages,
+    vec![
+      Message::Binary(b"AUTH".to_vec()),
+      Message::Binary(long),
+      Message::Binary(Vec::new()),
+    ]
+  );
+}
+
+#[test]
+fn decoder_joins_continuations() {
+  // An unmasked "Hel" + "lo" split across a text frame and a continuation,
+  // with a ping in between, as in RFC 6455 section 5.7
+  let bytes =
+    [0x01, 0x03, b'H', b'e', b'l', 0x89, 0x00, 0x80, 0x02, b'l', b'o'];
+  let mut decoder = Decoder::new();
+  assert_eq!(
+    decoder.push(&bytes),
+    Ok(vec![
+      Message::Ping(Vec::new()),
+      Message::Binary(b"Hello".to_vec())
+    ])
+  );
+  assert!(matches!(
+    decoder.push(&[0xC2, 0x00]),
+    Err(WebSocketError::Frame(_))
+  ));
+}
+
+#[test]
+fn decoders_share_a_buffer_budget() {
+  let budget = BufferBudget::new(100_000);
+  let frame = client_frame(&vec![7u8; 50_000]);
+  let (partial, rest) = frame.split_at(40_000);
+  let mut decoders: Vec<Decoder> =
+    (0..3).map(|_| Decoder::with_budget(budget.clone())).collect();
+  assert_eq!(
+    decoders[0].push(partial),
+    Ok(Vec::new())
+  );
+  assert_eq!(
+    decoders[1].push(partial),
+    Ok(Vec::new())
+  );
+  assert_eq!(budget.used(), 80_000);
+  assert!(!budget.is_spent());
+  // Each decoder is under any per-message cap, but together they are full.
+  assert_eq!(
+    decoders[2].push(partial),
+    Ok(Vec::new())
+  );
+  assert_eq!(budget.used(), 120_000);
+  assert!(budget.is_spent());
+
+  // A finished message is handed out and no longer counts.
+  assert_eq!(
+    decoders[0].push(&rest[..5_000]),
+    Ok(Vec::new())
+  );
+  assert_eq!(
+    decoders[0].push(&rest[5_000..]),
+    Ok(vec![Message::Binary(vec![7u8; 50_000])])
+  );
+  assert_eq!(budget.used(), 80_000);
+  assert!(!budget.is_spent());
+
+  decoders.clear();
+  assert_eq!(budget.used(), 0);
+}
@@ -0,0 +1,84 @@
+#[allow(unused_imports)]
+use crate::window::Window;
+#[allow(unused_imports)]
+use std::{sync::Arc, thread, time::Duration};
+#[allow(unused_imports)]
+use tokio::time::timeout;
+
+#[test]
+fn window_full_refuses_more() {
+  let window = Window::new(3);
+  for _ in 0..3 {
+    assert!(window.try_reserve());
+  }
+  assert_eq!(window.in_flight(), 3);
+  assert!(!window.try_reserve());
+  assert_eq!(window.in_flight(), 3);
+}
+
+#[test]
+fn ack_advances_window() {
+  let window = Window::new(2);
+  assert!(window.try_reserve());
+  assert!(window.try_reserve());
+  assert!(!window.try_reserve());
+
+  assert!(window.ack(1));
+  assert_eq!(window.in_flight(), 1);
+  assert!(window.try_reserve());
+  assert!(!window.try_reserve());
+
+  // Acks only ever move forward, and never past what was sent.
+  assert!(!window.ack(1));
+  assert!(!window.ack(4));
+  assert!(window.ack(3));
+  assert_eq!(window.in_flight(), 0);
+}
+
+#[tokio::test]
+async fn reserve_waits_for_ack() {
+  let window = Arc::new(Window::new(1));
+  assert!(window.reserve().await);
+  assert!(
+    timeout(
+      Duration::from_millis(50),
+      window.reserve()
+    )
+    .await
+    .is_err(),
+    "The window is full, reserve should wait"
+  );
+
+  let waiting = tokio::spawn({
+    let window = Arc::clone(&window);
+    async move { window.reserve().await }
+  });
+  tokio::task::yield_now().await;
+  assert!(window.ack(1));
+  assert!(timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap());
+  assert_eq!(window.in_flight(), 1);
+}
+
+#[test]
+fn reserve_blocking_released_by_ack_or_close() {
+  let window = Arc::new(Window::new(1));
+  assert!(window.reserve_blocking());
+
+  let waiting = thread::spawn({
+    let window = Arc::clone(&window);
+    move || window.reserve_blocking()
+  });
+  thread::sleep(Duration::from_millis(50));
+  assert!(!waiting.is_finished());
+  assert!(window.ack(1));
+  assert!(waiting.join().unwrap());
+
+  let waiting = thread::spawn({
+    let window = Arc::clone(&window);
+    move || window.reserve_blocking()
+  });
+  thread::sleep(Duration::from_millis(50));
+  window.close();
+  assert!(!waiting.join().unwrap());
+  assert!(!window.try_reserve());
+}
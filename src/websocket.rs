@@ -0,0 +1,544 @@
+//! Carries the control connection inside a WebSocket, for networks where only
+//! HTTP gets through. Each packet travels as one binary message; the packets
+//! themselves are the same as over plain TCP.
+
+use std::{
+  fmt::{Display, Formatter},
+  io,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  task::{Context, Poll},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use tokio::io::AsyncWrite;
+use uuid::Uuid;
+
+use crate::constants::MAX_WEBSOCKET_MESSAGE_BYTES;
+
+/// Appended to the client's key to compute the accept key, from RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// How the control connection is carried
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Transport {
+  /// Packets written straight to the TCP connection
+  #[default]
+  Tcp,
+  /// Packets carried in WebSocket binary messages, upgraded from an HTTP
+  /// request for `path`
+  WebSocket { path: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WebSocketError {
+  /// The upgrade request or response was not one this side accepts
+  Handshake(String),
+  /// A frame broke the framing rules
+  Frame(String),
+  /// A message longer than [`MAX_WEBSOCKET_MESSAGE_BYTES`]
+  TooLong,
+}
+
+impl WebSocketError {
+  pub fn value(&self) -> String {
+    match self {
+      | WebSocketError::Handshake(reason) => {
+        format!("Invalid WebSocket handshake: {reason}")
+      },
+      | WebSocketError::Frame(reason) => {
+        format!("Invalid WebSocket frame: {reason}")
+      },
+      | WebSocketError::TooLong => "WebSocket message too long".to_string(),
+    }
+  }
+}
+
+impl Display for WebSocketError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.value())
+  }
+}
+
+/// What a WebSocket peer sent, once a whole message is in
+#[derive(Debug, PartialEq)]
+pub enum Message {
+  /// A packet
+  Binary(Vec<u8>),
+  Ping(Vec<u8>),
+  /// The peer is closing the WebSocket
+  Close,
+}
+
+/// A fresh random `Sec-WebSocket-Key`
+pub fn new_key() -> String {
+  STANDARD.encode(Uuid::new_v4().as_bytes())
+}
+
+/// The `Sec-WebSocket-Accept` a server answers `key` with
+pub fn accept_key(key: &str) -> String {
+  STANDARD.encode(Sha1::digest(format!(
+    "{key}{ACCEPT_GUID}"
+  )))
+}
+
+/// The HTTP request asking `host` to upgrade `path` to a WebSocket
+pub fn upgrade_request(host: &str, path: &str, key: &str) -> Vec<u8> {
+  format!(
+    "GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+  )
+  .into_bytes()
+}
+
+/// The HTTP response refusing an upgrade
+pub fn rejection() -> Vec<u8> {
+  b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n".to_vec()
+}
+
+/// The HTTP response refusing a connection the server has no room for
+pub fn unavailable() -> Vec<u8> {
+  b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n".to_vec()
+}
+
+/// The start line and headers of an HTTP request or response
+struct Head {
+  start: String,
+  /// Names lowercased
+  headers: Vec<(String, String)>,
+  /// Bytes the head took, blank line included
+  consumed: usize,
+}
+
+impl Head {
+  fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .find(|(header, _)| header == name)
+      .map(|(_, value)| value.as_str())
+  }
+}
+
+/// Reads an HTTP head off `bytes`, or None until the whole head is in
+fn read_head(bytes: &[u8]) -> Result<Option<Head>, WebSocketError> {
+  let end = match bytes.windows(4).position(|window| window == b"\r\n\r\n") {
+    | Some(end) => end,
+    | None => return Ok(None),
+  };
+  let head = std::str::from_utf8(&bytes[..end])
+    .map_err(|_| WebSocketError::Handshake(String::from("not UTF-8")))?;
+  let mut lines = head.split("\r\n");
+  let start = lines.next().unwrap_or_default().to_string();
+  let headers = lines
+    .filter_map(|line| line.split_once(":"))
+    .map(|(name, value)| {
+      (
+        name.trim().to_lowercase(),
+        value.trim().to_string(),
+      )
+    })
+    .collect();
+  Ok(Some(Head {
+    start,
+    headers,
+    consumed: end + 4,
+  }))
+}
+
+/// Reads a client's upgrade request for `path`, returning the response to
+/// send back and how many bytes the request took, or None while it is still
+/// coming in
+pub fn accept_upgrade(
+  request: &[u8], path: &str,
+) -> Result<Option<(Vec<u8>, usize)>, WebSocketError> {
+  let head = match read_head(request)? {
+    | Some(head) => head,
+    | None => return Ok(None),
+  };
+  if head.start != format!("GET {path} HTTP/1.1") {
+    return Err(WebSocketError::Handshake(format!(
+      "expected a request for {path}, got {:?}",
+      head.start
+    )));
+  }
+  let upgrade = head.header("upgrade").unwrap_or_default();
+  if !upgrade.eq_ignore_ascii_case("websocket") {
+    return Err(WebSocketError::Handshake(String::from(
+      "not an upgrade to websocket",
+    )));
+  }
+  let key = head.header("sec-websocket-key").ok_or_else(|| {
+    WebSocketError::Handshake(String::from("no Sec-WebSocket-Key"))
+  })?;
+  let response = format!(
+    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+    accept_key(key)
+  );
+  Ok(Some((
+    response.into_bytes(),
+    head.consumed,
+  )))
+}
+
+/// Checks the server's answer to an upgrade request sent with `key`,
+/// returning how many bytes the response took, or None while it is still
+/// coming in
+pub fn check_upgrade(
+  response: &[u8], key: &str,
+) -> Result<Option<usize>, WebSocketError> {
+  let head = match read_head(response)? {
+    | Some(head) => head,
+    | None => return Ok(None),
+  };
+  if !head.start.starts_with("HTTP/1.1 101") {
+    return Err(WebSocketError::Handshake(format!(
+      "server answered {:?}",
+      head.start
+    )));
+  }
+  if head.header("sec-websocket-accept") != Some(&accept_key(key)) {
+    return Err(WebSocketError::Handshake(String::from(
+      "wrong Sec-WebSocket-Accept",
+    )));
+  }
+  Ok(Some(head.consumed))
+}
+
+/// A single, final frame. Clients mask every frame they send, servers never
+/// do.
+fn frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+  let mut frame = Vec::with_capacity(payload.len() + 14);
+  frame.push(0x80 | opcode);
+  let masked = if mask.is_some() {
+    0x80
+  } else {
+    0
+  };
+  match payload.len() {
+    | len if len < 126 => frame.push(masked | len as u8),
+    | len if len <= u16::MAX as usize => {
+      frame.push(masked | 126);
+      frame.extend((len as u16).to_be_bytes());
+    },
+    | len => {
+      frame.push(masked | 127);
+      frame.extend((len as u64).to_be_bytes());
+    },
+  }
+  match mask {
+    | Some(mask) => {
+      frame.extend(mask);
+      frame.extend(
+        payload.iter().enumerate().map(|(at, byte)| byte ^ mask[at % 4]),
+      );
+    },
+    | None => frame.extend(payload),
+  }
+  frame
+}
+
+fn new_mask() -> [u8; 4] {
+  let id = Uuid::new_v4();
+  let bytes = id.as_bytes();
+  [bytes[0], bytes[1], bytes[2], bytes[3]]
+}
+
+/// A binary message carrying `packet`, as the server sends it
+pub fn server_frame(packet: &[u8]) -> Vec<u8> {
+  frame(BINARY, packet, None)
+}
+
+/// A binary message carrying `packet`, masked as the client sends it
+pub fn client_frame(packet: &[u8]) -> Vec<u8> {
+  frame(BINARY, packet, Some(new_mask()))
+}
+
+/// The answer to a ping carrying `payload`, as the server sends it
+pub fn pong_frame(payload: &[u8]) -> Vec<u8> {
+  frame(PONG, payload, None)
+}
+
+/// Tells the peer the WebSocket is closing, as the server sends it
+pub fn close_frame() -> Vec<u8> {
+  frame(CLOSE, &[], None)
+}
+
+/// Bytes buffered by every [`Decoder`] sharing it, so that many connections
+/// with partial frames can't add up to unbounded memory. The decoders already
+/// sharing it may go past `max`, as each of them is held to
+/// [`MAX_WEBSOCKET_MESSAGE_BYTES`] anyway, but no more should be let in until
+/// they are back under it.
+#[derive(Clone, Debug)]
+pub struct BufferBudget {
+  max: usize,
+  used: Arc<AtomicUsize>,
+}
+
+impl BufferBudget {
+  pub fn new(max: usize) -> Self {
+    Self {
+      max,
+      used: Arc::new(AtomicUsize::new(0)),
+    }
+  }
+
+  /// Bytes held right now, across all decoders
+  pub fn used(&self) -> usize {
+    self.used.load(Ordering::SeqCst)
+  }
+
+  /// Whether the decoders sharing it hold `max` bytes or more
+  pub fn is_spent(&self) -> bool {
+    self.used() >= self.max
+  }
+
+  fn take(&self, bytes: usize) {
+    self.used.fetch_add(bytes, Ordering::SeqCst);
+  }
+
+  fn give_back(&self, bytes: usize) {
+    self.used.fetch_sub(bytes, Ordering::SeqCst);
+  }
+}
+
+/// Reassembles messages from the bytes of a WebSocket, whatever chunks they
+/// arrive in
+#[derive(Default)]
+pub struct Decoder {
+  buffer: Vec<u8>,
+  /// The frames so far of a message split across several
+  fragments: Option<Vec<u8>>,
+  budget: Option<BufferBudget>,
+  /// Bytes taken out of `budget` for `buffer` and `fragments`
+  held: usize,
+}
+
+impl Decoder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// A decoder whose buffered bytes count against `budget`
+  pub fn with_budget(budget: BufferBudget) -> Self {
+    Self {
+      buffer: Vec::new(),
+      fragments: None,
+      budget: Some(budget),
+      held: 0,
+    }
+  }
+
+  /// Takes in `bytes`, returning every message they complete
+  pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<Message>, WebSocketError> {
+    if let Some(budget) = &self.budget {
+      budget.take(bytes.len());
+      self.held += bytes.len();
+    }
+    self.buffer.extend_from_slice(bytes);
+    let messages = self.decode();
+    // Whatever was turned into messages is no longer held.
+    if let Some(budget) = &self.budget {
+      let held =
+        self.buffer.len() + self.fragments.as_ref().map_or(0, Vec::len);
+      budget.give_back(self.held.saturating_sub(held));
+      self.held = held.min(self.held);
+    }
+    messages
+  }
+
+  fn decode(&mut self) -> Result<Vec<Message>, WebSocketError> {
+    let mut messages = Vec::new();
+    while let Some((fin, opcode, payload)) = self.next_frame()? {
+      match opcode {
+        | CONTINUATION => {
+          let mut message = self.fragments.take().ok_or_else(|| {
+            WebSocketError::Frame(String::from(
+              "continuation without a start",
+            ))
+          })?;
+          message.extend(payload);
+          if message.len() > MAX_WEBSOCKET_MESSAGE_BYTES {
+            return Err(WebSocketError::TooLong);
+          }
+          match fin {
+            | true => messages.push(Message::Binary(message)),
+            | false => self.fragments = Some(message),
+          }
+        },
+        | TEXT | BINARY if self.fragments.is_some() => {
+          return Err(WebSocketError::Frame(String::from(
+            "new message before the last one ended",
+          )))
+        },
+        | TEXT | BINARY => match fin {
+          | true => messages.push(Message::Binary(payload)),
+          | false => self.fragments = Some(payload),
+        },
+        | CLOSE => messages.push(Message::Close),
+        | PING => messages.push(Message::Ping(payload)),
+        | PONG => (),
+        | opcode => {
+          return Err(WebSocketError::Frame(format!(
+            "unknown opcode {opcode:#x}"
+          )))
+        },
+      }
+    }
+    Ok(messages)
+  }
+
+  /// Takes the next whole frame off the buffer, unmasked
+  fn next_frame(
+    &mut self,
+  ) -> Result<Option<(bool, u8, Vec<u8>)>, WebSocketError> {
+    let (first, second) = match self.buffer.get(..2) {
+      | Some(head) => (head[0], head[1]),
+      | None => return Ok(None),
+    };
+    if first & 0x70 != 0 {
+      return Err(WebSocketError::Frame(String::from(
+        "reserved bits set",
+      )));
+    }
+    let fin = first & 0x80 != 0;
+    let opcode = first & 0x0F;
+    let (len, mut at) = match second & 0x7F {
+      | 126 => match self.buffer.get(2..4) {
+        | Some(len) => (
+          u16::from_be_bytes([len[0], len[1]]) as u64,
+          4,
+        ),
+        | None => return Ok(None),
+      },
+      | 127 => match self.buffer.get(2..10) {
+        | Some(len) => {
+          let mut bytes = [0u8; 8];
+          bytes.copy_from_slice(len);
+          (u64::from_be_bytes(bytes), 10)
+        },
+        | None => return Ok(None),
+      },
+      | len => (len as u64, 2),
+    };
+    if opcode >= CLOSE && (len > 125 || !fin) {
+      return Err(WebSocketError::Frame(String::from(
+        "control frame too long or fragmented",
+      )));
+    }
+    if len > MAX_WEBSOCKET_MESSAGE_BYTES as u64 {
+      return Err(WebSocketError::TooLong);
+    }
+    let mask = match second & 0x80 != 0 {
+      | true => match self.buffer.get(at..at + 4) {
+        | Some(mask) => {
+          at += 4;
+          Some([mask[0], mask[1], mask[2], mask[3]])
+        },
+        | None => return Ok(None),
+      },
+      | false => None,
+    };
+    let end = at + len as usize;
+    if self.buffer.len() < end {
+      return Ok(None);
+    }
+    let mut payload: Vec<u8> = self.buffer.drain(..end).skip(at).collect();
+    if let Some(mask) = mask {
+      for (at, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[at % 4];
+      }
+    }
+    Ok(Some((fin, opcode, payload)))
+  }
+}
+
+impl Drop for Decoder {
+  fn drop(&mut self) {
+    if let Some(budget) = &self.budget {
+      budget.give_back(self.held);
+    }
+  }
+}
+
+/// Writes everything handed to it as masked binary messages on `inner`, one
+/// message per write, for the client end
+pub struct WebSocketWriter<W> {
+  inner: W,
+  /// The frame being written and how much of it is out
+  pending: Vec<u8>,
+  written: usize,
+  /// Bytes of the caller's buffer `pending` carries
+  accepted: usize,
+}
+
+impl<W> WebSocketWriter<W> {
+  pub fn new(inner: W) -> Self {
+    Self {
+      inner,
+      pending: Vec::new(),
+      written: 0,
+      accepted: 0,
+    }
+  }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WebSocketWriter<W> {
+  fn poll_write(
+    self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    let this = self.get_mut();
+    // A write that returned Pending is retried with the same buffer, so the
+    // frame already built for it is carried on with.
+    if this.pending.is_empty() {
+      this.pending = client_frame(buf);
+      this.written = 0;
+      this.accepted = buf.len();
+    }
+    while this.written < this.pending.len() {
+      match Pin::new(&mut this.inner)
+        .poll_write(cx, &this.pending[this.written..])
+      {
+        | Poll::Ready(Ok(0)) => {
+          this.pending.clear();
+          return Poll::Ready(Err(io::Error::from(
+            io::ErrorKind::WriteZero,
+          )));
+        },
+        | Poll::Ready(Ok(written)) => this.written += written,
+        | Poll::Ready(Err(err)) => {
+          this.pending.clear();
+          return Poll::Ready(Err(err));
+        },
+        | Poll::Pending => return Poll::Pending,
+      }
+    }
+    this.pending.clear();
+    Poll::Ready(Ok(this.accepted))
+  }
+
+  fn poll_flush(
+    self: Pin<&mut Self>, cx: &mut Context<'_>,
+  ) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(
+    self: Pin<&mut Self>, cx: &mut Context<'_>,
+  ) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+  }
+}
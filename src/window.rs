@@ -0,0 +1,130 @@
+//! Flow control for the data packets of one connection, so a fast connection
+//! can't queue up packets without end toward a slow peer
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use tokio::sync::Notify;
+
+#[derive(Default)]
+struct State {
+  /// Data packets sent so far, which is also the sequence of the last one
+  sent: u64,
+  /// Sequence of the last data packet the other side acknowledged
+  acked: u64,
+  closed: bool,
+}
+
+/// Counts the data packets sent on a connection against the acknowledgements
+/// coming back for them, holding senders back while `size` of them are in
+/// flight
+pub struct Window {
+  size: u64,
+  state: Mutex<State>,
+  /// Wakes threads blocked in [`Window::reserve_blocking`]
+  room: Condvar,
+  /// Wakes tasks waiting in [`Window::reserve`]
+  notify: Notify,
+}
+
+impl Window {
+  pub fn new(size: u64) -> Self {
+    Self {
+      size,
+      state: Mutex::new(State::default()),
+      room: Condvar::new(),
+      notify: Notify::new(),
+    }
+  }
+
+  fn state(&self) -> MutexGuard<'_, State> {
+    match self.state.lock() {
+      | Ok(state) => state,
+      | Err(err) => err.into_inner(),
+    }
+  }
+
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// Data packets sent but not acknowledged yet
+  pub fn in_flight(&self) -> u64 {
+    let state = self.state();
+    state.sent - state.acked
+  }
+
+  /// Takes a slot for one more data packet, or returns None while the window
+  /// is full. Some(false) means the window was closed and nothing more should
+  /// be sent.
+  fn take(&self, state: &mut State) -> Option<bool> {
+    if state.closed {
+      return Some(false);
+    }
+    if state.sent - state.acked >= self.size {
+      return None;
+    }
+    state.sent += 1;
+    Some(true)
+  }
+
+  /// Takes a slot for one more data packet, if there is one
+  pub fn try_reserve(&self) -> bool {
+    self.take(&mut self.state()).unwrap_or(false)
+  }
+
+  /// Blocks the thread until there is room for one more data packet,
+  /// returning false if the window was closed instead
+  pub fn reserve_blocking(&self) -> bool {
+    let mut state = self.state();
+    loop {
+      if let Some(reserved) = self.take(&mut state) {
+        return reserved;
+      }
+      state = match self.room.wait(state) {
+        | Ok(state) => state,
+        | Err(err) => err.into_inner(),
+      };
+    }
+  }
+
+  /// Waits until there is room for one more data packet, returning false if
+  /// the window was closed instead
+  pub async fn reserve(&self) -> bool {
+    loop {
+      let notified = self.notify.notified();
+      tokio::pin!(notified);
+      // Registered before looking, so an ack landing in between still wakes
+      // this task up.
+      notified.as_mut().enable();
+      if let Some(reserved) = self.take(&mut self.state()) {
+        return reserved;
+      }
+      notified.await;
+    }
+  }
+
+  /// Records that the other side received every data packet up to
+  /// `sequence`, returning false when that acknowledges nothing new or more
+  /// than was sent
+  pub fn ack(&self, sequence: u64) -> bool {
+    let mut state = self.state();
+    if sequence <= state.acked || sequence > state.sent {
+      return false;
+    }
+    state.acked = sequence;
+    drop(state);
+    self.wake();
+    true
+  }
+
+  /// Lets every sender still waiting go, and any later one fail right away
+  pub fn close(&self) {
+    self.state().closed = true;
+    self.wake();
+  }
+
+  fn wake(&self) {
+    self.room.notify_all();
+    self.notify.notify_waiters();
+  }
+}